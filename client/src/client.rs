@@ -1,4 +1,6 @@
-use std::io::{Read, Write};
+use crate::tls::{connect_tls, TlsOptions};
+use crate::transport::Transport;
+use std::io::{ErrorKind, Read, Write};
 use std::iter;
 use std::net::TcpStream;
 use std::sync::{mpsc, Arc, Condvar, Mutex};
@@ -18,6 +20,34 @@ use std::sync::mpsc::{channel, Receiver, Sender};
 use std::time::Instant;
 use std::{sync, thread};
 
+/// Publish received from the broker
+#[derive(Clone, Debug, PartialEq)]
+pub struct Message {
+    pub topic: String,
+    pub message: String,
+    pub qos: u8,
+    pub retain: bool,
+}
+
+/// Bytes of the first packet of buffer, None if it is not complete. The publish packets
+/// carry the length of the message after the remaining length.
+fn packet_size(buffer: &[u8]) -> Option<usize> {
+    let mut length = 0;
+    for (index, byte) in buffer.iter().skip(1).take(4).enumerate() {
+        length |= ((byte & 0x7F) as usize) << (7 * index);
+        if byte & 0x80 == 0 {
+            let message_length = if buffer[0] & 0xF0 == control_type::PUBLISH {
+                2
+            } else {
+                0
+            };
+            let size = 2 + index + length + message_length;
+            return Some(size).filter(|size| buffer.len() >= *size);
+        }
+    }
+    None
+}
+
 #[allow(dead_code)]
 pub struct Client {
     server_host: String,
@@ -33,10 +63,12 @@ pub struct Client {
     username: String,
     password: String,
     connect_retries: usize,
+    tls_options: Option<TlsOptions>,
     tx_out: Arc<Mutex<Sender<String>>>,
     rx_out: Arc<Mutex<Receiver<String>>>,
     tx_events_handler: Arc<Mutex<Sender<Vec<u8>>>>,
     rx_events_handler: Arc<Mutex<Receiver<Vec<u8>>>>,
+    tx_messages: Option<Sender<Message>>, // receives the publishes when set
 }
 
 impl Client {
@@ -73,10 +105,12 @@ impl Client {
             username: String::from(""),
             password: String::from(""),
             connect_retries,
+            tls_options: None,
             tx_out: Arc::new(Mutex::new(tx_out)),
             rx_out: Arc::new(Mutex::new(rx_out)),
             tx_events_handler: Arc::new(Mutex::new(tx_events_handler)),
             rx_events_handler: Arc::new(Mutex::new(rx_events_handler)),
+            tx_messages: None,
         }
     }
 
@@ -85,11 +119,30 @@ impl Client {
         self.keepalive_interval = keepalive_interval;
     }
 
+    /// Connects to the broker through TLS on the next connect
+    #[allow(dead_code)]
+    pub fn set_tls(&mut self, tls_options: TlsOptions) {
+        self.tls_options = Some(tls_options);
+    }
+
+    /// Client identifier sent on the next connect, a random one is used by default
+    #[allow(dead_code)]
+    pub fn set_client_identifier(&mut self, client_identifier: String) {
+        self.client_identifier = client_identifier;
+    }
+
+    /// Sends the publishes received from the next connect to tx_messages
+    #[allow(dead_code)]
+    pub fn set_message_sender(&mut self, tx_messages: Sender<Message>) {
+        self.tx_messages = Some(tx_messages);
+    }
+
     fn print_all(text: String, tx_out: sync::Arc<Mutex<Sender<String>>>) {
         println!("{}", text);
         match tx_out.lock() {
             Ok(tx_out_) => {
-                tx_out_.send(text).unwrap();
+                // nobody reads the messages once the client is dropped
+                let _ = tx_out_.send(text);
             }
             Err(e) => {
                 println!("{}", e);
@@ -131,8 +184,9 @@ impl Client {
         let packet = Packet::<VariableHeader, Payload>::new();
         let packet = packet.disconnect();
         let tx = tx.lock().unwrap();
-        tx.send(packet.value())
-            .expect("Cannot send disconnect packet");
+        if tx.send(packet.value()).is_err() {
+            println!("Cannot send disconnect packet");
+        }
     }
 
     pub fn keepalive(
@@ -140,15 +194,13 @@ impl Client {
         keepalive_interval: usize,
         pair: Arc<(Mutex<bool>, Condvar)>,
     ) {
-        fn send_keepalive(tx: Arc<Mutex<Sender<Vec<u8>>>>) {
+        // false once the client is dropped
+        fn send_keepalive(tx: Arc<Mutex<Sender<Vec<u8>>>>) -> bool {
             let packet = Packet::<VariableHeader, Payload>::new();
             let packet = packet.pingreq();
             let msg = packet.value();
             println!("sending keepalive");
-            tx.lock()
-                .unwrap()
-                .send(msg)
-                .expect("Could not write to stream sending pingreq");
+            tx.lock().unwrap().send(msg).is_ok()
         }
 
         // if keepalive is set to zero disable
@@ -163,7 +215,9 @@ impl Client {
             .spawn(move || {
                 let (lock, cvar) = &*pair;
                 loop {
-                    send_keepalive(tx.clone());
+                    if !send_keepalive(tx.clone()) {
+                        break;
+                    }
                     let received = lock.lock().unwrap();
                     let result = cvar
                         .wait_timeout(received, Duration::from_secs(keepalive_interval as u64))
@@ -204,6 +258,18 @@ impl Client {
         self.password = password;
         match TcpStream::connect(self.server_host.to_string() + ":" + &self.server_port) {
             Ok(stream) => {
+                let stream: Box<dyn Transport> = match &self.tls_options {
+                    Some(tls_options) => {
+                        match connect_tls(stream, &self.server_host, tls_options) {
+                            Ok(tls_stream) => Box::new(tls_stream),
+                            Err(e) => {
+                                println!("Failed to establish TLS session: {}", e);
+                                return Err("Failed to establish TLS session");
+                            }
+                        }
+                    }
+                    None => Box::new(stream),
+                };
                 Client::print_all(
                     format!(
                         "Successfully connected to server in port {}",
@@ -252,9 +318,15 @@ impl Client {
                     self.keepalive_interval.into(),
                     self.keepalive_pair.clone(),
                     self.tx_out.clone(),
+                    self.tx_messages.clone(),
                 );
 
-                self.handle_io(stream_, self.rx.clone(), self.tx_events_handler.clone());
+                self.handle_io(
+                    stream_,
+                    self.rx.clone(),
+                    self.tx_events_handler.clone(),
+                    self.client_connection.clone(),
+                );
 
                 Ok(self.rx_out.clone())
             }
@@ -267,85 +339,108 @@ impl Client {
 
     fn handle_io(
         &mut self,
-        stream: Arc<Mutex<TcpStream>>,
+        stream: Arc<Mutex<Box<dyn Transport>>>,
         rx: Arc<Mutex<Receiver<Vec<u8>>>>,
         tx_events_handler: Arc<Mutex<Sender<Vec<u8>>>>,
+        client_connection: Arc<AtomicBool>,
     ) {
         let _handle_io = thread::Builder::new()
             .name("Thread: IO Events stream".to_string())
-            .spawn(move || loop {
-                // lock stream
-                let mut stream_ = stream.lock().unwrap();
-
-                // Stream Writter
-                // try to read from rx channel to send data to stream
-                let rx_guard = rx.lock().unwrap();
-                match rx_guard.try_recv() {
-                    Ok(msg) => {
-                        if msg[0] == control_type::DISCONNECT as u8 {
-                            // disconnect
-                            stream_.write_all(&msg).unwrap();
-                            println!("Thread IO sended the disconnect message");
-                            thread::sleep(Duration::from_secs(2));
-                            match stream_.shutdown(std::net::Shutdown::Both) {
-                                Ok(_) => {
-                                    println!("Stream shutdown");
+            .spawn(move || {
+                // bytes read that do not make a whole packet yet
+                let mut pending: Vec<u8> = Vec::new();
+                'io: loop {
+                    // lock stream
+                    let mut stream_ = stream.lock().unwrap();
+
+                    // Stream Writter
+                    // send to the stream every packet waiting in the rx channel
+                    let rx_guard = rx.lock().unwrap();
+                    loop {
+                        match rx_guard.try_recv() {
+                            Ok(msg) => {
+                                if msg[0] == control_type::DISCONNECT as u8 {
+                                    // disconnect
+                                    let _ = stream_.write_all(&msg);
+                                    println!("Thread IO sended the disconnect message");
+                                    thread::sleep(Duration::from_secs(2));
+                                    match stream_.shutdown(std::net::Shutdown::Both) {
+                                        Ok(_) => {
+                                            println!("Stream shutdown");
+                                        }
+                                        Err(_e) => {}
+                                    }
+                                    client_connection.store(false, Ordering::SeqCst);
+                                    break 'io;
+                                }
+                                println!(
+                                    "Thread IO got a msg to send with packet ID: {:?}",
+                                    msg[0]
+                                );
+                                // send message to stream
+                                if let Err(e) = stream_.write_all(&msg) {
+                                    println!("Thread IO cannot write to the stream: {}", e);
+                                    client_connection.store(false, Ordering::SeqCst);
+                                    break 'io;
                                 }
-                                Err(_e) => {}
+                                println!("Thread IO sended the message");
+                            }
+                            Err(mpsc::TryRecvError::Empty) => break,
+                            Err(mpsc::TryRecvError::Disconnected) => {
+                                println!("Thread IO channel is closed");
+                                break 'io;
                             }
-                            break;
-                        }
-                        println!("Thread IO got a msg to send with packet ID: {:?}", msg[0]);
-                        // send message to stream
-                        stream_.write_all(&msg).unwrap();
-                        println!("Thread IO sended the message");
-                    }
-                    Err(e) => {
-                        if e == mpsc::TryRecvError::Empty {
-                            // println!("Thread IO channel is empty");
-                        } else {
-                            println!("Thread IO channel is closed");
-                            break;
                         }
                     }
-                };
+                    drop(rx_guard);
 
-                // Stream Reader
-                {
+                    // Stream Reader
                     // read from stream until timeout or disconnect
                     let mut buff = [0_u8; 4098];
-                    let mut tpcstream = &*stream_;
-                    if let Err(e) = tpcstream.set_read_timeout(Some(Duration::from_millis(30))) {
+                    if let Err(e) = stream_.set_read_timeout(Some(Duration::from_millis(30))) {
                         println!("Failed to set read timeout: {}", e);
                         Client::disconnect_stream(tx_events_handler.clone());
                         break;
                     }
-                    match tpcstream.read(&mut buff) {
-                        Ok(n) => {
-                            if n > 0 {
-                                if buff[0] == 0xf0 {
-                                    // keepalive server do nothing
-                                    continue;
-                                }
-                                // send message to event handler
-                                tx_events_handler
-                                    .lock()
-                                    .unwrap()
-                                    .send(buff.to_vec())
-                                    .unwrap();
-                                println!(
-                                    "Thread IO got a msg to process event with Packet ID: {:?}",
-                                    buff[0]
-                                );
-                            }
+                    match stream_.read(&mut buff) {
+                        Ok(0) => {
+                            println!("Connection closed by the server");
+                            client_connection.store(false, Ordering::SeqCst);
+                            break;
+                        }
+                        Ok(n) => pending.extend_from_slice(&buff[..n]),
+                        Err(e)
+                            if e.kind() == ErrorKind::WouldBlock
+                                || e.kind() == ErrorKind::TimedOut => {}
+                        Err(e) => {
+                            println!("Thread IO cannot read from the stream: {}", e);
+                            client_connection.store(false, Ordering::SeqCst);
+                            break;
                         }
-                        Err(_e) => {}
                     };
+                    loop {
+                        // keepalive server do nothing
+                        let keepalives = pending.iter().take_while(|byte| **byte == 0xf0).count();
+                        pending.drain(..keepalives);
+                        let size = match packet_size(&pending) {
+                            Some(size) => size,
+                            None => break,
+                        };
+                        let packet: Vec<u8> = pending.drain(..size).collect();
+                        println!(
+                            "Thread IO got a msg to process event with Packet ID: {:?}",
+                            packet[0]
+                        );
+                        // send message to event handler
+                        let _ = tx_events_handler.lock().unwrap().send(packet);
+                    }
+                    drop(stream_);
+                    thread::yield_now();
                 }
-                thread::yield_now();
             });
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn handle_events(
         &mut self,
         rx_events_handler: Arc<Mutex<Receiver<Vec<u8>>>>,
@@ -354,6 +449,7 @@ impl Client {
         keepalive_interval: usize,
         keepalive_pair: Arc<(Mutex<bool>, Condvar)>,
         tx_out: Arc<Mutex<Sender<String>>>,
+        tx_messages: Option<Sender<Message>>,
     ) {
         let _handle_read = thread::Builder::new()
             .name("Thread: read from stream".to_string())
@@ -398,6 +494,23 @@ impl Client {
                                         ),
                                         tx_out.clone(),
                                     );
+                                    let qos = (msg[0] >> 1) & 0x03;
+                                    if qos > 0 {
+                                        let puback = Packet::<VariableHeader, Payload>::new()
+                                            .puback(unvalue.variable_header.packet_identifier);
+                                        let _ = tx.lock().unwrap().send(puback.value());
+                                    }
+                                    if let Some(tx_messages) = &tx_messages {
+                                        let _ = tx_messages.send(Message {
+                                            topic: String::from_utf8_lossy(
+                                                &unvalue.variable_header.topic_name,
+                                            )
+                                            .to_string(),
+                                            message: unvalue.payload.message,
+                                            qos,
+                                            retain: msg[0] & 0x01 == 0x01,
+                                        });
+                                    }
                                 }
                                 control_type::PINGRESP => {
                                     let (lock, cvar) = &*keepalive_pair;
@@ -430,7 +543,8 @@ impl Client {
                         }
                     }
                     Err(_error) => {
-                        continue;
+                        // the client and its io thread are gone
+                        break;
                     }
                 }
                 thread::yield_now();
@@ -457,9 +571,15 @@ impl Client {
 
     #[allow(dead_code)]
     pub fn subscribe(&mut self, topic: &str) {
+        self.subscribe_with_qos(topic, 0);
+    }
+
+    /// Subscribes to topic asking the broker for messages up to qos
+    #[allow(dead_code)]
+    pub fn subscribe_with_qos(&mut self, topic: &str, qos: u8) {
         let packet: Packet<VariableHeader, Payload> = Packet::<VariableHeader, Payload>::new();
         let packet_identifier = self.get_packet_identifier();
-        let packet = packet.subscribe(packet_identifier, vec![String::from(topic)], vec![0]);
+        let packet = packet.subscribe(packet_identifier, vec![String::from(topic)], vec![qos]);
         let pck_value = packet.value();
         self.last_packet_sent = pck_value.clone();
         Client::print_all(
@@ -496,3 +616,22 @@ impl Default for Client {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_packet_size() {
+        let publish = Packet::<VariableHeader, Payload>::new()
+            .publish(0, 1, 0, 7, "a/b".to_string(), "21.5".to_string())
+            .value();
+        let puback = Packet::<VariableHeader, Payload>::new().puback(7).value();
+        let mut buffer = publish.clone();
+        buffer.extend(&puback);
+        assert_eq!(packet_size(&buffer), Some(publish.len()));
+        assert_eq!(packet_size(&buffer[publish.len()..]), Some(puback.len()));
+        assert_eq!(packet_size(&publish[..publish.len() - 1]), None);
+        assert_eq!(packet_size(&[]), None);
+    }
+}
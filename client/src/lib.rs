@@ -1 +1,3 @@
 pub mod client;
+pub mod tls;
+pub mod transport;
@@ -1,4 +1,6 @@
 mod client;
+mod tls;
+mod transport;
 use crate::client::Client;
 use core::time;
 use mqtt_packet::mqtt_packet_service::payload_packet::Payload;
@@ -0,0 +1,145 @@
+use crate::transport::TlsStream;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::{ClientConfig, ClientConnection, RootCertStore};
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{BufReader, Error, ErrorKind, Result};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Options to connect to the broker TLS listener
+#[derive(Clone, Debug, Default)]
+pub struct TlsOptions {
+    pub ca_file: String,     // CA that signed the broker certificate
+    pub cert_file: String,   // client certificate, empty to connect without one
+    pub key_file: String,    // private key of the client certificate
+    pub server_name: String, // name checked against the broker certificate, the host is used if empty
+}
+
+fn load_certs(file_name: &str) -> Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(File::open(file_name)?);
+    rustls_pemfile::certs(&mut reader).collect()
+}
+
+fn load_private_key(file_name: &str) -> Result<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(File::open(file_name)?);
+    rustls_pemfile::private_key(&mut reader)?.ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("No private key found in {}", file_name),
+        )
+    })
+}
+
+pub fn client_config(options: &TlsOptions) -> Result<Arc<ClientConfig>> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(&options.ca_file)? {
+        roots
+            .add(cert)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    }
+    let builder = ClientConfig::builder().with_root_certificates(roots);
+    let config = if options.cert_file.is_empty() {
+        builder.with_no_client_auth()
+    } else {
+        builder
+            .with_client_auth_cert(
+                load_certs(&options.cert_file)?,
+                load_private_key(&options.key_file)?,
+            )
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?
+    };
+    Ok(Arc::new(config))
+}
+
+/// Starts a TLS session over stream and waits until the handshake is done
+pub fn connect_tls(stream: TcpStream, host: &str, options: &TlsOptions) -> Result<TlsStream> {
+    let config = client_config(options)?;
+    let server_name = if options.server_name.is_empty() {
+        host.to_string()
+    } else {
+        options.server_name.clone()
+    };
+    let server_name =
+        ServerName::try_from(server_name).map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+    let conn = ClientConnection::new(config, server_name)
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+    let mut tls_stream = TlsStream::new(conn, stream);
+    tls_stream
+        .sock
+        .set_read_timeout(Some(Duration::from_secs(10)))?;
+    while tls_stream.conn.is_handshaking() {
+        tls_stream.conn.complete_io(&mut tls_stream.sock)?;
+    }
+    Ok(tls_stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+    use rustls::{ServerConfig, ServerConnection, StreamOwned};
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn test_connect_tls() {
+        let dir = std::env::temp_dir().join(format!("mqtt_client_tls_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+        let server_key = KeyPair::generate().unwrap();
+        let server_cert = CertificateParams::new(vec!["localhost".to_string()])
+            .unwrap()
+            .signed_by(&server_key, &ca, &ca_key)
+            .unwrap();
+        let ca_file = dir.join("ca.pem");
+        std::fs::write(&ca_file, ca.pem()).unwrap();
+
+        let server_config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![server_cert.der().clone()],
+                PrivateKeyDer::try_from(server_key.serialize_der()).unwrap(),
+            )
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let (socket, _) = listener.accept().unwrap();
+            let conn = ServerConnection::new(Arc::new(server_config)).unwrap();
+            let mut stream = StreamOwned::new(conn, socket);
+            let mut buff = [0_u8; 2];
+            stream.read_exact(&mut buff).unwrap();
+            stream.write_all(&[0xD0, 0x00]).unwrap();
+            buff
+        });
+
+        let options = TlsOptions {
+            ca_file: ca_file.to_string_lossy().to_string(),
+            ..TlsOptions::default()
+        };
+        let mut stream =
+            connect_tls(TcpStream::connect(address).unwrap(), "localhost", &options).unwrap();
+        stream.write_all(&[0xC0, 0x00]).unwrap();
+        let mut buff = [0_u8; 2];
+        stream.read_exact(&mut buff).unwrap();
+        assert_eq!(buff, [0xD0, 0x00]);
+        assert_eq!(handle.join().unwrap(), [0xC0, 0x00]);
+    }
+
+    #[test]
+    fn test_connect_tls_missing_ca() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let options = TlsOptions {
+            ca_file: "not_found_ca.pem".to_string(),
+            ..TlsOptions::default()
+        };
+        assert!(connect_tls(stream, "localhost", &options).is_err());
+    }
+}
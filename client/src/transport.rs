@@ -0,0 +1,34 @@
+use rustls::{ClientConnection, StreamOwned};
+use std::io::{Read, Result, Write};
+use std::net::{Shutdown, TcpStream};
+use std::time::Duration;
+
+/// Stream used by the client to talk with the broker, a plain tcp socket or a TLS session
+pub trait Transport: Read + Write + Send {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()>;
+    fn shutdown(&mut self, how: Shutdown) -> Result<()>;
+}
+
+pub type TlsStream = StreamOwned<ClientConnection, TcpStream>;
+
+impl Transport for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn shutdown(&mut self, how: Shutdown) -> Result<()> {
+        TcpStream::shutdown(self, how)
+    }
+}
+
+impl Transport for TlsStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        self.sock.set_read_timeout(timeout)
+    }
+
+    fn shutdown(&mut self, how: Shutdown) -> Result<()> {
+        self.conn.send_close_notify();
+        let _ = self.conn.complete_io(&mut self.sock);
+        self.sock.shutdown(how)
+    }
+}
@@ -0,0 +1,71 @@
+use config::config_file::{ConfigError, ConfigFile};
+use serde::Deserialize;
+
+/// Settings of the device, read from its config file
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeviceConfig {
+    pub host: String,
+    pub port: u16,
+    pub topic: String, // required
+    pub user: String,
+    pub password: String,
+    pub interval_time: usize,  // seconds between two publishes
+    pub min_interval_num: i32, // the published numbers are in min..max
+    pub max_interval_num: i32,
+}
+
+impl Default for DeviceConfig {
+    fn default() -> DeviceConfig {
+        DeviceConfig {
+            host: "127.0.0.1".to_string(),
+            port: 1883,
+            topic: String::new(),
+            user: String::new(),
+            password: String::new(),
+            interval_time: 15,
+            min_interval_num: 1111,
+            max_interval_num: 9999,
+        }
+    }
+}
+
+impl DeviceConfig {
+    pub fn from_config(config: &ConfigFile) -> Result<DeviceConfig, ConfigError> {
+        let settings: DeviceConfig = config.deserialize()?;
+        if settings.topic.is_empty() {
+            return Err(config.error("topic", "Missing required key"));
+        }
+        if settings.min_interval_num >= settings.max_interval_num {
+            return Err(config.error("max_interval_num", "Must be greater than min_interval_num"));
+        }
+        Ok(settings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_config() {
+        let config = ConfigFile::load("src/config.yaml").unwrap();
+        let settings = DeviceConfig::from_config(&config).unwrap();
+        assert_eq!(settings.topic, "token");
+        assert_eq!(settings.user, "");
+        assert_eq!(settings.interval_time, 10);
+
+        let config =
+            ConfigFile::parse("config.yaml", "topic: token\nmax_interval_num: 10\n").unwrap();
+        let error = DeviceConfig::from_config(&config).unwrap_err();
+        assert_eq!(error.line, Some(2));
+        let config = ConfigFile::parse("config.yaml", "host: localhost\n").unwrap();
+        assert!(DeviceConfig::from_config(&config).is_err());
+        let config = ConfigFile::parse("config.yaml", "topic: token\nintervl_time: 5\n").unwrap();
+        let error = DeviceConfig::from_config(&config).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "config.yaml:2: intervl_time: Unknown key"
+        );
+    }
+}
@@ -6,63 +6,49 @@ use rand::distributions::Uniform;
 use rand::Rng;
 use std::thread::sleep;
 use std::time::Duration;
-mod file_loader;
-use crate::file_loader::load_contents;
+mod device_config;
+use crate::device_config::DeviceConfig;
+use config::config_file::{ConfigError, ConfigFile, DEFAULT_CONFIG_PATH};
 use core::time;
+use std::env;
+use std::process;
 use std::thread;
 
+/// Path given with --config, the default config otherwise
+fn config_path(args: &[String]) -> Result<String, String> {
+    match args {
+        [] => Ok(DEFAULT_CONFIG_PATH.to_string()),
+        [flag, path] if flag == "--config" => Ok(path.clone()),
+        _ => Err("usage: client_device [--config PATH]".to_string()),
+    }
+}
+
+fn load_config(path: &str) -> Result<DeviceConfig, ConfigError> {
+    DeviceConfig::from_config(&ConfigFile::load(path)?)
+}
+
 fn main() {
-    let file_config = "src/config.yaml";
-    let config = load_contents(file_config);
-    let host = config
-        .get("host")
-        .unwrap_or_else(|| panic!("Cannot found host in config"));
-    let port = config
-        .get("port")
-        .unwrap_or_else(|| panic!("Cannot found port in config"));
-    let topic = config
-        .get("topic")
-        .unwrap_or_else(|| panic!("Cannot found topic in config"));
-    let default_user = String::from("");
-    let user: String = config
-        .get("user")
-        .unwrap_or_else(|| &default_user)
-        .parse()
-        .unwrap();
-    let default_password = String::from("");
-    let password: String = config
-        .get("password")
-        .unwrap_or_else(|| &default_password)
-        .parse()
-        .unwrap();
-    let default_interval_time = String::from("15");
-    let interval_time: usize = config
-        .get("interval_time")
-        .unwrap_or_else(|| &default_interval_time)
-        .parse()
-        .unwrap();
-    let default_min_interval_num = String::from("1111");
-    let min_interval_num: i32 = config
-        .get("min_interval_num")
-        .unwrap_or_else(|| &default_min_interval_num)
-        .parse()
-        .unwrap();
-    let default_max_interval_num = String::from("9999");
-    let max_interval_num: i32 = config
-        .get("max_interval_num")
-        .unwrap_or_else(|| &default_max_interval_num)
-        .parse()
-        .unwrap();
+    let args: Vec<String> = env::args().skip(1).collect();
+    let config = match config_path(&args) {
+        Ok(path) => load_config(&path).unwrap_or_else(|e| {
+            eprintln!("Cannot load config: {}", e);
+            process::exit(1);
+        }),
+        Err(usage) => {
+            eprintln!("{}", usage);
+            process::exit(2);
+        }
+    };
 
     handle_connection(
-        host.to_string(),
-        port.to_string(),
-        topic.to_string(),
-        user,
-        password,
-        interval_time,
-        min_interval_num,
-        max_interval_num,
+        config.host,
+        config.port.to_string(),
+        config.topic,
+        config.user,
+        config.password,
+        config.interval_time,
+        config.min_interval_num,
+        config.max_interval_num,
     )
 }
 
@@ -145,8 +131,18 @@ fn handle_connection(
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     #[test]
     fn test_sample_client() {
         assert_eq!(1, 1)
     }
+
+    #[test]
+    fn test_config_path() {
+        assert_eq!(config_path(&[]), Ok(DEFAULT_CONFIG_PATH.to_string()));
+        let args = vec!["--config".to_string(), "device.yaml".to_string()];
+        assert_eq!(config_path(&args), Ok("device.yaml".to_string()));
+        assert!(config_path(&args[..1]).is_err());
+    }
 }
@@ -0,0 +1,280 @@
+//! Config files of the programs, written in YAML.
+//!
+//! Each program deserializes its file into a struct of its own settings, so a key that
+//! the program does not know is an error instead of a setting silently left out, and a
+//! value keeps the text written in the file. The errors name the file, the key and the
+//! line so a wrong value can be found without reading the whole file.
+
+use serde::de::DeserializeOwned;
+use serde_yaml::Value;
+use std::fmt;
+use std::fs;
+use std::io;
+
+/// Path of the config file when the program does not get one
+pub const DEFAULT_CONFIG_PATH: &str = "src/config.yaml";
+
+#[derive(Debug, PartialEq)]
+pub struct ConfigError {
+    pub path: String,
+    pub key: Option<String>,
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.path)?;
+        if let Some(line) = self.line {
+            write!(f, ":{}", line)?;
+        }
+        if let Some(key) = &self.key {
+            write!(f, ": {}", key)?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl From<ConfigError> for io::Error {
+    fn from(error: ConfigError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidInput, error.to_string())
+    }
+}
+
+#[derive(Debug)]
+pub struct ConfigFile {
+    path: String,
+    contents: String, // a mapping of keys, `{}` when the file sets none
+}
+
+impl ConfigFile {
+    /// Reads and parses the file at path, a missing file is an error
+    pub fn load(path: &str) -> Result<ConfigFile, ConfigError> {
+        let contents = fs::read_to_string(path).map_err(|e| ConfigError {
+            path: path.to_string(),
+            key: None,
+            line: None,
+            message: format!("Cannot read config file: {}", e),
+        })?;
+        ConfigFile::parse(path, &contents)
+    }
+
+    /// Parses contents, path is only used to name the file in the errors
+    pub fn parse(path: &str, contents: &str) -> Result<ConfigFile, ConfigError> {
+        let mut config = ConfigFile {
+            path: path.to_string(),
+            contents: contents.to_string(),
+        };
+        let document: Value = serde_yaml::from_str(contents).map_err(|e| config.yaml_error(e))?;
+        match document {
+            Value::Null => config.contents = "{}".to_string(),
+            Value::Mapping(_) => {}
+            _ => return Err(config.error_at(None, Some(1), "Expected a mapping of keys")),
+        }
+        Ok(config)
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// The settings of the file, every key must be a field of T
+    pub fn deserialize<T: DeserializeOwned>(&self) -> Result<T, ConfigError> {
+        serde_yaml::from_str(&self.contents).map_err(|e| self.yaml_error(e))
+    }
+
+    /// Line of the file where key is set, the keys of nested mappings are joined with dots
+    pub fn line(&self, key: &str) -> Option<usize> {
+        let path: Vec<&str> = key
+            .split('.')
+            .map(|key| key.split('[').next().unwrap_or(key))
+            .collect();
+        find_line(&self.contents, &path)
+    }
+
+    fn error_at(&self, key: Option<String>, line: Option<usize>, message: &str) -> ConfigError {
+        ConfigError {
+            path: self.path.clone(),
+            key,
+            line,
+            message: message.to_string(),
+        }
+    }
+
+    /// An error about the value of key, with the line where it is set
+    pub fn error(&self, key: &str, message: &str) -> ConfigError {
+        self.error_at(Some(key.to_string()), self.line(key), message)
+    }
+
+    /// Splits the messages of serde_yaml, `<key>: <message> at line <n> column <m>`
+    fn yaml_error(&self, error: serde_yaml::Error) -> ConfigError {
+        let line = error.location().map(|location| location.line());
+        let mut message = error.to_string();
+        if let Some(at) = message.rfind(" at line ") {
+            message.truncate(at);
+        }
+        let (parent, message) = match message.split_once(": ") {
+            Some((key, rest)) if !key.contains(' ') => (Some(key.to_string()), rest.to_string()),
+            _ => (None, message),
+        };
+        // the field named between backticks, as a key of the parent mapping
+        let field = |prefix: &str| {
+            let name = message.strip_prefix(prefix)?.split('`').nth(1)?;
+            Some(match &parent {
+                Some(parent) => format!("{}.{}", parent, name),
+                None => name.to_string(),
+            })
+        };
+        if let Some(key) = field("unknown field") {
+            return self.error_at(Some(key), line, "Unknown key");
+        }
+        let duplicate = message
+            .strip_prefix("duplicate entry with key ")
+            .map(|name| name.trim_matches('"').to_string());
+        if let Some(key) = field("duplicate field").or(duplicate) {
+            let line = self.line(&key);
+            return self.error_at(Some(key), line, "Key is set twice");
+        }
+        if let Some(key) = field("missing field") {
+            return self.error_at(Some(key), None, "Missing required key");
+        }
+        let mut chars = message.chars();
+        let message = match chars.next() {
+            Some(first) => first.to_uppercase().chain(chars).collect(),
+            None => message,
+        };
+        self.error_at(parent, line, &message)
+    }
+}
+
+/// Line of the last key of path, each key is looked for after the line of its parent
+fn find_line(contents: &str, path: &[&str]) -> Option<usize> {
+    let lines: Vec<&str> = contents.lines().collect();
+    let mut start = 0;
+    for key in path {
+        let candidates = [
+            key.to_string(),
+            format!("'{}'", key),
+            format!("\"{}\"", key),
+        ];
+        start += lines[start..].iter().position(|line| {
+            let line = line
+                .trim_start()
+                .trim_start_matches("- ")
+                .trim_start_matches('{');
+            candidates.iter().any(|candidate| {
+                line.strip_prefix(candidate.as_str())
+                    .map(|rest| rest.trim_start().starts_with(':'))
+                    .unwrap_or(false)
+            })
+        })?;
+    }
+    Some(start + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use std::collections::BTreeMap;
+
+    #[derive(Debug, Default, Deserialize, PartialEq)]
+    #[serde(default, deny_unknown_fields)]
+    struct Settings {
+        host: String,
+        port: u16,
+        topics: Vec<String>,
+        listeners: BTreeMap<String, Listener>,
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    #[serde(deny_unknown_fields)]
+    struct Listener {
+        bind: String,
+        #[serde(default)]
+        max_connections: usize,
+    }
+
+    #[test]
+    fn test_deserialize_nested_keys() {
+        let contents = "# broker\nhost: localhost\n\nport: 3333\n\
+                        listeners:\n  main:\n    bind: 0.0.0.0:1883\n    max_connections: 10\n\
+                        topics: [sensors/#, alerts]\n";
+        let config = ConfigFile::parse("config.yaml", contents).unwrap();
+        let settings: Settings = config.deserialize().unwrap();
+        assert_eq!(settings.host, "localhost");
+        assert_eq!(settings.port, 3333);
+        assert_eq!(settings.topics, vec!["sensors/#", "alerts"]);
+        assert_eq!(settings.listeners["main"].bind, "0.0.0.0:1883");
+        assert_eq!(settings.listeners["main"].max_connections, 10);
+        assert_eq!(config.line("port"), Some(4));
+        assert_eq!(config.line("listeners.main.bind"), Some(7));
+
+        let empty = ConfigFile::parse("empty.yaml", "# nothing set\n").unwrap();
+        assert_eq!(
+            empty.deserialize::<Settings>().unwrap(),
+            Settings::default()
+        );
+    }
+
+    #[test]
+    fn test_values_keep_their_text() {
+        let contents = "a: 0x1F\nb: 1e3\nc: 1.50\nd: ~\ne: true\n";
+        let config = ConfigFile::parse("config.yaml", contents).unwrap();
+        let values: BTreeMap<String, String> = config.deserialize().unwrap();
+        assert_eq!(values["a"], "0x1F");
+        assert_eq!(values["b"], "1e3");
+        assert_eq!(values["c"], "1.50");
+        assert_eq!(values["d"], "~");
+        assert_eq!(values["e"], "true");
+    }
+
+    #[test]
+    fn test_errors_name_the_key_and_line() {
+        let config = ConfigFile::parse("config.yaml", "host: localhost\nprot: 1884\n").unwrap();
+        let error = config.deserialize::<Settings>().unwrap_err();
+        assert_eq!(error.to_string(), "config.yaml:2: prot: Unknown key");
+
+        let contents = "listeners:\n  main:\n    bind: 0.0.0.0:1883\n    mux: 1\n";
+        let config = ConfigFile::parse("config.yaml", contents).unwrap();
+        let error = config.deserialize::<Settings>().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "config.yaml:4: listeners.main.mux: Unknown key"
+        );
+
+        let config = ConfigFile::parse("config.yaml", "listeners:\n  main: {}\n").unwrap();
+        let error = config.deserialize::<Settings>().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "config.yaml: listeners.main.bind: Missing required key"
+        );
+
+        let config = ConfigFile::parse("config.yaml", "host: localhost\nport: 33x3\n").unwrap();
+        let error = config.deserialize::<Settings>().unwrap_err();
+        assert_eq!(error.line, Some(2));
+        assert_eq!(error.key.as_deref(), Some("port"));
+        assert!(error.message.starts_with("Invalid type"));
+
+        let error = ConfigFile::parse("config.yaml", "port: 1883\nport: 1884\n").unwrap_err();
+        assert_eq!(error.to_string(), "config.yaml:1: port: Key is set twice");
+
+        let config = ConfigFile::parse("config.yaml", "topics: sensors/#, alerts\n").unwrap();
+        let error = config.deserialize::<Settings>().unwrap_err();
+        assert_eq!(error.key.as_deref(), Some("topics"));
+        assert_eq!(error.line, Some(1));
+    }
+
+    #[test]
+    fn test_invalid_files() {
+        assert!(ConfigFile::load("missing-config.yaml").is_err());
+        // a line without a value separator is an error, not a panic
+        assert!(ConfigFile::parse("config.yaml", "host: localhost\nport 3333\n").is_err());
+        assert!(ConfigFile::parse("config.yaml", "- host\n- port\n").is_err());
+        let config = ConfigFile::parse("config.yaml", "host: localhost\n").unwrap();
+        let error = config.error("host", "Cannot resolve");
+        assert_eq!(error.to_string(), "config.yaml:1: host: Cannot resolve");
+    }
+}
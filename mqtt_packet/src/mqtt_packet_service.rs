@@ -1,10 +1,13 @@
 pub mod header_packet;
 use header_packet::{control_flags, control_type, control_type_vec, Header, PacketHeader};
+pub mod properties;
+use properties::{PacketProperties, Properties};
 pub mod variable_header_packet;
 use variable_header_packet::{
-    connect_flags, PacketVariableHeader, PacketVariableHeaderConnack,
-    PacketVariableHeaderPacketIdentifier, PacketVariableHeaderPublish, VariableHeader,
-    VariableHeaderConnack, VariableHeaderPacketIdentifier, VariableHeaderPublish,
+    connect_flags, protocol_level, PacketVariableHeader, PacketVariableHeaderConnack,
+    PacketVariableHeaderPacketIdentifier, PacketVariableHeaderPublish,
+    PacketVariableHeaderReasonCode, VariableHeader, VariableHeaderConnack,
+    VariableHeaderPacketIdentifier, VariableHeaderPublish, VariableHeaderReasonCode,
 };
 pub mod payload_packet;
 use payload_packet::{
@@ -14,6 +17,12 @@ use payload_packet::{
 
 use self::payload_packet::SubackPayload;
 
+/// Index where the packet ends, x may be longer than the packet (i.e. a read buffer)
+fn packet_end(header: &Header, header_len: usize, x_len: usize) -> usize {
+    let remaining_length = Header::decode_remaining_length(&header.remaining_length_0) as usize;
+    (header_len + remaining_length).min(x_len)
+}
+
 /// Implementation of the MQTT packet service.
 /// This service is used to create and parse MQTT packets
 
@@ -162,8 +171,9 @@ impl Packet<VariableHeaderConnack, Payload> {
         let mut has_payload = false;
         let header = Header::unvalue(x.clone(), &mut readed);
         absolute_index += readed;
+        let end = packet_end(&header, absolute_index, x.len());
         let variable_header =
-            VariableHeaderConnack::unvalue(x[absolute_index..x.len()].to_vec(), &mut readed);
+            VariableHeaderConnack::unvalue(x[absolute_index..end].to_vec(), &mut readed);
         if readed > 0 {
             has_variable_header = true;
         }
@@ -605,6 +615,188 @@ impl Packet<VariableHeaderPacketIdentifier, UnsubscribePayload> {
         }
     }
 }
+// MQTT 5 implementations, the 3.1.1 packets above are kept unchanged
+impl Packet<VariableHeader, Payload> {
+    /// Turns a connect packet into a protocol level 5 connect with properties
+    pub fn with_properties(mut self, properties: Properties) -> Packet<VariableHeader, Payload> {
+        self.variable_header.protocol_level = protocol_level::MQTT_5;
+        self.variable_header.properties = properties;
+        let remaining_length =
+            (self.variable_header.value().len() + self.payload.value().len()) as u32;
+        self.header.set_remaining_length(remaining_length);
+        self
+    }
+}
+
+impl Packet<VariableHeaderPublish, PublishPayload> {
+    /// Adds the properties of a protocol level 5 publish
+    pub fn with_properties(
+        mut self,
+        properties: Properties,
+    ) -> Packet<VariableHeaderPublish, PublishPayload> {
+        self.variable_header.properties = Some(properties);
+        let remaining_length =
+            (self.variable_header.value().len() + self.payload.value().len()) as u32;
+        self.header.set_remaining_length(remaining_length);
+        self
+    }
+
+    /// Deserializes a protocol level 5 Packet<VariableHeaderPublish, PublishPayload>
+    pub fn unvalue_v5(x: Vec<u8>) -> Packet<VariableHeaderPublish, PublishPayload> {
+        let mut readed: usize = 0;
+        let header = Header::unvalue(x.clone(), &mut readed);
+        let mut absolute_index = readed;
+        let variable_header =
+            VariableHeaderPublish::unvalue_v5(x[absolute_index..x.len()].to_vec(), &mut readed);
+        absolute_index += readed;
+        let payload = PublishPayload::unvalue(x[absolute_index..x.len()].to_vec(), &mut readed);
+        Packet::<VariableHeaderPublish, PublishPayload> {
+            header,
+            has_variable_header: true,
+            variable_header,
+            has_payload: readed > 0,
+            payload,
+        }
+    }
+}
+
+impl Packet<VariableHeaderPacketIdentifier, Payload> {
+    /// Deserializes a protocol level 5 puback, the reason code and properties are optional
+    pub fn unvalue_v5(x: Vec<u8>) -> Packet<VariableHeaderPacketIdentifier, Payload> {
+        let mut readed: usize = 0;
+        let header = Header::unvalue(x.clone(), &mut readed);
+        let absolute_index = readed;
+        let end = packet_end(&header, absolute_index, x.len());
+        let mut variable_header =
+            VariableHeaderPacketIdentifier::unvalue(x[absolute_index..end].to_vec(), &mut readed);
+        if end > absolute_index + 2 {
+            variable_header.reason_code = Some(x[absolute_index + 2]);
+        }
+        if end > absolute_index + 3 {
+            variable_header.properties = Some(Properties::unvalue(
+                x[(absolute_index + 3)..end].to_vec(),
+                &mut readed,
+            ));
+        }
+        Packet::<VariableHeaderPacketIdentifier, Payload> {
+            header,
+            has_variable_header: true,
+            variable_header,
+            has_payload: false,
+            payload: Payload::default(),
+        }
+    }
+}
+
+impl Packet<VariableHeaderPacketIdentifier, SubscribePayload> {
+    /// Adds the properties of a protocol level 5 subscribe
+    pub fn with_properties(
+        mut self,
+        properties: Properties,
+    ) -> Packet<VariableHeaderPacketIdentifier, SubscribePayload> {
+        self.variable_header.properties = Some(properties);
+        self.header.set_remaining_length(
+            (self.variable_header.value().len() + self.payload.value().len()) as u32,
+        );
+        self
+    }
+
+    /// Deserializes a protocol level 5 Packet<VariableHeaderPacketIdentifier, SubscribePayload>
+    pub fn unvalue_v5(x: Vec<u8>) -> Packet<VariableHeaderPacketIdentifier, SubscribePayload> {
+        let mut readed: usize = 0;
+        let header = Header::unvalue(x.clone(), &mut readed);
+        let mut absolute_index = readed;
+        let end = packet_end(&header, absolute_index, x.len());
+        let variable_header = VariableHeaderPacketIdentifier::unvalue_v5(
+            x[absolute_index..end].to_vec(),
+            &mut readed,
+        );
+        absolute_index += readed;
+        let payload = SubscribePayload::unvalue(x[absolute_index..end].to_vec(), &mut readed);
+        Packet::<VariableHeaderPacketIdentifier, SubscribePayload> {
+            header,
+            has_variable_header: true,
+            variable_header,
+            has_payload: readed > 0,
+            payload,
+        }
+    }
+}
+
+impl Packet<VariableHeaderPacketIdentifier, UnsubscribePayload> {
+    /// Deserializes a protocol level 5 Packet<VariableHeaderPacketIdentifier, UnsubscribePayload>
+    pub fn unvalue_v5(x: Vec<u8>) -> Packet<VariableHeaderPacketIdentifier, UnsubscribePayload> {
+        let mut readed: usize = 0;
+        let header = Header::unvalue(x.clone(), &mut readed);
+        let mut absolute_index = readed;
+        let end = packet_end(&header, absolute_index, x.len());
+        let variable_header = VariableHeaderPacketIdentifier::unvalue_v5(
+            x[absolute_index..end].to_vec(),
+            &mut readed,
+        );
+        absolute_index += readed;
+        let payload = UnsubscribePayload::unvalue(x[absolute_index..end].to_vec(), &mut readed);
+        Packet::<VariableHeaderPacketIdentifier, UnsubscribePayload> {
+            header,
+            has_variable_header: true,
+            variable_header,
+            has_payload: readed > 0,
+            payload,
+        }
+    }
+}
+
+impl Packet<VariableHeaderPacketIdentifier, SubackPayload> {
+    /// Deserializes a protocol level 5 suback or unsuback, the payload has the reason codes
+    pub fn unvalue_v5(x: Vec<u8>) -> Packet<VariableHeaderPacketIdentifier, SubackPayload> {
+        let mut readed: usize = 0;
+        let header = Header::unvalue(x.clone(), &mut readed);
+        let mut absolute_index = readed;
+        let end = packet_end(&header, absolute_index, x.len());
+        let variable_header = VariableHeaderPacketIdentifier::unvalue_v5(
+            x[absolute_index..end].to_vec(),
+            &mut readed,
+        );
+        absolute_index += readed;
+        let payload = SubackPayload::unvalue(x[absolute_index..end].to_vec(), &mut readed);
+        Packet::<VariableHeaderPacketIdentifier, SubackPayload> {
+            header,
+            has_variable_header: true,
+            variable_header,
+            has_payload: readed > 0,
+            payload,
+        }
+    }
+}
+
+impl Packet<VariableHeaderReasonCode, Payload> {
+    /// Serializes a disconnect or auth Packet<VariableHeaderReasonCode, Payload>
+    pub fn value(&self) -> Vec<u8> {
+        let mut res: Vec<u8> = self.header.value();
+        if self.has_variable_header {
+            res.extend(self.variable_header.value());
+        }
+        res
+    }
+
+    /// Deserializes a disconnect or auth Packet<VariableHeaderReasonCode, Payload>
+    pub fn unvalue(x: Vec<u8>) -> Packet<VariableHeaderReasonCode, Payload> {
+        let mut readed: usize = 0;
+        let header = Header::unvalue(x.clone(), &mut readed);
+        let absolute_index = readed;
+        let end = packet_end(&header, absolute_index, x.len());
+        let variable_header =
+            VariableHeaderReasonCode::unvalue(x[absolute_index..end].to_vec(), &mut readed);
+        Packet::<VariableHeaderReasonCode, Payload> {
+            header,
+            has_variable_header: readed > 0,
+            variable_header,
+            has_payload: false,
+            payload: Payload::default(),
+        }
+    }
+}
+
 // general implementation for all packets
 pub trait Utils {
     fn get_packet_length(vec: &[u8], readed: &mut usize) -> usize;
@@ -673,6 +865,22 @@ pub trait ClientPacket {
         packet_identifier: u16,
         topic_names: Vec<String>,
     ) -> Packet<VariableHeaderPacketIdentifier, UnsubscribePayload>;
+    fn puback_v5(
+        &self,
+        packet_identifier: u16,
+        reason_code: u8,
+        properties: Properties,
+    ) -> Packet<VariableHeaderPacketIdentifier, Payload>;
+    fn disconnect_v5(
+        &self,
+        reason_code: u8,
+        properties: Properties,
+    ) -> Packet<VariableHeaderReasonCode, Payload>;
+    fn auth(
+        &self,
+        reason_code: u8,
+        properties: Properties,
+    ) -> Packet<VariableHeaderReasonCode, Payload>;
 }
 impl<T, P> ClientPacket for Packet<T, P> {
     /// Creates a Connect packet with credentials
@@ -714,13 +922,14 @@ impl<T, P> ClientPacket for Packet<T, P> {
         let protocol_name = [0x00, 0x04, b'M', b'Q', b'T', b'T'].to_vec();
         let variable_header: VariableHeader = VariableHeader {
             protocol_name,
-            protocol_level: 0x04,
+            protocol_level: protocol_level::MQTT_3_1_1,
             connect_flags: if clean_session {
                 connect_flags::CLEAN_SESSION
             } else {
                 connect_flags::RESERVED
             },
             keep_alive: 0x00,
+            properties: Properties::default(),
         };
         let payload = Payload {
             client_identifier,
@@ -786,7 +995,10 @@ impl<T, P> ClientPacket for Packet<T, P> {
             control_flags: control_flags::RESERVED,
             remaining_length_0: vec![2],
         };
-        let variable_header = VariableHeaderPacketIdentifier { packet_identifier };
+        let variable_header = VariableHeaderPacketIdentifier {
+            packet_identifier,
+            ..VariableHeaderPacketIdentifier::default()
+        };
         // building the struct packet
         Packet::<VariableHeaderPacketIdentifier, Payload> {
             header,
@@ -815,6 +1027,7 @@ impl<T, P> ClientPacket for Packet<T, P> {
         let variable_header = VariableHeaderPublish {
             topic_name: topic_name.as_bytes().to_vec(),
             packet_identifier,
+            properties: None,
         };
 
         let payload = PublishPayload { message };
@@ -844,7 +1057,10 @@ impl<T, P> ClientPacket for Packet<T, P> {
             control_flags: control_flags::RESERVED,
             remaining_length_0: vec![0],
         };
-        let variable_header = VariableHeaderPacketIdentifier { packet_identifier };
+        let variable_header = VariableHeaderPacketIdentifier {
+            packet_identifier,
+            ..VariableHeaderPacketIdentifier::default()
+        };
         let payload = SubscribePayload {
             topic_filter: topic_names,
             qos,
@@ -871,7 +1087,10 @@ impl<T, P> ClientPacket for Packet<T, P> {
             control_flags: control_flags::QOS0,
             remaining_length_0: vec![0],
         };
-        let variable_header = VariableHeaderPacketIdentifier { packet_identifier };
+        let variable_header = VariableHeaderPacketIdentifier {
+            packet_identifier,
+            ..VariableHeaderPacketIdentifier::default()
+        };
         let payload = UnsubscribePayload {
             topic_filter: topic_names,
         };
@@ -885,8 +1104,75 @@ impl<T, P> ClientPacket for Packet<T, P> {
             payload,
         }
     }
+    /// Creates a protocol level 5 PubAck packet
+    fn puback_v5(
+        &self,
+        packet_identifier: u16,
+        reason_code: u8,
+        properties: Properties,
+    ) -> Packet<VariableHeaderPacketIdentifier, Payload> {
+        let mut header = Header {
+            control_type: control_type::PUBACK,
+            control_flags: control_flags::RESERVED,
+            remaining_length_0: vec![0],
+        };
+        let variable_header = VariableHeaderPacketIdentifier {
+            packet_identifier,
+            reason_code: Some(reason_code),
+            properties: Some(properties),
+        };
+        header.set_remaining_length(variable_header.value().len() as u32);
+        Packet {
+            header,
+            has_variable_header: true,
+            variable_header,
+            has_payload: false,
+            payload: Payload::default(),
+        }
+    }
+
+    /// Creates a protocol level 5 Disconnect packet
+    fn disconnect_v5(
+        &self,
+        reason_code: u8,
+        properties: Properties,
+    ) -> Packet<VariableHeaderReasonCode, Payload> {
+        reason_code_packet(control_type::DISCONNECT, reason_code, properties)
+    }
+
+    /// Creates an Auth packet, only used by protocol level 5
+    fn auth(
+        &self,
+        reason_code: u8,
+        properties: Properties,
+    ) -> Packet<VariableHeaderReasonCode, Payload> {
+        reason_code_packet(control_type::AUTH, reason_code, properties)
+    }
 }
 
+fn reason_code_packet(
+    control_type: u8,
+    reason_code: u8,
+    properties: Properties,
+) -> Packet<VariableHeaderReasonCode, Payload> {
+    let mut header = Header {
+        control_type,
+        control_flags: control_flags::RESERVED,
+        remaining_length_0: vec![0],
+    };
+    let variable_header = VariableHeaderReasonCode {
+        reason_code,
+        properties,
+    };
+    header.set_remaining_length(variable_header.value().len() as u32);
+    Packet {
+        header,
+        has_variable_header: true,
+        variable_header,
+        has_payload: false,
+        payload: Payload::default(),
+    }
+}
 pub trait ServerPacket {
     fn connack(
         &self,
@@ -900,6 +1186,24 @@ pub trait ServerPacket {
         qos: Vec<u8>,
     ) -> Packet<VariableHeaderPacketIdentifier, SubackPayload>;
     fn unsuback(&self, packet_identifier: u16) -> Packet<VariableHeaderPacketIdentifier, Payload>;
+    fn connack_v5(
+        &self,
+        connect_ack_flags: u8,
+        reason_code: u8,
+        properties: Properties,
+    ) -> Packet<VariableHeaderConnack, Payload>;
+    fn suback_v5(
+        &self,
+        packet_identifier: u16,
+        reason_codes: Vec<u8>,
+        properties: Properties,
+    ) -> Packet<VariableHeaderPacketIdentifier, SubackPayload>;
+    fn unsuback_v5(
+        &self,
+        packet_identifier: u16,
+        reason_codes: Vec<u8>,
+        properties: Properties,
+    ) -> Packet<VariableHeaderPacketIdentifier, SubackPayload>;
 }
 
 impl<T, P> ServerPacket for Packet<T, P> {
@@ -912,6 +1216,7 @@ impl<T, P> ServerPacket for Packet<T, P> {
         let variable_header: VariableHeaderConnack = VariableHeaderConnack {
             acknoledge_flags: connect_ack_flags,
             return_code: connect_return,
+            properties: None,
         };
         let mut header = Header {
             control_type: control_type::CONNACK,
@@ -962,7 +1267,10 @@ impl<T, P> ServerPacket for Packet<T, P> {
             control_flags: control_flags::RESERVED,
             remaining_length_0: vec![0],
         };
-        let variable_header = VariableHeaderPacketIdentifier { packet_identifier };
+        let variable_header = VariableHeaderPacketIdentifier {
+            packet_identifier,
+            ..VariableHeaderPacketIdentifier::default()
+        };
 
         let payload = SubackPayload { qos };
         header.set_remaining_length((variable_header.value().len() + payload.value().len()) as u32);
@@ -983,7 +1291,10 @@ impl<T, P> ServerPacket for Packet<T, P> {
             control_flags: control_flags::RESERVED,
             remaining_length_0: vec![0],
         };
-        let variable_header = VariableHeaderPacketIdentifier { packet_identifier };
+        let variable_header = VariableHeaderPacketIdentifier {
+            packet_identifier,
+            ..VariableHeaderPacketIdentifier::default()
+        };
         header.set_remaining_length(variable_header.value().len() as u32);
         // building the struct packet
         Packet {
@@ -994,6 +1305,47 @@ impl<T, P> ServerPacket for Packet<T, P> {
             payload: Payload::default(),
         }
     }
+    /// Creates a protocol level 5 Connack packet
+    fn connack_v5(
+        &self,
+        connect_ack_flags: u8,
+        reason_code: u8,
+        properties: Properties,
+    ) -> Packet<VariableHeaderConnack, Payload> {
+        let mut packet = self.connack(connect_ack_flags, reason_code);
+        packet.variable_header.properties = Some(properties);
+        packet
+            .header
+            .set_remaining_length(packet.variable_header.value().len() as u32);
+        packet
+    }
+
+    /// Creates a protocol level 5 Suback packet
+    fn suback_v5(
+        &self,
+        packet_identifier: u16,
+        reason_codes: Vec<u8>,
+        properties: Properties,
+    ) -> Packet<VariableHeaderPacketIdentifier, SubackPayload> {
+        let mut packet = self.suback(packet_identifier, reason_codes);
+        packet.variable_header.properties = Some(properties);
+        packet.header.set_remaining_length(
+            (packet.variable_header.value().len() + packet.payload.value().len()) as u32,
+        );
+        packet
+    }
+
+    /// Creates a protocol level 5 Unsuback packet, it has a reason code for each topic filter
+    fn unsuback_v5(
+        &self,
+        packet_identifier: u16,
+        reason_codes: Vec<u8>,
+        properties: Properties,
+    ) -> Packet<VariableHeaderPacketIdentifier, SubackPayload> {
+        let mut packet = self.suback_v5(packet_identifier, reason_codes, properties);
+        packet.header.control_type = control_type::UNSUBACK;
+        packet
+    }
 }
 
 #[cfg(test)]
@@ -1001,8 +1353,9 @@ mod tests {
     use super::*;
 
     mod packets {
+        use crate::mqtt_packet_service::properties::{property_identifiers, PropertyValue};
         use crate::mqtt_packet_service::variable_header_packet::{
-            connect_ack_flags, connect_return,
+            connect_ack_flags, connect_return, reason_codes,
         };
 
         use super::*;
@@ -1168,7 +1521,7 @@ mod tests {
             // let connect_head_stub = vec![0x10, 18, 0, 4, 77, 81, 84, 84, 4, 2, 0, 0];
             let client_identifier = String::from("testId");
             let packet = Packet::<VariableHeader, Payload>::new();
-            let packet = packet.connect(client_identifier, true,"".to_string(), "".to_string());
+            let packet = packet.connect(client_identifier, true, "".to_string(), "".to_string());
             let mut readed: usize = 0;
             let value = packet.value();
             let remaining_len = Packet::<VariableHeader, Payload>::get_packet_length(
@@ -1400,5 +1753,146 @@ mod tests {
             );
             assert_eq!(unvalue.payload.message, payload.clone());
         }
+
+        #[test]
+        fn check_connect_packet_v5() {
+            let mut properties = Properties::new();
+            properties.set(
+                property_identifiers::SESSION_EXPIRY_INTERVAL,
+                PropertyValue::FourByteInteger(300),
+            );
+            let packet = Packet::<VariableHeader, Payload>::new();
+            let packet = packet
+                .connect("client5".to_string(), true, "".to_string(), "".to_string())
+                .with_properties(properties.clone());
+            let mut value = packet.value();
+            value.resize(1024, 0); // as read from the socket buffer
+            let unvalue = Packet::<VariableHeader, Payload>::unvalue(value);
+            assert_eq!(
+                unvalue.variable_header.protocol_level,
+                protocol_level::MQTT_5
+            );
+            assert_eq!(unvalue.variable_header.properties, properties);
+            assert_eq!(unvalue.payload.client_identifier, "client5".to_string());
+        }
+
+        #[test]
+        fn check_connack_packet_v5() {
+            let mut properties = Properties::new();
+            properties.set(
+                property_identifiers::REASON_STRING,
+                PropertyValue::Utf8String("not allowed".to_string()),
+            );
+            let packet = Packet::<VariableHeader, Payload>::new();
+            let packet = packet.connack_v5(0, reason_codes::NOT_AUTHORIZED, properties.clone());
+            let mut value = packet.value();
+            assert_eq!(value[1] as usize, value.len() - 2);
+            value.resize(1024, 0);
+            let unvalue = Packet::<VariableHeaderConnack, Payload>::unvalue(value);
+            assert_eq!(
+                unvalue.variable_header.return_code,
+                reason_codes::NOT_AUTHORIZED
+            );
+            assert_eq!(unvalue.variable_header.properties, Some(properties));
+
+            // a 3.1.1 connack read from a buffer has no properties
+            let packet = Packet::<VariableHeader, Payload>::new();
+            let mut value = packet.connack(0, connect_return::ACCEPTED).value();
+            value.resize(1024, 0);
+            let unvalue = Packet::<VariableHeaderConnack, Payload>::unvalue(value);
+            assert_eq!(unvalue.variable_header.properties, None);
+        }
+
+        #[test]
+        fn check_publish_packet_v5() {
+            let mut properties = Properties::new();
+            properties.set(
+                property_identifiers::TOPIC_ALIAS,
+                PropertyValue::TwoByteInteger(1),
+            );
+            properties.add_user_property("unit", "celsius");
+            let packet = Packet::<VariableHeader, Payload>::new();
+            let packet = packet
+                .publish(0, 1, 0, 10, "temp".to_string(), "21".to_string())
+                .with_properties(properties.clone());
+            let unvalue =
+                Packet::<VariableHeaderPublish, PublishPayload>::unvalue_v5(packet.value());
+            assert_eq!(unvalue.variable_header.topic_name, b"temp".to_vec());
+            assert_eq!(unvalue.variable_header.packet_identifier, 10);
+            assert_eq!(unvalue.variable_header.properties, Some(properties));
+            assert_eq!(unvalue.payload.message, "21".to_string());
+        }
+
+        #[test]
+        fn check_subscribe_and_suback_packet_v5() {
+            let mut properties = Properties::new();
+            properties.add_user_property("a", "b");
+            let packet = Packet::<VariableHeader, Payload>::new();
+            let packet = packet
+                .subscribe(7, vec!["topic1".to_string()], vec![1])
+                .with_properties(properties.clone());
+            let mut value = packet.value();
+            value.resize(1024, 0);
+            let unvalue =
+                Packet::<VariableHeaderPacketIdentifier, SubscribePayload>::unvalue_v5(value);
+            assert_eq!(unvalue.variable_header.packet_identifier, 7);
+            assert_eq!(unvalue.variable_header.properties, Some(properties));
+            assert_eq!(unvalue.payload.topic_filter, vec!["topic1".to_string()]);
+            assert_eq!(unvalue.payload.qos, vec![1]);
+
+            let packet = Packet::<VariableHeader, Payload>::new();
+            let packet = packet.unsuback_v5(
+                7,
+                vec![reason_codes::SUCCESS, reason_codes::NO_SUBSCRIPTION_EXISTED],
+                Properties::new(),
+            );
+            let mut value = packet.value();
+            value.resize(1024, 0);
+            let unvalue =
+                Packet::<VariableHeaderPacketIdentifier, SubackPayload>::unvalue_v5(value);
+            assert_eq!(unvalue.header.control_type, control_type::UNSUBACK);
+            assert_eq!(
+                unvalue.payload.qos,
+                vec![reason_codes::SUCCESS, reason_codes::NO_SUBSCRIPTION_EXISTED]
+            );
+        }
+
+        #[test]
+        fn check_puback_and_disconnect_packet_v5() {
+            let packet = Packet::<VariableHeader, Payload>::new();
+            let value = packet
+                .puback_v5(3, reason_codes::NO_MATCHING_SUBSCRIBERS, Properties::new())
+                .value();
+            assert_eq!(value, vec![0x40, 0x04, 0x00, 0x03, 0x10, 0x00]);
+            let unvalue = Packet::<VariableHeaderPacketIdentifier, Payload>::unvalue_v5(value);
+            assert_eq!(unvalue.variable_header.packet_identifier, 3);
+            assert_eq!(
+                unvalue.variable_header.reason_code,
+                Some(reason_codes::NO_MATCHING_SUBSCRIBERS)
+            );
+
+            // a disconnect without variable header is a normal disconnection
+            let unvalue =
+                Packet::<VariableHeaderReasonCode, Payload>::unvalue(vec![0xE0, 0x00, 0x00]);
+            assert_eq!(
+                unvalue.variable_header.reason_code,
+                reason_codes::NORMAL_DISCONNECTION
+            );
+            let packet = Packet::<VariableHeader, Payload>::new();
+            let value = packet
+                .disconnect_v5(
+                    reason_codes::DISCONNECT_WITH_WILL_MESSAGE,
+                    Properties::new(),
+                )
+                .value();
+            assert_eq!(value, vec![0xE0, 0x02, 0x04, 0x00]);
+            let unvalue = Packet::<VariableHeaderReasonCode, Payload>::unvalue(value);
+            assert_eq!(
+                unvalue.variable_header.reason_code,
+                reason_codes::DISCONNECT_WITH_WILL_MESSAGE
+            );
+            let auth = packet.auth(reason_codes::CONTINUE_AUTHENTICATION, Properties::new());
+            assert_eq!(auth.value()[0], control_type::AUTH);
+        }
     }
 }
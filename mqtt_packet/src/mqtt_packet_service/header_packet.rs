@@ -15,6 +15,7 @@ pub mod control_type {
     pub const PINGRESP: u8 = 0xD0;
     pub const DISCONNECT: u8 = 0xE0;
     pub const RESERVED: u8 = 0xF0;
+    pub const AUTH: u8 = 0xF0; // only on protocol level 5, reserved on 3.1.1
 }
 
 #[allow(dead_code)]
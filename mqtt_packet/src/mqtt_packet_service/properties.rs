@@ -0,0 +1,370 @@
+use super::header_packet::{Header, PacketHeader};
+
+/// Identifiers of the MQTT 5 properties (section 2.2.2.2 of the spec)
+#[allow(dead_code)]
+pub mod property_identifiers {
+    pub const PAYLOAD_FORMAT_INDICATOR: u8 = 0x01;
+    pub const MESSAGE_EXPIRY_INTERVAL: u8 = 0x02;
+    pub const CONTENT_TYPE: u8 = 0x03;
+    pub const RESPONSE_TOPIC: u8 = 0x08;
+    pub const CORRELATION_DATA: u8 = 0x09;
+    pub const SUBSCRIPTION_IDENTIFIER: u8 = 0x0B;
+    pub const SESSION_EXPIRY_INTERVAL: u8 = 0x11;
+    pub const ASSIGNED_CLIENT_IDENTIFIER: u8 = 0x12;
+    pub const SERVER_KEEP_ALIVE: u8 = 0x13;
+    pub const AUTHENTICATION_METHOD: u8 = 0x15;
+    pub const AUTHENTICATION_DATA: u8 = 0x16;
+    pub const REQUEST_PROBLEM_INFORMATION: u8 = 0x17;
+    pub const WILL_DELAY_INTERVAL: u8 = 0x18;
+    pub const REQUEST_RESPONSE_INFORMATION: u8 = 0x19;
+    pub const RESPONSE_INFORMATION: u8 = 0x1A;
+    pub const SERVER_REFERENCE: u8 = 0x1C;
+    pub const REASON_STRING: u8 = 0x1F;
+    pub const RECEIVE_MAXIMUM: u8 = 0x21;
+    pub const TOPIC_ALIAS_MAXIMUM: u8 = 0x22;
+    pub const TOPIC_ALIAS: u8 = 0x23;
+    pub const MAXIMUM_QOS: u8 = 0x24;
+    pub const RETAIN_AVAILABLE: u8 = 0x25;
+    pub const USER_PROPERTY: u8 = 0x26;
+    pub const MAXIMUM_PACKET_SIZE: u8 = 0x27;
+    pub const WILDCARD_SUBSCRIPTION_AVAILABLE: u8 = 0x28;
+    pub const SUBSCRIPTION_IDENTIFIER_AVAILABLE: u8 = 0x29;
+    pub const SHARED_SUBSCRIPTION_AVAILABLE: u8 = 0x2A;
+}
+
+/// Value of a property, the data type depends on the property identifier
+#[derive(Debug, Clone, PartialEq)]
+pub enum PropertyValue {
+    Byte(u8),
+    TwoByteInteger(u16),
+    FourByteInteger(u32),
+    VariableByteInteger(u32),
+    Utf8String(String),
+    BinaryData(Vec<u8>),
+    Utf8StringPair(String, String),
+}
+
+/// Properties of a MQTT 5 packet, kept in the order they were read or added
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Properties {
+    pub values: Vec<(u8, PropertyValue)>,
+}
+
+pub trait PacketProperties {
+    fn value(&self) -> Vec<u8>;
+    fn unvalue(x: Vec<u8>, readed: &mut usize) -> Properties;
+}
+
+/// Data type of each property identifier, None for unknown identifiers
+fn empty_value(identifier: u8) -> Option<PropertyValue> {
+    use property_identifiers::*;
+    match identifier {
+        PAYLOAD_FORMAT_INDICATOR
+        | REQUEST_PROBLEM_INFORMATION
+        | REQUEST_RESPONSE_INFORMATION
+        | MAXIMUM_QOS
+        | RETAIN_AVAILABLE
+        | WILDCARD_SUBSCRIPTION_AVAILABLE
+        | SUBSCRIPTION_IDENTIFIER_AVAILABLE
+        | SHARED_SUBSCRIPTION_AVAILABLE => Some(PropertyValue::Byte(0)),
+        SERVER_KEEP_ALIVE | RECEIVE_MAXIMUM | TOPIC_ALIAS_MAXIMUM | TOPIC_ALIAS => {
+            Some(PropertyValue::TwoByteInteger(0))
+        }
+        MESSAGE_EXPIRY_INTERVAL
+        | SESSION_EXPIRY_INTERVAL
+        | WILL_DELAY_INTERVAL
+        | MAXIMUM_PACKET_SIZE => Some(PropertyValue::FourByteInteger(0)),
+        SUBSCRIPTION_IDENTIFIER => Some(PropertyValue::VariableByteInteger(0)),
+        CONTENT_TYPE
+        | RESPONSE_TOPIC
+        | ASSIGNED_CLIENT_IDENTIFIER
+        | AUTHENTICATION_METHOD
+        | RESPONSE_INFORMATION
+        | SERVER_REFERENCE
+        | REASON_STRING => Some(PropertyValue::Utf8String(String::new())),
+        CORRELATION_DATA | AUTHENTICATION_DATA => Some(PropertyValue::BinaryData(Vec::new())),
+        USER_PROPERTY => Some(PropertyValue::Utf8StringPair(String::new(), String::new())),
+        _ => None,
+    }
+}
+
+fn read_binary(x: &[u8], index: &mut usize) -> Option<Vec<u8>> {
+    let len = u16::from_be_bytes([*x.get(*index)?, *x.get(*index + 1)?]) as usize;
+    let data = x.get(*index + 2..*index + 2 + len)?.to_vec();
+    *index += 2 + len;
+    Some(data)
+}
+
+fn read_string(x: &[u8], index: &mut usize) -> Option<String> {
+    String::from_utf8(read_binary(x, index)?).ok()
+}
+
+fn write_binary(vec: &mut Vec<u8>, data: &[u8]) {
+    vec.extend((data.len() as u16).to_be_bytes());
+    vec.extend(data);
+}
+
+/// Reads a variable byte integer, the same encoding used by the remaining length
+fn read_variable_byte_integer(x: &[u8], index: &mut usize) -> Option<u32> {
+    let bytes: Vec<u8> = x.get(*index..)?.iter().take(4).cloned().collect();
+    let len = bytes.iter().position(|byte| byte & 0x80 == 0)? + 1;
+    *index += len;
+    Some(Header::decode_remaining_length(&bytes[..len]))
+}
+
+fn read_value(empty: PropertyValue, x: &[u8], index: &mut usize) -> Option<PropertyValue> {
+    let value = match empty {
+        PropertyValue::Byte(_) => {
+            let value = *x.get(*index)?;
+            *index += 1;
+            PropertyValue::Byte(value)
+        }
+        PropertyValue::TwoByteInteger(_) => {
+            let value = u16::from_be_bytes([*x.get(*index)?, *x.get(*index + 1)?]);
+            *index += 2;
+            PropertyValue::TwoByteInteger(value)
+        }
+        PropertyValue::FourByteInteger(_) => {
+            let bytes = x.get(*index..*index + 4)?;
+            *index += 4;
+            PropertyValue::FourByteInteger(u32::from_be_bytes([
+                bytes[0], bytes[1], bytes[2], bytes[3],
+            ]))
+        }
+        PropertyValue::VariableByteInteger(_) => {
+            PropertyValue::VariableByteInteger(read_variable_byte_integer(x, index)?)
+        }
+        PropertyValue::Utf8String(_) => PropertyValue::Utf8String(read_string(x, index)?),
+        PropertyValue::BinaryData(_) => PropertyValue::BinaryData(read_binary(x, index)?),
+        PropertyValue::Utf8StringPair(_, _) => {
+            let key = read_string(x, index)?;
+            PropertyValue::Utf8StringPair(key, read_string(x, index)?)
+        }
+    };
+    Some(value)
+}
+
+impl PacketProperties for Properties {
+    /// Serializes the properties preceded by their length as a variable byte integer
+    fn value(&self) -> Vec<u8> {
+        let mut properties_vec: Vec<u8> = Vec::with_capacity(64);
+        for (identifier, value) in &self.values {
+            properties_vec.push(*identifier);
+            match value {
+                PropertyValue::Byte(value) => properties_vec.push(*value),
+                PropertyValue::TwoByteInteger(value) => properties_vec.extend(value.to_be_bytes()),
+                PropertyValue::FourByteInteger(value) => properties_vec.extend(value.to_be_bytes()),
+                PropertyValue::VariableByteInteger(value) => {
+                    properties_vec.extend(Header::encode_remaining_length(*value))
+                }
+                PropertyValue::Utf8String(value) => {
+                    write_binary(&mut properties_vec, value.as_bytes())
+                }
+                PropertyValue::BinaryData(value) => write_binary(&mut properties_vec, value),
+                PropertyValue::Utf8StringPair(key, value) => {
+                    write_binary(&mut properties_vec, key.as_bytes());
+                    write_binary(&mut properties_vec, value.as_bytes());
+                }
+            }
+        }
+        let mut vec = Header::encode_remaining_length(properties_vec.len() as u32);
+        vec.extend(properties_vec);
+        vec
+    }
+
+    /// Deserializes the properties, readed counts the length bytes too.
+    /// Unknown or malformed properties stop the parsing, the rest of the block is skipped.
+    fn unvalue(x: Vec<u8>, readed: &mut usize) -> Properties {
+        *readed = 0;
+        let mut index = 0;
+        let properties_len = match read_variable_byte_integer(&x, &mut index) {
+            Some(properties_len) => properties_len as usize,
+            None => return Properties::default(),
+        };
+        let end = (index + properties_len).min(x.len());
+        let block = &x[..end];
+        let mut values = Vec::new();
+        while index < end {
+            let identifier = block[index];
+            index += 1;
+            match empty_value(identifier).and_then(|empty| read_value(empty, block, &mut index)) {
+                Some(value) => values.push((identifier, value)),
+                None => break,
+            }
+        }
+        *readed = end;
+        Properties { values }
+    }
+}
+
+impl Properties {
+    pub fn new() -> Properties {
+        Properties::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    pub fn get(&self, identifier: u8) -> Option<&PropertyValue> {
+        self.values
+            .iter()
+            .find(|(id, _)| *id == identifier)
+            .map(|(_, value)| value)
+    }
+
+    /// Sets a property replacing the previous value, user properties must use add_user_property
+    pub fn set(&mut self, identifier: u8, value: PropertyValue) {
+        self.remove(identifier);
+        self.values.push((identifier, value));
+    }
+
+    pub fn remove(&mut self, identifier: u8) {
+        self.values.retain(|(id, _)| *id != identifier);
+    }
+
+    pub fn add_user_property(&mut self, key: &str, value: &str) {
+        self.values.push((
+            property_identifiers::USER_PROPERTY,
+            PropertyValue::Utf8StringPair(key.to_string(), value.to_string()),
+        ));
+    }
+
+    pub fn user_properties(&self) -> Vec<(String, String)> {
+        self.values
+            .iter()
+            .filter_map(|(_, value)| match value {
+                PropertyValue::Utf8StringPair(key, value) => Some((key.clone(), value.clone())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn get_byte(&self, identifier: u8) -> Option<u8> {
+        match self.get(identifier)? {
+            PropertyValue::Byte(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn get_u16(&self, identifier: u8) -> Option<u16> {
+        match self.get(identifier)? {
+            PropertyValue::TwoByteInteger(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn get_u32(&self, identifier: u8) -> Option<u32> {
+        match self.get(identifier)? {
+            PropertyValue::FourByteInteger(value) | PropertyValue::VariableByteInteger(value) => {
+                Some(*value)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn get_string(&self, identifier: u8) -> Option<String> {
+        match self.get(identifier)? {
+            PropertyValue::Utf8String(value) => Some(value.clone()),
+            _ => None,
+        }
+    }
+
+    /// Properties of a publish that the server forwards to the subscribers
+    pub fn forwarded(&self) -> Properties {
+        use property_identifiers::*;
+        let values = self
+            .values
+            .iter()
+            .filter(|(id, _)| {
+                matches!(
+                    *id,
+                    PAYLOAD_FORMAT_INDICATOR
+                        | MESSAGE_EXPIRY_INTERVAL
+                        | CONTENT_TYPE
+                        | RESPONSE_TOPIC
+                        | CORRELATION_DATA
+                        | USER_PROPERTY
+                )
+            })
+            .cloned()
+            .collect();
+        Properties { values }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::property_identifiers::*;
+    use super::*;
+
+    #[test]
+    fn properties_value_and_unvalue() {
+        let mut properties = Properties::new();
+        properties.set(SESSION_EXPIRY_INTERVAL, PropertyValue::FourByteInteger(120));
+        properties.set(TOPIC_ALIAS, PropertyValue::TwoByteInteger(3));
+        properties.set(REASON_STRING, PropertyValue::Utf8String("done".to_string()));
+        properties.set(CORRELATION_DATA, PropertyValue::BinaryData(vec![1, 2, 3]));
+        properties.set(
+            SUBSCRIPTION_IDENTIFIER,
+            PropertyValue::VariableByteInteger(300),
+        );
+        properties.add_user_property("room", "kitchen");
+        properties.add_user_property("room", "hall");
+
+        let value = properties.value();
+        // length as variable byte integer followed by the properties
+        assert_eq!(value[0] as usize, value.len() - 1);
+
+        // bytes after the properties are not read
+        let mut with_tail = value.clone();
+        with_tail.extend(vec![0xAA, 0xBB]);
+        let mut readed = 0;
+        let unvalue = Properties::unvalue(with_tail, &mut readed);
+        assert_eq!(readed, value.len());
+        assert_eq!(unvalue, properties);
+        assert_eq!(unvalue.get_u32(SESSION_EXPIRY_INTERVAL), Some(120));
+        assert_eq!(unvalue.get_u32(SUBSCRIPTION_IDENTIFIER), Some(300));
+        assert_eq!(unvalue.get_u16(TOPIC_ALIAS), Some(3));
+        assert_eq!(unvalue.get_string(REASON_STRING), Some("done".to_string()));
+        assert_eq!(
+            unvalue.user_properties(),
+            vec![
+                ("room".to_string(), "kitchen".to_string()),
+                ("room".to_string(), "hall".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn properties_empty() {
+        let properties = Properties::new();
+        assert_eq!(properties.value(), vec![0x00]);
+        let mut readed = 0;
+        let unvalue = Properties::unvalue(vec![0x00, 0x31], &mut readed);
+        assert!(unvalue.is_empty());
+        assert_eq!(readed, 1);
+    }
+
+    #[test]
+    fn properties_unknown_identifier_skips_block() {
+        // unknown identifier 0x7F inside a block of 4 bytes
+        let mut readed = 0;
+        let unvalue = Properties::unvalue(vec![0x04, 0x24, 0x01, 0x7F, 0x00, 0x55], &mut readed);
+        assert_eq!(unvalue.get_byte(MAXIMUM_QOS), Some(1));
+        assert_eq!(unvalue.values.len(), 1);
+        assert_eq!(readed, 5);
+    }
+
+    #[test]
+    fn properties_forwarded() {
+        let mut properties = Properties::new();
+        properties.set(TOPIC_ALIAS, PropertyValue::TwoByteInteger(1));
+        properties.set(MESSAGE_EXPIRY_INTERVAL, PropertyValue::FourByteInteger(10));
+        properties.add_user_property("a", "b");
+        let forwarded = properties.forwarded();
+        assert_eq!(forwarded.get_u16(TOPIC_ALIAS), None);
+        assert_eq!(forwarded.get_u32(MESSAGE_EXPIRY_INTERVAL), Some(10));
+        assert_eq!(forwarded.user_properties().len(), 1);
+    }
+}
@@ -1,3 +1,11 @@
+use super::properties::{PacketProperties, Properties};
+
+#[allow(dead_code)]
+pub mod protocol_level {
+    pub const MQTT_3_1_1: u8 = 0x04;
+    pub const MQTT_5: u8 = 0x05;
+}
+
 #[allow(dead_code)]
 pub mod connect_flags {
     pub const USERNAME: u8 = 0x80;
@@ -20,6 +28,56 @@ pub mod connect_return {
     pub const NOT_AUTHORIZED: u8 = 0x05;
 }
 
+/// Reason codes of the MQTT 5 acks, disconnect and auth packets
+#[allow(dead_code)]
+pub mod reason_codes {
+    pub const SUCCESS: u8 = 0x00;
+    pub const NORMAL_DISCONNECTION: u8 = 0x00;
+    pub const GRANTED_QOS_0: u8 = 0x00;
+    pub const GRANTED_QOS_1: u8 = 0x01;
+    pub const GRANTED_QOS_2: u8 = 0x02;
+    pub const DISCONNECT_WITH_WILL_MESSAGE: u8 = 0x04;
+    pub const NO_MATCHING_SUBSCRIBERS: u8 = 0x10;
+    pub const NO_SUBSCRIPTION_EXISTED: u8 = 0x11;
+    pub const CONTINUE_AUTHENTICATION: u8 = 0x18;
+    pub const RE_AUTHENTICATE: u8 = 0x19;
+    pub const UNSPECIFIED_ERROR: u8 = 0x80;
+    pub const MALFORMED_PACKET: u8 = 0x81;
+    pub const PROTOCOL_ERROR: u8 = 0x82;
+    pub const IMPLEMENTATION_SPECIFIC_ERROR: u8 = 0x83;
+    pub const UNSUPPORTED_PROTOCOL_VERSION: u8 = 0x84;
+    pub const CLIENT_IDENTIFIER_NOT_VALID: u8 = 0x85;
+    pub const BAD_USER_NAME_OR_PASSWORD: u8 = 0x86;
+    pub const NOT_AUTHORIZED: u8 = 0x87;
+    pub const SERVER_UNAVAILABLE: u8 = 0x88;
+    pub const SERVER_BUSY: u8 = 0x89;
+    pub const BANNED: u8 = 0x8A;
+    pub const SERVER_SHUTTING_DOWN: u8 = 0x8B;
+    pub const BAD_AUTHENTICATION_METHOD: u8 = 0x8C;
+    pub const KEEP_ALIVE_TIMEOUT: u8 = 0x8D;
+    pub const SESSION_TAKEN_OVER: u8 = 0x8E;
+    pub const TOPIC_FILTER_INVALID: u8 = 0x8F;
+    pub const TOPIC_NAME_INVALID: u8 = 0x90;
+    pub const PACKET_IDENTIFIER_IN_USE: u8 = 0x91;
+    pub const PACKET_IDENTIFIER_NOT_FOUND: u8 = 0x92;
+    pub const RECEIVE_MAXIMUM_EXCEEDED: u8 = 0x93;
+    pub const TOPIC_ALIAS_INVALID: u8 = 0x94;
+    pub const PACKET_TOO_LARGE: u8 = 0x95;
+    pub const MESSAGE_RATE_TOO_HIGH: u8 = 0x96;
+    pub const QUOTA_EXCEEDED: u8 = 0x97;
+    pub const ADMINISTRATIVE_ACTION: u8 = 0x98;
+    pub const PAYLOAD_FORMAT_INVALID: u8 = 0x99;
+    pub const RETAIN_NOT_SUPPORTED: u8 = 0x9A;
+    pub const QOS_NOT_SUPPORTED: u8 = 0x9B;
+    pub const USE_ANOTHER_SERVER: u8 = 0x9C;
+    pub const SERVER_MOVED: u8 = 0x9D;
+    pub const SHARED_SUBSCRIPTIONS_NOT_SUPPORTED: u8 = 0x9E;
+    pub const CONNECTION_RATE_EXCEEDED: u8 = 0x9F;
+    pub const MAXIMUM_CONNECT_TIME: u8 = 0xA0;
+    pub const SUBSCRIPTION_IDENTIFIERS_NOT_SUPPORTED: u8 = 0xA1;
+    pub const WILDCARD_SUBSCRIPTIONS_NOT_SUPPORTED: u8 = 0xA2;
+}
+
 #[allow(dead_code)]
 pub mod connect_ack_flags {
     pub const SESSION_PRESENT: u8 = 0x01;
@@ -31,6 +89,7 @@ pub struct VariableHeader {
     pub protocol_level: u8,     // for spec 3.1.1 mqtt the value of protocol is 4 (0x04)
     pub connect_flags: u8,      // 1 byte - note: to set use CONNECTFLAGS enum
     pub keep_alive: u16,        // 2 bytes
+    pub properties: Properties, // only for protocol level 5
 }
 
 pub trait PacketVariableHeader {
@@ -53,6 +112,9 @@ impl PacketVariableHeader for VariableHeader {
         variable_header_vec.push(self.connect_flags);
         variable_header_vec.push((self.keep_alive >> 8) as u8);
         variable_header_vec.push((self.keep_alive & 0xFF) as u8);
+        if self.protocol_level == protocol_level::MQTT_5 {
+            variable_header_vec.extend(self.properties.value());
+        }
         variable_header_vec
     }
 
@@ -62,19 +124,27 @@ impl PacketVariableHeader for VariableHeader {
         let connect_flags = x[7];
         let keep_alive = (x[8] as u16) << 8 | (x[9] as u16);
         *readed = 10;
+        let mut properties = Properties::default();
+        if protocol_level == protocol_level::MQTT_5 {
+            let mut properties_readed = 0;
+            properties = Properties::unvalue(x[10..].to_vec(), &mut properties_readed);
+            *readed += properties_readed;
+        }
         VariableHeader {
             protocol_name,
             protocol_level,
             connect_flags,
             keep_alive,
+            properties,
         }
     }
 }
 
 #[derive(Debug, Default)]
 pub struct VariableHeaderConnack {
-    pub acknoledge_flags: u8, // 1 byte
-    pub return_code: u8,      // 1 byte
+    pub acknoledge_flags: u8,           // 1 byte
+    pub return_code: u8,                // 1 byte, reason code for protocol level 5
+    pub properties: Option<Properties>, // only for protocol level 5
 }
 
 pub trait PacketVariableHeaderConnack {
@@ -84,13 +154,25 @@ pub trait PacketVariableHeaderConnack {
 
 impl PacketVariableHeaderConnack for VariableHeaderConnack {
     fn value(&self) -> Vec<u8> {
-        vec![self.acknoledge_flags, self.return_code]
+        let mut variable_header_vec = vec![self.acknoledge_flags, self.return_code];
+        if let Some(properties) = &self.properties {
+            variable_header_vec.extend(properties.value());
+        }
+        variable_header_vec
     }
+    /// Properties are read when x has more than the flags and the return code
     fn unvalue(x: Vec<u8>, readed: &mut usize) -> VariableHeaderConnack {
         *readed = 2;
+        let mut properties = None;
+        if x.len() > 2 {
+            let mut properties_readed = 0;
+            properties = Some(Properties::unvalue(x[2..].to_vec(), &mut properties_readed));
+            *readed += properties_readed;
+        }
         VariableHeaderConnack {
             acknoledge_flags: x[0],
             return_code: x[1],
+            properties,
         }
     }
 }
@@ -98,11 +180,13 @@ impl PacketVariableHeaderConnack for VariableHeaderConnack {
 #[derive(Debug, Default)]
 pub struct VariableHeaderPublish {
     pub topic_name: Vec<u8>,
-    pub packet_identifier: u16, // 2 bytes
+    pub packet_identifier: u16,         // 2 bytes
+    pub properties: Option<Properties>, // only for protocol level 5
 }
 pub trait PacketVariableHeaderPublish {
     fn value(&self) -> Vec<u8>;
     fn unvalue(x: Vec<u8>, readed: &mut usize) -> VariableHeaderPublish;
+    fn unvalue_v5(x: Vec<u8>, readed: &mut usize) -> VariableHeaderPublish;
 }
 
 impl PacketVariableHeaderPublish for VariableHeaderPublish {
@@ -116,8 +200,19 @@ impl PacketVariableHeaderPublish for VariableHeaderPublish {
         }
         variable_header_vec.push((self.packet_identifier >> 8) as u8);
         variable_header_vec.push((self.packet_identifier & 0xFF) as u8);
+        if let Some(properties) = &self.properties {
+            variable_header_vec.extend(properties.value());
+        }
         variable_header_vec
     }
+    fn unvalue_v5(x: Vec<u8>, readed: &mut usize) -> VariableHeaderPublish {
+        let mut variable_header = VariableHeaderPublish::unvalue(x.clone(), readed);
+        let mut properties_readed = 0;
+        let properties = Properties::unvalue(x[*readed..].to_vec(), &mut properties_readed);
+        *readed += properties_readed;
+        variable_header.properties = Some(properties);
+        variable_header
+    }
     fn unvalue(x: Vec<u8>, readed: &mut usize) -> VariableHeaderPublish {
         let topic_name_len = (x[0] as u16) << 8 | (x[1] as u16);
         let topic_name = x[2..(2 + topic_name_len as usize)].to_vec();
@@ -127,37 +222,93 @@ impl PacketVariableHeaderPublish for VariableHeaderPublish {
         VariableHeaderPublish {
             topic_name,
             packet_identifier,
+            properties: None,
         }
     }
 }
 
 #[derive(Debug, Default)]
 pub struct VariableHeaderPacketIdentifier {
-    pub packet_identifier: u16, // 2 bytes
+    pub packet_identifier: u16,         // 2 bytes
+    pub reason_code: Option<u8>,        // only for puback on protocol level 5
+    pub properties: Option<Properties>, // only for protocol level 5
 }
 
 pub trait PacketVariableHeaderPacketIdentifier {
     fn value(&self) -> Vec<u8>;
     fn unvalue(x: Vec<u8>, readed: &mut usize) -> VariableHeaderPacketIdentifier;
+    fn unvalue_v5(x: Vec<u8>, readed: &mut usize) -> VariableHeaderPacketIdentifier;
 }
 
 impl PacketVariableHeaderPacketIdentifier for VariableHeaderPacketIdentifier {
     fn value(&self) -> Vec<u8> {
-        vec![
+        let mut variable_header_vec = vec![
             (self.packet_identifier >> 8) as u8,
             (self.packet_identifier & 0xFF) as u8,
-        ]
+        ];
+        if let Some(reason_code) = self.reason_code {
+            variable_header_vec.push(reason_code);
+        }
+        if let Some(properties) = &self.properties {
+            variable_header_vec.extend(properties.value());
+        }
+        variable_header_vec
     }
     fn unvalue(x: Vec<u8>, readed: &mut usize) -> VariableHeaderPacketIdentifier {
         *readed = 2;
         VariableHeaderPacketIdentifier {
             packet_identifier: (x[0] as u16) << 8 | (x[1] as u16),
+            ..VariableHeaderPacketIdentifier::default()
+        }
+    }
+    /// Packet identifier followed by the properties, used by subscribe, suback, unsubscribe and unsuback
+    fn unvalue_v5(x: Vec<u8>, readed: &mut usize) -> VariableHeaderPacketIdentifier {
+        let mut variable_header = VariableHeaderPacketIdentifier::unvalue(x.clone(), readed);
+        let mut properties_readed = 0;
+        let properties = Properties::unvalue(x[2..].to_vec(), &mut properties_readed);
+        *readed += properties_readed;
+        variable_header.properties = Some(properties);
+        variable_header
+    }
+}
+
+/// Variable header of the disconnect and auth packets on protocol level 5
+#[derive(Debug, Default)]
+pub struct VariableHeaderReasonCode {
+    pub reason_code: u8,
+    pub properties: Properties,
+}
+
+pub trait PacketVariableHeaderReasonCode {
+    fn value(&self) -> Vec<u8>;
+    fn unvalue(x: Vec<u8>, readed: &mut usize) -> VariableHeaderReasonCode;
+}
+
+impl PacketVariableHeaderReasonCode for VariableHeaderReasonCode {
+    fn value(&self) -> Vec<u8> {
+        let mut variable_header_vec = vec![self.reason_code];
+        variable_header_vec.extend(self.properties.value());
+        variable_header_vec
+    }
+    /// An empty x is a success without properties
+    fn unvalue(x: Vec<u8>, readed: &mut usize) -> VariableHeaderReasonCode {
+        *readed = 0;
+        if x.is_empty() {
+            return VariableHeaderReasonCode::default();
+        }
+        let mut properties_readed = 0;
+        let properties = Properties::unvalue(x[1..].to_vec(), &mut properties_readed);
+        *readed = 1 + properties_readed;
+        VariableHeaderReasonCode {
+            reason_code: x[0],
+            properties,
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::super::properties::{property_identifiers, PropertyValue};
     use super::*;
 
     #[test]
@@ -167,6 +318,7 @@ mod tests {
             protocol_level: 4,
             connect_flags: 0,
             keep_alive: 0,
+            properties: Properties::default(),
         };
         assert_eq!(variable_header.clean_session(), false);
 
@@ -178,6 +330,7 @@ mod tests {
     fn test_value_publishack() {
         let variable_header_publishack_stub = VariableHeaderPacketIdentifier {
             packet_identifier: 0x1234,
+            ..VariableHeaderPacketIdentifier::default()
         };
         let variable_header_publishack_value = variable_header_publishack_stub.value();
         assert_eq!(variable_header_publishack_value, vec![0x34, 0x12]);
@@ -188,6 +341,7 @@ mod tests {
         let variable_header_publish = VariableHeaderPublish {
             topic_name: "Temperature".as_bytes().to_vec(),
             packet_identifier: 0x0001,
+            properties: None,
         };
         let value = variable_header_publish.value();
         // println!("value: {:?}", value);
@@ -207,6 +361,7 @@ mod tests {
         let variable_header_connack = VariableHeaderConnack {
             acknoledge_flags: 0x01,
             return_code: 0x02,
+            properties: None,
         };
         let value = variable_header_connack.value();
         let mut readed = 0;
@@ -231,6 +386,7 @@ mod tests {
             protocol_level: protocol_level,
             connect_flags: connect_flags,
             keep_alive: keep_alive,
+            properties: Properties::default(),
         };
         let value: Vec<u8> = variable_header.value();
         // println!("{:?}", value);
@@ -266,6 +422,7 @@ mod tests {
             protocol_level: protocol_level,
             connect_flags: connect_flags,
             keep_alive: keep_alive,
+            properties: Properties::default(),
         };
         let value: Vec<u8> = variable_header.value();
         // println!("variable header value: {:?}", value);
@@ -282,10 +439,98 @@ mod tests {
         let variable_header = VariableHeaderConnack {
             acknoledge_flags: acknoledge_flags,
             return_code: return_code,
+            properties: None,
         };
         let value: Vec<u8> = variable_header.value();
         println!("variable header value: {:?}", value);
         assert!(value.len() == vh_stub.len());
         assert!(vh_stub.eq(&value));
     }
+
+    #[test]
+    fn unvalue_variable_header_v5() {
+        let mut properties = Properties::default();
+        properties.set(
+            property_identifiers::SESSION_EXPIRY_INTERVAL,
+            PropertyValue::FourByteInteger(60),
+        );
+        let variable_header = VariableHeader {
+            protocol_name: [0x00, 0x04, b'M', b'Q', b'T', b'T'].to_vec(),
+            protocol_level: protocol_level::MQTT_5,
+            connect_flags: connect_flags::CLEAN_SESSION,
+            keep_alive: 30,
+            properties,
+        };
+        let mut value: Vec<u8> = variable_header.value();
+        let len = value.len();
+        value.extend(vec![0x00, 0x03]); // start of the payload
+        let mut readed = 0;
+        let unvalue = VariableHeader::unvalue(value, &mut readed);
+        assert_eq!(readed, len);
+        assert_eq!(unvalue.properties, variable_header.properties);
+        assert_eq!(unvalue.keep_alive, 30);
+    }
+
+    #[test]
+    fn unvalue_variable_header_connack_v5() {
+        let mut properties = Properties::default();
+        properties.set(
+            property_identifiers::TOPIC_ALIAS_MAXIMUM,
+            PropertyValue::TwoByteInteger(10),
+        );
+        let variable_header_connack = VariableHeaderConnack {
+            acknoledge_flags: 0x00,
+            return_code: reason_codes::SUCCESS,
+            properties: Some(properties),
+        };
+        let value = variable_header_connack.value();
+        assert_eq!(value.len(), 2 + 4);
+        let mut readed = 0;
+        let unvalue = VariableHeaderConnack::unvalue(value, &mut readed);
+        assert_eq!(unvalue.properties, variable_header_connack.properties);
+        assert_eq!(readed, 6);
+    }
+
+    #[test]
+    fn unvalue_variable_header_publish_v5() {
+        let mut properties = Properties::default();
+        properties.add_user_property("unit", "celsius");
+        let variable_header_publish = VariableHeaderPublish {
+            topic_name: "Temperature".as_bytes().to_vec(),
+            packet_identifier: 0x0001,
+            properties: Some(properties),
+        };
+        let value = variable_header_publish.value();
+        let mut readed = 0;
+        let unvalue = VariableHeaderPublish::unvalue_v5(value.clone(), &mut readed);
+        assert_eq!(unvalue.properties, variable_header_publish.properties);
+        assert_eq!(unvalue.topic_name, variable_header_publish.topic_name);
+        assert_eq!(readed, value.len());
+    }
+
+    #[test]
+    fn unvalue_variable_header_reason_code() {
+        let mut properties = Properties::default();
+        properties.set(
+            property_identifiers::REASON_STRING,
+            PropertyValue::Utf8String("bye".to_string()),
+        );
+        let variable_header = VariableHeaderReasonCode {
+            reason_code: reason_codes::DISCONNECT_WITH_WILL_MESSAGE,
+            properties,
+        };
+        let value = variable_header.value();
+        let mut readed = 0;
+        let unvalue = VariableHeaderReasonCode::unvalue(value.clone(), &mut readed);
+        assert_eq!(
+            unvalue.reason_code,
+            reason_codes::DISCONNECT_WITH_WILL_MESSAGE
+        );
+        assert_eq!(unvalue.properties, variable_header.properties);
+        assert_eq!(readed, value.len());
+
+        let unvalue = VariableHeaderReasonCode::unvalue(vec![], &mut readed);
+        assert_eq!(unvalue.reason_code, reason_codes::NORMAL_DISCONNECTION);
+        assert_eq!(readed, 0);
+    }
 }
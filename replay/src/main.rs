@@ -0,0 +1,184 @@
+use client::client::Client;
+use replay::recording::RecordReader;
+use std::env;
+use std::io::{Error, ErrorKind, Result};
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Time given to the broker to accept the connection
+const CONNACK_TIMEOUT_SECS: u64 = 10;
+/// Time given to the client to write the last publishes before closing the connection
+const DRAIN_MILLIS: u64 = 1000;
+
+const USAGE: &str = "usage: replay RECORDING HOST PORT [--speed FACTOR] [--username USER --password PASSWORD] [--client-id ID]";
+
+/// Options of the command line
+#[derive(Debug, PartialEq)]
+struct Options {
+    recording: String,
+    host: String,
+    port: String,
+    speed: f64, // 1 keeps the original times, 2 replays twice as fast, 0 without waiting
+    username: String,
+    password: String,
+    client_id: String,
+}
+
+fn parse_args(args: &[String]) -> std::result::Result<Options, String> {
+    let mut positional = Vec::new();
+    let mut options = Options {
+        recording: String::new(),
+        host: String::new(),
+        port: String::new(),
+        speed: 1.0,
+        username: String::new(),
+        password: String::new(),
+        client_id: String::new(),
+    };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            positional.push(arg.clone());
+            continue;
+        }
+        let value = args
+            .next()
+            .ok_or_else(|| format!("{} needs a value", arg))?
+            .clone();
+        match arg.as_str() {
+            "--speed" => {
+                options.speed = value
+                    .parse()
+                    .ok()
+                    .filter(|speed: &f64| *speed >= 0.0 && speed.is_finite())
+                    .ok_or_else(|| format!("Invalid speed: {}", value))?
+            }
+            "--username" => options.username = value,
+            "--password" => options.password = value,
+            "--client-id" => options.client_id = value,
+            _ => return Err(format!("Unknown option: {}", arg)),
+        }
+    }
+    match positional.as_slice() {
+        [recording, host, port] => {
+            options.recording = recording.clone();
+            options.host = host.clone();
+            options.port = port.clone();
+            Ok(options)
+        }
+        _ => Err("Expected a recording, a host and a port".to_string()),
+    }
+}
+
+/// Time from the start of the replay at which a record of the recording is published
+fn delay(first_timestamp: u64, timestamp: u64, speed: f64) -> Duration {
+    if speed == 0.0 {
+        return Duration::ZERO;
+    }
+    Duration::from_millis(timestamp.saturating_sub(first_timestamp)).div_f64(speed)
+}
+
+fn replay(options: &Options) -> Result<usize> {
+    let records = RecordReader::open(&options.recording)?;
+
+    let mut client = Client::new();
+    if !options.client_id.is_empty() {
+        client.set_client_identifier(options.client_id.clone());
+    }
+    client
+        .connect(
+            options.host.clone(),
+            options.port.clone(),
+            options.username.clone(),
+            options.password.clone(),
+            true,
+            String::new(),
+            String::new(),
+        )
+        .map_err(|e| Error::new(ErrorKind::ConnectionRefused, e))?;
+    let deadline = Instant::now() + Duration::from_secs(CONNACK_TIMEOUT_SECS);
+    while !client.is_connected() {
+        if Instant::now() > deadline {
+            return Err(Error::new(ErrorKind::TimedOut, "No connack received"));
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let start = Instant::now();
+    let mut first_timestamp = None;
+    let mut published = 0;
+    for record in records {
+        let record = record?;
+        let first = *first_timestamp.get_or_insert(record.timestamp);
+        let at = start + delay(first, record.timestamp, options.speed);
+        thread::sleep(at.saturating_duration_since(Instant::now()));
+        if !client.is_connected() {
+            return Err(Error::new(ErrorKind::ConnectionAborted, "Connection lost"));
+        }
+        client.publish(
+            record.qos,
+            0,
+            record.retain as u8,
+            &record.topic,
+            &String::from_utf8_lossy(&record.payload),
+        );
+        published += 1;
+    }
+    client.disconnect();
+    thread::sleep(Duration::from_millis(DRAIN_MILLIS));
+    Ok(published)
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let options = match parse_args(&args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+    let start = Instant::now();
+    match replay(&options) {
+        Ok(published) => println!(
+            "Replayed {} messages of {} in {:.1}s",
+            published,
+            options.recording,
+            start.elapsed().as_secs_f64()
+        ),
+        Err(e) => {
+            eprintln!("Cannot replay {}: {}", options.recording, e);
+            process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let options = parse_args(&args("messages.rec 127.0.0.1 1883 --speed 2.5")).unwrap();
+        assert_eq!(options.recording, "messages.rec");
+        assert_eq!(options.port, "1883");
+        assert_eq!(options.speed, 2.5);
+        assert!(parse_args(&args("messages.rec 127.0.0.1")).is_err());
+        assert!(parse_args(&args("messages.rec 127.0.0.1 1883 --speed -1")).is_err());
+        assert!(parse_args(&args("messages.rec 127.0.0.1 1883 --speed")).is_err());
+    }
+
+    #[test]
+    fn test_delay() {
+        assert_eq!(delay(1000, 3000, 1.0), Duration::from_secs(2));
+        assert_eq!(delay(1000, 3000, 4.0), Duration::from_millis(500));
+        assert_eq!(delay(1000, 3000, 0.0), Duration::ZERO);
+        // clocks going back do not wait
+        assert_eq!(delay(3000, 1000, 1.0), Duration::ZERO);
+    }
+}
@@ -0,0 +1,185 @@
+//! Recordings of the messages published on a broker.
+//!
+//! A recording starts with the 8 bytes `MQTTREC1`, followed by one record for each
+//! message, all numbers big endian:
+//! * timestamp: u64, milliseconds since the unix epoch when the broker dispatched it
+//! * flags: u8, the QoS in bits 0-1 and the retain flag in bit 2
+//! * topic: u16 length and the topic in utf-8
+//! * payload: u32 length and the payload bytes
+
+use std::convert::TryFrom;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Result, Write};
+use std::path::Path;
+
+pub const MAGIC: &[u8; 8] = b"MQTTREC1";
+
+const RETAIN_FLAG: u8 = 0x04;
+const QOS_MASK: u8 = 0x03;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Record {
+    pub timestamp: u64, // milliseconds since the unix epoch
+    pub topic: String,
+    pub qos: u8,
+    pub retain: bool,
+    pub payload: Vec<u8>,
+}
+
+impl Record {
+    fn encode(&self) -> Result<Vec<u8>> {
+        let topic_length = u16::try_from(self.topic.len())
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, "Topic too long to record"))?;
+        let payload_length = u32::try_from(self.payload.len())
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, "Payload too long to record"))?;
+        let mut bytes = Vec::with_capacity(15 + self.topic.len() + self.payload.len());
+        bytes.extend(self.timestamp.to_be_bytes());
+        bytes.push(self.qos & QOS_MASK | if self.retain { RETAIN_FLAG } else { 0 });
+        bytes.extend(topic_length.to_be_bytes());
+        bytes.extend(self.topic.as_bytes());
+        bytes.extend(payload_length.to_be_bytes());
+        bytes.extend(&self.payload);
+        Ok(bytes)
+    }
+}
+
+/// Appends records to a recording
+pub struct RecordWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> RecordWriter<W> {
+    /// Starts a new recording on writer
+    pub fn new(mut writer: W) -> Result<RecordWriter<W>> {
+        writer.write_all(MAGIC)?;
+        Ok(RecordWriter { writer })
+    }
+
+    /// Writes a record, it is flushed so a recording cut by a crash keeps every record
+    pub fn write(&mut self, record: &Record) -> Result<()> {
+        self.writer.write_all(&record.encode()?)?;
+        self.writer.flush()
+    }
+}
+
+impl RecordWriter<BufWriter<File>> {
+    /// Opens the recording at path to add records after the ones it has, a new or
+    /// empty file gets the header first
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<RecordWriter<BufWriter<File>>> {
+        let file = OpenOptions::new().append(true).create(true).open(path)?;
+        if file.metadata()?.len() == 0 {
+            RecordWriter::new(BufWriter::new(file))
+        } else {
+            Ok(RecordWriter {
+                writer: BufWriter::new(file),
+            })
+        }
+    }
+}
+
+/// Reads the records of a recording in the order they were written
+pub struct RecordReader<R: Read> {
+    reader: R,
+}
+
+impl<R: Read> RecordReader<R> {
+    pub fn new(mut reader: R) -> Result<RecordReader<R>> {
+        let mut magic = [0_u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData, "Not a recording"));
+        }
+        Ok(RecordReader { reader })
+    }
+
+    fn bytes(&mut self, size: usize) -> Result<Vec<u8>> {
+        let mut bytes = vec![0_u8; size];
+        self.reader.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// Next record, None at the end of the recording
+    pub fn next_record(&mut self) -> Result<Option<Record>> {
+        let mut timestamp = [0_u8; 8];
+        match self.reader.read(&mut timestamp[..1])? {
+            0 => return Ok(None),
+            _ => self.reader.read_exact(&mut timestamp[1..])?,
+        }
+        let flags = self.bytes(1)?[0];
+        let topic_length = self.bytes(2)?;
+        let topic = self.bytes(u16::from_be_bytes([topic_length[0], topic_length[1]]) as usize)?;
+        let payload_length = self.bytes(4)?;
+        let payload_length = u32::from_be_bytes([
+            payload_length[0],
+            payload_length[1],
+            payload_length[2],
+            payload_length[3],
+        ]);
+        Ok(Some(Record {
+            timestamp: u64::from_be_bytes(timestamp),
+            topic: String::from_utf8(topic)
+                .map_err(|_| Error::new(ErrorKind::InvalidData, "Topic is not utf-8"))?,
+            qos: flags & QOS_MASK,
+            retain: flags & RETAIN_FLAG != 0,
+            payload: self.bytes(payload_length as usize)?,
+        }))
+    }
+}
+
+impl RecordReader<BufReader<File>> {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<RecordReader<BufReader<File>>> {
+        RecordReader::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> Iterator for RecordReader<R> {
+    type Item = Result<Record>;
+
+    fn next(&mut self) -> Option<Result<Record>> {
+        self.next_record().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(timestamp: u64, topic: &str, payload: &str) -> Record {
+        Record {
+            timestamp,
+            topic: topic.to_string(),
+            qos: 1,
+            retain: topic.ends_with("temperature"),
+            payload: payload.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_write_and_read() {
+        let records = vec![
+            record(1_700_000_000_000, "sensors/temperature", "21.5"),
+            record(1_700_000_000_250, "sensors/humidity", ""),
+        ];
+        let mut writer = RecordWriter::new(Vec::new()).unwrap();
+        for record in &records {
+            writer.write(record).unwrap();
+        }
+        let bytes = writer.writer;
+        assert_eq!(&bytes[..8], MAGIC);
+
+        let reader = RecordReader::new(bytes.as_slice()).unwrap();
+        let read: Vec<Record> = reader.map(|record| record.unwrap()).collect();
+        assert_eq!(read, records);
+    }
+
+    #[test]
+    fn test_invalid_recordings() {
+        assert!(RecordReader::new(&b"MQTTREC0"[..]).is_err());
+        // a record cut in the middle is an error, not the end of the recording
+        let mut writer = RecordWriter::new(Vec::new()).unwrap();
+        writer.write(&record(1, "a", "b")).unwrap();
+        let bytes = &writer.writer[..writer.writer.len() - 1];
+        let mut reader = RecordReader::new(bytes).unwrap();
+        assert!(reader.next_record().is_err());
+    }
+}
@@ -0,0 +1,340 @@
+//! Black box benchmarks of the broker binary.
+//!
+//! Every scenario starts the server with its own config in a temporary directory:
+//! * idle: connects BENCH_IDLE_CLIENTS clients (500) that do nothing and measures the
+//!   cpu used by the broker during BENCH_IDLE_SECS seconds (5).
+//! * throughput: BENCH_SUBSCRIBERS clients (1) subscribe to a topic and a client
+//!   publishes BENCH_MESSAGES QoS 1 messages (500) waiting for each puback, measures
+//!   the messages per second delivered to the subscribers.
+//! * fanout: BENCH_FANOUT_SUBSCRIBERS clients (1000) subscribe to BENCH_FANOUT_TOPICS
+//!   topics (4) and a client for each topic publishes BENCH_FANOUT_MESSAGES QoS 1
+//!   messages (100) at the same time, measures the messages per second delivered.
+//!
+//! BENCH_DISPATCHERS sets the dispatcher threads of the broker, one for each cpu by default.
+//!
+//! Run with `cargo bench`, or `cargo bench -- idle` to run one scenario.
+
+use std::env;
+use std::fs;
+use std::io::{Read, Result, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Clock ticks per second of the cpu times in /proc/<pid>/stat
+const CLOCK_TICKS: f64 = 100.0;
+
+fn setting(name: &str, default: usize) -> usize {
+    env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+fn string(value: &str) -> Vec<u8> {
+    let mut bytes = vec![(value.len() >> 8) as u8, value.len() as u8];
+    bytes.extend_from_slice(value.as_bytes());
+    bytes
+}
+
+fn remaining_length(mut length: usize) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    loop {
+        let mut byte = (length % 128) as u8;
+        length /= 128;
+        if length > 0 {
+            byte |= 0x80;
+        }
+        bytes.push(byte);
+        if length == 0 {
+            return bytes;
+        }
+    }
+}
+
+fn packet(first_byte: u8, body: Vec<u8>) -> Vec<u8> {
+    let mut packet = vec![first_byte];
+    packet.extend(remaining_length(body.len()));
+    packet.extend(body);
+    packet
+}
+
+fn connect_packet(client_id: &str) -> Vec<u8> {
+    let mut body = string("MQTT");
+    body.extend([4, 0x02, 0, 60]);
+    body.extend(string(client_id));
+    // the broker expects the will topic and message even when they are not used
+    body.extend(string(""));
+    body.extend(string(""));
+    packet(0x10, body)
+}
+
+fn subscribe_packet(topic: &str) -> Vec<u8> {
+    let mut body = vec![0x12, 0x34];
+    body.extend(string(topic));
+    body.push(1);
+    packet(0x82, body)
+}
+
+fn publish_packet(topic: &str, packet_identifier: u16, message: &str) -> Vec<u8> {
+    let mut body = string(topic);
+    body.extend(packet_identifier.to_be_bytes());
+    let mut packet = packet(0x32, body);
+    packet.extend(string(message));
+    packet
+}
+
+// The publish packets of this broker carry the length of the message before it,
+// those two bytes are not counted in the remaining length of the fixed header.
+
+/// Reads mqtt packets from a stream, the server keepalive checks (0xF0) are skipped
+struct PacketReader {
+    stream: TcpStream,
+    buffer: Vec<u8>,
+}
+
+impl PacketReader {
+    fn new(stream: TcpStream) -> PacketReader {
+        PacketReader {
+            stream,
+            buffer: Vec::new(),
+        }
+    }
+
+    fn next_packet(&mut self) -> Result<Vec<u8>> {
+        loop {
+            while self.buffer.first() == Some(&0xF0) {
+                self.buffer.remove(0);
+            }
+            if let Some(size) = packet_size(&self.buffer) {
+                if self.buffer.len() >= size {
+                    return Ok(self.buffer.drain(..size).collect());
+                }
+            }
+            let mut chunk = [0_u8; 4096];
+            let size = self.stream.read(&mut chunk)?;
+            if size == 0 {
+                return Err(std::io::ErrorKind::UnexpectedEof.into());
+            }
+            self.buffer.extend_from_slice(&chunk[..size]);
+        }
+    }
+
+    fn expect(&mut self, control_type: u8) -> Result<()> {
+        let packet = self.next_packet()?;
+        assert_eq!(
+            packet[0] & 0xF0,
+            control_type,
+            "unexpected packet {:?}",
+            packet
+        );
+        Ok(())
+    }
+}
+
+fn packet_size(buffer: &[u8]) -> Option<usize> {
+    let mut length = 0;
+    for (index, byte) in buffer.iter().skip(1).take(4).enumerate() {
+        length |= ((byte & 0x7F) as usize) << (7 * index);
+        if byte & 0x80 == 0 {
+            let message_length = if buffer[0] & 0xF0 == 0x30 { 2 } else { 0 };
+            return Some(2 + index + length + message_length);
+        }
+    }
+    None
+}
+
+/// Broker process running with a config of its own
+struct Broker {
+    child: Child,
+    address: String,
+    directory: PathBuf,
+}
+
+impl Broker {
+    fn start(name: &str) -> Result<Broker> {
+        let port = TcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+        let directory = env::temp_dir().join(format!("mqtt-bench-{}-{}", name, port));
+        fs::create_dir_all(directory.join("src"))?;
+        let mut config = format!(
+            "host: 127.0.0.1\nport: {}\nlogfile: broker.log\ncredentials_file: \nsys_interval: 0\n",
+            port
+        );
+        // the broker defaults are used unless the variable is set
+        match setting("BENCH_DISPATCHERS", 0) {
+            0 => {}
+            dispatchers => config.push_str(&format!("dispatcher_threads: {}\n", dispatchers)),
+        }
+        fs::write(directory.join("src/config.yaml"), config)?;
+        let child = Command::new(env!("CARGO_BIN_EXE_server"))
+            .current_dir(&directory)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?;
+        let address = format!("127.0.0.1:{}", port);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while TcpStream::connect(&address).is_err() {
+            assert!(Instant::now() < deadline, "broker did not start");
+            thread::sleep(Duration::from_millis(50));
+        }
+        Ok(Broker {
+            child,
+            address,
+            directory,
+        })
+    }
+
+    fn client(&self, client_id: &str) -> Result<PacketReader> {
+        let mut stream = TcpStream::connect(&self.address)?;
+        stream.set_nodelay(true)?;
+        stream.write_all(&connect_packet(client_id))?;
+        let mut reader = PacketReader::new(stream);
+        reader.expect(0x20)?;
+        Ok(reader)
+    }
+
+    /// (cpu seconds used, threads) of the broker process
+    fn usage(&self) -> Result<(f64, usize)> {
+        let stat = fs::read_to_string(format!("/proc/{}/stat", self.child.id()))?;
+        // the fields after the process name, that can have spaces, start at the state
+        let fields: Vec<&str> = stat[stat.rfind(')').unwrap_or(0) + 2..]
+            .split_whitespace()
+            .collect();
+        let ticks: f64 =
+            fields[11].parse::<f64>().unwrap_or(0.0) + fields[12].parse::<f64>().unwrap_or(0.0);
+        Ok((ticks / CLOCK_TICKS, fields[17].parse().unwrap_or(0)))
+    }
+}
+
+impl Drop for Broker {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_dir_all(&self.directory);
+    }
+}
+
+fn idle() -> Result<()> {
+    let clients = setting("BENCH_IDLE_CLIENTS", 500);
+    let seconds = setting("BENCH_IDLE_SECS", 5);
+    let broker = Broker::start("idle")?;
+    let _clients = (0..clients)
+        .map(|index| broker.client(&format!("idle-{}", index)))
+        .collect::<Result<Vec<_>>>()?;
+    thread::sleep(Duration::from_secs(1));
+    let (cpu_before, threads) = broker.usage()?;
+    thread::sleep(Duration::from_secs(seconds as u64));
+    let (cpu_after, _) = broker.usage()?;
+    println!(
+        "idle: {} clients, {} broker threads, cpu {:.1}% of a core",
+        clients,
+        threads,
+        100.0 * (cpu_after - cpu_before) / seconds as f64
+    );
+    Ok(())
+}
+
+fn throughput() -> Result<()> {
+    fan_out(
+        "throughput",
+        setting("BENCH_SUBSCRIBERS", 1),
+        1,
+        setting("BENCH_MESSAGES", 500),
+    )
+}
+
+fn fanout() -> Result<()> {
+    fan_out(
+        "fanout",
+        setting("BENCH_FANOUT_SUBSCRIBERS", 1000),
+        setting("BENCH_FANOUT_TOPICS", 4),
+        setting("BENCH_FANOUT_MESSAGES", 100),
+    )
+}
+
+/// Subscriber i subscribes to topic i % topics, a publisher for each topic sends
+/// messages QoS 1 publishes while the other publishers send theirs
+fn fan_out(name: &str, subscribers: usize, topics: usize, messages: usize) -> Result<()> {
+    let broker = Broker::start(name)?;
+    let receivers: Vec<_> = (0..subscribers)
+        .map(|index| {
+            let mut subscriber = broker.client(&format!("subscriber-{}", index))?;
+            subscriber
+                .stream
+                .write_all(&subscribe_packet(&format!("bench/{}", index % topics)))?;
+            subscriber.expect(0x90)?;
+            Ok(subscriber)
+        })
+        .collect::<Result<Vec<_>>>()?
+        .into_iter()
+        .map(|mut subscriber| {
+            thread::spawn(move || {
+                for _ in 0..messages {
+                    subscriber.expect(0x30).unwrap();
+                }
+            })
+        })
+        .collect();
+
+    let publishers = (0..topics)
+        .map(|topic| broker.client(&format!("publisher-{}", topic)))
+        .collect::<Result<Vec<_>>>()?;
+    let start = Instant::now();
+    let publishers: Vec<_> = publishers
+        .into_iter()
+        .enumerate()
+        .map(|(topic, mut publisher)| {
+            thread::spawn(move || {
+                let topic = format!("bench/{}", topic);
+                for index in 0..messages {
+                    let packet_identifier = (index % 0xFFFF + 1) as u16;
+                    publisher
+                        .stream
+                        .write_all(&publish_packet(&topic, packet_identifier, "21.5"))
+                        .unwrap();
+                    publisher.expect(0x40).unwrap();
+                }
+            })
+        })
+        .collect();
+    for publisher in publishers {
+        let _ = publisher.join();
+    }
+    let published = start.elapsed();
+    for receiver in receivers {
+        let _ = receiver.join();
+    }
+    let delivered = start.elapsed();
+    println!(
+        "{}: {} messages on {} topics to {} subscribers, {:.0} publish/s, {:.0} deliveries/s ({:.2}s)",
+        name,
+        messages * topics,
+        topics,
+        subscribers,
+        (messages * topics) as f64 / published.as_secs_f64(),
+        (messages * subscribers) as f64 / delivered.as_secs_f64(),
+        delivered.as_secs_f64()
+    );
+    Ok(())
+}
+
+/// (name, function) of a benchmark
+type Scenario = (&'static str, fn() -> Result<()>);
+
+fn main() -> Result<()> {
+    // cargo bench passes --bench and the filter given after --
+    let filter = env::args().skip(1).find(|arg| !arg.starts_with("--"));
+    let scenarios: [Scenario; 3] = [
+        ("idle", idle),
+        ("throughput", throughput),
+        ("fanout", fanout),
+    ];
+    for (name, scenario) in scenarios {
+        if filter.as_deref().is_none_or(|filter| name.contains(filter)) {
+            scenario()?;
+        }
+    }
+    Ok(())
+}
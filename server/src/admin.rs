@@ -0,0 +1,388 @@
+use crate::http::{HttpRequest, HttpResponse};
+use crate::limits::Limits;
+use crate::validation;
+use serde_json::{json, Value};
+use std::io::Result;
+
+/// State of a client session as shown by the admin api
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientInfo {
+    pub client_id: String,
+    pub peer: String,
+    pub user_name: String,
+    pub protocol_level: u8,
+    pub connected: bool,
+    pub subscriptions: Vec<String>,
+    pub inflight: Vec<u16>, // packet identifiers of the QoS 1 messages waiting for a puback
+    pub queued: Vec<QueuedMessage>, // messages waiting to be written to the client, oldest first
+}
+
+/// Message waiting in the queue of a client as shown by the admin api
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueuedMessage {
+    pub topic: String,
+    pub qos: u8,
+    pub payload_size: usize,
+}
+
+/// Subscribers and retained message of a topic as shown by the admin api
+#[derive(Debug, Clone, PartialEq)]
+pub struct TopicInfo {
+    pub topic: String,
+    pub subscribers: Vec<String>,
+    pub retained: Option<String>,
+}
+
+/// Broker operations used by the admin api
+pub trait AdminBackend {
+    fn clients(&self) -> Vec<ClientInfo>;
+    fn topics(&self) -> Vec<TopicInfo>;
+    /// Disconnects a client, false when it is not connected
+    fn kick(&self, client_id: &str) -> bool;
+    /// Removes the retained message of a topic, false when it has none
+    fn delete_retained(&self, topic: &str) -> bool;
+    /// Publishes a message as the broker
+    fn publish(&self, topic: &str, message: &str, qos: u8, retain: bool) -> Result<()>;
+    /// Client ids and ips whose packets are traced
+    fn traced(&self) -> Vec<String>;
+    /// Starts or stops tracing a client id or ip, false when nothing changed
+    fn trace(&self, target: &str, traced: bool) -> bool;
+    /// Limits the topics and messages published through the api are checked against
+    fn limits(&self) -> Limits;
+}
+
+fn client_json(client: &ClientInfo) -> Value {
+    json!({
+        "client_id": client.client_id,
+        "peer": client.peer,
+        "user_name": client.user_name,
+        "protocol_level": client.protocol_level,
+        "connected": client.connected,
+        "subscriptions": client.subscriptions,
+        "inflight": client.inflight,
+        "queued": client
+            .queued
+            .iter()
+            .map(|message| json!({
+                "topic": message.topic,
+                "qos": message.qos,
+                "payload_size": message.payload_size,
+            }))
+            .collect::<Vec<Value>>(),
+    })
+}
+
+fn topic_json(topic: &TopicInfo) -> Value {
+    json!({
+        "topic": topic.topic,
+        "subscribers": topic.subscribers,
+        "retained": topic.retained,
+    })
+}
+
+fn json_response(status: u16, body: Value) -> HttpResponse {
+    HttpResponse::new(status, "application/json", format!("{}\n", body))
+}
+
+fn error_response(status: u16, message: &str) -> HttpResponse {
+    json_response(status, json!({ "error": message }))
+}
+
+/// Compares the whole token so the time taken does not tell how much of it matched
+fn is_authorized(request: &HttpRequest, token: &str) -> bool {
+    let given = match request
+        .headers
+        .get("authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+    {
+        Some(given) => given.as_bytes(),
+        None => return false,
+    };
+    given.len() == token.len()
+        && given
+            .iter()
+            .zip(token.as_bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Reads the body of POST /publish: {"topic", "message", "qos" (0 or 1), "retain"},
+/// the topic and message are checked as the ones of a client publish
+fn parse_publish(
+    body: &[u8],
+    limits: &Limits,
+) -> std::result::Result<(String, String, u8, bool), String> {
+    let value: Value = serde_json::from_slice(body).map_err(|e| format!("Invalid json: {}", e))?;
+    let topic = match value.get("topic").and_then(Value::as_str) {
+        Some(topic) => topic.to_string(),
+        None => return Err("topic must be a string".to_string()),
+    };
+    validation::topic_name(&topic, limits).map_err(|e| e.to_string())?;
+    let message = match value.get("message") {
+        Some(Value::String(message)) => message.clone(),
+        _ => return Err("message must be a string".to_string()),
+    };
+    validation::payload(&message, limits).map_err(|e| e.to_string())?;
+    let qos = match value.get("qos").map(Value::as_u64) {
+        None => 0,
+        Some(Some(qos)) if qos <= 1 => qos as u8,
+        Some(_) => return Err("qos must be 0 or 1".to_string()),
+    };
+    let retain = match value.get("retain").map(Value::as_bool) {
+        None => false,
+        Some(Some(retain)) => retain,
+        Some(None) => return Err("retain must be a boolean".to_string()),
+    };
+    Ok((topic, message, qos, retain))
+}
+
+/// Answers a request of the admin api, every request needs the header
+/// "Authorization: Bearer <token>"
+pub fn handle_request<B: AdminBackend>(
+    request: &HttpRequest,
+    token: &str,
+    backend: &B,
+) -> HttpResponse {
+    if !is_authorized(request, token) {
+        return error_response(401, "Missing or wrong admin token");
+    }
+    let segments: Vec<&str> = request
+        .path
+        .trim_start_matches('/')
+        .splitn(2, '/')
+        .collect();
+    match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["clients"]) => json_response(
+            200,
+            Value::Array(backend.clients().iter().map(client_json).collect()),
+        ),
+        ("GET", ["clients", client_id]) => {
+            match backend.clients().iter().find(|c| c.client_id == *client_id) {
+                Some(client) => json_response(200, client_json(client)),
+                None => error_response(404, "Unknown client id"),
+            }
+        }
+        ("DELETE", ["clients", client_id]) => match backend.kick(client_id) {
+            true => json_response(200, json!({ "kicked": client_id })),
+            false => error_response(404, "Client is not connected"),
+        },
+        ("GET", ["topics"]) => json_response(
+            200,
+            Value::Array(backend.topics().iter().map(topic_json).collect()),
+        ),
+        ("DELETE", ["retained"]) => match request.query.get("topic") {
+            Some(topic) if backend.delete_retained(topic) => {
+                json_response(200, json!({ "deleted": topic }))
+            }
+            Some(_) => error_response(404, "Topic has no retained message"),
+            None => error_response(400, "Missing topic parameter"),
+        },
+        ("POST", ["publish"]) => match parse_publish(&request.body, &backend.limits()) {
+            Ok((topic, message, qos, retain)) => {
+                match backend.publish(&topic, &message, qos, retain) {
+                    Ok(_) => json_response(200, json!({ "published": topic })),
+                    Err(e) => error_response(500, &e.to_string()),
+                }
+            }
+            Err(e) => error_response(400, &e),
+        },
+        ("GET", ["trace"]) => json_response(200, json!(backend.traced())),
+        ("PUT", ["trace", target]) => match backend.trace(target, true) {
+            true => json_response(200, json!({ "traced": target })),
+            false => error_response(409, "Already traced"),
+        },
+        ("DELETE", ["trace", target]) => match backend.trace(target, false) {
+            true => json_response(200, json!({ "untraced": target })),
+            false => error_response(404, "Not traced"),
+        },
+        (_, ["clients"])
+        | (_, ["clients", _])
+        | (_, ["topics"])
+        | (_, ["retained"])
+        | (_, ["publish"])
+        | (_, ["trace"])
+        | (_, ["trace", _]) => error_response(405, "Method not allowed"),
+        _ => error_response(404, "Not found"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    #[derive(Default)]
+    struct FakeBackend {
+        published: RefCell<Vec<(String, String, u8, bool)>>,
+        traced: RefCell<Vec<String>>,
+    }
+
+    impl AdminBackend for FakeBackend {
+        fn clients(&self) -> Vec<ClientInfo> {
+            vec![ClientInfo {
+                client_id: "sensor/1".to_string(),
+                peer: "127.0.0.1:5000".to_string(),
+                user_name: "fiuba".to_string(),
+                protocol_level: 4,
+                connected: true,
+                subscriptions: vec!["temperature".to_string()],
+                inflight: vec![7],
+                queued: vec![QueuedMessage {
+                    topic: "temperature".to_string(),
+                    qos: 1,
+                    payload_size: 2,
+                }],
+            }]
+        }
+
+        fn topics(&self) -> Vec<TopicInfo> {
+            vec![TopicInfo {
+                topic: "temperature".to_string(),
+                subscribers: vec!["sensor/1".to_string()],
+                retained: Some("21".to_string()),
+            }]
+        }
+
+        fn kick(&self, client_id: &str) -> bool {
+            client_id == "sensor/1"
+        }
+
+        fn delete_retained(&self, topic: &str) -> bool {
+            topic == "temperature"
+        }
+
+        fn publish(&self, topic: &str, message: &str, qos: u8, retain: bool) -> Result<()> {
+            self.published
+                .borrow_mut()
+                .push((topic.to_string(), message.to_string(), qos, retain));
+            Ok(())
+        }
+
+        fn traced(&self) -> Vec<String> {
+            self.traced.borrow().clone()
+        }
+
+        fn trace(&self, target: &str, traced: bool) -> bool {
+            let mut targets = self.traced.borrow_mut();
+            let present = targets.iter().any(|t| t == target);
+            match (traced, present) {
+                (true, false) => targets.push(target.to_string()),
+                (false, true) => targets.retain(|t| t != target),
+                _ => return false,
+            }
+            true
+        }
+
+        fn limits(&self) -> Limits {
+            Limits {
+                max_payload_size: 8,
+                ..Limits::default()
+            }
+        }
+    }
+
+    fn request(method: &str, path: &str, body: &str) -> HttpRequest {
+        let (path, query) = path.split_once('?').unwrap_or((path, ""));
+        let mut request = HttpRequest {
+            method: method.to_string(),
+            path: path.to_string(),
+            body: body.as_bytes().to_vec(),
+            ..Default::default()
+        };
+        if let Some((key, value)) = query.split_once('=') {
+            request.query.insert(key.to_string(), value.to_string());
+        }
+        request
+            .headers
+            .insert("authorization".to_string(), "Bearer secret".to_string());
+        request
+    }
+
+    fn body(response: &HttpResponse) -> Value {
+        serde_json::from_str(&response.body).unwrap()
+    }
+
+    #[test]
+    fn test_token_is_required() {
+        let backend = FakeBackend::default();
+        let mut unauthorized = request("GET", "/clients", "");
+        unauthorized.headers.clear();
+        assert_eq!(
+            handle_request(&unauthorized, "secret", &backend).status,
+            401
+        );
+        let wrong_token = request("GET", "/clients", "");
+        assert_eq!(handle_request(&wrong_token, "secreT", &backend).status, 401);
+        assert_eq!(handle_request(&wrong_token, "secret", &backend).status, 200);
+    }
+
+    #[test]
+    fn test_clients_and_topics() {
+        let backend = FakeBackend::default();
+        let response = handle_request(&request("GET", "/clients", ""), "secret", &backend);
+        assert_eq!(body(&response)[0]["client_id"], "sensor/1");
+        assert_eq!(body(&response)[0]["inflight"], json!([7]));
+
+        let response = handle_request(&request("GET", "/clients/sensor/1", ""), "secret", &backend);
+        assert_eq!(
+            body(&response)["queued"],
+            json!([{ "topic": "temperature", "qos": 1, "payload_size": 2 }])
+        );
+        let response = handle_request(&request("GET", "/clients/other", ""), "secret", &backend);
+        assert_eq!(response.status, 404);
+
+        let response = handle_request(&request("GET", "/topics", ""), "secret", &backend);
+        assert_eq!(body(&response)[0]["retained"], "21");
+    }
+
+    #[test]
+    fn test_kick_and_delete_retained() {
+        let backend = FakeBackend::default();
+        let kick = |path| handle_request(&request("DELETE", path, ""), "secret", &backend).status;
+        assert_eq!(kick("/clients/sensor/1"), 200);
+        assert_eq!(kick("/clients/other"), 404);
+        assert_eq!(kick("/retained?topic=temperature"), 200);
+        assert_eq!(kick("/retained?topic=humidity"), 404);
+        assert_eq!(kick("/retained"), 400);
+        assert_eq!(kick("/topics"), 405);
+    }
+
+    #[test]
+    fn test_publish() {
+        let backend = FakeBackend::default();
+        let publish = |body| handle_request(&request("POST", "/publish", body), "secret", &backend);
+        let response =
+            publish(r#"{"topic": "alerts", "message": "reboot", "qos": 1, "retain": true}"#);
+        assert_eq!(response.status, 200);
+        assert_eq!(
+            publish(r#"{"topic": "alerts/#", "message": "x"}"#).status,
+            400
+        );
+        assert_eq!(
+            publish(r#"{"topic": "alerts", "message": "x", "qos": 2}"#).status,
+            400
+        );
+        assert_eq!(publish("not json").status, 400);
+        assert_eq!(publish(r#"{"topic": "", "message": "x"}"#).status, 400);
+        assert_eq!(
+            publish(r#"{"topic": "alerts", "message": "too long for the limit"}"#).status,
+            400
+        );
+        assert_eq!(
+            *backend.published.borrow(),
+            vec![("alerts".to_string(), "reboot".to_string(), 1, true)]
+        );
+    }
+
+    #[test]
+    fn test_trace() {
+        let backend = FakeBackend::default();
+        let call = |method, path| handle_request(&request(method, path, ""), "secret", &backend);
+        assert_eq!(call("PUT", "/trace/sensor/1").status, 200);
+        assert_eq!(call("PUT", "/trace/sensor/1").status, 409);
+        assert_eq!(body(&call("GET", "/trace")), json!(["sensor/1"]));
+        assert_eq!(call("DELETE", "/trace/sensor/1").status, 200);
+        assert_eq!(call("DELETE", "/trace/10.0.0.5").status, 404);
+        assert_eq!(call("POST", "/trace").status, 405);
+    }
+}
@@ -0,0 +1,513 @@
+use crate::broker_config::BrokerConfig;
+use crate::dispatcher::{Command, DispatchSender};
+use crate::logger::{Logger, Logging};
+use crate::stats::BrokerStats;
+use crate::topics::topic_matches;
+use client::client::{Client, Message};
+use client::tls::TlsOptions;
+use config::config_file::{ConfigError, ConfigFile};
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind, Result};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Seconds between two connection attempts of a bridge when not set in config
+pub const DEFAULT_RECONNECT_INTERVAL_SECS: u64 = 5;
+/// Messages waiting to be sent to the remote broker when not set in config
+pub const DEFAULT_BRIDGE_QUEUED_MESSAGES: usize = 1000;
+/// Time given to the remote broker to accept the connection
+const CONNACK_TIMEOUT_SECS: u64 = 10;
+/// Longest wait of a bridge for messages, the connection and shutdown flag are checked after it
+const POLL_MILLIS: u64 = 100;
+/// Messages sent to the remote broker that are remembered to drop their echo
+const MAX_ECHOES: usize = 1000;
+
+/// Way the messages of a bridge topic are forwarded
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    In, // from the remote broker to this one
+    #[default]
+    Out, // from this broker to the remote one
+    Both, // both ways
+}
+
+impl Direction {
+    fn is_in(self) -> bool {
+        self != Direction::Out
+    }
+
+    fn is_out(self) -> bool {
+        self != Direction::In
+    }
+}
+
+/// Topic forwarded by a bridge. The pattern is matched against the topic without
+/// its prefix, local_prefix on this broker and remote_prefix on the remote one.
+/// The messages go out with QoS 0 and without prefixes by default.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct BridgeTopic {
+    pub pattern: String,
+    #[serde(default)]
+    pub direction: Direction,
+    #[serde(default)]
+    pub qos: u8, // used to subscribe and publish to the remote broker
+    #[serde(default)]
+    pub local_prefix: String,
+    #[serde(default)]
+    pub remote_prefix: String,
+}
+
+/// A bridge of the `bridges` mapping of the server config
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct BridgeConfig {
+    pub address: String, // host:port of the remote broker
+    pub topics: Vec<BridgeTopic>,
+    #[serde(default)]
+    pub client_id: String,
+    #[serde(default)]
+    pub username: String,
+    #[serde(default)]
+    pub password: String,
+    pub keepalive: Option<u16>,
+    pub reconnect_interval: Option<u64>, // seconds
+    pub max_queued_messages: Option<usize>,
+    pub tls_ca_file: Option<String>, // the connection uses tls when it is set
+    #[serde(default)]
+    pub tls_cert_file: String,
+    #[serde(default)]
+    pub tls_key_file: String,
+    #[serde(default)]
+    pub tls_server_name: String,
+}
+
+impl BridgeTopic {
+    /// Topic on the remote broker of a message published on local_topic, None if
+    /// it is not forwarded out
+    fn remote_topic(&self, local_topic: &str) -> Option<String> {
+        let topic = local_topic.strip_prefix(self.local_prefix.as_str())?;
+        Some(format!("{}{}", self.remote_prefix, topic))
+            .filter(|_| self.direction.is_out() && topic_matches(&self.pattern, topic))
+    }
+
+    /// Topic on this broker of a message published on remote_topic, None if it is
+    /// not forwarded in
+    fn local_topic(&self, remote_topic: &str) -> Option<String> {
+        let topic = remote_topic.strip_prefix(self.remote_prefix.as_str())?;
+        Some(format!("{}{}", self.local_prefix, topic))
+            .filter(|_| self.direction.is_in() && topic_matches(&self.pattern, topic))
+    }
+}
+
+/// Settings of a bridge read from the server config file
+#[derive(Clone, Debug)]
+pub struct BridgeSettings {
+    pub name: String,
+    pub address: String,   // host:port of the remote broker
+    pub client_id: String, // empty to connect with a random one
+    pub username: String,
+    pub password: String,
+    pub keepalive: u16,
+    pub reconnect_interval: Duration,
+    pub max_queued_messages: usize, // messages waiting for the remote broker, the newer ones are dropped
+    pub tls: Option<TlsOptions>,
+    pub topics: Vec<BridgeTopic>,
+}
+
+impl BridgeSettings {
+    pub fn new(name: &str, address: String, topics: Vec<BridgeTopic>) -> BridgeSettings {
+        BridgeSettings {
+            name: name.to_string(),
+            address,
+            client_id: String::new(),
+            username: String::new(),
+            password: String::new(),
+            keepalive: 60,
+            reconnect_interval: Duration::from_secs(DEFAULT_RECONNECT_INTERVAL_SECS),
+            max_queued_messages: DEFAULT_BRIDGE_QUEUED_MESSAGES,
+            tls: None,
+            topics,
+        }
+    }
+}
+
+/// Reads the bridges of the server config, each one configured under its name
+/// in the `bridges` mapping
+pub fn bridges_from_config(
+    config: &BrokerConfig,
+    file: &ConfigFile,
+) -> std::result::Result<Vec<BridgeSettings>, ConfigError> {
+    let mut bridges = Vec::new();
+    for (name, bridge) in &config.bridges {
+        let key = |setting: &str| format!("bridges.{}.{}", name, setting);
+        if bridge.topics.is_empty() {
+            return Err(file.error(&key("topics"), "Needs at least one topic"));
+        }
+        if let Some(index) = bridge.topics.iter().position(|topic| topic.qos > 1) {
+            let qos_key = format!("{}[{}].qos", key("topics"), index);
+            return Err(file.error(&qos_key, "Expected 0 or 1"));
+        }
+        if bridge.address.rsplit_once(':').is_none() {
+            return Err(file.error(&key("address"), "Expected host:port"));
+        }
+        let mut settings = BridgeSettings::new(name, bridge.address.clone(), bridge.topics.clone());
+        settings.client_id = bridge.client_id.clone();
+        settings.username = bridge.username.clone();
+        settings.password = bridge.password.clone();
+        settings.keepalive = bridge.keepalive.unwrap_or(settings.keepalive);
+        if let Some(seconds) = bridge.reconnect_interval {
+            settings.reconnect_interval = Duration::from_secs(seconds);
+        }
+        settings.max_queued_messages = bridge
+            .max_queued_messages
+            .unwrap_or(settings.max_queued_messages);
+        if let Some(ca_file) = &bridge.tls_ca_file {
+            settings.tls = Some(TlsOptions {
+                ca_file: ca_file.clone(),
+                cert_file: bridge.tls_cert_file.clone(),
+                key_file: bridge.tls_key_file.clone(),
+                server_name: bridge.tls_server_name.clone(),
+            });
+        }
+        bridges.push(settings);
+    }
+    Ok(bridges)
+}
+
+/// Message for the remote broker: (topic, message, qos, retain)
+type Outgoing = (String, String, u8, bool);
+
+/// Messages sent to the remote broker that it may send back through the
+/// subscriptions of the bridge, oldest first
+#[derive(Debug, Default)]
+struct Echoes {
+    messages: VecDeque<(String, String)>,
+}
+
+impl Echoes {
+    fn sent(&mut self, topic: &str, message: &str) {
+        if self.messages.len() == MAX_ECHOES {
+            self.messages.pop_front();
+        }
+        self.messages
+            .push_back((topic.to_string(), message.to_string()));
+    }
+
+    /// True if the message was sent by the bridge, it is forgotten
+    fn is_echo(&mut self, topic: &str, message: &str) -> bool {
+        match self
+            .messages
+            .iter()
+            .position(|(t, m)| t == topic && m == message)
+        {
+            Some(index) => {
+                self.messages.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Connection of this broker to a remote one, forwards the messages of its topics
+/// and reconnects when the connection is lost
+pub struct Bridge {
+    settings: BridgeSettings,
+    tx: SyncSender<Outgoing>,
+    rx: Mutex<Option<Receiver<Outgoing>>>, // taken by the bridge thread when it starts
+}
+
+impl Bridge {
+    pub fn new(settings: BridgeSettings) -> Bridge {
+        let (tx, rx) = mpsc::sync_channel(settings.max_queued_messages.max(1));
+        Bridge {
+            settings,
+            tx,
+            rx: Mutex::new(Some(rx)),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.settings.name
+    }
+
+    /// Queues a local publish for the remote broker when one of the topics forwards
+    /// it out. The messages that came from this bridge (origin) are not sent back.
+    /// Returns false if the message was dropped because the queue is full.
+    pub fn forward(&self, topic: &str, message: &str, retain: bool, origin: Option<&str>) -> bool {
+        if origin == Some(self.name()) {
+            return true;
+        }
+        let (remote_topic, qos) = match self
+            .settings
+            .topics
+            .iter()
+            .find_map(|t| t.remote_topic(topic).map(|remote| (remote, t.qos)))
+        {
+            Some(forwarded) => forwarded,
+            None => return true,
+        };
+        let outgoing = (remote_topic, message.to_string(), qos, retain);
+        !matches!(self.tx.try_send(outgoing), Err(TrySendError::Full(_)))
+    }
+
+    /// Topic on this broker of a message of the remote broker
+    fn local_topic(&self, remote_topic: &str) -> Option<String> {
+        self.settings
+            .topics
+            .iter()
+            .find_map(|t| t.local_topic(remote_topic))
+    }
+
+    /// Starts the thread of the bridge, it stops once shutdown is set
+    pub fn start(
+        self: &Arc<Self>,
+        tx_server: DispatchSender,
+        stats: Arc<BrokerStats>,
+        shutdown: Arc<AtomicBool>,
+        logger: Arc<Logger>,
+    ) -> Result<()> {
+        let rx = match self.rx.lock().unwrap().take() {
+            Some(rx) => rx,
+            None => return Ok(()),
+        };
+        let bridge = self.clone();
+        thread::Builder::new()
+            .name(format!("Thread: bridge {}", self.name()))
+            .spawn(move || {
+                while !shutdown.load(Ordering::SeqCst) {
+                    if let Err(e) = bridge.session(&rx, &tx_server, &stats, &shutdown, &logger) {
+                        logger.error(format!(
+                            "Bridge {} to {}: {}, reconnecting in {}s",
+                            bridge.name(),
+                            bridge.settings.address,
+                            e,
+                            bridge.settings.reconnect_interval.as_secs()
+                        ));
+                        let retry = Instant::now() + bridge.settings.reconnect_interval;
+                        while Instant::now() < retry && !shutdown.load(Ordering::SeqCst) {
+                            thread::sleep(Duration::from_millis(POLL_MILLIS));
+                        }
+                    }
+                }
+                logger.debug(format!("Thread bridge {} stopped", bridge.name()));
+            })?;
+        Ok(())
+    }
+
+    /// Connects to the remote broker and forwards messages until the connection is
+    /// lost (Err) or the server shuts down (Ok)
+    fn session(
+        &self,
+        rx: &Receiver<Outgoing>,
+        tx_server: &DispatchSender,
+        stats: &BrokerStats,
+        shutdown: &AtomicBool,
+        logger: &Logger,
+    ) -> Result<()> {
+        let settings = &self.settings;
+        let (tx_messages, rx_messages) = mpsc::channel::<Message>();
+        let mut client = Client::new();
+        client.set_message_sender(tx_messages);
+        client.set_keepalive_interval(settings.keepalive);
+        if !settings.client_id.is_empty() {
+            client.set_client_identifier(settings.client_id.clone());
+        }
+        if let Some(tls) = &settings.tls {
+            client.set_tls(tls.clone());
+        }
+        let (host, port) = settings.address.rsplit_once(':').unwrap_or_default();
+        let rx_out = client
+            .connect(
+                host.to_string(),
+                port.to_string(),
+                settings.username.clone(),
+                settings.password.clone(),
+                true,
+                String::new(),
+                String::new(),
+            )
+            .map_err(|e| Error::new(ErrorKind::ConnectionRefused, e))?;
+        let deadline = Instant::now() + Duration::from_secs(CONNACK_TIMEOUT_SECS);
+        while !client.is_connected() {
+            if Instant::now() > deadline || shutdown.load(Ordering::SeqCst) {
+                return Err(Error::new(ErrorKind::TimedOut, "No connack received"));
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        logger.info(format!(
+            "Bridge {} connected to {} as client id {}",
+            self.name(),
+            settings.address,
+            client.get_id_client()
+        ));
+        for topic in settings.topics.iter().filter(|t| t.direction.is_in()) {
+            client.subscribe_with_qos(
+                &format!("{}{}", topic.remote_prefix, topic.pattern),
+                topic.qos,
+            );
+        }
+
+        let mut echoes = Echoes::default();
+        loop {
+            if shutdown.load(Ordering::SeqCst) {
+                client.disconnect();
+                return Ok(());
+            }
+            let first = match rx.recv_timeout(Duration::from_millis(POLL_MILLIS)) {
+                Ok(outgoing) => Some(outgoing),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            };
+            for (topic, message, qos, retain) in first.into_iter().chain(rx.try_iter()) {
+                client.publish(qos, 0, retain as u8, &topic, &message);
+                // the remote broker sends it back when the bridge subscribed to its topic
+                if self.local_topic(&topic).is_some() {
+                    echoes.sent(&topic, &message);
+                }
+            }
+
+            for message in rx_messages.try_iter() {
+                if echoes.is_echo(&message.topic, &message.message) {
+                    continue;
+                }
+                let topic = match self.local_topic(&message.topic) {
+                    Some(topic) => topic,
+                    None => continue,
+                };
+                let received = stats.message_received();
+                // message = [ packet_type, dup, qos, retain, topic, message, properties, received, origin ]
+                let msg_server: Command = vec![
+                    "publish".to_string(),
+                    0.to_string(),
+                    message.qos.to_string(),
+                    (message.retain as u8).to_string(),
+                    topic,
+                    message.message,
+                    String::new(),
+                    received.to_string(),
+                    self.name().to_string(),
+                ];
+                tx_server
+                    .send(msg_server)
+                    .map_err(|e| Error::new(ErrorKind::BrokenPipe, e.to_string()))?;
+            }
+            // the client also reports what it does as text, nobody reads it
+            rx_out.lock().unwrap().try_iter().for_each(drop);
+
+            if !client.is_connected() {
+                return Err(Error::new(ErrorKind::ConnectionAborted, "Connection lost"));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn topic(pattern: &str, direction: Direction, qos: u8) -> BridgeTopic {
+        BridgeTopic {
+            pattern: pattern.to_string(),
+            direction,
+            qos,
+            local_prefix: String::new(),
+            remote_prefix: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_bridge_topic_prefixes() {
+        let mut both = topic("sensors/#", Direction::Both, 1);
+        both.local_prefix = "site/".to_string();
+        assert_eq!(
+            both.remote_topic("site/sensors/a"),
+            Some("sensors/a".to_string())
+        );
+        assert_eq!(both.remote_topic("sensors/a"), None);
+        assert_eq!(
+            both.local_topic("sensors/a"),
+            Some("site/sensors/a".to_string())
+        );
+
+        let out = topic("commands/+", Direction::Out, 0);
+        assert_eq!(out.local_topic("commands/a"), None);
+    }
+
+    fn bridges(yaml: &str) -> std::result::Result<Vec<BridgeSettings>, ConfigError> {
+        let file = ConfigFile::parse("config.yaml", yaml).unwrap();
+        bridges_from_config(&BrokerConfig::from_config(&file)?, &file)
+    }
+
+    #[test]
+    fn test_bridges_from_config() {
+        let yaml = "bridges:
+  cloud:
+    address: 127.0.0.1:1884
+    topics:
+      - {pattern: sensors/#, qos: 1, local_prefix: site/}
+      - {pattern: commands/#, direction: in}
+    reconnect_interval: 2
+";
+        let settings = bridges(yaml).unwrap();
+        assert_eq!(settings.len(), 1);
+        assert_eq!(settings[0].topics.len(), 2);
+        assert_eq!(settings[0].topics[0].direction, Direction::Out);
+        assert_eq!(settings[0].topics[0].qos, 1);
+        assert_eq!(settings[0].topics[0].local_prefix, "site/");
+        assert_eq!(settings[0].topics[1].direction, Direction::In);
+        assert_eq!(settings[0].topics[1].qos, 0);
+        assert_eq!(settings[0].reconnect_interval, Duration::from_secs(2));
+        assert!(settings[0].tls.is_none());
+
+        let missing_topics = "bridges:\n  cloud:\n    address: 127.0.0.1:1884\n";
+        assert!(bridges(missing_topics).is_err());
+        let error = bridges(&yaml.replace("direction: in", "direction: sideways")).unwrap_err();
+        assert_eq!(error.line, Some(6));
+        assert_eq!(
+            error.key.as_deref(),
+            Some("bridges.cloud.topics[1].direction")
+        );
+        let error = bridges(&yaml.replace("qos: 1", "qos: 2")).unwrap_err();
+        assert_eq!(error.key.as_deref(), Some("bridges.cloud.topics[0].qos"));
+        let error = bridges(&yaml.replace("127.0.0.1:1884", "remote")).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "config.yaml:3: bridges.cloud.address: Expected host:port"
+        );
+        assert!(bridges("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_forward_skips_own_messages() {
+        let mut settings = BridgeSettings::new(
+            "cloud",
+            "127.0.0.1:1884".to_string(),
+            vec![topic("sensors/#", Direction::Both, 1)],
+        );
+        settings.max_queued_messages = 1;
+        let bridge = Bridge::new(settings);
+        assert!(bridge.forward("sensors/a", "1", false, Some("cloud")));
+        assert!(bridge.forward("lights/a", "1", false, None));
+        assert!(bridge.forward("sensors/a", "2", true, None));
+        // the queue is full
+        assert!(!bridge.forward("sensors/a", "3", false, None));
+        let rx = bridge.rx.lock().unwrap().take().unwrap();
+        assert_eq!(
+            rx.try_iter().collect::<Vec<_>>(),
+            vec![("sensors/a".to_string(), "2".to_string(), 1, true)]
+        );
+
+        let mut echoes = Echoes::default();
+        echoes.sent("sensors/a", "2");
+        assert!(!echoes.is_echo("sensors/a", "3"));
+        assert!(echoes.is_echo("sensors/a", "2"));
+        assert!(!echoes.is_echo("sensors/a", "2"));
+    }
+}
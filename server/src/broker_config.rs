@@ -0,0 +1,274 @@
+use crate::bridge::BridgeConfig;
+use crate::expiry::TtlRule;
+use crate::listener::ListenerConfig;
+use crate::logger::{Level, LogFormat, LogSettings};
+use crate::outbound::{DropPolicy, QueueSettings};
+use crate::tls::CertAuth;
+use crate::trace::DEFAULT_TRACE_FILE;
+use config::config_file::{ConfigError, ConfigFile};
+use serde::de::IntoDeserializer;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+
+pub const DEFAULT_HOST: &str = "127.0.0.1";
+pub const DEFAULT_PORT: u16 = 1883;
+pub const DEFAULT_LOGFILE: &str = "log.txt";
+
+/// Settings of the server config file, a key that is not a field is an error.
+/// Main reads the keys of the server, each module builds its settings from its own keys.
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct BrokerConfig {
+    pub host: String,
+    pub port: u16,
+    pub logfile: String,
+    pub credentials_file: String, // empty when every client can connect
+    pub sys_interval: Option<u64>,
+    pub dispatcher_threads: Option<usize>,
+    pub client_workers: Option<usize>, // threads that serve the client connections
+    pub metrics_bind: Option<String>,
+    pub admin_bind: Option<String>,
+    pub admin_token: Option<String>,
+    pub pid_file: Option<String>,    // written while the server runs
+    pub session_expiry: Option<u64>, // seconds a disconnected persistent session is kept
+    // listeners, without `listeners` they are taken from host, port, tls_port and ws_port
+    pub listeners: Option<BTreeMap<String, ListenerConfig>>,
+    pub tls_port: Option<u16>,
+    pub tls_cert_file: Option<String>,
+    pub tls_key_file: Option<String>,
+    pub tls_ca_file: String,
+    pub tls_require_client_cert: bool,
+    pub tls_cert_auth: CertAuth,
+    pub ws_port: Option<u16>,
+    // limits, 0 means no limit
+    pub max_connections: usize,
+    pub max_connections_per_ip: usize,
+    pub max_publish_rate: u32,
+    pub max_bandwidth: u32,
+    pub max_packet_size: usize,
+    pub max_payload_size: usize,
+    pub max_topic_length: usize,
+    pub max_topic_levels: usize,
+    pub max_client_id_length: usize,
+    // client queues
+    pub max_queued_messages: usize,
+    pub qos0_drop_policy: DropPolicy,
+    pub slow_consumer_timeout: u64,
+    // log file
+    pub log_level: Level,
+    pub log_format: LogFormat,
+    pub log_stdout: bool,
+    pub log_max_size: u64,
+    pub log_rotate_interval: u64,
+    pub log_max_files: usize,
+    pub trace_file: String,
+    pub trace_targets: Vec<String>, // client ids and ips
+    pub record_file: Option<String>,
+    pub record_topics: Vec<String>, // topic filters
+    pub message_ttl: Vec<TtlRule>,  // the first rule matching the topic is taken
+    pub bridges: BTreeMap<String, BridgeConfig>,
+    pub cluster_bind: Option<String>,
+    pub cluster_node: Option<String>,
+    pub cluster_peers: Vec<String>,
+}
+
+impl Default for BrokerConfig {
+    fn default() -> BrokerConfig {
+        let log = LogSettings::default();
+        let queues = QueueSettings::default();
+        BrokerConfig {
+            host: DEFAULT_HOST.to_string(),
+            port: DEFAULT_PORT,
+            logfile: DEFAULT_LOGFILE.to_string(),
+            credentials_file: String::new(),
+            sys_interval: None,
+            dispatcher_threads: None,
+            client_workers: None,
+            metrics_bind: None,
+            admin_bind: None,
+            admin_token: None,
+            pid_file: None,
+            session_expiry: None,
+            listeners: None,
+            tls_port: None,
+            tls_cert_file: None,
+            tls_key_file: None,
+            tls_ca_file: String::new(),
+            tls_require_client_cert: false,
+            tls_cert_auth: CertAuth::default(),
+            ws_port: None,
+            max_connections: 0,
+            max_connections_per_ip: 0,
+            max_publish_rate: 0,
+            max_bandwidth: 0,
+            max_packet_size: 0,
+            max_payload_size: 0,
+            max_topic_length: 0,
+            max_topic_levels: 0,
+            max_client_id_length: 0,
+            max_queued_messages: queues.max_queued_messages,
+            qos0_drop_policy: queues.qos0_drop_policy,
+            slow_consumer_timeout: queues.slow_consumer_timeout.as_secs(),
+            log_level: log.level,
+            log_format: log.format,
+            log_stdout: log.stdout,
+            log_max_size: log.max_size,
+            log_rotate_interval: log.rotate_interval.as_secs(),
+            log_max_files: log.max_files,
+            trace_file: DEFAULT_TRACE_FILE.to_string(),
+            trace_targets: Vec::new(),
+            record_file: None,
+            record_topics: Vec::new(),
+            message_ttl: Vec::new(),
+            bridges: BTreeMap::new(),
+            cluster_bind: None,
+            cluster_node: None,
+            cluster_peers: Vec::new(),
+        }
+    }
+}
+
+impl BrokerConfig {
+    /// Settings of the config file, checks the keys read by main
+    pub fn from_config(config: &ConfigFile) -> Result<BrokerConfig, ConfigError> {
+        let settings: BrokerConfig = config.deserialize()?;
+        if settings.dispatcher_threads == Some(0) {
+            return Err(config.error("dispatcher_threads", "Must be at least 1"));
+        }
+        if settings.client_workers == Some(0) {
+            return Err(config.error("client_workers", "Must be at least 1"));
+        }
+        if settings.admin_bind.is_some() && settings.admin().is_none() {
+            return Err(config.error("admin_bind", "Needs an admin_token"));
+        }
+        Ok(settings)
+    }
+
+    /// Replaces the value of key with one given on the command line
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let invalid = || format!("Invalid value: {}", value);
+        match key {
+            "host" => self.host = value.to_string(),
+            "port" => self.port = value.parse().map_err(|_| invalid())?,
+            "logfile" => self.logfile = value.to_string(),
+            "credentials_file" => self.credentials_file = value.to_string(),
+            "log_level" => {
+                self.log_level = Level::deserialize(value.into_deserializer())
+                    .map_err(|_: serde::de::value::Error| invalid())?
+            }
+            "pid_file" => self.pid_file = Some(value.to_string()),
+            _ => return Err("Unknown key".to_string()),
+        }
+        Ok(())
+    }
+
+    /// Address and token of the admin api, None without admin_bind or with an empty token
+    pub fn admin(&self) -> Option<(String, String)> {
+        let token = self
+            .admin_token
+            .as_ref()
+            .filter(|token| !token.is_empty())?;
+        Some((self.admin_bind.clone()?, token.clone()))
+    }
+
+    /// User names and passwords of the credentials file, a `user: password` mapping.
+    /// The passwords keep their text and a user without one is an error.
+    pub fn credentials(&self) -> Result<HashMap<String, String>, ConfigError> {
+        if self.credentials_file.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let file = ConfigFile::load(&self.credentials_file)?;
+        let credentials: HashMap<String, Option<String>> = file.deserialize()?;
+        credentials
+            .into_iter()
+            .map(
+                |(user, password)| match password.filter(|password| !password.is_empty()) {
+                    Some(password) => Ok((user, password)),
+                    None => Err(file.error(&user, "Needs a password")),
+                },
+            )
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(yaml: &str) -> Result<BrokerConfig, ConfigError> {
+        BrokerConfig::from_config(&ConfigFile::parse("config.yaml", yaml).unwrap())
+    }
+
+    #[test]
+    fn test_defaults_and_validation() {
+        let settings = parse("# every key has a default\n").unwrap();
+        assert_eq!(settings.host, DEFAULT_HOST);
+        assert_eq!(settings.port, DEFAULT_PORT);
+        assert_eq!(settings.admin(), None);
+        assert!(settings.credentials().unwrap().is_empty());
+
+        let error = parse("host: 0.0.0.0\nport: 70000\n").unwrap_err();
+        assert_eq!(error.line, Some(2));
+        assert_eq!(error.key.as_deref(), Some("port"));
+
+        let error = parse("host: 0.0.0.0\nprot: 1884\n").unwrap_err();
+        assert_eq!(error.to_string(), "config.yaml:2: prot: Unknown key");
+
+        let error = parse("admin_bind: 127.0.0.1:9200\n").unwrap_err();
+        assert_eq!(error.line, Some(1));
+        assert_eq!(error.key.as_deref(), Some("admin_bind"));
+        let settings = parse("admin_bind: 127.0.0.1:9200\nadmin_token: '0x1F'\n").unwrap();
+        assert_eq!(
+            settings.admin(),
+            Some(("127.0.0.1:9200".to_string(), "0x1F".to_string()))
+        );
+
+        let error = parse("port: 1884\nclient_workers: 0\n").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "config.yaml:2: client_workers: Must be at least 1"
+        );
+    }
+
+    #[test]
+    fn test_set() {
+        let mut settings = BrokerConfig::default();
+        settings.set("port", "1884").unwrap();
+        settings.set("log_level", "debug").unwrap();
+        settings.set("pid_file", "server.pid").unwrap();
+        assert_eq!(settings.port, 1884);
+        assert_eq!(settings.log_level, Level::Debug);
+        assert_eq!(settings.pid_file.as_deref(), Some("server.pid"));
+        assert!(settings.set("port", "70000").is_err());
+        assert!(settings.set("log_level", "verbose").is_err());
+        assert!(settings.set("prot", "1884").is_err());
+    }
+
+    #[test]
+    fn test_credentials_file() {
+        let credentials = parse("credentials_file: src/credentials.yaml")
+            .unwrap()
+            .credentials()
+            .unwrap();
+        assert_eq!(credentials.get("test").map(String::as_str), Some("test"));
+        assert_eq!(credentials.get("facu").map(String::as_str), Some("12345"));
+
+        let path = std::env::temp_dir().join("mqtt-credentials-test.yaml");
+        let settings = BrokerConfig {
+            credentials_file: path.display().to_string(),
+            ..BrokerConfig::default()
+        };
+        std::fs::write(&path, "a: 0x1F\nb: 1e3\nc: 1.50\n").unwrap();
+        let credentials = settings.credentials().unwrap();
+        assert_eq!(credentials["a"], "0x1F");
+        assert_eq!(credentials["b"], "1e3");
+        assert_eq!(credentials["c"], "1.50");
+        for empty in ["d:", "d: ~", "d: null", "d: ''"].iter() {
+            std::fs::write(&path, format!("a: 0x1F\n{}\n", empty)).unwrap();
+            let error = settings.credentials().unwrap_err();
+            assert_eq!(error.line, Some(2));
+            assert_eq!(error.key.as_deref(), Some("d"));
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+}
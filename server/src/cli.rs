@@ -0,0 +1,105 @@
+use config::config_file::DEFAULT_CONFIG_PATH;
+
+pub const USAGE: &str =
+    "usage: server [--config PATH] [--host HOST] [--port PORT] [--log-file PATH] \
+[--credentials PATH] [--log-level debug|info|error] [--pid-file PATH] [--check-config] [--version]";
+
+/// Flags that replace a key of the config file
+const OVERRIDES: [(&str, &str); 6] = [
+    ("--host", "host"),
+    ("--port", "port"),
+    ("--log-file", "logfile"),
+    ("--credentials", "credentials_file"),
+    ("--log-level", "log_level"),
+    ("--pid-file", "pid_file"),
+];
+
+/// Options of the command line
+#[derive(Debug, PartialEq)]
+pub struct Options {
+    pub config: String,
+    pub overrides: Vec<(&'static str, String)>, // config key and the value given for it
+    pub check_config: bool,                     // validates the config and exits
+    pub version: bool,
+}
+
+pub fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut options = Options {
+        config: DEFAULT_CONFIG_PATH.to_string(),
+        overrides: Vec::new(),
+        check_config: false,
+        version: false,
+    };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--check-config" => {
+                options.check_config = true;
+                continue;
+            }
+            "--version" => {
+                options.version = true;
+                continue;
+            }
+            _ => {}
+        }
+        let value = args
+            .next()
+            .ok_or_else(|| format!("{} needs a value", arg))?
+            .clone();
+        if arg == "--config" {
+            options.config = value;
+            continue;
+        }
+        let key = match OVERRIDES.iter().find(|(flag, _)| flag == arg) {
+            Some((_, key)) => *key,
+            None => return Err(format!("Unknown option: {}", arg)),
+        };
+        match key {
+            "port" if value.parse::<u16>().is_err() => {
+                return Err(format!("Invalid port: {}", value))
+            }
+            "log_level" if !["debug", "info", "error"].contains(&value.as_str()) => {
+                return Err(format!("Invalid log level: {}", value))
+            }
+            _ => options.overrides.push((key, value)),
+        }
+    }
+    Ok(options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let options = parse_args(&args("")).unwrap();
+        assert_eq!(options.config, DEFAULT_CONFIG_PATH);
+        assert!(options.overrides.is_empty());
+
+        let options = parse_args(&args(
+            "--config /etc/broker.yaml --port 1884 --log-level debug --check-config",
+        ))
+        .unwrap();
+        assert_eq!(options.config, "/etc/broker.yaml");
+        assert_eq!(
+            options.overrides,
+            vec![
+                ("port", "1884".to_string()),
+                ("log_level", "debug".to_string())
+            ]
+        );
+        assert!(options.check_config);
+        assert!(!options.version);
+
+        assert!(parse_args(&args("--port")).is_err());
+        assert!(parse_args(&args("--port 70000")).is_err());
+        assert!(parse_args(&args("--log-level verbose")).is_err());
+        assert!(parse_args(&args("broker.yaml")).is_err());
+    }
+}
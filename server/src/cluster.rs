@@ -0,0 +1,474 @@
+use crate::broker_config::BrokerConfig;
+use crate::logger::{Logger, Logging};
+use crate::topics::topic_matches;
+use config::config_file::{ConfigError, ConfigFile};
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, BufReader, Result, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Origin of the publishes received from other nodes, they are not sent back to the cluster
+pub const CLUSTER_ORIGIN: &str = "$cluster";
+/// Seconds between two connection attempts to a peer
+const RECONNECT_INTERVAL_SECS: u64 = 2;
+/// Lines waiting to be sent to a peer, the newer ones are dropped when it is full
+const MAX_QUEUED_LINES: usize = 10000;
+/// Longest wait of a peer connection for lines, the shutdown flag is checked after it
+const POLL_MILLIS: u64 = 500;
+
+/// Settings of the cluster read from the server config file
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClusterSettings {
+    pub bind: String,       // host:port where the other nodes connect
+    pub node: String,       // name of this node, the address the other nodes use to reach it
+    pub peers: Vec<String>, // host:port of the other nodes
+}
+
+/// Takes cluster_bind, cluster_node (cluster_bind by default) and cluster_peers
+/// from the server config, None when there is no cluster_bind
+pub fn cluster_from_config(
+    config: &BrokerConfig,
+    file: &ConfigFile,
+) -> std::result::Result<Option<ClusterSettings>, ConfigError> {
+    let bind = match &config.cluster_bind {
+        Some(bind) => bind.clone(),
+        None => return Ok(None),
+    };
+    if config.cluster_peers.is_empty() {
+        return Err(file.error("cluster_bind", "Needs cluster_peers"));
+    }
+    Ok(Some(ClusterSettings {
+        node: config.cluster_node.clone().unwrap_or_else(|| bind.clone()),
+        bind,
+        peers: config.cluster_peers.clone(),
+    }))
+}
+
+/// Broker operations used by the cluster to apply what the other nodes send
+pub trait ClusterBackend {
+    /// Topics with subscribers on this node
+    fn interest(&self) -> Vec<String>;
+    /// (topic, message) of the retained messages of this node
+    fn retained(&self) -> Vec<(String, String)>;
+    /// Delivers a publish received by another node to the subscribers of this node
+    fn publish(&self, topic: &str, message: &str, qos: u8, retain: bool);
+    /// Stores a retained message without delivering it, empty removes it
+    fn store_retained(&self, topic: &str, message: &str);
+    /// Ends the session of client_id on this node, returns its subscriptions or
+    /// None when this node does not have it
+    fn take_over(&self, client_id: &str) -> Option<Vec<String>>;
+    /// Subscribes client_id to the topics of the session it had on another node
+    fn resume(&self, client_id: &str, topics: &[String]);
+}
+
+/// Outgoing connection to another node
+struct Peer {
+    address: String,
+    tx: Mutex<Option<SyncSender<String>>>, // lines for the peer, None while disconnected
+}
+
+impl Peer {
+    /// Queues a line, false when the peer is not connected or its queue is full
+    fn send(&self, line: &str) -> bool {
+        match &*self.tx.lock().unwrap() {
+            Some(tx) => !matches!(
+                tx.try_send(line.to_string()),
+                Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_))
+            ),
+            None => false,
+        }
+    }
+}
+
+/// Node of a cluster of brokers. Every node connects to the others to send them
+/// lines of json: the topics it has subscribers for, the publishes the others have
+/// subscribers for, every retained message and the session takeovers.
+pub struct Cluster {
+    settings: ClusterSettings,
+    peers: Vec<Peer>,
+    interest: Mutex<HashMap<String, HashSet<String>>>, // topic filters with subscribers of each node by name
+}
+
+impl Cluster {
+    pub fn new(settings: ClusterSettings) -> Cluster {
+        let peers = settings
+            .peers
+            .iter()
+            .map(|address| Peer {
+                address: address.to_owned(),
+                tx: Mutex::new(None),
+            })
+            .collect();
+        Cluster {
+            settings,
+            peers,
+            interest: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn node(&self) -> &str {
+        &self.settings.node
+    }
+
+    pub fn bind(&self) -> &str {
+        &self.settings.bind
+    }
+
+    fn broadcast(&self, message: Value) {
+        let line = message.to_string();
+        for peer in &self.peers {
+            peer.send(&line);
+        }
+    }
+
+    /// Sends a publish of this node to the nodes with subscribers for topic, the
+    /// retained messages go to every node
+    pub fn publish(&self, topic: &str, message: &str, qos: u8, retain: bool) {
+        let line = json!({
+            "type": "publish",
+            "topic": topic,
+            "message": message,
+            "qos": qos,
+            "retain": retain,
+        })
+        .to_string();
+        let interest = self.interest.lock().unwrap();
+        for peer in &self.peers {
+            let interested = interest
+                .get(&peer.address)
+                .is_some_and(|filters| filters.iter().any(|filter| topic_matches(filter, topic)));
+            if retain || interested {
+                peer.send(&line);
+            }
+        }
+    }
+
+    /// A topic got subscribers on this node
+    pub fn subscribed(&self, topic: &str) {
+        self.broadcast(json!({ "type": "subscribe", "topic": topic }));
+    }
+
+    /// A topic has no subscribers left on this node
+    pub fn unsubscribed(&self, topic: &str) {
+        self.broadcast(json!({ "type": "unsubscribe", "topic": topic }));
+    }
+
+    pub fn delete_retained(&self, topic: &str) {
+        self.broadcast(json!({ "type": "retained", "topic": topic, "message": "" }));
+    }
+
+    /// client_id connected to this node, the node that has its session closes it and
+    /// sends its subscriptions back unless clean_session is set
+    pub fn take_over(&self, client_id: &str, clean_session: bool) {
+        self.broadcast(json!({
+            "type": "takeover",
+            "client_id": client_id,
+            "clean_session": clean_session,
+        }));
+    }
+
+    /// Applies a line sent by another node, node is the name given by its hello
+    pub fn handle_line<B: ClusterBackend>(&self, node: &mut String, line: &str, backend: &B) {
+        let message: Value = match serde_json::from_str(line) {
+            Ok(message) => message,
+            Err(_) => return,
+        };
+        let text = |key: &str| message[key].as_str().unwrap_or_default();
+        let topics = |key: &str| -> Vec<String> {
+            message[key]
+                .as_array()
+                .map(|topics| {
+                    topics
+                        .iter()
+                        .filter_map(|topic| topic.as_str().map(str::to_string))
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+        match text("type") {
+            "hello" => {
+                *node = text("node").to_string();
+                let topics = topics("interest").into_iter().collect();
+                self.interest.lock().unwrap().insert(node.clone(), topics);
+            }
+            "subscribe" => {
+                if let Some(topics) = self.interest.lock().unwrap().get_mut(node.as_str()) {
+                    topics.insert(text("topic").to_string());
+                }
+            }
+            "unsubscribe" => {
+                if let Some(topics) = self.interest.lock().unwrap().get_mut(node.as_str()) {
+                    topics.remove(text("topic"));
+                }
+            }
+            "publish" => backend.publish(
+                text("topic"),
+                text("message"),
+                message["qos"].as_u64().unwrap_or(0) as u8,
+                message["retain"].as_bool().unwrap_or(false),
+            ),
+            "retained" => backend.store_retained(text("topic"), text("message")),
+            "takeover" => {
+                let client_id = text("client_id");
+                if let Some(topics) = backend.take_over(client_id) {
+                    if message["clean_session"] == json!(false) {
+                        self.broadcast(json!({
+                            "type": "session",
+                            "client_id": client_id,
+                            "topics": topics,
+                        }));
+                    }
+                }
+            }
+            "session" => backend.resume(text("client_id"), &topics("topics")),
+            _ => {}
+        }
+    }
+
+    /// Accepts the connections of the other nodes and connects to each of them
+    pub fn start<B>(
+        self: &Arc<Self>,
+        backend: B,
+        shutdown: Arc<AtomicBool>,
+        logger: Arc<Logger>,
+    ) -> Result<()>
+    where
+        B: ClusterBackend + Clone + Send + 'static,
+    {
+        let tcp_listener = TcpListener::bind(&self.settings.bind)?;
+        let cluster = self.clone();
+        let accept_backend = backend.clone();
+        let accept_logger = logger.clone();
+        thread::Builder::new()
+            .name("Thread: cluster listener".to_string())
+            .spawn(move || {
+                for stream in tcp_listener.incoming().flatten() {
+                    let cluster = cluster.clone();
+                    let backend = accept_backend.clone();
+                    let logger = accept_logger.clone();
+                    let _ = thread::Builder::new()
+                        .name("Thread: cluster node".to_string())
+                        .spawn(move || cluster.receive(stream, &backend, &logger));
+                }
+            })?;
+        for index in 0..self.peers.len() {
+            let cluster = self.clone();
+            let backend = backend.clone();
+            let shutdown = shutdown.clone();
+            let logger = logger.clone();
+            thread::Builder::new()
+                .name(format!(
+                    "Thread: cluster peer {}",
+                    self.peers[index].address
+                ))
+                .spawn(move || {
+                    let peer = &cluster.peers[index];
+                    while !shutdown.load(Ordering::SeqCst) {
+                        if let Err(e) = cluster.send_to(peer, &backend, &shutdown, &logger) {
+                            logger
+                                .debug(format!("Cluster node {} unreachable: {}", peer.address, e));
+                        }
+                        *peer.tx.lock().unwrap() = None;
+                        let retry = Instant::now() + Duration::from_secs(RECONNECT_INTERVAL_SECS);
+                        while Instant::now() < retry && !shutdown.load(Ordering::SeqCst) {
+                            thread::sleep(Duration::from_millis(POLL_MILLIS));
+                        }
+                    }
+                })?;
+        }
+        Ok(())
+    }
+
+    /// Reads the lines of a node until it closes the connection
+    fn receive<B: ClusterBackend>(&self, stream: TcpStream, backend: &B, logger: &Logger) {
+        let mut node = String::new();
+        for line in BufReader::new(stream).lines() {
+            match line {
+                Ok(line) => self.handle_line(&mut node, &line, backend),
+                Err(_) => break,
+            }
+        }
+        logger.info(format!("Cluster node {} disconnected", node));
+        // the node sends its subscriptions again when it reconnects
+        self.interest.lock().unwrap().remove(&node);
+    }
+
+    /// Connects to peer and sends it the lines of this node until the connection
+    /// is lost (Err) or the server shuts down (Ok)
+    fn send_to<B: ClusterBackend>(
+        &self,
+        peer: &Peer,
+        backend: &B,
+        shutdown: &AtomicBool,
+        logger: &Logger,
+    ) -> Result<()> {
+        let mut stream = TcpStream::connect(&peer.address)?;
+        stream.set_nodelay(true)?;
+        let (tx, rx) = mpsc::sync_channel(MAX_QUEUED_LINES);
+        // the lines queued from now on come after the state sent below
+        *peer.tx.lock().unwrap() = Some(tx);
+        let hello = json!({
+            "type": "hello",
+            "node": self.settings.node,
+            "interest": backend.interest(),
+        });
+        writeln!(stream, "{}", hello)?;
+        for (topic, message) in backend.retained() {
+            let retained = json!({ "type": "retained", "topic": topic, "message": message });
+            writeln!(stream, "{}", retained)?;
+        }
+        logger.info(format!("Cluster node {} connected", peer.address));
+        while !shutdown.load(Ordering::SeqCst) {
+            match rx.recv_timeout(Duration::from_millis(POLL_MILLIS)) {
+                Ok(line) => writeln!(stream, "{}", line)?,
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct FakeBackend {
+        calls: Mutex<Vec<String>>,
+    }
+
+    impl ClusterBackend for FakeBackend {
+        fn interest(&self) -> Vec<String> {
+            vec!["temperature".to_string()]
+        }
+        fn retained(&self) -> Vec<(String, String)> {
+            Vec::new()
+        }
+        fn publish(&self, topic: &str, message: &str, qos: u8, retain: bool) {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("publish {} {} {} {}", topic, message, qos, retain));
+        }
+        fn store_retained(&self, topic: &str, message: &str) {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("retained {} {}", topic, message));
+        }
+        fn take_over(&self, client_id: &str) -> Option<Vec<String>> {
+            Some(vec!["temperature".to_string()]).filter(|_| client_id == "sensor")
+        }
+        fn resume(&self, client_id: &str, topics: &[String]) {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("resume {} {:?}", client_id, topics));
+        }
+    }
+
+    fn cluster() -> Cluster {
+        Cluster::new(ClusterSettings {
+            bind: "127.0.0.1:7001".to_string(),
+            node: "127.0.0.1:7001".to_string(),
+            peers: vec!["127.0.0.1:7002".to_string()],
+        })
+    }
+
+    #[test]
+    fn test_cluster_from_config() {
+        let settings = |yaml: &str| {
+            let file = ConfigFile::parse("config.yaml", yaml).unwrap();
+            cluster_from_config(&BrokerConfig::from_config(&file).unwrap(), &file)
+        };
+        assert_eq!(settings("").unwrap(), None);
+        let error = settings("port: 1883\ncluster_bind: 127.0.0.1:7001\n").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "config.yaml:2: cluster_bind: Needs cluster_peers"
+        );
+        let settings = settings(
+            "cluster_bind: 127.0.0.1:7001\ncluster_peers: [127.0.0.1:7002, 127.0.0.1:7003]\n",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(settings.node, "127.0.0.1:7001");
+        assert_eq!(settings.peers, vec!["127.0.0.1:7002", "127.0.0.1:7003"]);
+    }
+
+    #[test]
+    fn test_publishes_follow_interest() {
+        let cluster = cluster();
+        let backend = FakeBackend::default();
+        let (tx, rx) = mpsc::sync_channel(10);
+        *cluster.peers[0].tx.lock().unwrap() = Some(tx);
+        let mut node = String::new();
+        let hello = r#"{"type":"hello","node":"127.0.0.1:7002","interest":["temperature"]}"#;
+        cluster.handle_line(&mut node, hello, &backend);
+        cluster.handle_line(
+            &mut node,
+            r#"{"type":"subscribe","topic":"humidity"}"#,
+            &backend,
+        );
+        cluster.handle_line(
+            &mut node,
+            r#"{"type":"unsubscribe","topic":"temperature"}"#,
+            &backend,
+        );
+        cluster.handle_line(
+            &mut node,
+            r#"{"type":"subscribe","topic":"jobs/#"}"#,
+            &backend,
+        );
+        cluster.publish("temperature", "21", 0, false);
+        cluster.publish("humidity", "40", 1, false);
+        cluster.publish("pressure", "1013", 0, true);
+        cluster.publish("jobs/1", "run", 1, false);
+        let topics: Vec<String> = rx
+            .try_iter()
+            .map(|line| serde_json::from_str::<Value>(&line).unwrap()["topic"].to_string())
+            .collect();
+        assert_eq!(topics, vec!["\"humidity\"", "\"pressure\"", "\"jobs/1\""]);
+    }
+
+    #[test]
+    fn test_takeover_sends_the_session() {
+        let cluster = cluster();
+        let backend = FakeBackend::default();
+        let (tx, rx) = mpsc::sync_channel(10);
+        *cluster.peers[0].tx.lock().unwrap() = Some(tx);
+        let mut node = "127.0.0.1:7002".to_string();
+        for client_id in ["other", "sensor"] {
+            let takeover = json!({
+                "type": "takeover",
+                "client_id": client_id,
+                "clean_session": false,
+            });
+            cluster.handle_line(&mut node, &takeover.to_string(), &backend);
+        }
+        let session: Value = serde_json::from_str(&rx.try_recv().unwrap()).unwrap();
+        assert_eq!(session["type"], "session");
+        assert_eq!(session["topics"], json!(["temperature"]));
+        assert!(rx.try_recv().is_err());
+
+        cluster.handle_line(&mut node, &session.to_string(), &backend);
+        cluster.handle_line(
+            &mut node,
+            r#"{"type":"publish","topic":"a","message":"1","qos":1,"retain":true}"#,
+            &backend,
+        );
+        assert_eq!(
+            *backend.calls.lock().unwrap(),
+            vec![
+                "resume sensor [\"temperature\"]".to_string(),
+                "publish a 1 1 true".to_string()
+            ]
+        );
+    }
+}
@@ -0,0 +1,118 @@
+use crate::topics::shard_index;
+use std::sync::mpsc::{self, Receiver, SendError, Sender};
+use std::thread;
+
+/// Commands of the message handler:
+/// * [ publish, dup, qos, retain, topic, message, properties, received, origin, client_id ]
+///
+/// The subscriptions are not commands, the client connections apply them before
+/// answering so the publishes sent after the suback find them.
+pub type Command = Vec<String>;
+
+/// Key that decides the dispatcher of command: the client, bridge or cluster that
+/// published it and the topic of the publishes of the server
+fn dispatch_key(command: &[String]) -> Option<&str> {
+    if command.first().map(String::as_str) != Some("publish") {
+        return None;
+    }
+    let publisher = [9, 8]
+        .iter()
+        .filter_map(|index| command.get(*index))
+        .find(|publisher| !publisher.is_empty());
+    publisher.or_else(|| command.get(4)).map(String::as_str)
+}
+
+/// Sends the commands of the clients to the dispatcher threads
+#[derive(Clone, Debug)]
+pub struct DispatchSender {
+    senders: Vec<Sender<Command>>,
+}
+
+/// One dispatcher for each cpu
+pub fn default_dispatchers() -> usize {
+    thread::available_parallelism().map_or(1, |cpus| cpus.get())
+}
+
+/// Channels of dispatchers threads (at least one)
+pub fn channels(dispatchers: usize) -> (DispatchSender, Vec<Receiver<Command>>) {
+    let (senders, receivers) = (0..dispatchers.max(1)).map(|_| mpsc::channel()).unzip();
+    (DispatchSender { senders }, receivers)
+}
+
+impl DispatchSender {
+    /// The publishes of a client always go to the same dispatcher, so its subscribers get
+    /// them in the order they were published whatever their topic. The commands without
+    /// key go to every dispatcher.
+    pub fn send(&self, command: Command) -> Result<(), SendError<Command>> {
+        match dispatch_key(&command) {
+            Some(key) => {
+                let index = shard_index(key, self.senders.len());
+                self.senders[index].send(command)
+            }
+            None => {
+                for sender in &self.senders {
+                    sender.send(command.clone())?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(values: &[&str]) -> Command {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn test_publishes_of_the_server_go_by_topic() {
+        let (tx, rx) = channels(4);
+        let topics = ["temperature", "humidity", "pressure", "wind", "rain"];
+        for topic in topics {
+            for message in ["1", "2"] {
+                tx.send(command(&["publish", "0", "0", "0", topic, message, ""]))
+                    .unwrap();
+            }
+        }
+
+        let mut dispatcher_of = std::collections::HashMap::new();
+        let mut total = 0;
+        for (index, receiver) in rx.iter().enumerate() {
+            for command in receiver.try_iter() {
+                total += 1;
+                let topic = dispatch_key(&command).unwrap().to_string();
+                assert_eq!(*dispatcher_of.entry(topic).or_insert(index), index);
+            }
+        }
+        assert_eq!(dispatcher_of.len(), topics.len());
+        assert_eq!(total, topics.len() * 2);
+    }
+
+    #[test]
+    fn test_publishes_of_a_client_keep_their_order() {
+        let (tx, rx) = channels(4);
+        let topics = ["temperature", "humidity", "pressure", "wind", "rain"];
+        for (index, topic) in topics.iter().enumerate() {
+            let message = index.to_string();
+            tx.send(command(&[
+                "publish", "0", "1", "0", topic, &message, "", "", "", "sensor",
+            ]))
+            .unwrap();
+        }
+        let mut dispatched: Vec<Vec<Command>> = rx
+            .iter()
+            .map(|receiver| receiver.try_iter().collect())
+            .filter(|commands: &Vec<Command>| !commands.is_empty())
+            .collect();
+        assert_eq!(dispatched.len(), 1);
+        let messages: Vec<String> = dispatched
+            .remove(0)
+            .into_iter()
+            .map(|command| command[5].clone())
+            .collect();
+        assert_eq!(messages, vec!["0", "1", "2", "3", "4"]);
+    }
+}
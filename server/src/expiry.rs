@@ -0,0 +1,152 @@
+use crate::broker_config::BrokerConfig;
+use crate::topics::topic_matches;
+use mqtt_packet::mqtt_packet_service::properties::{property_identifiers, Properties};
+use mqtt_packet::mqtt_packet_service::variable_header_packet::protocol_level;
+use serde::Deserialize;
+use std::time::{Duration, Instant};
+
+/// Seconds between two purges of the expired retained and queued messages
+pub const EXPIRY_SWEEP_SECS: u64 = 10;
+
+/// Session expiry interval of MQTT 5 clients whose session never expires
+const SESSION_NEVER_EXPIRES: u32 = u32::MAX;
+
+/// Time a disconnected session is kept, None when it is kept until the client connects
+/// again. Clean sessions end with the connection, MQTT 5 clients ask for an interval
+/// and the persistent 3.1.1 sessions are kept for max_expiry, the session_expiry config.
+pub fn session_expiry(
+    level: u8,
+    clean_session: bool,
+    interval: u32,
+    max_expiry: Option<Duration>,
+) -> Option<Duration> {
+    if level == protocol_level::MQTT_5 {
+        let asked = match interval {
+            SESSION_NEVER_EXPIRES => None,
+            seconds => Some(Duration::from_secs(seconds.into())),
+        };
+        return match (asked, max_expiry) {
+            (Some(asked), Some(max_expiry)) => Some(asked.min(max_expiry)),
+            (asked, max_expiry) => asked.or(max_expiry),
+        };
+    }
+    if clean_session {
+        return Some(Duration::ZERO);
+    }
+    max_expiry
+}
+
+/// Rule of message_ttl, the messages of the topics matching filter wait for seconds
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TtlRule {
+    pub filter: String,
+    pub seconds: u64,
+}
+
+/// Time a message can wait to be delivered. MQTT 5 publishers set it with the message
+/// expiry interval, the other messages take the first message_ttl rule matching their topic.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ExpiryRules {
+    rules: Vec<(String, Duration)>, // (topic filter, ttl)
+}
+
+impl ExpiryRules {
+    /// Takes the message_ttl rules from the server config
+    pub fn from_config(config: &BrokerConfig) -> ExpiryRules {
+        ExpiryRules {
+            rules: config
+                .message_ttl
+                .iter()
+                .map(|rule| (rule.filter.clone(), Duration::from_secs(rule.seconds)))
+                .collect(),
+        }
+    }
+
+    /// Time a message of topic can wait, None when it does not expire
+    pub fn ttl(&self, topic: &str, properties: &Properties) -> Option<Duration> {
+        if let Some(seconds) = properties.get_u32(property_identifiers::MESSAGE_EXPIRY_INTERVAL) {
+            return Some(Duration::from_secs(seconds.into()));
+        }
+        self.rules
+            .iter()
+            .find(|(filter, _)| topic_matches(filter, topic))
+            .map(|(_, ttl)| *ttl)
+    }
+
+    /// Instant after which a message of topic published now is not delivered
+    pub fn expires(&self, topic: &str, properties: &Properties) -> Option<Instant> {
+        self.ttl(topic, properties).map(|ttl| Instant::now() + ttl)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::config_file::ConfigFile;
+    use mqtt_packet::mqtt_packet_service::properties::PropertyValue;
+
+    #[test]
+    fn test_rules() {
+        let config = ConfigFile::parse(
+            "config.yaml",
+            "message_ttl:\n  - {filter: sensors/+/temperature, seconds: 60}\n  \
+             - {filter: sensors/#, seconds: 300}\n",
+        )
+        .unwrap();
+        let rules = ExpiryRules::from_config(&BrokerConfig::from_config(&config).unwrap());
+        let none = Properties::new();
+        assert_eq!(
+            rules.ttl("sensors/1/temperature", &none),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(
+            rules.ttl("sensors/1/humidity", &none),
+            Some(Duration::from_secs(300))
+        );
+        assert_eq!(rules.ttl("commands/reboot", &none), None);
+
+        // the message expiry interval of the publisher takes precedence
+        let mut properties = Properties::new();
+        properties.set(
+            property_identifiers::MESSAGE_EXPIRY_INTERVAL,
+            PropertyValue::FourByteInteger(5),
+        );
+        assert_eq!(
+            rules.ttl("sensors/1/humidity", &properties),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(
+            ExpiryRules::default().ttl("commands/reboot", &properties),
+            Some(Duration::from_secs(5))
+        );
+
+        let yaml = "port: 1883\nmessage_ttl:\n  - {filter: sensors/#, seconds: soon}\n";
+        let config = ConfigFile::parse("config.yaml", yaml).unwrap();
+        let error = BrokerConfig::from_config(&config).unwrap_err();
+        assert_eq!(error.line, Some(3));
+        assert_eq!(error.key.as_deref(), Some("message_ttl[0].seconds"));
+        let config = ConfigFile::parse("config.yaml", "message_ttl: sensors/# 60\n").unwrap();
+        assert!(BrokerConfig::from_config(&config).is_err());
+    }
+
+    #[test]
+    fn test_session_expiry() {
+        let hour = Some(Duration::from_secs(3600));
+        let v4 = protocol_level::MQTT_3_1_1;
+        let v5 = protocol_level::MQTT_5;
+        assert_eq!(session_expiry(v4, true, 0, hour), Some(Duration::ZERO));
+        assert_eq!(session_expiry(v4, false, 0, hour), hour);
+        assert_eq!(session_expiry(v4, false, 0, None), None);
+
+        // MQTT 5 clients ask for their interval, capped by the config
+        assert_eq!(session_expiry(v5, false, 0, None), Some(Duration::ZERO));
+        assert_eq!(
+            session_expiry(v5, false, 60, hour),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(session_expiry(v5, false, 7200, hour), hour);
+        assert_eq!(session_expiry(v5, false, u32::MAX, hour), hour);
+        assert_eq!(session_expiry(v5, false, u32::MAX, None), None);
+    }
+}
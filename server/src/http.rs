@@ -0,0 +1,264 @@
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Result, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+/// Time allowed to a client of the http endpoints to send its request
+const REQUEST_TIMEOUT_SECS: u64 = 5;
+/// Biggest request body accepted by the http endpoints
+const MAX_BODY_LEN: usize = 64 * 1024;
+/// Longest request line or header line accepted by the http endpoints
+const MAX_LINE_LEN: usize = 8 * 1024;
+/// Most headers accepted in a request
+const MAX_HEADERS: usize = 64;
+
+/// Request received by the embedded http endpoints (metrics and admin api)
+#[derive(Debug, Default, PartialEq)]
+pub struct HttpRequest {
+    pub method: String,
+    pub path: String,
+    pub query: HashMap<String, String>,
+    pub headers: HashMap<String, String>, // names in lowercase
+    pub body: Vec<u8>,
+}
+
+/// Response of the embedded http endpoints
+#[derive(Debug, PartialEq)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl HttpResponse {
+    pub fn new(status: u16, content_type: &'static str, body: String) -> HttpResponse {
+        HttpResponse {
+            status,
+            content_type,
+            body,
+        }
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            409 => "Conflict",
+            _ => "Internal Server Error",
+        }
+    }
+}
+
+/// Decodes the %XX escapes and the '+' of an url component
+pub fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        let escape = bytes
+            .get(index + 1..index + 3)
+            .filter(|hex| bytes[index] == b'%' && hex.iter().all(|byte| byte.is_ascii_hexdigit()));
+        match (escape, bytes[index]) {
+            (Some(hex), _) => {
+                let hex = String::from_utf8_lossy(hex);
+                decoded.push(u8::from_str_radix(&hex, 16).unwrap_or_default());
+                index += 3;
+                continue;
+            }
+            (None, b'+') => decoded.push(b' '),
+            (None, byte) => decoded.push(byte),
+        }
+        index += 1;
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+/// Reads a line of at most MAX_LINE_LEN bytes, empty at the end of the stream
+fn read_line<R: BufRead>(reader: &mut R) -> Result<String> {
+    let mut line = String::new();
+    reader.take(MAX_LINE_LEN as u64 + 1).read_line(&mut line)?;
+    if line.len() > MAX_LINE_LEN {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Http line longer than {} bytes", MAX_LINE_LEN),
+        ));
+    }
+    Ok(line)
+}
+
+/// Reads a request line, its headers and the body given by Content-Length
+pub fn read_request(stream: &TcpStream) -> Result<HttpRequest> {
+    stream.set_read_timeout(Some(Duration::from_secs(REQUEST_TIMEOUT_SECS)))?;
+    let mut reader = BufReader::new(stream);
+    let request_line = read_line(&mut reader)?;
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method.to_string(), target),
+        _ => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid http request line: {:?}", request_line),
+            ))
+        }
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let query = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (percent_decode(key), percent_decode(value))
+        })
+        .collect();
+
+    let mut headers = HashMap::new();
+    for count in 0.. {
+        let header = read_line(&mut reader)?;
+        if header.trim().is_empty() {
+            break;
+        }
+        if count == MAX_HEADERS {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("More than {} http headers", MAX_HEADERS),
+            ));
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+        }
+    }
+
+    let body_len = match headers.get("content-length") {
+        Some(len) => len
+            .parse::<usize>()
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid Content-Length"))?,
+        None => 0,
+    };
+    if body_len > MAX_BODY_LEN {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Request body too large: {} bytes", body_len),
+        ));
+    }
+    let mut body = vec![0_u8; body_len];
+    reader.read_exact(&mut body)?;
+
+    Ok(HttpRequest {
+        method,
+        // '+' only means a space in the query
+        path: percent_decode(&path.replace('+', "%2B")),
+        query,
+        headers,
+        body,
+    })
+}
+
+pub fn write_response(stream: &mut TcpStream, response: &HttpResponse) -> Result<()> {
+    let text = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.status,
+        response.reason(),
+        response.content_type,
+        response.body.len(),
+        response.body
+    );
+    stream.write_all(text.as_bytes())?;
+    stream.flush()
+}
+
+/// Answers one request of an http endpoint with the response given by handler
+pub fn handle_connection<F>(mut stream: TcpStream, handler: F) -> Result<()>
+where
+    F: FnOnce(&HttpRequest) -> HttpResponse,
+{
+    let response = match read_request(&stream) {
+        Ok(request) => handler(&request),
+        Err(e) if e.kind() == ErrorKind::InvalidData => {
+            HttpResponse::new(400, "text/plain", format!("{}\n", e))
+        }
+        Err(e) => return Err(e),
+    };
+    write_response(&mut stream, &response)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Sends raw_request to an endpoint served by handler and returns the raw response
+    pub fn request<F>(raw_request: &str, handler: F) -> String
+    where
+        F: FnOnce(&HttpRequest) -> HttpResponse + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            handle_connection(stream, handler).unwrap();
+        });
+        let mut client = TcpStream::connect(address).unwrap();
+        client.write_all(raw_request.as_bytes()).unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        handle.join().unwrap();
+        response
+    }
+
+    #[test]
+    fn test_read_request() {
+        let raw = "POST /publish?topic=%24SYS%2Fa&x HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer abc\r\nContent-Length: 4\r\n\r\nbody";
+        let response = request(raw, |request| {
+            assert_eq!(request.method, "POST");
+            assert_eq!(request.path, "/publish");
+            assert_eq!(request.query["topic"], "$SYS/a");
+            assert_eq!(request.query["x"], "");
+            assert_eq!(request.headers["authorization"], "Bearer abc");
+            assert_eq!(request.body, b"body");
+            HttpResponse::new(200, "text/plain", "done".to_string())
+        });
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\ndone"));
+    }
+
+    #[test]
+    fn test_bad_request() {
+        let response = request("nonsense\r\n\r\n", |_| {
+            HttpResponse::new(200, "text/plain", String::new())
+        });
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+
+        let long_header = format!("GET / HTTP/1.1\r\nX: {}\r\n\r\n", "a".repeat(MAX_LINE_LEN));
+        let response = request(&long_header, |_| {
+            HttpResponse::new(200, "text/plain", String::new())
+        });
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+
+        let many_headers = format!(
+            "GET / HTTP/1.1\r\n{}\r\n",
+            "X: a\r\n".repeat(MAX_HEADERS + 1)
+        );
+        let response = request(&many_headers, |_| {
+            HttpResponse::new(200, "text/plain", String::new())
+        });
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert!(response.contains("More than 64 http headers"));
+    }
+
+    #[test]
+    fn test_reason() {
+        let response = HttpResponse::new(409, "text/plain", String::new());
+        assert_eq!(response.reason(), "Conflict");
+    }
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode("a%2Fb+c"), "a/b c");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz"), "%zz");
+    }
+}
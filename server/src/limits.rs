@@ -0,0 +1,324 @@
+use crate::broker_config::BrokerConfig;
+use mqtt_packet::mqtt_packet_service::header_packet::control_type;
+use mqtt_packet::mqtt_packet_service::variable_header_packet::reason_codes;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Smallest burst of the bandwidth limit, a full read of a client thread must fit in it
+const MIN_BANDWIDTH_BURST: f64 = 1024.0;
+
+/// Limits applied to every client of the server, 0 means no limit
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Limits {
+    pub max_connections: usize, // concurrent connections of the whole server
+    pub max_connections_per_ip: usize, // concurrent connections from the same address
+    pub max_publish_rate: u32,  // publish packets per second of a client
+    pub max_bandwidth: u32,     // bytes per second received from a client
+    pub max_packet_size: usize, // bytes of a packet, fixed header included
+    pub max_payload_size: usize, // bytes of the message of a publish
+    pub max_topic_length: usize, // bytes of a topic name or topic filter
+    pub max_topic_levels: usize, // levels of a topic name or topic filter
+    pub max_client_id_length: usize, // bytes of a client identifier
+}
+
+impl Limits {
+    /// Takes max_connections, max_connections_per_ip, max_publish_rate, max_bandwidth,
+    /// max_packet_size, max_payload_size, max_topic_length, max_topic_levels and
+    /// max_client_id_length from the server config
+    pub fn from_config(config: &BrokerConfig) -> Limits {
+        Limits {
+            max_connections: config.max_connections,
+            max_connections_per_ip: config.max_connections_per_ip,
+            max_publish_rate: config.max_publish_rate,
+            max_bandwidth: config.max_bandwidth,
+            max_packet_size: config.max_packet_size,
+            max_payload_size: config.max_payload_size,
+            max_topic_length: config.max_topic_length,
+            max_topic_levels: config.max_topic_levels,
+            max_client_id_length: config.max_client_id_length,
+        }
+    }
+}
+
+/// Counts the connections of the server and of each address
+#[derive(Debug)]
+pub struct ConnectionLimiter {
+    limits: Limits,
+    connections: Mutex<(usize, HashMap<IpAddr, usize>)>, // (total, by address)
+}
+
+/// Place taken by a connection in the limiter, it is given back on drop
+#[derive(Debug)]
+pub struct ConnectionPermit {
+    limiter: Arc<ConnectionLimiter>,
+    ip: IpAddr,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let mut connections = self.limiter.connections.lock().unwrap();
+        connections.0 -= 1;
+        if let Some(count) = connections.1.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                connections.1.remove(&self.ip);
+            }
+        }
+    }
+}
+
+impl ConnectionLimiter {
+    pub fn new(limits: Limits) -> ConnectionLimiter {
+        ConnectionLimiter {
+            limits,
+            connections: Mutex::new((0, HashMap::new())),
+        }
+    }
+
+    /// Takes a place for a connection from ip, the error tells which limit was reached
+    pub fn try_acquire(self: &Arc<Self>, ip: IpAddr) -> Result<ConnectionPermit> {
+        let mut connections = self.connections.lock().unwrap();
+        let max_connections = self.limits.max_connections;
+        if max_connections > 0 && connections.0 >= max_connections {
+            return Err(Error::new(
+                ErrorKind::ConnectionRefused,
+                format!("server reached max connections ({})", max_connections),
+            ));
+        }
+        let from_ip = connections.1.get(&ip).copied().unwrap_or(0);
+        let max_per_ip = self.limits.max_connections_per_ip;
+        if max_per_ip > 0 && from_ip >= max_per_ip {
+            return Err(Error::new(
+                ErrorKind::ConnectionRefused,
+                format!(
+                    "{} reached max connections per address ({})",
+                    ip, max_per_ip
+                ),
+            ));
+        }
+        connections.0 += 1;
+        connections.1.insert(ip, from_ip + 1);
+        Ok(ConnectionPermit {
+            limiter: self.clone(),
+            ip,
+        })
+    }
+}
+
+/// Token bucket, it refills rate tokens per second up to capacity
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: f64, capacity: f64) -> TokenBucket {
+        TokenBucket {
+            rate,
+            capacity,
+            tokens: capacity,
+            last: Instant::now(),
+        }
+    }
+
+    /// Takes amount tokens, false when there are not enough of them
+    pub fn try_take(&mut self, amount: f64) -> bool {
+        self.take_at(amount, Instant::now())
+    }
+
+    fn take_at(&mut self, amount: f64, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last = now;
+        if self.tokens < amount {
+            return false;
+        }
+        self.tokens -= amount;
+        true
+    }
+}
+
+/// Limit broken by a client, its connection is closed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Violation {
+    PacketTooLarge,
+    PublishRate,
+    Bandwidth,
+}
+
+impl Violation {
+    /// Reason code of the DISCONNECT sent to MQTT 5 clients
+    pub fn reason_code(&self) -> u8 {
+        match self {
+            Violation::PacketTooLarge => reason_codes::PACKET_TOO_LARGE,
+            Violation::PublishRate => reason_codes::MESSAGE_RATE_TOO_HIGH,
+            Violation::Bandwidth => reason_codes::QUOTA_EXCEEDED,
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            Violation::PacketTooLarge => "Packet too large",
+            Violation::PublishRate => "Publish rate too high",
+            Violation::Bandwidth => "Bandwidth limit exceeded",
+        }
+    }
+}
+
+/// Rate limits of one client connection
+#[derive(Debug)]
+pub struct ClientLimiter {
+    max_packet_size: usize,
+    publish: Option<TokenBucket>,
+    bandwidth: Option<TokenBucket>,
+}
+
+impl ClientLimiter {
+    pub fn new(limits: &Limits) -> ClientLimiter {
+        let rate = limits.max_publish_rate as f64;
+        let bandwidth = limits.max_bandwidth as f64;
+        ClientLimiter {
+            max_packet_size: limits.max_packet_size,
+            // a second worth of tokens is allowed as burst
+            publish: Some(TokenBucket::new(rate, rate)).filter(|_| rate > 0.0),
+            bandwidth: Some(TokenBucket::new(
+                bandwidth,
+                bandwidth.max(MIN_BANDWIDTH_BURST),
+            ))
+            .filter(|_| bandwidth > 0.0),
+        }
+    }
+
+    /// Counts len bytes read from the client
+    pub fn received(&mut self, len: usize) -> std::result::Result<(), Violation> {
+        let allowed = self
+            .bandwidth
+            .as_mut()
+            .is_none_or(|bucket| bucket.try_take(len as f64));
+        if !allowed {
+            return Err(Violation::Bandwidth);
+        }
+        Ok(())
+    }
+
+    /// Checks a packet that starts at buff
+    pub fn packet(&mut self, buff: &[u8]) -> std::result::Result<(), Violation> {
+        if self.max_packet_size > 0 {
+            match packet_size(buff) {
+                Some(size) if size <= self.max_packet_size => {}
+                _ => return Err(Violation::PacketTooLarge),
+            }
+        }
+        let is_publish = buff.first().map(|b| b & 0xF0) == Some(control_type::PUBLISH);
+        let allowed = !is_publish
+            || self
+                .publish
+                .as_mut()
+                .is_none_or(|bucket| bucket.try_take(1.0));
+        if !allowed {
+            return Err(Violation::PublishRate);
+        }
+        Ok(())
+    }
+}
+
+/// Size of the packet that starts at buff, given by the remaining length of its fixed header
+pub fn packet_size(buff: &[u8]) -> Option<usize> {
+    let mut remaining_length = 0_usize;
+    for (index, byte) in buff.iter().skip(1).take(4).enumerate() {
+        remaining_length |= ((byte & 0x7F) as usize) << (7 * index);
+        if byte & 0x80 == 0 {
+            return Some(1 + (index + 1) + remaining_length);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::config_file::ConfigFile;
+    use std::time::Duration;
+
+    #[test]
+    fn test_limits_from_config() {
+        let config = ConfigFile::parse(
+            "config.yaml",
+            "max_connections: 100\nmax_publish_rate: 10\n",
+        )
+        .unwrap();
+        let limits = Limits::from_config(&BrokerConfig::from_config(&config).unwrap());
+        assert_eq!(limits.max_connections, 100);
+        assert_eq!(limits.max_publish_rate, 10);
+        assert_eq!(limits.max_packet_size, 0);
+
+        let bad = ConfigFile::parse("config.yaml", "max_connections: 100\nmax_bandwidth: fast\n")
+            .unwrap();
+        let error = BrokerConfig::from_config(&bad).unwrap_err();
+        assert_eq!(error.line, Some(2));
+        assert_eq!(error.key.as_deref(), Some("max_bandwidth"));
+    }
+
+    #[test]
+    fn test_connection_limiter() {
+        let limiter = Arc::new(ConnectionLimiter::new(Limits {
+            max_connections: 3,
+            max_connections_per_ip: 2,
+            ..Limits::default()
+        }));
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        let b: IpAddr = "10.0.0.2".parse().unwrap();
+        let first = limiter.try_acquire(a).unwrap();
+        let _second = limiter.try_acquire(a).unwrap();
+        assert!(limiter.try_acquire(a).is_err());
+        let _third = limiter.try_acquire(b).unwrap();
+        assert!(limiter.try_acquire(b).is_err());
+        drop(first);
+        assert!(limiter.try_acquire(a).is_ok());
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let mut bucket = TokenBucket::new(2.0, 2.0);
+        let start = bucket.last;
+        assert!(bucket.take_at(1.0, start));
+        assert!(bucket.take_at(1.0, start));
+        assert!(!bucket.take_at(1.0, start));
+        assert!(bucket.take_at(1.0, start + Duration::from_millis(500)));
+        // the bucket does not fill over its capacity
+        assert!(bucket.take_at(2.0, start + Duration::from_secs(10)));
+        assert!(!bucket.take_at(1.0, start + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn test_client_limiter() {
+        let mut limiter = ClientLimiter::new(&Limits {
+            max_publish_rate: 1,
+            max_packet_size: 8,
+            ..Limits::default()
+        });
+        let publish = [0x30, 0x04, 0x00, 0x01, b'a', b'b'];
+        assert_eq!(limiter.packet(&publish), Ok(()));
+        assert_eq!(limiter.packet(&publish), Err(Violation::PublishRate));
+        assert_eq!(limiter.packet(&[0xC0, 0x00]), Ok(()));
+        assert_eq!(
+            limiter.packet(&[0x30, 0x07]),
+            Err(Violation::PacketTooLarge)
+        );
+        assert_eq!(limiter.received(1_000_000), Ok(()));
+    }
+
+    #[test]
+    fn test_packet_size() {
+        assert_eq!(packet_size(&[0xC0, 0x00]), Some(2));
+        assert_eq!(packet_size(&[0x30, 0x7F]), Some(129));
+        assert_eq!(packet_size(&[0x30, 0x80, 0x01]), Some(131));
+        assert_eq!(packet_size(&[0x30]), None);
+    }
+}
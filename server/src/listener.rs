@@ -0,0 +1,327 @@
+use crate::broker_config::BrokerConfig;
+use crate::tls::{server_config, CertAuth, TlsSettings};
+use config::config_file::{ConfigError, ConfigFile};
+use rustls::ServerConfig;
+use serde::Deserialize;
+use std::io::{Error, ErrorKind, Result};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Kind of connection accepted by a listener
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    #[default]
+    Tcp,
+    Tls,
+    #[serde(alias = "ws")]
+    WebSocket,
+}
+
+/// A listener of the `listeners` mapping of the server config
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerConfig {
+    pub bind: String, // host:port
+    #[serde(default)]
+    pub protocol: Protocol,
+    #[serde(default)]
+    pub max_connections: usize, // 0 means no limit
+    #[serde(default)]
+    pub require_credentials: bool,
+    pub tls: Option<TlsSettings>, // needed by the tls listeners, only used by them
+}
+
+/// Authentication required to the clients of a listener
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ListenerAuth {
+    pub require_credentials: bool, // refuse clients that connect without user and password
+    pub cert_auth: CertAuth,       // only used by tls listeners
+}
+
+/// Settings of a listener read from the server config file
+#[derive(Clone, Debug)]
+pub struct ListenerSettings {
+    pub name: String,
+    pub address: String, // bind address as host:port
+    pub protocol: Protocol,
+    pub max_connections: usize, // 0 means no limit
+    pub auth: ListenerAuth,
+    pub tls: Option<TlsSettings>,
+}
+
+impl ListenerSettings {
+    pub fn new(name: &str, address: String, protocol: Protocol) -> ListenerSettings {
+        ListenerSettings {
+            name: name.to_string(),
+            address,
+            protocol,
+            max_connections: 0,
+            auth: ListenerAuth::default(),
+            tls: None,
+        }
+    }
+}
+
+/// A listener of the server, counts the clients connected through it
+pub struct Listener {
+    pub settings: ListenerSettings,
+    pub tls_config: Option<Arc<ServerConfig>>,
+    connections: AtomicUsize,
+}
+
+/// Place taken by a connected client, it is given back to the listener on drop
+pub struct ConnectionSlot {
+    listener: Arc<Listener>,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.listener.connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Listener {
+    pub fn new(settings: ListenerSettings) -> Result<Listener> {
+        let tls_config = match (&settings.protocol, &settings.tls) {
+            (Protocol::Tls, Some(tls)) => Some(server_config(tls)?),
+            (Protocol::Tls, None) => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Listener {} has no TLS settings", settings.name),
+                ))
+            }
+            _ => None,
+        };
+        Ok(Listener {
+            settings,
+            tls_config,
+            connections: AtomicUsize::new(0),
+        })
+    }
+
+    /// Takes a connection slot, None if the listener already has max_connections clients
+    pub fn try_acquire(self: &Arc<Self>) -> Option<ConnectionSlot> {
+        let max_connections = self.settings.max_connections;
+        self.connections
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |connections| {
+                if max_connections == 0 || connections < max_connections {
+                    Some(connections + 1)
+                } else {
+                    None
+                }
+            })
+            .ok()?;
+        Some(ConnectionSlot {
+            listener: self.clone(),
+        })
+    }
+
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::SeqCst)
+    }
+}
+
+/// Reads the listeners of the server config.
+/// With a `listeners` mapping each listener is configured under its name,
+/// otherwise the listeners are taken from host, port, tls_port and ws_port.
+pub fn listeners_from_config(
+    config: &BrokerConfig,
+    file: &ConfigFile,
+) -> std::result::Result<Vec<ListenerSettings>, ConfigError> {
+    let listeners = match &config.listeners {
+        Some(listeners) => listeners,
+        None => return legacy_listeners(config, file),
+    };
+    if listeners.is_empty() {
+        return Err(file.error("listeners", "No listeners found in config"));
+    }
+    let mut settings = Vec::new();
+    for (name, listener) in listeners {
+        let mut listener_settings =
+            ListenerSettings::new(name, listener.bind.clone(), listener.protocol);
+        listener_settings.max_connections = listener.max_connections;
+        listener_settings.auth.require_credentials = listener.require_credentials;
+        match (listener.protocol, &listener.tls) {
+            (Protocol::Tls, Some(tls)) => {
+                listener_settings.auth.cert_auth = tls.cert_auth;
+                listener_settings.tls = Some(tls.clone());
+            }
+            (Protocol::Tls, None) => {
+                let key = format!("listeners.{}.tls", name);
+                return Err(file.error(&key, "Missing required key"));
+            }
+            (_, Some(_)) => {
+                let key = format!("listeners.{}.tls", name);
+                return Err(file.error(&key, "Only used by tls listeners"));
+            }
+            (_, None) => {}
+        }
+        settings.push(listener_settings);
+    }
+    Ok(settings)
+}
+
+fn legacy_listeners(
+    config: &BrokerConfig,
+    file: &ConfigFile,
+) -> std::result::Result<Vec<ListenerSettings>, ConfigError> {
+    let host = &config.host;
+    let mut listeners = vec![ListenerSettings::new(
+        "default",
+        format!("{}:{}", host, config.port),
+        Protocol::Tcp,
+    )];
+    if let Some(tls_port) = config.tls_port {
+        let required = |key: &str, value: &Option<String>| {
+            value
+                .clone()
+                .ok_or_else(|| file.error(key, "Missing required key"))
+        };
+        let tls = TlsSettings {
+            cert_file: required("tls_cert_file", &config.tls_cert_file)?,
+            key_file: required("tls_key_file", &config.tls_key_file)?,
+            ca_file: config.tls_ca_file.clone(),
+            require_client_cert: config.tls_require_client_cert,
+            cert_auth: config.tls_cert_auth,
+        };
+        let mut settings =
+            ListenerSettings::new("tls", format!("{}:{}", host, tls_port), Protocol::Tls);
+        settings.auth.cert_auth = tls.cert_auth;
+        settings.tls = Some(tls);
+        listeners.push(settings);
+    }
+    if let Some(ws_port) = config.ws_port {
+        listeners.push(ListenerSettings::new(
+            "websocket",
+            format!("{}:{}", host, ws_port),
+            Protocol::WebSocket,
+        ));
+    }
+    Ok(listeners)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_listeners(yaml: &str) -> std::result::Result<Vec<ListenerSettings>, ConfigError> {
+        let file = ConfigFile::parse("config.yaml", yaml).unwrap();
+        listeners_from_config(&BrokerConfig::from_config(&file)?, &file)
+    }
+
+    #[test]
+    fn test_listeners_from_config() {
+        let listeners = parse_listeners(
+            "listeners:
+  internal:
+    bind: 127.0.0.1:1883
+    require_credentials: true
+  web:
+    bind: 0.0.0.0:8080
+    protocol: websocket
+    max_connections: 100
+",
+        )
+        .unwrap();
+        assert_eq!(listeners.len(), 2);
+        assert_eq!(listeners[0].name, "internal");
+        assert_eq!(listeners[0].address, "127.0.0.1:1883");
+        assert_eq!(listeners[0].protocol, Protocol::Tcp);
+        assert!(listeners[0].auth.require_credentials);
+        assert_eq!(listeners[0].max_connections, 0);
+        assert_eq!(listeners[1].protocol, Protocol::WebSocket);
+        assert_eq!(listeners[1].max_connections, 100);
+        assert!(!listeners[1].auth.require_credentials);
+
+        let listeners = parse_listeners(
+            "listeners:
+  secure:
+    bind: 0.0.0.0:8883
+    protocol: tls
+    tls: {cert_file: server.pem, key_file: server.key, cert_auth: allow}
+",
+        )
+        .unwrap();
+        assert_eq!(listeners[0].auth.cert_auth, CertAuth::Allow);
+        assert_eq!(listeners[0].tls.as_ref().unwrap().cert_file, "server.pem");
+    }
+
+    #[test]
+    fn test_listeners_from_config_errors() {
+        assert!(parse_listeners("listeners: {}\n").is_err());
+        assert!(parse_listeners("listeners:\n  main: {}\n").is_err());
+        let bad_protocol = "listeners:\n  main:\n    bind: 127.0.0.1:1883\n    protocol: udp\n";
+        assert!(parse_listeners(bad_protocol).is_err());
+        let missing_tls_files = "listeners:
+  main:
+    bind: 127.0.0.1:8883
+    protocol: tls
+    tls: {cert_file: server.pem}
+";
+        assert!(parse_listeners(missing_tls_files).is_err());
+        let missing_tls = "listeners:\n  main:\n    bind: 127.0.0.1:8883\n    protocol: tls\n";
+        let error = parse_listeners(missing_tls).unwrap_err();
+        assert_eq!(error.key.as_deref(), Some("listeners.main.tls"));
+    }
+
+    #[test]
+    fn test_listener_errors_name_the_line() {
+        let not_a_bool = "listeners:
+  main:
+    bind: 127.0.0.1:1883
+    require_credentials: yes
+";
+        let error = parse_listeners(not_a_bool).unwrap_err();
+        assert_eq!(error.line, Some(4));
+        assert_eq!(
+            error.key.as_deref(),
+            Some("listeners.main.require_credentials")
+        );
+        let bad_protocol = "listeners:
+  main:
+    protocol: udp
+    bind: 127.0.0.1:1883
+";
+        let error = parse_listeners(bad_protocol).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "config.yaml:3: listeners.main.protocol: Unknown variant `udp`, expected one of `tcp`, `tls`, `websocket`, `ws`"
+        );
+        let misspelled = "listeners:\n  main:\n    bind: 127.0.0.1:1883\n    protcol: ws\n";
+        let error = parse_listeners(misspelled).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "config.yaml:4: listeners.main.protcol: Unknown key"
+        );
+    }
+
+    #[test]
+    fn test_legacy_listeners() {
+        let listeners = parse_listeners("host: 127.0.0.1\nport: 3333\nws_port: 8080\n").unwrap();
+        assert_eq!(listeners.len(), 2);
+        assert_eq!(listeners[0].address, "127.0.0.1:3333");
+        assert_eq!(listeners[0].protocol, Protocol::Tcp);
+        assert_eq!(listeners[1].address, "127.0.0.1:8080");
+        assert_eq!(listeners[1].protocol, Protocol::WebSocket);
+        assert!(parse_listeners("tls_port: 8883\ntls_cert_file: server.pem\n").is_err());
+        let tls = "tls_port: 8883\ntls_cert_file: server.pem\ntls_key_file: server.key\n";
+        let listeners = parse_listeners(&format!("{}tls_cert_auth: false\n", tls)).unwrap();
+        assert_eq!(listeners[1].auth.cert_auth, CertAuth::Disabled);
+        let listeners = parse_listeners(&format!("{}tls_cert_auth: require\n", tls)).unwrap();
+        assert_eq!(listeners[1].auth.cert_auth, CertAuth::Require);
+    }
+
+    #[test]
+    fn test_max_connections() {
+        let mut settings = ListenerSettings::new("main", "127.0.0.1:0".to_string(), Protocol::Tcp);
+        settings.max_connections = 2;
+        let listener = Arc::new(Listener::new(settings).unwrap());
+        let first = listener.try_acquire().unwrap();
+        let _second = listener.try_acquire().unwrap();
+        assert!(listener.try_acquire().is_none());
+        drop(first);
+        assert_eq!(listener.connections(), 1);
+        assert!(listener.try_acquire().is_some());
+    }
+}
@@ -1,54 +1,287 @@
-use std::fs::{File, OpenOptions};
+use crate::broker_config::BrokerConfig;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::fs::{self, File, OpenOptions};
 use std::io::Write;
 use std::io::{BufWriter, ErrorKind};
 use std::io::{Error, Result};
-use std::sync::{Arc, Mutex};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Time a line can wait in the buffer before it is written to the log file
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Debug,
+    Info,
+    Error,
+}
+
+impl Level {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Level::Debug => "DEBUG",
+            Level::Info => "INFO",
+            Level::Error => "ERROR",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text, // <unix secs.millis> [LEVEL] message key=value
+    Json, // one json object for each line
+}
+
+/// Settings of the log file read from the server config
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogSettings {
+    pub level: Level,              // lines with a lower level are not written
+    pub format: LogFormat,         // format of the lines
+    pub stdout: bool,              // also writes the lines to stdout
+    pub max_size: u64,             // bytes of the log file before rotating it, 0 disables it
+    pub rotate_interval: Duration, // age of the log file before rotating it, zero disables it
+    pub max_files: usize,          // rotated files kept, <logfile>.1 is the newest
+}
+
+impl Default for LogSettings {
+    fn default() -> LogSettings {
+        LogSettings {
+            level: Level::Info,
+            format: LogFormat::Text,
+            stdout: true,
+            max_size: 0,
+            rotate_interval: Duration::ZERO,
+            max_files: 5,
+        }
+    }
+}
+
+impl LogSettings {
+    /// Takes log_level, log_format, log_stdout, log_max_size, log_rotate_interval
+    /// and log_max_files from the server config
+    pub fn from_config(config: &BrokerConfig) -> LogSettings {
+        LogSettings {
+            level: config.log_level,
+            format: config.log_format,
+            stdout: config.log_stdout,
+            max_size: config.log_max_size,
+            rotate_interval: Duration::from_secs(config.log_rotate_interval),
+            max_files: config.log_max_files,
+        }
+    }
+}
+
+/// Optional fields of a line, written as keys of the json object or key=value in text
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LogFields<'a> {
+    pub client_id: Option<&'a str>,
+    pub peer: Option<&'a str>,
+    pub packet_type: Option<&'a str>,
+    pub topic: Option<&'a str>,
+}
+
+impl<'a> LogFields<'a> {
+    /// Fields of a packet of client_id received from peer
+    pub fn packet(packet_type: &'a str, client_id: &'a str, peer: &'a str) -> LogFields<'a> {
+        LogFields {
+            client_id: Some(client_id).filter(|id| !id.is_empty()),
+            peer: Some(peer),
+            packet_type: Some(packet_type),
+            topic: None,
+        }
+    }
+
+    pub fn topic(self, topic: &'a str) -> LogFields<'a> {
+        LogFields {
+            topic: Some(topic),
+            ..self
+        }
+    }
+
+    fn pairs(&self) -> [(&'static str, Option<&'a str>); 4] {
+        [
+            ("client_id", self.client_id),
+            ("peer", self.peer),
+            ("packet_type", self.packet_type),
+            ("topic", self.topic),
+        ]
+    }
+}
+
+struct LogFile {
+    writer: BufWriter<File>,
+    size: u64,       // bytes of the file, including the buffered ones
+    opened: Instant, // the rotation interval counts from here
+    unflushed: bool, // lines in the buffer
+}
+
+impl LogFile {
+    fn open(file_source: &str) -> Result<LogFile> {
+        let file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(file_source)?;
+        Ok(LogFile {
+            size: file.metadata()?.len(),
+            writer: BufWriter::new(file),
+            opened: Instant::now(),
+            unflushed: false,
+        })
+    }
+}
 
 pub struct Logger {
-    file: Arc<Mutex<BufWriter<File>>>,
-    _file_source: String,
-    debug: bool,
+    file: Arc<Mutex<LogFile>>,
+    file_source: String,
+    settings: LogSettings,
 }
 pub trait Logging {
     fn new(file_source: &str, debug: bool) -> Self;
-    fn log(&self, msg: String) -> Result<&'static str>;
+    /// Writes message with its fields when level is not below the configured one
+    fn log(&self, level: Level, message: String, fields: &LogFields) -> Result<&'static str>;
     fn debug(&self, message: String) -> Option<&str>;
     fn error(&self, message: String) -> Option<&str>;
     fn info(&self, message: String) -> Option<&str>;
+    /// Writes to the log file any line still buffered
+    fn flush(&self) -> Result<()>;
+}
+
+impl Logger {
+    /// Opens file_source for appending, the buffered lines are written every second
+    /// and right away for errors
+    pub fn with_settings(file_source: &str, settings: LogSettings) -> Result<Logger> {
+        let file = Arc::new(Mutex::new(LogFile::open(file_source)?));
+        let weak = Arc::downgrade(&file);
+        thread::Builder::new()
+            .name("Thread: log flusher".to_string())
+            .spawn(move || Logger::flusher(weak))?;
+        Ok(Logger {
+            file,
+            file_source: file_source.to_owned(),
+            settings,
+        })
+    }
+
+    /// Flushes the buffered lines until the logger is dropped
+    fn flusher(file: Weak<Mutex<LogFile>>) {
+        loop {
+            thread::sleep(FLUSH_INTERVAL);
+            let file = match file.upgrade() {
+                Some(file) => file,
+                None => return,
+            };
+            let mut file = file.lock().unwrap_or_else(|e| e.into_inner());
+            if file.unflushed {
+                let _ = file.writer.flush();
+                file.unflushed = false;
+            }
+        }
+    }
+
+    pub fn enabled(&self, level: Level) -> bool {
+        level >= self.settings.level
+    }
+
+    fn format(&self, level: Level, message: &str, fields: &LogFields) -> String {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis())
+            .unwrap_or_default();
+        match self.settings.format {
+            LogFormat::Text => {
+                let mut line = format!(
+                    "{}.{:03} [{}] {}",
+                    millis / 1000,
+                    millis % 1000,
+                    level.name(),
+                    message
+                );
+                for (key, value) in fields.pairs() {
+                    if let Some(value) = value {
+                        line.push_str(&format!(" {}={}", key, value));
+                    }
+                }
+                line
+            }
+            LogFormat::Json => {
+                let mut object = Map::new();
+                object.insert("timestamp".to_string(), Value::from(millis as u64));
+                object.insert("level".to_string(), Value::from(level.name()));
+                object.insert("message".to_string(), Value::from(message));
+                for (key, value) in fields.pairs() {
+                    if let Some(value) = value {
+                        object.insert(key.to_string(), Value::from(value));
+                    }
+                }
+                Value::Object(object).to_string()
+            }
+        }
+    }
+
+    /// Renames the log file to <logfile>.1, shifting the older ones, and opens a new one
+    fn rotate(&self, file: &mut LogFile) -> Result<()> {
+        file.writer.flush()?;
+        let rotated = |index: usize| format!("{}.{}", self.file_source, index);
+        if self.settings.max_files == 0 {
+            fs::remove_file(&self.file_source)?;
+        } else {
+            let _ = fs::remove_file(rotated(self.settings.max_files));
+            for index in (1..self.settings.max_files).rev() {
+                let _ = fs::rename(rotated(index), rotated(index + 1));
+            }
+            fs::rename(&self.file_source, rotated(1))?;
+        }
+        *file = LogFile::open(&self.file_source)?;
+        Ok(())
+    }
+
+    fn needs_rotation(&self, file: &LogFile, line_size: u64) -> bool {
+        let settings = &self.settings;
+        (settings.max_size > 0 && file.size > 0 && file.size + line_size > settings.max_size)
+            || (!settings.rotate_interval.is_zero()
+                && file.opened.elapsed() >= settings.rotate_interval)
+    }
 }
 
 impl Logging for Logger {
     fn new(file_source: &str, debug: bool) -> Logger {
-        let file = match OpenOptions::new()
-            .read(false)
-            .append(true)
-            .write(true)
-            .create(true)
-            .open(file_source)
-        {
-            Err(_file) => panic!("Unable to open log file "),
-            Ok(file) => file,
+        let settings = LogSettings {
+            stdout: debug,
+            ..LogSettings::default()
         };
-        Logger {
-            file: Arc::new(Mutex::new(BufWriter::new(file))),
-            _file_source: file_source.to_owned(),
-            debug,
+        match Logger::with_settings(file_source, settings) {
+            Err(_file) => panic!("Unable to open log file "),
+            Ok(logger) => logger,
         }
     }
 
-    fn log(&self, message: String) -> Result<&'static str> {
-        let start = SystemTime::now();
-        let timestamp_str = start
-            .duration_since(UNIX_EPOCH)
-            .expect("Time went backwards");
+    fn log(&self, level: Level, message: String, fields: &LogFields) -> Result<&'static str> {
+        if !self.enabled(level) {
+            return Ok("return to log");
+        }
+        let line = self.format(level, &message, fields);
 
         match self.file.lock() {
             Ok(mut file) => {
-                file.write_all(format!("{} {}\n", timestamp_str.as_secs(), message).as_bytes())?;
-                file.flush()?;
-                if self.debug {
-                    println!("{} {}", timestamp_str.as_secs(), message);
+                let line_size = line.len() as u64 + 1;
+                if self.needs_rotation(&file, line_size) {
+                    self.rotate(&mut file)?;
+                }
+                file.writer.write_all(line.as_bytes())?;
+                file.writer.write_all(b"\n")?;
+                file.size += line_size;
+                file.unflushed = true;
+                if level == Level::Error {
+                    file.writer.flush()?;
+                    file.unflushed = false;
+                }
+                if self.settings.stdout {
+                    println!("{}", line);
                 }
                 Ok("return to log")
             }
@@ -57,14 +290,111 @@ impl Logging for Logger {
     }
 
     fn debug(&self, message: String) -> Option<&str> {
-        self.log("[DEBUG] ".to_string() + &message).ok()
+        self.log(Level::Debug, message, &LogFields::default()).ok()
     }
 
     fn error(&self, message: String) -> Option<&str> {
-        self.log("[ERROR] ".to_string() + &message).ok()
+        self.log(Level::Error, message, &LogFields::default()).ok()
     }
 
     fn info(&self, message: String) -> Option<&str> {
-        self.log("[INFO] ".to_string() + &message).ok()
+        self.log(Level::Info, message, &LogFields::default()).ok()
+    }
+
+    fn flush(&self) -> Result<()> {
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        file.unflushed = false;
+        file.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::config_file::ConfigFile;
+
+    fn log_file(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("mqtt-logger-{}.log", name));
+        for index in 0..4 {
+            let _ = fs::remove_file(format!("{}.{}", path.display(), index));
+        }
+        let _ = fs::remove_file(&path);
+        path.display().to_string()
+    }
+
+    #[test]
+    fn test_log_settings_from_config() {
+        let yaml = "log_level: debug
+log_format: json
+log_max_size: 1048576
+log_rotate_interval: 3600
+";
+        let config = ConfigFile::parse("config.yaml", yaml).unwrap();
+        let settings = LogSettings::from_config(&BrokerConfig::from_config(&config).unwrap());
+        assert_eq!(settings.level, Level::Debug);
+        assert_eq!(settings.format, LogFormat::Json);
+        assert_eq!(settings.max_size, 1048576);
+        assert_eq!(settings.rotate_interval, Duration::from_secs(3600));
+        assert_eq!(settings.max_files, 5);
+
+        let config = ConfigFile::parse("config.yaml", &yaml.replace("json", "yaml")).unwrap();
+        let error = BrokerConfig::from_config(&config).unwrap_err();
+        assert_eq!(error.line, Some(2));
+        assert_eq!(error.key.as_deref(), Some("log_format"));
+        assert!(error.message.starts_with("Unknown variant `yaml`"));
+    }
+
+    #[test]
+    fn test_level_and_json_fields() {
+        let path = log_file("json");
+        let settings = LogSettings {
+            format: LogFormat::Json,
+            stdout: false,
+            ..LogSettings::default()
+        };
+        let logger = Logger::with_settings(&path, settings).unwrap();
+        logger.debug("not written".to_string());
+        let fields = LogFields::packet("PUBLISH", "sensor", "127.0.0.1:5000").topic("a/b");
+        logger
+            .log(Level::Info, "Publish packet received".to_string(), &fields)
+            .unwrap();
+        logger.flush().unwrap();
+
+        let contents = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 1);
+        let line: Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["message"], "Publish packet received");
+        assert_eq!(line["client_id"], "sensor");
+        assert_eq!(line["peer"], "127.0.0.1:5000");
+        assert_eq!(line["packet_type"], "PUBLISH");
+        assert_eq!(line["topic"], "a/b");
+        assert!(line["timestamp"].as_u64().unwrap() > 1_000_000_000_000);
+    }
+
+    #[test]
+    fn test_rotation_by_size() {
+        let path = log_file("rotation");
+        let settings = LogSettings {
+            stdout: false,
+            max_size: 100,
+            max_files: 2,
+            ..LogSettings::default()
+        };
+        let logger = Logger::with_settings(&path, settings).unwrap();
+        for index in 0..10 {
+            logger.info(format!("line {} of the rotation test", index));
+        }
+        logger.flush().unwrap();
+
+        let current = fs::read_to_string(&path).unwrap();
+        assert!(current.contains("line 9"));
+        assert!(current.len() <= 100);
+        assert!(fs::read_to_string(format!("{}.1", path))
+            .unwrap()
+            .contains("line 7"));
+        assert!(fs::metadata(format!("{}.2", path)).is_ok());
+        assert!(fs::metadata(format!("{}.3", path)).is_err());
     }
 }
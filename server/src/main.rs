@@ -1,45 +1,257 @@
-mod file_loader;
+mod admin;
+mod bridge;
+mod broker_config;
+mod cli;
+mod cluster;
+mod dispatcher;
+mod expiry;
+mod http;
+mod limits;
+mod listener;
 mod logger;
+mod metrics;
+mod mqtt5;
+mod outbound;
+mod pid_file;
+mod readiness;
+mod recorder;
 mod server;
-use crate::file_loader::load_contents;
-use crate::logger::{Logger, Logging};
+mod shared_subscription;
+mod stats;
+mod tls;
+mod topics;
+mod trace;
+mod transport;
+mod validation;
+mod websocket;
+use crate::bridge::bridges_from_config;
+use crate::broker_config::BrokerConfig;
+use crate::cli::{parse_args, Options, USAGE};
+use crate::cluster::cluster_from_config;
+use crate::expiry::ExpiryRules;
+use crate::limits::Limits;
+use crate::listener::listeners_from_config;
+use crate::logger::{LogSettings, Logger, Logging};
+use crate::outbound::QueueSettings;
+use crate::pid_file::PidFile;
+use crate::recorder::Recorder;
 use crate::server::Server;
+use crate::tls::server_config;
+use crate::trace::Tracer;
+use config::config_file::{ConfigError, ConfigFile};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use std::env;
 use std::io::{Error, ErrorKind, Result};
+use std::process;
+use std::sync::Arc;
+use std::thread;
+
+/// The first SIGINT or SIGTERM shuts the server down, a second one ends the process at once
+fn handle_signals(server: Server) -> Result<()> {
+    let mut signals = Signals::new([SIGINT, SIGTERM])?;
+    thread::Builder::new()
+        .name("Thread: signals".to_string())
+        .spawn(move || {
+            let mut shutting_down = false;
+            for signal in signals.forever() {
+                if shutting_down {
+                    process::exit(128 + signal);
+                }
+                shutting_down = true;
+                server.shutdown();
+            }
+        })?;
+    Ok(())
+}
+
+fn load_config(options: &Options) -> std::result::Result<(ConfigFile, BrokerConfig), ConfigError> {
+    let config = ConfigFile::load(&options.config)?;
+    let mut broker_config = BrokerConfig::from_config(&config)?;
+    for (key, value) in &options.overrides {
+        broker_config
+            .set(key, value)
+            .map_err(|message| ConfigError {
+                path: "command line".to_string(),
+                key: Some(key.to_string()),
+                line: None,
+                message,
+            })?;
+    }
+    Ok((config, broker_config))
+}
+
+/// Reads every setting of the config without opening files for writing or sockets
+fn check_config(config: &ConfigFile, broker_config: &BrokerConfig) -> Result<()> {
+    broker_config.credentials()?;
+    for listener in listeners_from_config(broker_config, config)? {
+        if let Some(tls) = &listener.tls {
+            server_config(tls).map_err(|e| {
+                let message = format!("{}: listener {}: {}", config.path(), listener.name, e);
+                Error::new(e.kind(), message)
+            })?;
+        }
+    }
+    bridges_from_config(broker_config, config)?;
+    cluster_from_config(broker_config, config)?;
+    Recorder::settings_from_config(broker_config, config)?;
+    Ok(())
+}
 
 fn main() -> Result<()> {
-    let file_config = "src/config.yaml";
-    let config = load_contents(file_config);
-    let host = config
-        .get("host")
-        .unwrap_or_else(|| panic!("Cannot found host in config"));
-    let port = config
-        .get("port")
-        .unwrap_or_else(|| panic!("Cannot found port in config"));
-    let logfile = config
-        .get("logfile")
-        .unwrap_or_else(|| panic!("Cannot found logfile in config"));
-    let credentials_file = config
-        .get("credentials_file")
-        .unwrap_or_else(|| panic!("Cannot found credentials_file in config"));
-    let logger = Logger::new(logfile, true);
-
-    let server = Server::new(host.to_owned(), port.to_owned(), logfile, credentials_file);
-
-    match server.listening() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let options = match parse_args(&args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+    if options.version {
+        println!("server {}", env!("CARGO_PKG_VERSION"));
+        return Ok(());
+    }
+    let (config_file, broker_config) = match load_config(&options) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Cannot load config: {}", e);
+            process::exit(1);
+        }
+    };
+    if options.check_config {
+        match check_config(&config_file, &broker_config) {
+            Ok(()) => {
+                println!("{}: config is valid", options.config);
+                return Ok(());
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        }
+    }
+    let config = &config_file;
+    let logfile = &broker_config.logfile;
+    let logger = match Logger::with_settings(logfile, LogSettings::from_config(&broker_config)) {
+        Ok(logger) => Arc::new(logger),
+        Err(e) => {
+            eprintln!("Cannot open log file {}: {}", logfile, e);
+            return Err(e);
+        }
+    };
+    let credentials = match broker_config.credentials() {
+        Ok(credentials) => credentials,
+        Err(e) => {
+            logger.error(format!("Cannot load credentials: {}", e));
+            return Err(e.into());
+        }
+    };
+
+    let _pid_file = match &broker_config.pid_file {
+        Some(path) => match PidFile::create(path) {
+            Ok(pid_file) => Some(pid_file),
+            Err(e) => {
+                logger.error(format!("Cannot write pid file {}: {}", path, e));
+                return Err(e);
+            }
+        },
+        None => None,
+    };
+
+    let mut server = Server::new(
+        broker_config.host.clone(),
+        broker_config.port.to_string(),
+        logfile,
+        credentials,
+    );
+    server.set_logger(logger.clone());
+
+    if let Some(seconds) = broker_config.sys_interval {
+        server.set_sys_interval(seconds);
+    }
+    if let Some(dispatchers) = broker_config.dispatcher_threads {
+        server.set_dispatchers(dispatchers);
+    }
+    if let Some(workers) = broker_config.client_workers {
+        server.set_client_workers(workers);
+    }
+    if let Some(metrics_bind) = broker_config.metrics_bind.clone() {
+        server.set_metrics_address(metrics_bind);
+    }
+    if let Some((admin_bind, admin_token)) = broker_config.admin() {
+        server.set_admin(admin_bind, admin_token);
+    }
+    if let Some(seconds) = broker_config.session_expiry {
+        server.set_session_expiry(seconds);
+    }
+
+    server.set_limits(Limits::from_config(&broker_config));
+    server.set_queue_settings(QueueSettings::from_config(&broker_config));
+
+    let listeners = match listeners_from_config(&broker_config, config) {
+        Ok(listeners) => listeners,
+        Err(e) => {
+            logger.error(format!("Cannot load listeners: {}", e));
+            return Err(e.into());
+        }
+    };
+    for settings in listeners {
+        let name = settings.name.clone();
+        if let Err(e) = server.add_listener(settings) {
+            logger.error(format!("Cannot load listener {}: {}", name, e));
+            return Err(e);
+        }
+    }
+
+    match bridges_from_config(&broker_config, config) {
+        Ok(bridges) => bridges
+            .into_iter()
+            .for_each(|settings| server.add_bridge(settings)),
+        Err(e) => {
+            logger.error(format!("Cannot load bridges: {}", e));
+            return Err(e.into());
+        }
+    }
+
+    match cluster_from_config(&broker_config, config) {
+        Ok(Some(settings)) => server.set_cluster(settings),
+        Ok(None) => {}
+        Err(e) => {
+            logger.error(format!("Cannot load cluster: {}", e));
+            return Err(e.into());
+        }
+    }
+
+    server.set_tracer(Tracer::from_config(&broker_config));
+    server.set_expiry_rules(ExpiryRules::from_config(&broker_config));
+
+    match Recorder::from_config(&broker_config, config) {
+        Ok(Some(recorder)) => server.set_recorder(recorder),
+        Ok(None) => {}
+        Err(e) => {
+            logger.error(format!("Cannot open recording: {}", e));
+            return Err(e.into());
+        }
+    }
+
+    handle_signals(server.clone())?;
+
+    let result = match server.listening() {
         Ok(_) => {
-            logger.info("Successfully listening to incoming clients.".to_string());
+            logger.info("Server stopped, all the clients were disconnected.".to_string());
+            Ok(())
         }
         Err(e) => {
             logger.info(format!("Unexpected error{:?}", e));
-            return Err(Error::new(ErrorKind::Other, "Error server"));
+            Err(Error::new(ErrorKind::Other, "Error server"))
         }
-    }
-    Ok(())
+    };
+    let _ = logger.flush();
+    result
 }
 
 #[cfg(test)]
 mod tests {
-
     #[test]
     fn test_sample_server() {
         assert_eq!(1, 1)
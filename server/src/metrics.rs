@@ -0,0 +1,43 @@
+use crate::http::{HttpRequest, HttpResponse};
+
+/// Path scraped by Prometheus
+pub const METRICS_PATH: &str = "/metrics";
+
+/// GET /metrics gets the text returned by render and any other request gets a 404
+pub fn handle_request<F>(request: &HttpRequest, render: F) -> HttpResponse
+where
+    F: FnOnce() -> String,
+{
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", METRICS_PATH) => {
+            HttpResponse::new(200, "text/plain; version=0.0.4; charset=utf-8", render())
+        }
+        _ => HttpResponse::new(404, "text/plain", "Not Found\n".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::tests::request;
+
+    fn scrape(raw_request: &str) -> String {
+        request(raw_request, |request| {
+            handle_request(request, || "mqtt_clients_connected 3\n".to_string())
+        })
+    }
+
+    #[test]
+    fn test_metrics_request() {
+        let response = scrape("GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Length: 25\r\n"));
+        assert!(response.ends_with("\r\n\r\nmqtt_clients_connected 3\n"));
+    }
+
+    #[test]
+    fn test_unknown_path() {
+        let response = scrape("GET / HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
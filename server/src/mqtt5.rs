@@ -0,0 +1,137 @@
+use mqtt_packet::mqtt_packet_service::properties::{
+    property_identifiers, PacketProperties, Properties, PropertyValue,
+};
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+
+/// Topic aliases that each MQTT 5 client can use on its publish packets
+pub const TOPIC_ALIAS_MAXIMUM: u16 = 16;
+
+/// Capabilities announced on the connack of MQTT 5 clients
+pub fn connack_properties() -> Properties {
+    let mut properties = Properties::new();
+    properties.set(
+        property_identifiers::TOPIC_ALIAS_MAXIMUM,
+        PropertyValue::TwoByteInteger(TOPIC_ALIAS_MAXIMUM),
+    );
+    properties.set(property_identifiers::MAXIMUM_QOS, PropertyValue::Byte(1));
+    properties.set(
+        property_identifiers::RETAIN_AVAILABLE,
+        PropertyValue::Byte(1),
+    );
+    properties.set(
+        property_identifiers::WILDCARD_SUBSCRIPTION_AVAILABLE,
+        PropertyValue::Byte(1),
+    );
+    properties.set(
+        property_identifiers::SUBSCRIPTION_IDENTIFIER_AVAILABLE,
+        PropertyValue::Byte(0),
+    );
+    properties.set(
+        property_identifiers::SHARED_SUBSCRIPTION_AVAILABLE,
+        PropertyValue::Byte(1),
+    );
+    properties
+}
+
+/// Properties with only a reason string, sent along with the error reason codes
+pub fn reason_string(reason: &str) -> Properties {
+    let mut properties = Properties::new();
+    properties.set(
+        property_identifiers::REASON_STRING,
+        PropertyValue::Utf8String(reason.to_string()),
+    );
+    properties
+}
+
+/// Gets the topic of a publish, registering or using the topic alias of its properties
+pub fn resolve_topic_alias(
+    topic_aliases: &mut HashMap<u16, String>,
+    topic: String,
+    properties: &Properties,
+) -> Result<String> {
+    let alias = match properties.get_u16(property_identifiers::TOPIC_ALIAS) {
+        Some(alias) => alias,
+        None => return Ok(topic),
+    };
+    if alias == 0 || alias > TOPIC_ALIAS_MAXIMUM {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Topic alias {} out of range", alias),
+        ));
+    }
+    if !topic.is_empty() {
+        topic_aliases.insert(alias, topic.clone());
+        return Ok(topic);
+    }
+    topic_aliases.get(&alias).cloned().ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("Topic alias {} was not registered", alias),
+        )
+    })
+}
+
+/// Properties are sent to the message handler as hex text along with the publish
+pub fn encode_properties(properties: &Properties) -> String {
+    properties
+        .value()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+pub fn decode_properties(hex: &str) -> Properties {
+    let bytes: Vec<u8> = (0..hex.len() / 2)
+        .filter_map(|i| u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok())
+        .collect();
+    let mut readed = 0;
+    Properties::unvalue(bytes, &mut readed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_topic_alias() {
+        let mut topic_aliases = HashMap::new();
+        let mut properties = Properties::new();
+        properties.set(
+            property_identifiers::TOPIC_ALIAS,
+            PropertyValue::TwoByteInteger(2),
+        );
+        // first publish registers the alias, the next ones can send an empty topic
+        let topic = resolve_topic_alias(&mut topic_aliases, "temp".to_string(), &properties);
+        assert_eq!(topic.unwrap(), "temp");
+        let topic = resolve_topic_alias(&mut topic_aliases, String::new(), &properties);
+        assert_eq!(topic.unwrap(), "temp");
+
+        properties.set(
+            property_identifiers::TOPIC_ALIAS,
+            PropertyValue::TwoByteInteger(3),
+        );
+        assert!(resolve_topic_alias(&mut topic_aliases, String::new(), &properties).is_err());
+        properties.set(
+            property_identifiers::TOPIC_ALIAS,
+            PropertyValue::TwoByteInteger(TOPIC_ALIAS_MAXIMUM + 1),
+        );
+        assert!(resolve_topic_alias(&mut topic_aliases, "temp".to_string(), &properties).is_err());
+
+        let topic = resolve_topic_alias(&mut topic_aliases, "hum".to_string(), &Properties::new());
+        assert_eq!(topic.unwrap(), "hum");
+    }
+
+    #[test]
+    fn test_encode_properties() {
+        let mut properties = Properties::new();
+        properties.add_user_property("unit", "celsius");
+        properties.set(
+            property_identifiers::CORRELATION_DATA,
+            PropertyValue::BinaryData(vec![0x00, 0xFF]),
+        );
+        let hex = encode_properties(&properties);
+        assert_eq!(decode_properties(&hex), properties);
+        assert!(decode_properties("").is_empty());
+    }
+}
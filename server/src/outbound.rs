@@ -0,0 +1,467 @@
+use crate::broker_config::BrokerConfig;
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Weak};
+use std::time::{Duration, Instant};
+
+/// Messages a client queue holds when not set in config
+pub const DEFAULT_MAX_QUEUED_MESSAGES: usize = 1000;
+/// Seconds a client queue can stay full before the client is disconnected
+pub const DEFAULT_SLOW_CONSUMER_TIMEOUT_SECS: u64 = 5;
+
+/// QoS 0 message dropped when the queue of a client is full
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DropPolicy {
+    Newest, // the message that does not fit
+    Oldest, // the oldest QoS 0 message of the queue, the newest one when there is none
+}
+
+/// Size of the client queues and what is done when they are full
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QueueSettings {
+    pub max_queued_messages: usize, // 0 means no limit
+    pub qos0_drop_policy: DropPolicy,
+    pub slow_consumer_timeout: Duration, // zero never disconnects the clients for a full queue
+}
+
+impl Default for QueueSettings {
+    fn default() -> QueueSettings {
+        QueueSettings {
+            max_queued_messages: DEFAULT_MAX_QUEUED_MESSAGES,
+            qos0_drop_policy: DropPolicy::Newest,
+            slow_consumer_timeout: Duration::from_secs(DEFAULT_SLOW_CONSUMER_TIMEOUT_SECS),
+        }
+    }
+}
+
+impl QueueSettings {
+    /// Takes max_queued_messages, qos0_drop_policy (newest or oldest) and
+    /// slow_consumer_timeout (seconds) from the server config
+    pub fn from_config(config: &BrokerConfig) -> QueueSettings {
+        QueueSettings {
+            max_queued_messages: config.max_queued_messages,
+            qos0_drop_policy: config.qos0_drop_policy,
+            slow_consumer_timeout: Duration::from_secs(config.slow_consumer_timeout),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Queued {
+    Added,
+    ReplacedOldest, // the oldest QoS 0 message was dropped to make room
+}
+
+/// Why a message was not queued
+#[derive(Debug, PartialEq)]
+pub enum QueueError {
+    Dropped,      // the queue is full
+    SlowConsumer, // the queue stayed full too long, the client is being disconnected
+    Closed,       // nobody reads the queue anymore
+}
+
+impl std::fmt::Display for QueueError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            QueueError::Dropped => write!(f, "queue full, message dropped"),
+            QueueError::SlowConsumer => write!(f, "slow consumer"),
+            QueueError::Closed => write!(f, "queue closed"),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    packets: VecDeque<(Vec<u8>, u8, Option<Instant>)>, // (packet, qos, expires)
+    full_since: Option<Instant>, // since the queue is full, until half of it is written
+    slow_consumer: bool,
+    closed: bool,
+    // waker of the connection that writes the queue to the client, it is gone once the
+    // connection ends so the queue of a disconnected client is not waited for
+    waker: Weak<Waker>,
+}
+
+/// Wakes the connection that writes a queue, so it writes the new packets and checks
+/// its flags. The connection holds it while it is open.
+pub struct Waker(Box<dyn Fn() + Send + Sync>);
+
+impl Waker {
+    pub fn new<F: Fn() + Send + Sync + 'static>(wake: F) -> Waker {
+        Waker(Box::new(wake))
+    }
+
+    pub fn wake(&self) {
+        (self.0)()
+    }
+}
+
+impl fmt::Debug for Waker {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Waker")
+    }
+}
+
+#[derive(Debug)]
+struct Queue {
+    settings: QueueSettings,
+    state: Mutex<State>,
+    space: Condvar, // notified when the client connection takes messages
+}
+
+impl Queue {
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl State {
+    fn wake(&self) {
+        if let Some(waker) = self.waker.upgrade() {
+            waker.wake();
+        }
+    }
+}
+
+/// Queue of the packets to write to a client, the sends wake the client connection
+pub fn channel(settings: QueueSettings) -> (OutboundSender, OutboundReceiver) {
+    let queue = Arc::new(Queue {
+        settings,
+        state: Mutex::new(State {
+            packets: VecDeque::new(),
+            full_since: None,
+            slow_consumer: false,
+            closed: false,
+            waker: Weak::new(),
+        }),
+        space: Condvar::new(),
+    });
+    (
+        OutboundSender {
+            queue: queue.clone(),
+        },
+        OutboundReceiver { queue },
+    )
+}
+
+#[derive(Clone, Debug)]
+pub struct OutboundSender {
+    queue: Arc<Queue>,
+}
+
+impl OutboundSender {
+    /// Queues a packet sent with qos. When the queue is full a QoS 0 packet is dropped
+    /// following the drop policy and a QoS 1 packet waits for room while the client is
+    /// connected. A client whose queue stays full for the slow consumer timeout is
+    /// disconnected. A packet still queued at expires is dropped by purge_expired.
+    pub fn send(
+        &self,
+        packet: Vec<u8>,
+        qos: u8,
+        expires: Option<Instant>,
+    ) -> std::result::Result<Queued, QueueError> {
+        self.push(packet, qos, expires, true)
+    }
+
+    /// Queues a packet like send but a QoS 1 packet is dropped instead of waiting for room,
+    /// for the client connection itself, which is the one that makes room in its queue
+    pub fn try_send(
+        &self,
+        packet: Vec<u8>,
+        qos: u8,
+        expires: Option<Instant>,
+    ) -> std::result::Result<Queued, QueueError> {
+        self.push(packet, qos, expires, false)
+    }
+
+    fn push(
+        &self,
+        packet: Vec<u8>,
+        qos: u8,
+        expires: Option<Instant>,
+        wait: bool,
+    ) -> std::result::Result<Queued, QueueError> {
+        let settings = &self.queue.settings;
+        let mut state = self.queue.state();
+        loop {
+            if state.closed {
+                return Err(QueueError::Closed);
+            }
+            if state.slow_consumer {
+                return Err(QueueError::SlowConsumer);
+            }
+            if settings.max_queued_messages == 0
+                || state.packets.len() < settings.max_queued_messages
+            {
+                state.packets.push_back((packet, qos, expires));
+                state.wake();
+                return Ok(Queued::Added);
+            }
+            let now = Instant::now();
+            let full_since = *state.full_since.get_or_insert(now);
+            let connected = state.waker.strong_count() > 0;
+            let timeout = settings.slow_consumer_timeout;
+            if connected && !timeout.is_zero() && now >= full_since + timeout {
+                state.slow_consumer = true;
+                state.wake();
+                return Err(QueueError::SlowConsumer);
+            }
+            if qos == 0 {
+                let oldest = state.packets.iter().position(|(_, qos, _)| *qos == 0);
+                return match (settings.qos0_drop_policy, oldest) {
+                    (DropPolicy::Oldest, Some(index)) => {
+                        state.packets.remove(index);
+                        state.packets.push_back((packet, qos, expires));
+                        state.wake();
+                        Ok(Queued::ReplacedOldest)
+                    }
+                    _ => Err(QueueError::Dropped),
+                };
+            }
+            if !connected || timeout.is_zero() || !wait {
+                return Err(QueueError::Dropped);
+            }
+            // back pressure: the dispatcher waits for the client connection to write
+            state = self
+                .queue
+                .space
+                .wait_timeout(state, full_since + timeout - now)
+                .unwrap_or_else(|e| e.into_inner())
+                .0;
+        }
+    }
+
+    /// True when a QoS 1 send would wait for room in the queue
+    pub fn is_full(&self) -> bool {
+        let max_queued_messages = self.queue.settings.max_queued_messages;
+        max_queued_messages > 0 && self.queue.state().packets.len() >= max_queued_messages
+    }
+
+    /// (packet, qos) of the messages waiting in the queue, oldest first
+    pub fn queued(&self) -> Vec<(Vec<u8>, u8)> {
+        let state = self.queue.state();
+        state
+            .packets
+            .iter()
+            .map(|(packet, qos, _)| (packet.clone(), *qos))
+            .collect()
+    }
+
+    /// Wakes the client connection so it checks its queue and its flags
+    pub fn wake(&self) {
+        self.queue.state().wake();
+    }
+
+    /// Removes the queued packets whose expiry passed, returns them with their qos
+    pub fn purge_expired(&self) -> Vec<(Vec<u8>, u8)> {
+        let now = Instant::now();
+        let mut state = self.queue.state();
+        if !state
+            .packets
+            .iter()
+            .any(|(_, _, expires)| expires.is_some_and(|e| e <= now))
+        {
+            return Vec::new();
+        }
+        let (expired, kept) = state
+            .packets
+            .drain(..)
+            .partition(|(_, _, expires)| expires.is_some_and(|e| e <= now));
+        state.packets = kept;
+        if state.packets.len() <= self.queue.settings.max_queued_messages / 2 {
+            state.full_since = None;
+        }
+        self.queue.space.notify_all();
+        expired
+            .into_iter()
+            .map(|(packet, qos, _)| (packet, qos))
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+pub struct OutboundReceiver {
+    queue: Arc<Queue>,
+}
+
+impl OutboundReceiver {
+    pub fn try_recv(&self) -> Option<Vec<u8>> {
+        let max_queued_messages = self.queue.settings.max_queued_messages;
+        let mut state = self.queue.state();
+        let was_full = max_queued_messages > 0 && state.packets.len() >= max_queued_messages;
+        let (packet, _, _) = state.packets.pop_front()?;
+        // the client keeps up again once it wrote half of its queue
+        if state.packets.len() <= max_queued_messages / 2 {
+            state.full_since = None;
+        }
+        if was_full {
+            self.queue.space.notify_all();
+        }
+        Some(packet)
+    }
+
+    /// The following sends call waker. A resumed session moves the queue to its
+    /// new connection, that is not a slow consumer yet.
+    pub fn set_waker(&self, waker: &Arc<Waker>) {
+        let mut state = self.queue.state();
+        if !std::ptr::eq(state.waker.as_ptr(), Arc::as_ptr(waker)) {
+            state.waker = Arc::downgrade(waker);
+            state.slow_consumer = false;
+            state.full_since = None;
+        }
+    }
+
+    /// True once the queue stayed full for the slow consumer timeout
+    pub fn is_slow_consumer(&self) -> bool {
+        self.queue.state().slow_consumer
+    }
+}
+
+impl Drop for OutboundReceiver {
+    fn drop(&mut self) {
+        self.queue.state().closed = true;
+        self.queue.space.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::config_file::ConfigFile;
+    use std::sync::mpsc;
+
+    fn waker() -> (Arc<Waker>, mpsc::Receiver<()>) {
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        (
+            Arc::new(Waker::new(move || {
+                let _ = tx.lock().unwrap().send(());
+            })),
+            rx,
+        )
+    }
+
+    fn bounded(max_queued_messages: usize, qos0_drop_policy: DropPolicy) -> QueueSettings {
+        QueueSettings {
+            max_queued_messages,
+            qos0_drop_policy,
+            slow_consumer_timeout: Duration::from_millis(100),
+        }
+    }
+
+    #[test]
+    fn test_send_wakes_the_reader() {
+        let (tx, rx) = channel(QueueSettings::default());
+        // nobody waits for the queue yet
+        tx.send(vec![1], 0, None).unwrap();
+        let (waker, woken) = waker();
+        rx.set_waker(&waker);
+        assert!(woken.try_recv().is_err());
+        tx.send(vec![2], 0, None).unwrap();
+        assert_eq!(woken.try_recv(), Ok(()));
+        assert_eq!(tx.queued(), vec![(vec![1], 0), (vec![2], 0)]);
+        assert_eq!(rx.try_recv(), Some(vec![1]));
+        assert_eq!(rx.try_recv(), Some(vec![2]));
+        assert_eq!(rx.try_recv(), None);
+    }
+
+    #[test]
+    fn test_qos0_drop_policies() {
+        let (tx, rx) = channel(bounded(2, DropPolicy::Newest));
+        assert_eq!(tx.send(vec![1], 0, None), Ok(Queued::Added));
+        assert_eq!(tx.send(vec![2], 0, None), Ok(Queued::Added));
+        assert_eq!(tx.send(vec![3], 0, None), Err(QueueError::Dropped));
+        assert_eq!(rx.try_recv(), Some(vec![1]));
+
+        let (tx, rx) = channel(bounded(2, DropPolicy::Oldest));
+        tx.send(vec![1], 1, None).unwrap();
+        tx.send(vec![2], 0, None).unwrap();
+        assert_eq!(tx.send(vec![3], 0, None), Ok(Queued::ReplacedOldest));
+        assert_eq!(rx.try_recv(), Some(vec![1]));
+        assert_eq!(rx.try_recv(), Some(vec![3]));
+    }
+
+    #[test]
+    fn test_qos1_waits_for_room() {
+        let (tx, rx) = channel(bounded(1, DropPolicy::Newest));
+        // without a client thread the queue is not waited for
+        tx.send(vec![1], 1, None).unwrap();
+        assert_eq!(tx.send(vec![2], 1, None), Err(QueueError::Dropped));
+
+        let (waker, _woken) = waker();
+        rx.set_waker(&waker);
+        // the client connection does not wait for its own queue
+        let start = Instant::now();
+        assert_eq!(tx.try_send(vec![2], 1, None), Err(QueueError::Dropped));
+        assert!(start.elapsed() < Duration::from_millis(100));
+        let reader = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            (rx.try_recv(), rx)
+        });
+        assert_eq!(tx.send(vec![2], 1, None), Ok(Queued::Added));
+        let (packet, rx) = reader.join().unwrap();
+        assert_eq!(packet, Some(vec![1]));
+        assert_eq!(rx.try_recv(), Some(vec![2]));
+    }
+
+    #[test]
+    fn test_purge_expired() {
+        let (tx, rx) = channel(QueueSettings::default());
+        let past = Instant::now() - Duration::from_millis(1);
+        tx.send(vec![1], 1, Some(past)).unwrap();
+        tx.send(vec![2], 0, Some(Instant::now() + Duration::from_secs(60)))
+            .unwrap();
+        tx.send(vec![3], 0, None).unwrap();
+        tx.send(vec![4], 0, Some(past)).unwrap();
+        assert_eq!(tx.purge_expired(), vec![(vec![1], 1), (vec![4], 0)]);
+        assert!(tx.purge_expired().is_empty());
+        assert_eq!(rx.try_recv(), Some(vec![2]));
+        assert_eq!(rx.try_recv(), Some(vec![3]));
+        assert_eq!(rx.try_recv(), None);
+    }
+
+    #[test]
+    fn test_slow_consumer() {
+        let (tx, rx) = channel(bounded(1, DropPolicy::Newest));
+        let (first, _woken) = waker();
+        rx.set_waker(&first);
+        tx.send(vec![1], 1, None).unwrap();
+        let start = Instant::now();
+        assert_eq!(tx.send(vec![2], 1, None), Err(QueueError::SlowConsumer));
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert!(rx.is_slow_consumer());
+        assert_eq!(tx.send(vec![3], 0, None), Err(QueueError::SlowConsumer));
+
+        // a new connection of the session starts over
+        rx.set_waker(&waker().0);
+        assert!(!rx.is_slow_consumer());
+        drop(rx);
+        assert_eq!(tx.send(vec![4], 0, None), Err(QueueError::Closed));
+    }
+
+    #[test]
+    fn test_settings_from_config() {
+        let config = ConfigFile::parse(
+            "config.yaml",
+            "max_queued_messages: 10\nqos0_drop_policy: oldest\n",
+        )
+        .unwrap();
+        let settings = QueueSettings::from_config(&BrokerConfig::from_config(&config).unwrap());
+        assert_eq!(settings.max_queued_messages, 10);
+        assert_eq!(settings.qos0_drop_policy, DropPolicy::Oldest);
+        assert_eq!(
+            settings.slow_consumer_timeout,
+            Duration::from_secs(DEFAULT_SLOW_CONSUMER_TIMEOUT_SECS)
+        );
+
+        let bad = ConfigFile::parse(
+            "config.yaml",
+            "max_queued_messages: 10\nqos0_drop_policy: random\n",
+        )
+        .unwrap();
+        let error = BrokerConfig::from_config(&bad).unwrap_err();
+        assert_eq!(error.line, Some(2));
+        assert_eq!(error.key.as_deref(), Some("qos0_drop_policy"));
+    }
+}
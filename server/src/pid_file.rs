@@ -0,0 +1,55 @@
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// File holding the pid of the running server, it is removed on drop
+#[derive(Debug)]
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    /// Writes the pid of this process to path. A file left by a server that is still
+    /// running is an error, one left by a server that died is replaced.
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<PidFile> {
+        let path = path.as_ref().to_path_buf();
+        if let Ok(contents) = fs::read_to_string(&path) {
+            if let Ok(pid) = contents.trim().parse::<u32>() {
+                if pid != process::id() && Path::new(&format!("/proc/{}", pid)).exists() {
+                    return Err(Error::new(
+                        ErrorKind::AlreadyExists,
+                        format!("Server already running with pid {}", pid),
+                    ));
+                }
+            }
+        }
+        fs::write(&path, format!("{}\n", process::id()))?;
+        Ok(PidFile { path })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pid_file() {
+        let path = std::env::temp_dir().join("mqtt-server-test.pid");
+        // a pid that cannot be running is replaced
+        fs::write(&path, "4294967295\n").unwrap();
+        let pid_file = PidFile::create(&path).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!("{}\n", process::id())
+        );
+        drop(pid_file);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,379 @@
+use crate::outbound::Waker;
+use polling::{Event, Events, Poller};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+use std::io::Result;
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Longest wait of the poller thread, the stop flag is checked after it
+const MAX_POLL_WAIT: Duration = Duration::from_secs(1);
+
+/// What a session asks for after serving an event
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Step {
+    Wait(Instant), // until the socket is readable, the session is woken or the instant
+    Read,          // data can be left to read, it is served again without waiting
+    Done,          // the connection ended, the session is dropped
+}
+
+/// A connection served by the workers of an EventLoop, one worker at a time
+pub trait Session: Send {
+    /// Serves one event, readable tells if the socket has data to read
+    fn serve(&mut self, readable: bool) -> Step;
+}
+
+/// Workers a client event loop uses when not set in config. A worker is blocked
+/// while it writes to a client, up to the write timeout of its socket.
+pub fn default_workers() -> usize {
+    thread::available_parallelism().map_or(1, |cpus| cpus.get()) * 4
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Schedule {
+    Idle,
+    Queued(bool),          // waiting for a worker, true if the socket is readable
+    Running(Option<bool>), // served by a worker, Some when it has to be served again
+}
+
+struct Entry {
+    socket: TcpStream, // clone of the session socket, registered in the poller
+    session: Option<Box<dyn Session>>, // taken by the worker that serves it
+    schedule: Schedule,
+    deadline: Option<Instant>, // served at this instant even without events
+    _waker: Arc<Waker>,        // given to the session, it stops waking once the entry is gone
+}
+
+#[derive(Default)]
+struct State {
+    entries: HashMap<usize, Entry>,
+    queue: VecDeque<usize>, // keys of the queued entries, oldest first
+    next_key: usize,
+    // (deadline, key) of the entries, earliest first. A deadline that is not the one
+    // of its entry anymore is skipped when it is reached.
+    deadlines: BinaryHeap<Reverse<(Instant, usize)>>,
+}
+
+struct Shared {
+    poller: Poller,
+    state: Mutex<State>,
+    ready: Condvar, // notified when a key is queued
+    stopped: AtomicBool,
+}
+
+impl Shared {
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Queues the entry of key for a worker unless it is already queued
+    fn schedule(&self, state: &mut State, key: usize, readable: bool) {
+        let entry = match state.entries.get_mut(&key) {
+            Some(entry) => entry,
+            None => return,
+        };
+        entry.schedule = match entry.schedule {
+            Schedule::Idle => {
+                state.queue.push_back(key);
+                self.ready.notify_one();
+                Schedule::Queued(readable)
+            }
+            Schedule::Queued(queued) => Schedule::Queued(queued || readable),
+            Schedule::Running(again) => Schedule::Running(Some(again == Some(true) || readable)),
+        };
+    }
+
+    /// Polls the sockets and queues the entries that are readable or reached their deadline
+    fn poll(&self) {
+        let mut events = Events::new();
+        while !self.stopped.load(Ordering::SeqCst) {
+            let now = Instant::now();
+            let timeout = self
+                .state()
+                .deadlines
+                .peek()
+                .map_or(MAX_POLL_WAIT, |Reverse((deadline, _))| {
+                    deadline.saturating_duration_since(now).min(MAX_POLL_WAIT)
+                });
+            events.clear();
+            if self.poller.wait(&mut events, Some(timeout)).is_err() {
+                thread::sleep(timeout);
+            }
+            let mut state = self.state();
+            for event in events.iter() {
+                self.schedule(&mut state, event.key, event.readable);
+            }
+            let now = Instant::now();
+            while let Some(&Reverse((deadline, key))) = state.deadlines.peek() {
+                if deadline > now {
+                    break;
+                }
+                state.deadlines.pop();
+                match state.entries.get_mut(&key) {
+                    Some(entry) if entry.deadline == Some(deadline) => entry.deadline = None,
+                    _ => continue,
+                }
+                self.schedule(&mut state, key, false);
+            }
+        }
+    }
+
+    /// Serves the queued entries until the event loop stops
+    fn work(&self) {
+        loop {
+            let mut state = self.state();
+            let key = loop {
+                if self.stopped.load(Ordering::SeqCst) {
+                    return;
+                }
+                match state.queue.pop_front() {
+                    Some(key) => break key,
+                    None => state = self.ready.wait(state).unwrap_or_else(|e| e.into_inner()),
+                }
+            };
+            let entry = match state.entries.get_mut(&key) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            let readable = matches!(entry.schedule, Schedule::Queued(true));
+            entry.schedule = Schedule::Running(None);
+            let mut session = match entry.session.take() {
+                Some(session) => session,
+                None => continue,
+            };
+            drop(state);
+
+            let step = session.serve(readable);
+
+            let mut state = self.state();
+            if step == Step::Done {
+                if let Some(entry) = state.entries.remove(&key) {
+                    drop(state);
+                    let _ = self.poller.delete(&entry.socket);
+                    // the session closes its socket once the poller forgot it
+                    drop(session);
+                }
+                continue;
+            }
+            let entry = match state.entries.get_mut(&key) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            entry.session = Some(session);
+            let again = match (step, entry.schedule) {
+                (Step::Read, _) => Some(true),
+                (_, Schedule::Running(again)) => again,
+                _ => None,
+            };
+            entry.schedule = Schedule::Idle;
+            if let Step::Wait(deadline) = step {
+                // the socket is registered in oneshot mode, it is armed again
+                let _ = self.poller.modify(&entry.socket, Event::readable(key));
+                if entry.deadline != Some(deadline) {
+                    entry.deadline = Some(deadline);
+                    let earliest = state
+                        .deadlines
+                        .peek()
+                        .is_none_or(|Reverse((next, _))| deadline < *next);
+                    state.deadlines.push(Reverse((deadline, key)));
+                    if earliest {
+                        let _ = self.poller.notify();
+                    }
+                }
+            }
+            if let Some(readable) = again {
+                self.schedule(&mut state, key, readable);
+            }
+        }
+    }
+}
+
+/// Serves the client connections with a shared poller and a bounded pool of workers,
+/// so idle clients do not take a thread each. A session is served when its socket
+/// is readable, when its waker is called (see OutboundSender::wake) or at its deadline.
+pub struct EventLoop {
+    shared: Arc<Shared>,
+}
+
+impl EventLoop {
+    /// Starts the poller thread and workers worker threads (at least one)
+    pub fn start(workers: usize) -> Result<EventLoop> {
+        let shared = Arc::new(Shared {
+            poller: Poller::new()?,
+            state: Mutex::new(State::default()),
+            ready: Condvar::new(),
+            stopped: AtomicBool::new(false),
+        });
+        let poller = shared.clone();
+        thread::Builder::new()
+            .name("Thread: client poller".to_string())
+            .spawn(move || poller.poll())?;
+        for index in 0..workers.max(1) {
+            let worker = shared.clone();
+            thread::Builder::new()
+                .name(format!("Thread: client worker {}", index))
+                .spawn(move || worker.work())?;
+        }
+        Ok(EventLoop { shared })
+    }
+
+    /// Registers socket and serves the session built by session right away, the
+    /// session gets the waker that queues it for a worker
+    pub fn add<F>(&self, socket: TcpStream, session: F) -> Result<()>
+    where
+        F: FnOnce(Arc<Waker>) -> Box<dyn Session>,
+    {
+        let key = {
+            let mut state = self.shared.state();
+            state.next_key += 1;
+            state.next_key
+        };
+        let shared = Arc::downgrade(&self.shared);
+        let waker = Arc::new(Waker::new(move || wake(&shared, key)));
+        let session = session(waker.clone());
+        // Safety: the socket is deleted from the poller before the entry drops it.
+        unsafe { self.shared.poller.add(&socket, Event::none(key))? };
+        let mut state = self.shared.state();
+        state.entries.insert(
+            key,
+            Entry {
+                socket,
+                session: Some(session),
+                schedule: Schedule::Idle,
+                deadline: None,
+                _waker: waker,
+            },
+        );
+        self.shared.schedule(&mut state, key, false);
+        Ok(())
+    }
+
+    /// Queues every session for a worker, as if their wakers were called
+    pub fn wake_all(&self) {
+        let mut state = self.shared.state();
+        let keys: Vec<usize> = state.entries.keys().copied().collect();
+        for key in keys {
+            self.shared.schedule(&mut state, key, false);
+        }
+    }
+
+    /// Sessions that did not end yet
+    pub fn sessions(&self) -> usize {
+        self.shared.state().entries.len()
+    }
+
+    /// Stops the poller and the workers once they end the sessions they serve
+    pub fn stop(&self) {
+        self.shared.stopped.store(true, Ordering::SeqCst);
+        let _state = self.shared.state();
+        self.shared.ready.notify_all();
+        let _ = self.shared.poller.notify();
+    }
+}
+
+fn wake(shared: &Weak<Shared>, key: usize) {
+    if let Some(shared) = shared.upgrade() {
+        let mut state = shared.state();
+        shared.schedule(&mut state, key, false);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc::{self, Sender};
+
+    /// Reports each serve and ends when it reads a 0
+    struct Echo {
+        socket: TcpStream,
+        served: Sender<(bool, Vec<u8>)>,
+    }
+
+    impl Session for Echo {
+        fn serve(&mut self, readable: bool) -> Step {
+            let mut buff = [0_u8; 16];
+            let read = match readable {
+                true => self.socket.read(&mut buff).unwrap_or(0),
+                false => 0,
+            };
+            let _ = self.served.send((readable, buff[..read].to_vec()));
+            match buff[..read].contains(&0) {
+                true => Step::Done,
+                false => Step::Wait(Instant::now() + Duration::from_secs(60)),
+            }
+        }
+    }
+
+    fn pair(listener: &TcpListener) -> (TcpStream, TcpStream) {
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (socket, _) = listener.accept().unwrap();
+        (client, socket)
+    }
+
+    #[test]
+    fn test_sessions_are_served_on_events() {
+        let event_loop = EventLoop::start(2).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (served, rx) = mpsc::channel();
+        let (mut client, socket) = pair(&listener);
+        let (waker_tx, waker_rx) = mpsc::channel();
+        event_loop
+            .add(socket.try_clone().unwrap(), |waker| {
+                waker_tx.send(waker).unwrap();
+                Box::new(Echo { socket, served })
+            })
+            .unwrap();
+        let timeout = Duration::from_secs(5);
+        // served once when added
+        assert_eq!(rx.recv_timeout(timeout).unwrap(), (false, vec![]));
+
+        client.write_all(&[1, 2]).unwrap();
+        assert_eq!(rx.recv_timeout(timeout).unwrap(), (true, vec![1, 2]));
+        waker_rx.recv().unwrap().wake();
+        assert_eq!(rx.recv_timeout(timeout).unwrap(), (false, vec![]));
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+
+        client.write_all(&[0]).unwrap();
+        assert_eq!(rx.recv_timeout(timeout).unwrap(), (true, vec![0]));
+        let start = Instant::now();
+        while event_loop.sessions() > 0 {
+            assert!(start.elapsed() < timeout);
+            thread::sleep(Duration::from_millis(5));
+        }
+        event_loop.stop();
+    }
+
+    #[test]
+    fn test_workers_serve_more_sessions_than_them() {
+        let event_loop = EventLoop::start(2).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (served, _rx) = mpsc::channel();
+        let mut clients = Vec::new();
+        for _ in 0..50 {
+            let (client, socket) = pair(&listener);
+            let served = served.clone();
+            event_loop
+                .add(socket.try_clone().unwrap(), |_| {
+                    Box::new(Echo { socket, served })
+                })
+                .unwrap();
+            clients.push(client);
+        }
+        for client in &mut clients {
+            client.write_all(&[7, 0]).unwrap();
+        }
+        // each session ends once it reads the 0
+        let timeout = Duration::from_secs(5);
+        let start = Instant::now();
+        while event_loop.sessions() > 0 {
+            assert!(start.elapsed() < timeout);
+            thread::sleep(Duration::from_millis(5));
+        }
+        event_loop.stop();
+    }
+}
@@ -0,0 +1,143 @@
+use crate::broker_config::BrokerConfig;
+use crate::topics::topic_matches;
+use config::config_file::{ConfigError, ConfigFile};
+use replay::recording::{Record, RecordWriter};
+use std::fs::File;
+use std::io::{BufWriter, Result};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Writes the messages published on the topics of its filters to a recording,
+/// the replay tool publishes them again on a broker
+pub struct Recorder {
+    filters: Vec<String>,
+    writer: Mutex<RecordWriter<BufWriter<File>>>,
+}
+
+impl Recorder {
+    pub fn new(file_source: &str, filters: Vec<String>) -> Result<Recorder> {
+        Ok(Recorder {
+            filters,
+            writer: Mutex::new(RecordWriter::open(file_source)?),
+        })
+    }
+
+    /// Takes record_file and record_topics, a list of topic filters, from the server
+    /// config, None when there is no record_file
+    pub fn settings_from_config(
+        config: &BrokerConfig,
+        file: &ConfigFile,
+    ) -> std::result::Result<Option<(String, Vec<String>)>, ConfigError> {
+        let file_source = match config
+            .record_file
+            .as_deref()
+            .filter(|file| !file.is_empty())
+        {
+            Some(file_source) => file_source,
+            None => return Ok(None),
+        };
+        if config.record_topics.is_empty() {
+            return Err(file.error("record_file", "Needs record_topics"));
+        }
+        Ok(Some((file_source.to_owned(), config.record_topics.clone())))
+    }
+
+    /// Opens the recording of the server config, None when there is no record_file
+    pub fn from_config(
+        config: &BrokerConfig,
+        file: &ConfigFile,
+    ) -> std::result::Result<Option<Recorder>, ConfigError> {
+        match Recorder::settings_from_config(config, file)? {
+            Some((file_source, filters)) => Recorder::new(&file_source, filters)
+                .map(Some)
+                .map_err(|e| file.error("record_file", &e.to_string())),
+            None => Ok(None),
+        }
+    }
+
+    pub fn is_recorded(&self, topic: &str) -> bool {
+        self.filters
+            .iter()
+            .any(|filter| topic_matches(filter, topic))
+    }
+
+    /// Adds the message to the recording when its topic matches a filter
+    pub fn record(&self, topic: &str, message: &str, qos: u8, retain: bool) -> Result<()> {
+        if !self.is_recorded(topic) {
+            return Ok(());
+        }
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis() as u64)
+            .unwrap_or_default();
+        let record = Record {
+            timestamp,
+            topic: topic.to_string(),
+            qos,
+            retain,
+            payload: message.as_bytes().to_vec(),
+        };
+        self.writer
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .write(&record)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use replay::recording::RecordReader;
+    use std::fs;
+
+    #[test]
+    fn test_records_the_topics_of_its_filters() {
+        let path = std::env::temp_dir().join("mqtt-recorder-test.rec");
+        let _ = fs::remove_file(&path);
+        let yaml = format!("record_file: {}\n", path.display());
+        let file = ConfigFile::parse("config.yaml", &yaml).unwrap();
+        let config = BrokerConfig::from_config(&file).unwrap();
+        let error = Recorder::from_config(&config, &file).err().unwrap();
+        assert_eq!(error.line, Some(1));
+        assert_eq!(error.key.as_deref(), Some("record_file"));
+        let yaml = yaml + "record_topics: [sensors/#, alerts]\n";
+        let file = ConfigFile::parse("config.yaml", &yaml).unwrap();
+        let config = BrokerConfig::from_config(&file).unwrap();
+
+        let recorder = Recorder::from_config(&config, &file).unwrap().unwrap();
+        recorder
+            .record("sensors/temperature", "21.5", 1, true)
+            .unwrap();
+        recorder.record("commands/reboot", "now", 0, false).unwrap();
+        recorder.record("alerts", "fire", 0, false).unwrap();
+        drop(recorder);
+        // a second recorder adds its records to the same recording
+        let recorder = Recorder::from_config(&config, &file).unwrap().unwrap();
+        recorder.record("alerts", "smoke", 0, false).unwrap();
+
+        let records: Vec<(String, String, u8, bool)> = RecordReader::open(&path)
+            .unwrap()
+            .map(|record| {
+                let record = record.unwrap();
+                let payload = String::from_utf8(record.payload).unwrap();
+                (record.topic, payload, record.qos, record.retain)
+            })
+            .collect();
+        assert_eq!(
+            records,
+            vec![
+                (
+                    "sensors/temperature".to_string(),
+                    "21.5".to_string(),
+                    1,
+                    true
+                ),
+                ("alerts".to_string(), "fire".to_string(), 0, false),
+                ("alerts".to_string(), "smoke".to_string(), 0, false),
+            ]
+        );
+        let file = ConfigFile::parse("config.yaml", "").unwrap();
+        let config = BrokerConfig::from_config(&file).unwrap();
+        assert!(Recorder::from_config(&config, &file).unwrap().is_none());
+    }
+}
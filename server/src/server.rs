@@ -1,47 +1,438 @@
-use crate::file_loader::load_contents;
-use crate::logger::{Logger, Logging};
+use crate::admin::{self, AdminBackend, ClientInfo, QueuedMessage, TopicInfo};
+use crate::bridge::{Bridge, BridgeSettings};
+use crate::cluster::{Cluster, ClusterBackend, ClusterSettings, CLUSTER_ORIGIN};
+use crate::dispatcher::{self, Command, DispatchSender};
+use crate::expiry::{session_expiry, ExpiryRules, EXPIRY_SWEEP_SECS};
+use crate::http;
+use crate::limits::{ClientLimiter, ConnectionLimiter, ConnectionPermit, Limits, Violation};
+use crate::listener::{ConnectionSlot, Listener, ListenerAuth, ListenerSettings, Protocol};
+use crate::logger::{Level, LogFields, Logger, Logging};
+use crate::metrics;
+use crate::mqtt5;
+use crate::outbound::{
+    self, OutboundReceiver, OutboundSender, QueueError, QueueSettings, Queued, Waker,
+};
+use crate::readiness::{self, EventLoop, Session, Step};
+use crate::recorder::Recorder;
+use crate::shared_subscription::{
+    self, parse_shared_topic, HashSharedSubscriptions, SHARED_PREFIX,
+};
+use crate::stats::{BrokerStats, CountingStream, DEFAULT_SYS_INTERVAL_SECS, SYS_PREFIX};
+use crate::tls::CertAuth;
+use crate::topics::TopicTable;
+use crate::trace::{Tracer, TracingStream};
+use crate::transport::{TlsStream, Transport};
+use crate::validation;
+use crate::websocket::WebSocketStream;
 use mqtt_packet::mqtt_packet_service::header_packet::control_flags::{self};
 use mqtt_packet::mqtt_packet_service::header_packet::{control_type, PacketHeader};
 use mqtt_packet::mqtt_packet_service::payload_packet::{
     suback_return_codes, Payload, PublishPayload, SubscribePayload, UnsubscribePayload,
 };
+use mqtt_packet::mqtt_packet_service::properties::{
+    property_identifiers, Properties, PropertyValue,
+};
 use mqtt_packet::mqtt_packet_service::variable_header_packet::{
-    connect_ack_flags, connect_return, PacketVariableHeader, VariableHeader,
-    VariableHeaderPacketIdentifier, VariableHeaderPublish,
+    connect_ack_flags, connect_return, protocol_level, reason_codes, PacketVariableHeader,
+    VariableHeader, VariableHeaderPacketIdentifier, VariableHeaderPublish,
+    VariableHeaderReasonCode,
 };
 use mqtt_packet::mqtt_packet_service::{ClientPacket, Packet, ServerPacket, Utils};
 use rand::Rng;
-use std::collections::HashMap;
+use rustls::ServerConnection;
+use std::collections::{BTreeSet, HashMap};
 use std::io::{Error, ErrorKind, Read, Result, Write};
-use std::net::{Shutdown, TcpListener, TcpStream};
-use std::sync::mpsc::{channel, Receiver, Sender};
+use std::net::{Shutdown, TcpListener};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Receiver;
 use std::sync::{Arc, Mutex};
-use std::thread::JoinHandle;
 use std::thread::{self};
-use std::time::Duration;
+use std::time::{Duration, Instant};
+/// Time given to the clients to close their connections on shutdown
+const SHUTDOWN_TIMEOUT_SECS: u64 = 5;
+/// Time between two checks of the shutdown flag while waiting for clients
+const ACCEPT_POLL_MILLIS: u64 = 50;
+/// Time between two server keepalive checks of a MQTT 3.1.1 client
+const SERVER_KEEPALIVE_SECS: u64 = 9;
+/// Read timeout of the client sockets once they are readable
+const READ_TIMEOUT_MILLIS: u64 = 1;
+/// Write timeout of the client sockets when slow_consumer_timeout is 0
+const WRITE_TIMEOUT_SECS: u64 = 5;
 type LastWill = (String, String);
-type HashPersistanceConnections = HashMap<String, (JoinHandle<()>, String)>; // la clave es el ip address contiene como valor (Joinhandle del thread, el client_id)
+type HashPersistanceConnections = HashMap<String, String>; // la clave es el ip address contiene como valor el client_id
 type HashServerConnections = HashMap<String, (HandleClientConnections, LastWill)>; // la clave es el client_id de mqtt
-type HashTopics = HashMap<String, (Vec<(String, Sender<Vec<u8>>)>, String)>; // tuple value (vec of (client_id, tx senders), message of retain)
 type HashCredentials = HashMap<String, String>;
+
 #[derive(Clone)]
 pub struct Server {
     server_address: Arc<String>,
     server_port: Arc<String>,
+    listeners: Vec<Arc<Listener>>, // when empty a tcp listener on server_address:server_port is used
     logger: Arc<Logger>,
     hash_persistance_connections: Arc<Mutex<HashPersistanceConnections>>,
     hash_server_connections: Arc<Mutex<HashServerConnections>>,
-    hash_topics: Arc<Mutex<HashTopics>>,
-    tx_server: Arc<Mutex<Sender<Vec<String>>>>,
-    rx_server: Arc<Mutex<Receiver<Vec<String>>>>,
+    hash_topics: Arc<TopicTable>,
+    hash_shared_subscriptions: Arc<Mutex<HashSharedSubscriptions>>,
+    tx_server: Arc<Mutex<DispatchSender>>,
+    rx_server: Arc<Mutex<Vec<Receiver<Command>>>>, // taken by the dispatchers when listening starts
     hash_credentials: Arc<Mutex<HashCredentials>>,
+    stats: Arc<BrokerStats>,
+    sys_interval: Duration, // time between $SYS publications, zero disables them
+    metrics_address: Option<String>, // host:port of the prometheus endpoint, None disables it
+    admin: Option<(String, String)>, // host:port and token of the admin api, None disables it
+    shutdown: Arc<AtomicBool>, // set once the server has to stop
+    limits: Limits,
+    connection_limiter: Arc<ConnectionLimiter>,
+    queue_settings: QueueSettings, // size of the client queues and slow consumer handling
+    bridges: Vec<Arc<Bridge>>,     // connections to remote brokers
+    cluster: Option<Arc<Cluster>>, // None when the server is not part of a cluster
+    tracer: Arc<Tracer>,           // packet trace of the clients chosen by config or the admin api
+    recorder: Option<Arc<Recorder>>, // recording of the messages of some topics, None disables it
+    expiry: Arc<ExpiryRules>,      // time the messages of each topic can wait to be delivered
+    session_expiry: Option<Duration>, // longest time a disconnected session is kept, None keeps it forever
+    client_workers: usize,            // threads that serve the client connections
 }
 #[derive(Clone, Debug)]
 pub struct HandleClientConnections {
-    tx: Arc<Mutex<Sender<Vec<u8>>>>,
-    rx: Arc<Mutex<Receiver<Vec<u8>>>>,
+    tx: Arc<Mutex<OutboundSender>>,
+    rx: Arc<Mutex<OutboundReceiver>>,
     #[allow(dead_code)]
     peer: Arc<Mutex<String>>,
+    user_name: Arc<Mutex<String>>, // user authenticated on connect, empty for anonymous clients
+    protocol_level: Arc<Mutex<u8>>, // 4 for MQTT 3.1.1 and 5 for MQTT 5 clients
+    topic_aliases: Arc<Mutex<HashMap<u16, String>>>, // topic aliases set by MQTT 5 publish packets
+    session_expiry_interval: Arc<Mutex<u32>>, // seconds asked by MQTT 5 clients on connect or disconnect
+    clean_session: Arc<Mutex<bool>>,          // clean session flag of the connect packet
+    connected: Arc<Mutex<bool>>,              // false once this connection ends
+    disconnected_at: Arc<Mutex<Option<Instant>>>, // set when this connection ends
+    queued: Arc<Mutex<usize>>,                // messages sent through tx that are not written yet
+    inflight: Arc<Mutex<BTreeSet<u16>>>, // packet identifiers of QoS 1 messages waiting for a puback
+    kicked: Arc<Mutex<Option<u8>>>, // reason code to close the connection, set by the admin api or the cluster
+}
+
+impl HandleClientConnections {
+    /// True once the client stayed disconnected longer than its session expiry,
+    /// max_expiry is the longest one allowed by the server config
+    fn session_expired(&self, now: Instant, max_expiry: Option<Duration>) -> bool {
+        let disconnected_at = match *self.disconnected_at.lock().unwrap() {
+            Some(disconnected_at) => disconnected_at,
+            None => return false,
+        };
+        if *self.connected.lock().unwrap() {
+            return false;
+        }
+        let expiry = session_expiry(
+            *self.protocol_level.lock().unwrap(),
+            *self.clean_session.lock().unwrap(),
+            *self.session_expiry_interval.lock().unwrap(),
+            max_expiry,
+        );
+        expiry.is_some_and(|expiry| now >= disconnected_at + expiry)
+    }
+}
+
+/// Connection of a client, served by the workers of the client EventLoop
+struct ClientSession {
+    peer: String,
+    stream: Box<dyn Transport>,
+    started: bool, // true once the handshake is done
+    logger: Arc<Logger>,
+    hash_server_connections: Arc<Mutex<HashServerConnections>>,
+    hash_persistance_connections: Arc<Mutex<HashPersistanceConnections>>,
+    hash_credentials: Arc<Mutex<HashCredentials>>,
+    hash_topics: Arc<TopicTable>,
+    hash_shared_subscriptions: Arc<Mutex<HashSharedSubscriptions>>,
+    client_connections: HandleClientConnections,
+    tx_server: DispatchSender,
+    auth: ListenerAuth,
+    client_limiter: ClientLimiter,
+    limits: Limits,
+    stats: Arc<BrokerStats>,
+    shutdown: Arc<AtomicBool>,
+    cluster: Option<Arc<Cluster>>,
+    waker: Arc<Waker>, // queues the session for a worker, called by the sends to its queue
+    buff: [u8; 1024],
+    client_id: String,
+    next_keepalive: Instant,
+    // a read can leave data behind (tls records), it is read again until it blocks
+    pending_read: bool,
+    // the listener slot and the limiter permit are given back when the session ends
+    _connection_slot: ConnectionSlot,
+    _connection_permit: ConnectionPermit,
+}
+
+impl Session for ClientSession {
+    fn serve(&mut self, readable: bool) -> Step {
+        let result = match self.started {
+            true => Ok(()),
+            false => self.start(),
+        };
+        match result.and_then(|_| self.step(readable)) {
+            Ok(Some(step)) => step,
+            Ok(None) => {
+                self.end(Ok(()));
+                Step::Done
+            }
+            Err(e) => {
+                self.end(Err(e));
+                Step::Done
+            }
+        }
+    }
+}
+
+impl ClientSession {
+    fn start(&mut self) -> Result<()> {
+        // the socket is only read once it is readable, the timeout only covers the
+        // reads of tls and websocket frames that are not complete yet
+        self.stream
+            .set_read_timeout(Some(Duration::from_millis(READ_TIMEOUT_MILLIS)))?;
+        self.client_connections
+            .rx
+            .lock()
+            .unwrap()
+            .set_waker(&self.waker);
+        self.started = true;
+        Ok(())
+    }
+
+    /// Reads what the client sent and writes its queued messages. None once the
+    /// connection is closed, otherwise what the session waits for.
+    fn step(&mut self, readable: bool) -> Result<Option<Step>> {
+        let readable = readable || self.pending_read;
+        let is_v5 =
+            *self.client_connections.protocol_level.lock().unwrap() == protocol_level::MQTT_5;
+        if self.shutdown.load(Ordering::SeqCst) {
+            // the messages already queued for the client are written before closing
+            self.write_queued()?;
+            if is_v5 {
+                let packet = Packet::<VariableHeader, Payload>::new().disconnect_v5(
+                    reason_codes::SERVER_SHUTTING_DOWN,
+                    mqtt5::reason_string("Server shutting down"),
+                );
+                self.stream.write_all(&packet.value())?;
+            }
+            self.stream.flush()?;
+            let _ = self.stream.shutdown(Shutdown::Both);
+            return Ok(None);
+        }
+        let kicked = *self.client_connections.kicked.lock().unwrap();
+        if let Some(reason_code) = kicked {
+            let reason = if reason_code == reason_codes::SESSION_TAKEN_OVER {
+                "Session taken over by another node"
+            } else {
+                "Disconnected by the administrator"
+            };
+            if is_v5 {
+                let packet = Packet::<VariableHeader, Payload>::new()
+                    .disconnect_v5(reason_code, mqtt5::reason_string(reason));
+                let _ = self.stream.write_all(&packet.value());
+            }
+            return Err(Error::new(
+                ErrorKind::ConnectionAborted,
+                format!("Client id {}: {}", self.client_id, reason),
+            ));
+        }
+        if self
+            .client_connections
+            .rx
+            .lock()
+            .unwrap()
+            .is_slow_consumer()
+        {
+            self.stats.slow_consumer_disconnected();
+            self.logger.info(format!(
+                "Closing connection of client id {}: slow consumer",
+                self.client_id
+            ));
+            if is_v5 {
+                let packet = Packet::<VariableHeader, Payload>::new().disconnect_v5(
+                    reason_codes::QUOTA_EXCEEDED,
+                    mqtt5::reason_string("Slow consumer"),
+                );
+                let _ = self.stream.write_all(&packet.value());
+            }
+            return Err(Error::new(
+                ErrorKind::TimedOut,
+                format!("Client id {} is a slow consumer", self.client_id),
+            ));
+        }
+        let read = if readable {
+            self.stream.read(&mut self.buff)
+        } else {
+            Err(ErrorKind::WouldBlock.into())
+        };
+        let read = match read {
+            Err(e)
+                if !matches!(
+                    e.kind(),
+                    ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted
+                ) =>
+            {
+                return Err(e);
+            }
+            read => read,
+        };
+        self.pending_read = read
+            .as_ref()
+            .map_or_else(|e| e.kind() == ErrorKind::Interrupted, |_| true);
+        if let Ok(_size) = read {
+            if _size == 0 {
+                return Err(Error::new(
+                    ErrorKind::ConnectionAborted,
+                    format!("Client id {} closed the connection", self.client_id),
+                ));
+            }
+            if let Err(violation) = self
+                .client_limiter
+                .received(_size)
+                .and_then(|_| self.client_limiter.packet(&self.buff))
+            {
+                return Err(Server::limit_violation(
+                    self.stream.as_mut(),
+                    &self.logger,
+                    is_v5,
+                    &self.client_id,
+                    violation,
+                ));
+            }
+            let control_type = self.buff[0];
+            self.logger
+                .debug("Check if a MQTT PACKET is received".to_string());
+            if Packet::<VariableHeader, Payload>::is_mqtt_packet(&self.buff) {
+                self.logger
+                    .debug(format!("Found a MQTT packet: {:?}", control_type));
+                match Server::handle_packet(
+                    self.buff.to_vec(),
+                    self.stream.as_mut(),
+                    self.logger.clone(),
+                    self.hash_server_connections.clone(),
+                    self.hash_credentials.clone(),
+                    &self.hash_topics,
+                    &self.hash_shared_subscriptions,
+                    &mut self.client_connections,
+                    self.tx_server.clone(),
+                    &mut self.client_id,
+                    self.auth,
+                    &self.limits,
+                    &self.stats,
+                    self.cluster.as_deref(),
+                ) {
+                    Ok(client_id) => {
+                        // a resumed session brings the queue of its last connection
+                        self.client_connections
+                            .rx
+                            .lock()
+                            .unwrap()
+                            .set_waker(&self.waker);
+                        self.logger.debug(format!(
+                            "Packet from peer {} and client id: {} has been processed",
+                            self.stream.peer_addr()?,
+                            client_id
+                        ));
+                        self.hash_persistance_connections
+                            .lock()
+                            .unwrap()
+                            .entry(self.peer.clone())
+                            .and_modify(|e| {
+                                *e = client_id.clone();
+                            });
+
+                        self.logger.debug("Cleaning buffer".to_string());
+                        self.buff = [0_u8; 1024];
+                    }
+                    Err(e) => {
+                        self.logger.debug(format!("Error (handle_packet): {}", e));
+                        return Ok(None); // closing the connection
+                    }
+                }
+            } else {
+                self.logger
+                    .debug("Clean buffer to continue reading from stream".to_string());
+                self.buff = [0_u8; 1024];
+            };
+        }
+
+        self.write_queued()?;
+
+        // server keepalive check using RESERVED bits of mqtt packet,
+        // on MQTT 5 that control type is AUTH so the check is skipped
+        if Instant::now() >= self.next_keepalive {
+            self.next_keepalive = Instant::now() + Duration::from_secs(SERVER_KEEPALIVE_SECS);
+            if !is_v5 {
+                let msg = vec![0xF0_u8];
+                if let Err(e) = self.stream.write_all(&msg) {
+                    self.logger
+                        .debug(format!("Error (server keepalive failed): {}", e));
+                    return Err(e);
+                }
+                self.logger.debug(format!(
+                    "Server client id {} keepalive check",
+                    self.client_id
+                ));
+            }
+        }
+
+        // the expiry sweeper drops the messages that expire while the session waits
+        Ok(Some(match self.pending_read {
+            true => Step::Read,
+            false => Step::Wait(self.next_keepalive),
+        }))
+    }
+
+    /// Writes the messages queued for the client, except the expired ones
+    fn write_queued(&mut self) -> Result<()> {
+        Server::drop_expired(&self.client_connections, &self.stats);
+        let client_rx = &*self.client_connections.rx.lock().unwrap();
+        while let Some(msg) = client_rx.try_recv() {
+            self.logger
+                .debug("Received message from server through channel".to_string());
+            self.stream.write_all(&msg)?;
+            self.stats.message_written();
+            let mut queued = self.client_connections.queued.lock().unwrap();
+            *queued = queued.saturating_sub(1);
+        }
+        Ok(())
+    }
+
+    /// Marks the session as disconnected, sending the last will of the client
+    /// when the connection failed
+    fn end(&mut self, result: Result<()>) {
+        let mut connected = self.client_connections.connected.lock().unwrap();
+        if *connected {
+            self.stats.client_disconnected();
+        }
+        *connected = false;
+        drop(connected);
+        let client_id = self
+            .hash_persistance_connections
+            .lock()
+            .unwrap()
+            .remove(&self.peer)
+            .unwrap_or_default();
+        match result {
+            Ok(_) => {
+                self.logger
+                    .debug(format!("Connection with {} closed", self.peer));
+            }
+            Err(e) => {
+                self.logger.debug(format!(
+                    "Error (_handle_client_): {} for client id: {}",
+                    e, client_id
+                ));
+                if !self.shutdown.load(Ordering::SeqCst) {
+                    Server::send_last_will(
+                        self.hash_server_connections.clone(),
+                        self.tx_server.clone(),
+                        client_id,
+                        &self.logger,
+                    );
+                }
+            }
+        }
+        // set after the last will so the session is not deleted before sending it
+        *self.client_connections.disconnected_at.lock().unwrap() = Some(Instant::now());
+    }
 }
 
 #[allow(clippy::unit_arg)]
@@ -50,238 +441,651 @@ impl Server {
         server_address: String,
         server_port: String,
         file_source: &str,
-        credentials_file: &str,
+        hash_credentials: HashCredentials,
     ) -> Server {
-        let hash_credentials = if !credentials_file.is_empty() {
-            load_contents(credentials_file)
-        } else {
-            HashMap::new()
-        };
-
         let hash_persistance_connections: Arc<Mutex<HashPersistanceConnections>> =
             Arc::new(Mutex::new(HashMap::new()));
         let hash_server_connections: Arc<Mutex<HashServerConnections>> =
             Arc::new(Mutex::new(HashMap::new()));
-        let hash_topics: Arc<Mutex<HashTopics>> = Arc::new(Mutex::new(HashMap::new()));
-        let (tx_server, rx_server) = channel::<Vec<String>>();
+        let hash_topics = Arc::new(TopicTable::new());
+        let hash_shared_subscriptions: Arc<Mutex<HashSharedSubscriptions>> =
+            Arc::new(Mutex::new(HashMap::new()));
+        let (tx_server, rx_server) = dispatcher::channels(dispatcher::default_dispatchers());
 
-        let server = Server {
+        Server {
             server_address: Arc::new(server_address),
             server_port: Arc::new(server_port),
+            listeners: Vec::new(),
             logger: Arc::new(Logger::new(file_source, true)),
             hash_persistance_connections,
             hash_server_connections,
             hash_topics,
+            hash_shared_subscriptions,
             tx_server: Arc::new(Mutex::new(tx_server)),
             rx_server: Arc::new(Mutex::new(rx_server)),
             hash_credentials: Arc::new(Mutex::new(hash_credentials)),
+            stats: Arc::new(BrokerStats::new()),
+            sys_interval: Duration::from_secs(DEFAULT_SYS_INTERVAL_SECS),
+            metrics_address: None,
+            admin: None,
+            shutdown: Arc::new(AtomicBool::new(false)),
+            limits: Limits::default(),
+            connection_limiter: Arc::new(ConnectionLimiter::new(Limits::default())),
+            queue_settings: QueueSettings::default(),
+            bridges: Vec::new(),
+            cluster: None,
+            tracer: Arc::new(Tracer::default()),
+            recorder: None,
+            expiry: Arc::new(ExpiryRules::default()),
+            session_expiry: None,
+            client_workers: readiness::default_workers(),
+        }
+    }
+
+    /// Traces the packets of the clients chosen by tracer
+    pub fn set_tracer(&mut self, tracer: Tracer) {
+        self.tracer = Arc::new(tracer);
+    }
+
+    /// Records the messages published on the topics of recorder
+    pub fn set_recorder(&mut self, recorder: Recorder) {
+        self.recorder = Some(Arc::new(recorder));
+    }
+
+    /// Drops the retained and queued messages once their ttl passes
+    pub fn set_expiry_rules(&mut self, expiry: ExpiryRules) {
+        self.expiry = Arc::new(expiry);
+    }
+
+    /// Deletes the sessions of the clients disconnected for longer than seconds,
+    /// MQTT 5 clients can ask for less with their session expiry interval
+    pub fn set_session_expiry(&mut self, seconds: u64) {
+        self.session_expiry = Some(Duration::from_secs(seconds));
+    }
+
+    /// Replaces the logger opened by new, so the server writes with the configured settings
+    pub fn set_logger(&mut self, logger: Arc<Logger>) {
+        self.logger = logger;
+    }
+
+    /// Threads that send the publishes to the subscribers, 0 uses one for each cpu
+    pub fn set_dispatchers(&mut self, dispatchers: usize) {
+        let dispatchers = match dispatchers {
+            0 => dispatcher::default_dispatchers(),
+            dispatchers => dispatchers,
         };
-        let _handle = Server::message_handler(
-            server.tx_server.clone(),
-            server.rx_server.clone(),
-            server.hash_topics.clone(),
-            server.hash_server_connections.clone(),
-            server.logger.clone(),
-        );
-        server
+        let (tx_server, rx_server) = dispatcher::channels(dispatchers);
+        self.tx_server = Arc::new(Mutex::new(tx_server));
+        self.rx_server = Arc::new(Mutex::new(rx_server));
+    }
+
+    /// Threads that serve the client connections, 0 uses four for each cpu
+    pub fn set_client_workers(&mut self, workers: usize) {
+        self.client_workers = match workers {
+            0 => readiness::default_workers(),
+            workers => workers,
+        };
+    }
+
+    /// Seconds between two publications of the $SYS topics, 0 disables them
+    pub fn set_sys_interval(&mut self, seconds: u64) {
+        self.sys_interval = Duration::from_secs(seconds);
+    }
+
+    /// Serves the broker metrics for Prometheus on address (host:port)
+    pub fn set_metrics_address(&mut self, address: String) {
+        self.metrics_address = Some(address);
+    }
+
+    /// Serves the admin api on address (host:port), its requests must carry token
+    pub fn set_admin(&mut self, address: String, token: String) {
+        self.admin = Some((address, token));
+    }
+
+    /// Limits of the connections and of the traffic of each client
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+        self.connection_limiter = Arc::new(ConnectionLimiter::new(limits));
     }
 
+    /// Size of the queue of messages of each client and what is done when it is full
+    pub fn set_queue_settings(&mut self, queue_settings: QueueSettings) {
+        self.queue_settings = queue_settings;
+    }
+
+    /// Adds a bridge to a remote broker, it connects when listening starts
+    pub fn add_bridge(&mut self, settings: BridgeSettings) {
+        self.bridges.push(Arc::new(Bridge::new(settings)));
+    }
+
+    /// Joins the cluster of settings, the other nodes are reached when listening starts
+    pub fn set_cluster(&mut self, settings: ClusterSettings) {
+        self.cluster = Some(Arc::new(Cluster::new(settings)));
+    }
+
+    /// Stops accepting clients and closes the connections, listening returns once
+    /// the client connections end. The last will of the clients is not sent.
+    pub fn shutdown(&self) {
+        if !self.shutdown.swap(true, Ordering::SeqCst) {
+            self.logger.info("Shutting down the server".to_string());
+        }
+        for (connection, _) in self.hash_server_connections.lock().unwrap().values() {
+            connection.tx.lock().unwrap().wake();
+        }
+    }
+
+    /// Adds a listener, all of them feed the same message handler
+    pub fn add_listener(&mut self, settings: ListenerSettings) -> Result<()> {
+        self.listeners.push(Arc::new(Listener::new(settings)?));
+        Ok(())
+    }
+
+    /// Serves the connection of peer from event_loop, stream is the socket wrapped by its
+    /// listener and handshake tells if it needs one before reading mqtt packets
+    #[allow(clippy::too_many_arguments)]
     fn handle_client(
+        event_loop: &Arc<EventLoop>,
         peer: String,
-        stream: TcpStream,
+        stream: Box<dyn Transport>,
+        handshake: bool,
         logger: Arc<Logger>,
         hash_server_connections: Arc<Mutex<HashServerConnections>>,
         hash_persistance_connections: Arc<Mutex<HashPersistanceConnections>>,
         hash_credentials: Arc<Mutex<HashCredentials>>,
-        tx_server: Sender<Vec<String>>,
-    ) -> Result<JoinHandle<()>> {
-        fn _handle_client_(
-            mut stream: TcpStream,
-            logger: Arc<Logger>,
-            hash_server_connections: Arc<Mutex<HashServerConnections>>,
-            hash_persistance_connections: Arc<Mutex<HashPersistanceConnections>>,
-            hash_credentials: Arc<Mutex<HashCredentials>>,
-            mut client_connections: HandleClientConnections,
-            tx_server: Sender<Vec<String>>,
-        ) -> Result<()> {
-            let mut buff = [0_u8; 1024];
-            let mut _client_id = String::new();
-            let keepalive_retry: usize = 300;
-            let mut keepalive_count: usize = keepalive_retry;
-            #[allow(unreachable_code)]
-            Ok(loop {
-                // this timeout checks when the client is disconnected
-                stream.set_read_timeout(Some(Duration::from_millis(30)))?;
-                if let Ok(_size) = stream.read(&mut buff) {
-                    if _size > 0 {
-                        let control_type = buff[0];
-                        logger.debug("Check if a MQTT PACKET is received".to_string());
-                        if Packet::<VariableHeader, Payload>::is_mqtt_packet(&buff) {
-                            logger.debug(format!("Found a MQTT packet: {:?}", control_type));
-                            match Server::handle_packet(
-                                buff.to_vec(),
-                                &mut stream,
-                                logger.clone(),
-                                hash_server_connections.clone(),
-                                hash_credentials.clone(),
-                                &mut client_connections,
-                                tx_server.clone(),
-                                &mut _client_id,
-                            ) {
-                                Ok(client_id) => {
-                                    logger.debug(format!(
-                                        "Packet from peer {} and client id: {} has been processed",
-                                        stream.peer_addr()?,
-                                        client_id
-                                    ));
-                                    let peer = client_connections.peer.lock().unwrap().clone();
-                                    hash_persistance_connections
-                                        .lock()
-                                        .unwrap()
-                                        .entry(peer.clone())
-                                        .and_modify(|e| {
-                                            e.1 = client_id.clone();
-                                        });
-
-                                    logger.debug("Cleaning buffer".to_string());
-                                    buff = [0_u8; 1024];
-                                }
-                                Err(e) => {
-                                    logger.debug(format!("Error (handle_packet): {}", e));
-                                    break; // stopping thread
-                                }
-                            }
-                        } else {
-                            logger
-                                .debug("Clean buffer to continue reading from stream".to_string());
-                            buff = [0_u8; 1024];
-                        };
-                    };
-                }
+        hash_topics: Arc<TopicTable>,
+        hash_shared_subscriptions: Arc<Mutex<HashSharedSubscriptions>>,
+        tx_server: DispatchSender,
+        auth: ListenerAuth,
+        connection_slot: ConnectionSlot,
+        connection_permit: ConnectionPermit,
+        limits: Limits,
+        queue_settings: QueueSettings,
+        stats: Arc<BrokerStats>,
+        shutdown: Arc<AtomicBool>,
+        cluster: Option<Arc<Cluster>>,
+        tracer: Arc<Tracer>,
+    ) -> Result<()> {
+        let socket = stream.try_clone_socket()?;
+        // a client that does not read its socket is a slow consumer too, the writes have
+        // a timeout even when the slow consumers are kept so they never block a worker
+        let write_timeout = match queue_settings.slow_consumer_timeout {
+            timeout if timeout.is_zero() => Duration::from_secs(WRITE_TIMEOUT_SECS),
+            timeout => timeout,
+        };
+        socket.set_write_timeout(Some(write_timeout))?;
+        let stream: Box<dyn Transport> = Box::new(CountingStream::new(stream, stats.clone()));
+        let stream: Box<dyn Transport> = Box::new(TracingStream::new(stream, tracer)?);
 
-                let client_rx = &*client_connections.rx.lock().unwrap();
-                if let Ok(msg) = client_rx.try_recv() {
-                    logger.debug("Received message from server through channel".to_string());
-                    stream.write_all(&msg)?;
-                }
+        let (tx, rx) = outbound::channel(queue_settings);
 
-                // server keepalive check using RESERVED bits of mqtt packet
-                if keepalive_count == 0 {
-                    let msg = vec![0xF0_u8];
-                    if let Err(e) = stream.write_all(&msg) {
-                        logger.debug(format!("Error (server keepalive failed): {}", e));
-                        return Err(e);
+        let client_connections = HandleClientConnections {
+            tx: Arc::new(Mutex::new(tx)),
+            rx: Arc::new(Mutex::new(rx)),
+            peer: Arc::new(Mutex::new(peer.to_string())),
+            user_name: Arc::new(Mutex::new(String::new())),
+            protocol_level: Arc::new(Mutex::new(protocol_level::MQTT_3_1_1)),
+            topic_aliases: Arc::new(Mutex::new(HashMap::new())),
+            session_expiry_interval: Arc::new(Mutex::new(0)),
+            clean_session: Arc::new(Mutex::new(true)),
+            connected: Arc::new(Mutex::new(false)),
+            disconnected_at: Arc::new(Mutex::new(None)),
+            queued: Arc::new(Mutex::new(0)),
+            inflight: Arc::new(Mutex::new(BTreeSet::new())),
+            kicked: Arc::new(Mutex::new(None)),
+        };
+
+        // connection succeeded
+        logger.debug(format!("Connection from {}", peer));
+        let handshake_peer = peer.clone();
+        let handshake_logger = logger.clone();
+        let handshake_connections = hash_persistance_connections.clone();
+        let handshake_shutdown = shutdown.clone();
+        let event_loop = event_loop.clone();
+        let serve = move |stream: Box<dyn Transport>| {
+            event_loop.add(socket, move |waker| {
+                Box::new(ClientSession {
+                    peer,
+                    stream,
+                    started: false,
+                    logger,
+                    hash_server_connections,
+                    hash_persistance_connections,
+                    hash_credentials,
+                    hash_topics,
+                    hash_shared_subscriptions,
+                    client_connections,
+                    tx_server,
+                    auth,
+                    client_limiter: ClientLimiter::new(&limits),
+                    limits,
+                    stats,
+                    shutdown,
+                    cluster,
+                    waker,
+                    buff: [0_u8; 1024],
+                    client_id: String::new(),
+                    next_keepalive: Instant::now() + Duration::from_secs(SERVER_KEEPALIVE_SECS),
+                    pending_read: false,
+                    _connection_slot: connection_slot,
+                    _connection_permit: connection_permit,
+                })
+            })
+        };
+        if !handshake {
+            return serve(stream);
+        }
+        // the handshake waits for the client, it runs on a thread of its own and the session
+        // joins the event loop once it is done, so a client that does not complete it does
+        // not take a worker from the other clients
+        let mut stream = stream;
+        thread::Builder::new()
+            .name(format!("Thread: handshake {}", handshake_peer))
+            .spawn(move || {
+                let result = stream.handshake().and_then(|_| {
+                    if handshake_shutdown.load(Ordering::SeqCst) {
+                        return Err(Error::new(ErrorKind::Interrupted, "Server shutting down"));
                     }
-                    keepalive_count = keepalive_retry;
-                    logger.debug(format!("Server client id {} keepalive check", _client_id));
-                } else {
-                    keepalive_count -= 1;
+                    serve(stream)
+                });
+                if let Err(e) = result {
+                    handshake_logger
+                        .debug(format!("Handshake with {} failed: {}", handshake_peer, e));
+                    handshake_connections
+                        .lock()
+                        .unwrap()
+                        .remove(&handshake_peer);
                 }
+            })?;
+        Ok(())
+    }
 
-                // ends loop returns ok result
-            })
+    pub fn listening(&self) -> Result<()> {
+        let mut listeners = self.listeners.clone();
+        if listeners.is_empty() {
+            let address = format!("{}:{}", self.server_address, self.server_port);
+            let settings = ListenerSettings::new("default", address, Protocol::Tcp);
+            listeners.push(Arc::new(Listener::new(settings)?));
+        }
+        self.logger.debug("ready to binding".to_string());
+        // every listener is bound before accepting clients so a wrong address stops the server
+        let mut tcp_listeners = Vec::new();
+        for listener in listeners {
+            self.logger.info(format!(
+                "server address: {:?} ({:?} listener {})",
+                listener.settings.address, listener.settings.protocol, listener.settings.name
+            ));
+            tcp_listeners.push((TcpListener::bind(&listener.settings.address)?, listener));
         }
 
-        let (tx, rx) = channel::<Vec<u8>>();
+        Server::message_handler(
+            self.rx_server.clone(),
+            self.hash_topics.clone(),
+            self.hash_shared_subscriptions.clone(),
+            self.hash_server_connections.clone(),
+            self.bridges.clone(),
+            self.cluster.clone(),
+            self.recorder.clone(),
+            self.expiry.clone(),
+            self.stats.clone(),
+            self.logger.clone(),
+        )?;
+        if let Some(cluster) = &self.cluster {
+            self.logger.info(format!(
+                "Cluster node {} listening on {}",
+                cluster.node(),
+                cluster.bind()
+            ));
+            cluster.start(self.clone(), self.shutdown.clone(), self.logger.clone())?;
+        }
+        for bridge in &self.bridges {
+            self.logger.info(format!("bridge: {}", bridge.name()));
+            bridge.start(
+                self.tx_server.lock().unwrap().clone(),
+                self.stats.clone(),
+                self.shutdown.clone(),
+                self.logger.clone(),
+            )?;
+        }
+        if let Some(address) = &self.metrics_address {
+            self.logger.info(format!(
+                "metrics endpoint: http://{}{}",
+                address,
+                metrics::METRICS_PATH
+            ));
+            self.metrics_endpoint(TcpListener::bind(address)?)?;
+        }
+        if let Some((address, _)) = &self.admin {
+            self.logger.info(format!("admin api: http://{}", address));
+            self.admin_endpoint(TcpListener::bind(address)?)?;
+        }
+        if !self.sys_interval.is_zero() {
+            self.sys_publisher();
+        }
+        self.expiry_sweeper();
 
-        let handle_client_connections = HandleClientConnections {
-            tx: Arc::new(Mutex::new(tx)),
-            rx: Arc::new(Mutex::new(rx)),
-            peer: Arc::new(Mutex::new(peer.to_string())),
-        };
+        self.logger
+            .info("starting listening to clients".to_string());
+        let event_loop = Arc::new(EventLoop::start(self.client_workers)?);
+        let mut handles = Vec::new();
+        for (tcp_listener, listener) in tcp_listeners {
+            let server = self.clone();
+            let event_loop = event_loop.clone();
+            let handle = thread::Builder::new()
+                .name(format!("Thread: {} listener", listener.settings.name))
+                .spawn(move || {
+                    let name = listener.settings.name.clone();
+                    if let Err(e) = server.accept_clients(&event_loop, tcp_listener, listener) {
+                        server
+                            .logger
+                            .error(format!("{} listener stopped: {}", name, e));
+                    }
+                })?;
+            handles.push(handle);
+        }
+        for handle in handles {
+            let _ = handle.join();
+        }
+        let result = self.close_clients(&event_loop);
+        event_loop.stop();
+        self.logger.info("Server terminated.".to_string());
+        let _ = self.logger.flush();
+        result
+    }
 
-        // let peer = stream.peer_addr()?;
+    /// Waits for the client connections, they close once the shutdown flag is set.
+    /// Fails if some of them do not end in time.
+    fn close_clients(&self, event_loop: &EventLoop) -> Result<()> {
+        self.logger.info(format!(
+            "Closing {} client connections",
+            event_loop.sessions()
+        ));
+        self.shutdown();
+        // the clients that did not connect yet are woken too
+        event_loop.wake_all();
+        let deadline = Instant::now() + Duration::from_secs(SHUTDOWN_TIMEOUT_SECS);
+        while event_loop.sessions() > 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(ACCEPT_POLL_MILLIS));
+        }
+        let pending = event_loop.sessions();
+        if pending > 0 {
+            for (peer, client_id) in self.hash_persistance_connections.lock().unwrap().iter() {
+                self.logger.error(format!(
+                    "Connection with {} (client id: {}) did not close in time",
+                    peer, client_id
+                ));
+            }
+            return Err(Error::new(
+                ErrorKind::TimedOut,
+                format!("{} client connections did not close in time", pending),
+            ));
+        }
+        Ok(())
+    }
 
-        let handle = thread::Builder::new()
-            .name("thread peer: ".to_string() + peer.to_string().as_str())
+    /// Subscriptions, including the members of shared groups, and retained messages
+    fn topics_counts(&self) -> (usize, usize) {
+        let (mut subscriptions, mut retained) = (0, 0);
+        self.hash_topics.for_each(|_, subscribers, message| {
+            subscriptions += subscribers.len();
+            retained += !message.is_empty() as usize;
+        });
+        let shared_subscriptions: usize = self
+            .hash_shared_subscriptions
+            .lock()
+            .unwrap()
+            .values()
+            .flatten()
+            .map(|group| group.len())
+            .sum();
+        (subscriptions + shared_subscriptions, retained)
+    }
+
+    /// Answers the Prometheus scrapes, one request at a time
+    fn metrics_endpoint(&self, tcp_listener: TcpListener) -> Result<()> {
+        let server = self.clone();
+        thread::Builder::new()
+            .name("Thread: metrics endpoint".to_string())
             .spawn(move || {
-                // connection succeeded
-                logger.debug(format!("Connection from {}", peer));
-                match _handle_client_(
-                    stream,
-                    logger.clone(),
-                    hash_server_connections.clone(),
-                    hash_persistance_connections.clone(),
-                    hash_credentials,
-                    handle_client_connections,
-                    tx_server.clone(),
-                ) {
-                    Ok(_) => {
-                        logger.debug(format!("Connection with {} closed", peer));
+                for stream in tcp_listener.incoming() {
+                    let result = stream.and_then(|stream| {
+                        http::handle_connection(stream, |request| {
+                            metrics::handle_request(request, || {
+                                let (subscriptions, retained) = server.topics_counts();
+                                server.stats.prometheus(subscriptions, retained)
+                            })
+                        })
+                    });
+                    if let Err(e) = result {
+                        server
+                            .logger
+                            .debug(format!("Error (metrics endpoint): {}", e));
                     }
-                    Err(e) => {
-                        let client_id = hash_persistance_connections
-                            .lock()
-                            .unwrap()
-                            .get(&peer.to_string())
-                            .unwrap()
-                            .1
-                            .clone();
-                        logger.debug(format!(
-                            "Error (_handle_client_): {} for client id: {}",
-                            e, client_id
+                }
+            })?;
+        Ok(())
+    }
+
+    /// Answers the admin api requests, one at a time
+    fn admin_endpoint(&self, tcp_listener: TcpListener) -> Result<()> {
+        let server = self.clone();
+        let token = self.admin.clone().unwrap_or_default().1;
+        thread::Builder::new()
+            .name("Thread: admin api".to_string())
+            .spawn(move || {
+                for stream in tcp_listener.incoming() {
+                    let result = stream.and_then(|stream| {
+                        http::handle_connection(stream, |request| {
+                            let response = admin::handle_request(request, &token, &server);
+                            server.logger.info(format!(
+                                "Admin api: {} {} answered {}",
+                                request.method, request.path, response.status
+                            ));
+                            response
+                        })
+                    });
+                    if let Err(e) = result {
+                        server.logger.debug(format!("Error (admin api): {}", e));
+                    }
+                }
+            })?;
+        Ok(())
+    }
+
+    /// Publishes the broker statistics on the $SYS topics every sys_interval,
+    /// the messages are retained so new subscribers get the last values
+    fn sys_publisher(&self) {
+        let server = self.clone();
+        let _handle = thread::Builder::new()
+            .name("Thread: $SYS publisher".to_string())
+            .spawn(move || loop {
+                thread::sleep(server.sys_interval);
+                let (subscriptions, retained) = server.topics_counts();
+                let tx_server = server.tx_server.lock().unwrap().clone();
+                for (topic, value) in server.stats.sys_messages(subscriptions, retained) {
+                    // message = [ packet_type, dup, qos, retain, topic, message, properties ]
+                    let msg_server = vec![
+                        "publish".to_string(),
+                        0.to_string(),
+                        0.to_string(),
+                        1.to_string(),
+                        topic,
+                        value,
+                        String::new(),
+                    ];
+                    if let Err(e) = tx_server.send(msg_server) {
+                        server
+                            .logger
+                            .error(format!("$SYS publisher stopped: {}", e));
+                        return;
+                    }
+                }
+            });
+    }
+
+    /// Drops the expired retained messages, the expired messages queued for the clients
+    /// and the expired sessions every EXPIRY_SWEEP_SECS, so offline sessions do not pile up
+    fn expiry_sweeper(&self) {
+        let server = self.clone();
+        let _handle = thread::Builder::new()
+            .name("Thread: expiry sweeper".to_string())
+            .spawn(move || {
+                while !server.shutdown.load(Ordering::SeqCst) {
+                    thread::sleep(Duration::from_secs(EXPIRY_SWEEP_SECS));
+                    let retained = server.hash_topics.purge_expired();
+                    server.stats.messages_expired(retained, false);
+                    let connections: Vec<HandleClientConnections> = server
+                        .hash_server_connections
+                        .lock()
+                        .unwrap()
+                        .values()
+                        .map(|(connection, _)| connection.clone())
+                        .collect();
+                    let queued: usize = connections
+                        .iter()
+                        .map(|connection| Server::drop_expired(connection, &server.stats))
+                        .sum();
+                    if retained + queued > 0 {
+                        server.logger.debug(format!(
+                            "Expired {} retained and {} queued messages",
+                            retained, queued
                         ));
-                        Server::send_last_will(
-                            hash_server_connections.clone(),
-                            tx_server.clone(),
-                            client_id,
-                            &logger,
-                        );
-                        hash_persistance_connections
-                            .lock()
-                            .unwrap()
-                            .remove(&peer.to_string());
                     }
+                    server.expire_sessions();
                 }
             });
-        handle
     }
 
-    pub fn listening(&self) -> Result<()> {
-        let server_address = Arc::clone(&self.server_address);
-        let server_port = Arc::clone(&self.server_port);
-        self.logger.debug("ready to binding".to_string());
-        self.logger.info(format!(
-            "server address: {:?}",
-            server_address.to_string() + ":" + server_port.as_str()
-        ));
-        let address = format!("{}:{}", server_address, server_port);
-        let listener = TcpListener::bind(address)?;
-        // accept connections and process them, spawning a new thread for each one
-        self.logger.debug("start binding".to_string());
-        self.logger
-            .debug(format!("Server listening on port {}", self.server_port));
+    /// Deletes the sessions of the clients that stayed disconnected longer than their
+    /// session expiry, with their subscriptions and queued messages. Returns how many.
+    fn expire_sessions(&self) -> usize {
+        let now = Instant::now();
+        let expired: Vec<(String, HandleClientConnections)> = {
+            let mut connections = self.hash_server_connections.lock().unwrap();
+            let client_ids: Vec<String> = connections
+                .iter()
+                .filter(|(_, (connection, _))| connection.session_expired(now, self.session_expiry))
+                .map(|(client_id, _)| client_id.clone())
+                .collect();
+            client_ids
+                .into_iter()
+                .filter_map(|client_id| {
+                    let (connection, _) = connections.remove(&client_id)?;
+                    Some((client_id, connection))
+                })
+                .collect()
+        };
+        for (client_id, connection) in &expired {
+            self.stats
+                .session_expired(*connection.queued.lock().unwrap());
+            Server::clean_session(
+                client_id,
+                &self.hash_topics,
+                &self.hash_shared_subscriptions,
+                self.cluster.as_deref(),
+                &self.logger,
+            );
+            self.logger
+                .info(format!("Session of client id {} expired", client_id));
+        }
+        expired.len()
+    }
 
-        self.logger
-            .info("starting listening to clients".to_string());
+    /// Accepts clients from tcp_listener, wrapping the sockets according to the listener protocol
+    fn accept_clients(
+        &self,
+        event_loop: &Arc<EventLoop>,
+        tcp_listener: TcpListener,
+        listener: Arc<Listener>,
+    ) -> Result<()> {
         let server_mutex = Arc::new(Mutex::new(self)); // moved self to a Arc Mutex to access the server struct
-        for stream in listener.incoming() {
+                                                       // the listener does not block so the shutdown flag is checked between clients
+        tcp_listener.set_nonblocking(true)?;
+        while !self.shutdown.load(Ordering::SeqCst) {
             let _clone_server = Arc::clone(&server_mutex);
-            match stream {
-                Ok(stream) => {
-                    let peer = stream.peer_addr()?;
+            match tcp_listener.accept() {
+                Ok((stream, peer)) => {
+                    stream.set_nonblocking(false)?;
                     let this = server_mutex.lock().unwrap();
                     let logger = this.logger.clone();
-                    logger.info(format!("New client connected: {}", peer));
-                    let tx = this.tx_server.lock().unwrap();
+                    let connection_slot = match listener.try_acquire() {
+                        Some(connection_slot) => connection_slot,
+                        None => {
+                            logger.info(format!(
+                                "Client {} refused, listener {} reached max connections ({})",
+                                peer, listener.settings.name, listener.settings.max_connections
+                            ));
+                            let _ = stream.shutdown(Shutdown::Both);
+                            continue;
+                        }
+                    };
+                    let connection_permit = match this.connection_limiter.try_acquire(peer.ip()) {
+                        Ok(connection_permit) => connection_permit,
+                        Err(e) => {
+                            logger.info(format!("Client {} refused, {}", peer, e));
+                            let _ = stream.shutdown(Shutdown::Both);
+                            continue;
+                        }
+                    };
+                    logger.info(format!(
+                        "New client connected: {} on listener {} ({} connections)",
+                        peer,
+                        listener.settings.name,
+                        listener.connections()
+                    ));
+                    let stream: Box<dyn Transport> = match &listener.tls_config {
+                        Some(config) => match ServerConnection::new(config.clone()) {
+                            Ok(conn) => Box::new(TlsStream::new(conn, stream)),
+                            Err(e) => {
+                                logger.error(format!("Cannot start TLS session: {}", e));
+                                continue;
+                            }
+                        },
+                        None if listener.settings.protocol == Protocol::WebSocket => {
+                            Box::new(WebSocketStream::new(stream))
+                        }
+                        None => Box::new(stream),
+                    };
+                    let tx = this.tx_server.lock().unwrap().clone();
+                    // inserted before the session starts, it is removed when the session ends
+                    self.hash_persistance_connections
+                        .lock()
+                        .unwrap()
+                        .insert(peer.to_string(), String::new());
+                    let handshake = listener.tls_config.is_some()
+                        || listener.settings.protocol == Protocol::WebSocket;
                     let _handle = Server::handle_client(
+                        event_loop,
                         peer.to_string(),
                         stream,
+                        handshake,
                         logger.clone(),
                         this.hash_server_connections.clone(),
                         this.hash_persistance_connections.clone(),
                         this.hash_credentials.clone(),
-                        tx.clone(),
+                        this.hash_topics.clone(),
+                        this.hash_shared_subscriptions.clone(),
+                        tx,
+                        listener.settings.auth,
+                        connection_slot,
+                        connection_permit,
+                        this.limits,
+                        this.queue_settings,
+                        this.stats.clone(),
+                        this.shutdown.clone(),
+                        this.cluster.clone(),
+                        this.tracer.clone(),
                     );
                     if let Err(e) = _handle {
                         logger.error(format!("Error: {}", e));
+                        self.hash_persistance_connections
+                            .lock()
+                            .unwrap()
+                            .remove(&peer.to_string());
                         break;
                     }
-                    // Updating hash_persistance_connections with the JoinHandle of the clients thread
-                    self.hash_persistance_connections
-                        .lock()
-                        .unwrap()
-                        .insert(peer.to_string(), (_handle.unwrap(), "".to_string()));
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    thread::sleep(Duration::from_millis(ACCEPT_POLL_MILLIS));
                 }
                 Err(e) => {
                     /* connection failed */
@@ -291,41 +1095,156 @@ impl Server {
             }
         }
         // close the socket server
-        drop(listener);
-        server_mutex
-            .lock()
-            .unwrap()
-            .logger
-            .info("Server terminated.".to_string()); //ver porque no se escribe esta linea no se escribe en el log
+        drop(tcp_listener);
         Ok(())
     }
 
     #[allow(clippy::too_many_arguments)]
     fn handle_packet(
         buff: Vec<u8>,
-        stream: &mut TcpStream,
+        stream: &mut dyn Transport,
         logger: Arc<Logger>,
         hash_server_connections: Arc<Mutex<HashServerConnections>>,
         hash_credentials: Arc<Mutex<HashCredentials>>,
+        hash_topics: &TopicTable,
+        hash_shared_subscriptions: &Arc<Mutex<HashSharedSubscriptions>>,
         client_connections: &mut HandleClientConnections,
-        tx_server: Sender<Vec<String>>,
+        tx_server: DispatchSender,
         client_id: &mut String,
+        auth: ListenerAuth,
+        limits: &Limits,
+        stats: &BrokerStats,
+        cluster: Option<&Cluster>,
     ) -> Result<String> {
         let packet_id = buff[0] & 0xF0;
+        stats.packet_received(packet_id);
 
         let peer_addr = stream.peer_addr()?;
+        let peer = peer_addr.to_string();
 
         if packet_id == control_type::CONNECT as u8 {
             let unvalued_packet = Packet::<VariableHeader, Payload>::unvalue(buff.clone());
-            let client_identifier: String = unvalued_packet.payload.client_identifier;
+            let mut client_identifier: String = unvalued_packet.payload.client_identifier;
             *client_id = client_identifier.clone();
             logger.debug(format!(
                 "Client Connection with Client identifier: {}, verifying that was connected...",
                 client_identifier
             ));
 
-            // Credentials check
-            if !hash_credentials.lock().unwrap().is_empty() {
+            let level = unvalued_packet.variable_header.protocol_level;
+            if level != protocol_level::MQTT_3_1_1 && level != protocol_level::MQTT_5 {
+                let packet = Packet::<VariableHeader, Payload>::new();
+                let packet = packet.connack(0, connect_return::UNACCEPTABLE_PROTOCOL_VERSION);
+                let _ = stream.write_all(&packet.value());
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Client Connection with Client identifier: {} refused, protocol level {} not supported", client_identifier, level),
+                ));
+            }
+            if let Err(e) = validation::client_identifier(&client_identifier, limits) {
+                let packet = Packet::<VariableHeader, Payload>::new();
+                let packet = if level == protocol_level::MQTT_5 {
+                    packet.connack_v5(
+                        0,
+                        reason_codes::CLIENT_IDENTIFIER_NOT_VALID,
+                        mqtt5::reason_string(&e.to_string()),
+                    )
+                } else {
+                    packet.connack(0, connect_return::IDENTIFIER_REJECTED)
+                };
+                let _ = stream.write_all(&packet.value());
+                return Err(e);
+            }
+            if client_identifier.is_empty() {
+                // MQTT 5 clients always get an identifier, 3.1.1 ones only with a clean session
+                if level == protocol_level::MQTT_3_1_1
+                    && !unvalued_packet.variable_header.clean_session()
+                {
+                    let packet = Packet::<VariableHeader, Payload>::new();
+                    let packet = packet.connack(0, connect_return::IDENTIFIER_REJECTED);
+                    let _ = stream.write_all(&packet.value());
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Client Connection from {} refused, empty client identifier without clean session", peer),
+                    ));
+                }
+                client_identifier =
+                    assign_client_identifier(&hash_server_connections.lock().unwrap());
+                *client_id = client_identifier.clone();
+                logger.info(format!(
+                    "Client {} connected with an empty client identifier, assigned client id {}",
+                    peer, client_identifier
+                ));
+            }
+            let will_topic = &unvalued_packet.payload.will_topic;
+            if !will_topic.is_empty() {
+                if let Err(e) = validation::topic_name(will_topic, limits) {
+                    // 3.1.1 has no connack return code for it, the connection is just closed
+                    if level == protocol_level::MQTT_5 {
+                        let packet = Packet::<VariableHeader, Payload>::new().connack_v5(
+                            0,
+                            reason_codes::TOPIC_NAME_INVALID,
+                            mqtt5::reason_string(&e.to_string()),
+                        );
+                        let _ = stream.write_all(&packet.value());
+                    }
+                    return Err(e);
+                }
+            }
+            *client_connections.protocol_level.lock().unwrap() = level;
+            *client_connections.clean_session.lock().unwrap() =
+                unvalued_packet.variable_header.clean_session();
+            let properties = &unvalued_packet.variable_header.properties;
+            if let Some(interval) =
+                properties.get_u32(property_identifiers::SESSION_EXPIRY_INTERVAL)
+            {
+                *client_connections.session_expiry_interval.lock().unwrap() = interval;
+            }
+            if properties
+                .get(property_identifiers::AUTHENTICATION_METHOD)
+                .is_some()
+            {
+                Server::refuse_connection(
+                    stream,
+                    stats,
+                    level,
+                    reason_codes::BAD_AUTHENTICATION_METHOD,
+                    "Enhanced authentication is not supported",
+                );
+                return Err(Error::new(
+                    ErrorKind::PermissionDenied,
+                    format!("Client Connection with Client identifier: {} refused, enhanced authentication not supported", client_identifier),
+                ));
+            }
+
+            // Credentials check, a certificate identity takes the place of user and password
+            let identity = match auth.cert_auth {
+                CertAuth::Disabled => None,
+                _ => stream.peer_identity(),
+            };
+            if let Some(identity) = identity {
+                logger.info(format!(
+                    "Client identifier: {} authenticated by certificate as user {}",
+                    client_identifier, identity
+                ));
+                *client_connections.user_name.lock().unwrap() = identity;
+            } else if auth.cert_auth == CertAuth::Require {
+                logger.debug(format!(
+                    "Client identifier: {} did not present a certificate identity",
+                    client_identifier
+                ));
+                Server::refuse_connection(
+                    stream,
+                    stats,
+                    level,
+                    reason_codes::NOT_AUTHORIZED,
+                    "Certificate required",
+                );
+                return Err(Error::new(
+                    ErrorKind::PermissionDenied,
+                    format!("Client Connection with Client identifier: {} refused, certificate required", client_identifier),
+                ));
+            } else if !hash_credentials.lock().unwrap().is_empty() {
                 // get the user and password from the packet
                 let user = unvalued_packet.payload.user_name;
                 let password = unvalued_packet.payload.password;
@@ -337,29 +1256,73 @@ impl Server {
                         Some(password_saved) => {
                             if *password_saved != password {
                                 logger.debug(format!("User {} password is incorrect", user));
+                                Server::refuse_connection(
+                                    stream,
+                                    stats,
+                                    level,
+                                    reason_codes::BAD_USER_NAME_OR_PASSWORD,
+                                    "Bad user name or password",
+                                );
                                 return Err(Error::new(
                                     ErrorKind::Other,
                                     format!("Client Connection with Client identifier: {} refused, user password incorrect", client_identifier),
                                 ));
                             }
+                            *client_connections.user_name.lock().unwrap() = user.clone();
                         }
                         None => {
                             logger.debug(format!(
                                 "User {} not found in server credential file",
                                 user
                             ));
+                            Server::refuse_connection(
+                                stream,
+                                stats,
+                                level,
+                                reason_codes::BAD_USER_NAME_OR_PASSWORD,
+                                "Bad user name or password",
+                            );
                             return Err(Error::new(
                                 ErrorKind::Other,
                                 format!("Client Connection with Client identifier: {} refused, user not found", client_identifier),
                             ));
                         }
                     }
+                } else if auth.require_credentials {
+                    logger.debug(format!(
+                        "User and password not found in connecting packet for client {}",
+                        client_identifier
+                    ));
+                    Server::refuse_connection(
+                        stream,
+                        stats,
+                        level,
+                        reason_codes::NOT_AUTHORIZED,
+                        "Credentials required",
+                    );
+                    return Err(Error::new(
+                        ErrorKind::PermissionDenied,
+                        format!("Client Connection with Client identifier: {} refused, credentials required", client_identifier),
+                    ));
                 } else {
                     logger.debug(format!(
                         "User and password not found in connecting packet for client {}, connecting anyway",
                         client_identifier
                     ));
                 }
+            } else if auth.require_credentials {
+                logger.debug("No credentials registered for this server".to_string());
+                Server::refuse_connection(
+                    stream,
+                    stats,
+                    level,
+                    reason_codes::NOT_AUTHORIZED,
+                    "Credentials required",
+                );
+                return Err(Error::new(
+                    ErrorKind::PermissionDenied,
+                    format!("Client Connection with Client identifier: {} refused, credentials required", client_identifier),
+                ));
             } else {
                 logger.debug("No credentials registered for this server".to_string());
             }
@@ -379,6 +1342,8 @@ impl Server {
                             let old_client_connection = &old_client_connection.0;
                             client_connections.tx = old_client_connection.tx.clone();
                             client_connections.rx = old_client_connection.rx.clone();
+                            client_connections.queued = old_client_connection.queued.clone();
+                            client_connections.inflight = old_client_connection.inflight.clone();
                             logger.debug(format!(
                                 "Client connection set with old channel for clientId: {}",
                                 client_id
@@ -394,23 +1359,13 @@ impl Server {
                     };
                 } else {
                     // unsubscribe tx of old client client_id from topics
-                    match tx_server.send(vec![
-                        "request_clean_session".to_string(),
-                        client_id.to_string(),
-                    ]) {
-                        Ok(_) => {
-                            logger.debug(format!(
-                                "Clean session was requested for clientId: {}",
-                                client_id
-                            ));
-                        }
-                        Err(e) => {
-                            logger.debug(format!(
-                                "Error sending request_clean_session to server: {}",
-                                e
-                            ));
-                        }
-                    };
+                    Server::clean_session(
+                        client_id,
+                        hash_topics,
+                        hash_shared_subscriptions,
+                        cluster,
+                        &logger,
+                    );
                 }
 
                 // do not return error this causes not to send a connack to client on reconnect
@@ -422,20 +1377,28 @@ impl Server {
             // si no lo encuentra debemos seguir sin dar error ...
         }
 
+        let is_v5 = *client_connections.protocol_level.lock().unwrap() == protocol_level::MQTT_5;
         // check other packets type
         match packet_id {
             control_type::CONNECT => {
-                logger.info("Connect packet received".to_string());
-                logger.debug(format!(
-                    "Peer mqtt connected: {}, with action type: {} ",
-                    peer_addr, packet_id
-                ));
+                let _ = logger.log(
+                    Level::Info,
+                    "Connect packet received".to_string(),
+                    &LogFields::packet("CONNECT", client_id, &peer),
+                );
 
                 let unvalued_packet = Packet::<VariableHeader, Payload>::unvalue(buff);
-                let client_identifier: String = unvalued_packet.payload.client_identifier;
+                // an empty client identifier was replaced by the assigned one
+                let assigned = unvalued_packet.payload.client_identifier.is_empty();
+                let client_identifier = client_id.clone();
                 let will_topic: String = unvalued_packet.payload.will_topic;
                 let will_message: String = unvalued_packet.payload.will_message;
-                *client_id = client_identifier.clone();
+                let mut connected = client_connections.connected.lock().unwrap();
+                if !*connected {
+                    stats.client_connected();
+                }
+                *connected = true;
+                drop(connected);
 
                 // check if it has Last will statement and update hash_server_connections
                 if will_topic.is_empty() {
@@ -456,6 +1419,7 @@ impl Server {
                         .unwrap()
                         .entry(client_identifier)
                         .and_modify(|e| {
+                            e.0 = client_connections.clone();
                             let will_tuple = &mut e.1;
                             will_tuple.0 = will_topic.clone();
                             will_tuple.1 = will_message.clone();
@@ -463,9 +1427,29 @@ impl Server {
                         .or_insert((client_connections.clone(), (will_topic, will_message)));
                 }
 
+                // the node that had the session of the client closes it
+                if let Some(cluster) = cluster {
+                    cluster.take_over(client_id, unvalued_packet.variable_header.clean_session());
+                }
+
                 let packet = Packet::<VariableHeader, Payload>::new();
                 let packet =
-                    packet.connack(connect_ack_flags::SESSION_PRESENT, connect_return::ACCEPTED);
+                    if unvalued_packet.variable_header.protocol_level == protocol_level::MQTT_5 {
+                        let mut properties = mqtt5::connack_properties();
+                        if assigned {
+                            properties.set(
+                                property_identifiers::ASSIGNED_CLIENT_IDENTIFIER,
+                                PropertyValue::Utf8String(client_id.to_string()),
+                            );
+                        }
+                        packet.connack_v5(
+                            connect_ack_flags::SESSION_PRESENT,
+                            reason_codes::SUCCESS,
+                            properties,
+                        )
+                    } else {
+                        packet.connack(connect_ack_flags::SESSION_PRESENT, connect_return::ACCEPTED)
+                    };
                 logger.debug("Sending connack packet".to_string());
                 if let Err(e) = stream.write_all(&packet.value()) {
                     logger.debug("Client disconnect".to_string());
@@ -477,10 +1461,69 @@ impl Server {
             }
 
             control_type::PUBLISH => {
-                logger.debug("Publish packet received".to_string());
-                logger.debug(format!("Peer mqtt publish: {:?}", peer_addr));
-
-                let unvalue = Packet::<VariableHeaderPublish, PublishPayload>::unvalue(buff);
+                let unvalue = if is_v5 {
+                    Packet::<VariableHeaderPublish, PublishPayload>::unvalue_v5(buff)
+                } else {
+                    Packet::<VariableHeaderPublish, PublishPayload>::unvalue(buff)
+                };
+                let topic =
+                    String::from_utf8_lossy(&unvalue.variable_header.topic_name).to_string();
+                let properties = unvalue
+                    .variable_header
+                    .properties
+                    .clone()
+                    .unwrap_or_default();
+                let topic = if is_v5 {
+                    let mut topic_aliases = client_connections.topic_aliases.lock().unwrap();
+                    match mqtt5::resolve_topic_alias(&mut topic_aliases, topic, &properties) {
+                        Ok(topic) => topic,
+                        Err(e) => {
+                            logger.debug(format!("Client id {}: {}", client_id, e));
+                            let packet = Packet::<VariableHeader, Payload>::new();
+                            let packet = packet.disconnect_v5(
+                                reason_codes::TOPIC_ALIAS_INVALID,
+                                mqtt5::reason_string(&e.to_string()),
+                            );
+                            let _ = stream.write_all(&packet.value());
+                            return Err(e);
+                        }
+                    }
+                } else {
+                    topic
+                };
+                if let Err(e) = validation::topic_name(&topic, limits) {
+                    return Err(Server::invalid_packet(
+                        stream,
+                        &logger,
+                        is_v5,
+                        client_id,
+                        reason_codes::TOPIC_NAME_INVALID,
+                        e,
+                    ));
+                }
+                if let Err(e) = validation::payload(&unvalue.payload.message, limits) {
+                    return Err(Server::invalid_packet(
+                        stream,
+                        &logger,
+                        is_v5,
+                        client_id,
+                        reason_codes::PACKET_TOO_LARGE,
+                        e,
+                    ));
+                }
+                let _ = logger.log(
+                    Level::Debug,
+                    "Publish packet received".to_string(),
+                    &LogFields::packet("PUBLISH", client_id, &peer).topic(&topic),
+                );
+                if topic.starts_with(SYS_PREFIX) {
+                    logger.info(format!(
+                        "Client id {} cannot publish on topic: {}",
+                        client_id, topic
+                    ));
+                    return Ok(client_id.to_string());
+                }
+                let received = stats.message_received();
                 let msg_server: Vec<String> = vec![
                     "publish".to_string(),
                     if unvalue.header.get_dup() { 1 } else { 0 }.to_string(),
@@ -491,8 +1534,12 @@ impl Server {
                     }
                     .to_string(),
                     if unvalue.header.get_retain() { 1 } else { 0 }.to_string(),
-                    String::from_utf8_lossy(&unvalue.variable_header.topic_name).to_string(),
+                    topic,
                     unvalue.payload.message,
+                    mqtt5::encode_properties(&properties.forwarded()),
+                    received.to_string(),
+                    String::new(),
+                    client_id.to_string(),
                 ];
 
                 match tx_server.send(msg_server) {
@@ -505,7 +1552,15 @@ impl Server {
                         if unvalue.header.get_qos() == control_flags::QOS0 {
                             logger.debug("Identified QoS1 flag. PubAck sent".to_string());
                             let packet = Packet::<VariableHeaderPacketIdentifier, Payload>::new();
-                            let packet = packet.puback(packet_id as u16);
+                            let packet = if is_v5 {
+                                packet.puback_v5(
+                                    unvalue.variable_header.packet_identifier,
+                                    reason_codes::SUCCESS,
+                                    Properties::new(),
+                                )
+                            } else {
+                                packet.puback(packet_id as u16)
+                            };
 
                             if let Err(e) = stream.write_all(&packet.value()) {
                                 logger.debug("Client disconnect".to_string());
@@ -525,8 +1580,36 @@ impl Server {
             }
 
             control_type::DISCONNECT => {
-                logger.debug("Disconnect packet received".to_string());
-                logger.info(format!("Peer {:?} will be disconnected ", peer_addr));
+                let _ = logger.log(
+                    Level::Info,
+                    "Disconnect packet received".to_string(),
+                    &LogFields::packet("DISCONNECT", client_id, &peer),
+                );
+                if is_v5 {
+                    let unvalue = Packet::<VariableHeaderReasonCode, Payload>::unvalue(buff);
+                    let reason_code = unvalue.variable_header.reason_code;
+                    if let Some(interval) = unvalue
+                        .variable_header
+                        .properties
+                        .get_u32(property_identifiers::SESSION_EXPIRY_INTERVAL)
+                    {
+                        *client_connections.session_expiry_interval.lock().unwrap() = interval;
+                    }
+                    if reason_code == reason_codes::DISCONNECT_WITH_WILL_MESSAGE {
+                        Server::send_last_will(
+                            hash_server_connections.clone(),
+                            tx_server.clone(),
+                            client_id.to_string(),
+                            &logger,
+                        );
+                    } else {
+                        // a normal disconnection discards the last will
+                        Server::clear_last_will(
+                            hash_server_connections.clone(),
+                            client_id.to_string(),
+                        );
+                    }
+                }
 
                 match stream.shutdown(Shutdown::Both) {
                     Ok(_) => {
@@ -537,11 +1620,17 @@ impl Server {
             }
 
             control_type::SUBSCRIBE => {
-                logger.debug("Suscribe packet received".to_string());
-                logger.debug(format!("Peer mqtt suscribe: {:?}", peer_addr));
+                let _ = logger.log(
+                    Level::Debug,
+                    "Subscribe packet received".to_string(),
+                    &LogFields::packet("SUBSCRIBE", client_id, &peer),
+                );
 
-                let unvalue =
-                    Packet::<VariableHeaderPacketIdentifier, SubscribePayload>::unvalue(buff);
+                let unvalue = if is_v5 {
+                    Packet::<VariableHeaderPacketIdentifier, SubscribePayload>::unvalue_v5(buff)
+                } else {
+                    Packet::<VariableHeaderPacketIdentifier, SubscribePayload>::unvalue(buff)
+                };
                 let packet_identifier = unvalue.variable_header.packet_identifier;
                 let topics = unvalue.payload.topic_filter;
                 let qos_vec = unvalue.payload.qos;
@@ -550,30 +1639,41 @@ impl Server {
                 // enviando al tx del server los topics suscriptos
                 logger.debug("Sending tx server to the subcribed topics".to_string());
                 for (index, topic) in topics.iter().enumerate() {
-                    let msg_server = vec![
-                        "subscribe".to_string(),
-                        client_id.to_string(),
-                        packet_identifier.to_string(),
-                        topic.to_string(),
-                    ];
-                    match tx_server.send(msg_server.clone()) {
-                        Ok(_) => {
-                            logger.debug(format!("Suscribe topic {} sent to server", topic));
-                            qos_result.push(qos_vec[index]);
-                        }
-                        Err(e) => {
-                            logger.debug(format!(
-                                "Error sending suscribe topic {} to server: {}",
-                                topic, e
-                            ));
-                            qos_result.push(suback_return_codes::FAILURE);
-                        }
-                    };
+                    if let Err(e) = validation::subscription_filter(topic, limits) {
+                        logger.debug(format!("Client id {}: {}", client_id, e));
+                        qos_result.push(if is_v5 {
+                            reason_codes::TOPIC_FILTER_INVALID
+                        } else {
+                            suback_return_codes::FAILURE
+                        });
+                        continue;
+                    }
+                    // applied before the suback so the publishes sent after it find the subscription
+                    Server::subscribe(
+                        client_id,
+                        topic,
+                        hash_topics,
+                        hash_shared_subscriptions,
+                        &hash_server_connections,
+                        cluster,
+                        stats,
+                        &logger,
+                    );
+                    if is_v5 {
+                        // subscription options carry more than the qos on MQTT 5
+                        qos_result.push((qos_vec[index] & 0x03).min(1));
+                    } else {
+                        qos_result.push(qos_vec[index]);
+                    }
                 }
                 // enviar el suback
                 logger.debug("Sending suback packet to client".to_string());
                 let packet = Packet::<VariableHeader, Payload>::new();
-                let packet = packet.suback(packet_identifier, qos_result);
+                let packet = if is_v5 {
+                    packet.suback_v5(packet_identifier, qos_result, Properties::new())
+                } else {
+                    packet.suback(packet_identifier, qos_result)
+                };
                 if let Err(e) = stream.write_all(&packet.value()) {
                     logger.debug("Client disconnect".to_string());
                     return Err(Error::new(
@@ -584,36 +1684,70 @@ impl Server {
             }
 
             control_type::UNSUBSCRIBE => {
-                logger.debug("Unsubscribe packet received".to_string());
-                logger.debug(format!("Peer mqtt unsubscribe: {:?}", peer_addr));
+                let _ = logger.log(
+                    Level::Debug,
+                    "Unsubscribe packet received".to_string(),
+                    &LogFields::packet("UNSUBSCRIBE", client_id, &peer),
+                );
 
-                let unvalue =
-                    Packet::<VariableHeaderPacketIdentifier, UnsubscribePayload>::unvalue(buff);
+                let unvalue = if is_v5 {
+                    Packet::<VariableHeaderPacketIdentifier, UnsubscribePayload>::unvalue_v5(buff)
+                } else {
+                    Packet::<VariableHeaderPacketIdentifier, UnsubscribePayload>::unvalue(buff)
+                };
                 let packet_identifier = unvalue.variable_header.packet_identifier;
                 // need to get the client identifier and the topics to unsubscribe
                 let topics = unvalue.payload.topic_filter;
-                topics.iter().for_each(|topic| {
-                    let msg_server = vec![
-                        "unsubscribe".to_string(),
-                        client_id.to_string(),
-                        packet_identifier.to_string(),
-                        topic.to_string(),
-                    ];
-                    tx_server.send(msg_server.clone()).unwrap_or_else(|_| {
-                        panic!("Cannot proccess unsubscribe message {:?}", msg_server)
-                    });
-                });
+                let mut reason_codes_v5 = Vec::new();
+                for topic in topics.iter() {
+                    if let Err(e) = validation::subscription_filter(topic, limits) {
+                        // 3.1.1 has no failure code in the unsuback, the connection is closed
+                        if !is_v5 {
+                            return Err(Server::invalid_packet(
+                                stream,
+                                &logger,
+                                is_v5,
+                                client_id,
+                                reason_codes::TOPIC_FILTER_INVALID,
+                                e,
+                            ));
+                        }
+                        logger.debug(format!("Client id {}: {}", client_id, e));
+                        reason_codes_v5.push(reason_codes::TOPIC_FILTER_INVALID);
+                        continue;
+                    }
+                    Server::unsubscribe(
+                        client_id,
+                        topic,
+                        hash_topics,
+                        hash_shared_subscriptions,
+                        cluster,
+                        &logger,
+                    );
+                    reason_codes_v5.push(reason_codes::SUCCESS);
+                }
 
                 let qos = unvalue.header.get_qos();
-                // send the unsuback if the qos is set to 1
-                if qos == control_flags::QOS0 {
+                let packet = Packet::<VariableHeader, Payload>::new();
+                let packet = if is_v5 {
+                    // MQTT 5 clients always get an unsuback with a reason code per topic
+                    Some(
+                        packet
+                            .unsuback_v5(packet_identifier, reason_codes_v5, Properties::new())
+                            .value(),
+                    )
+                } else if qos == control_flags::QOS0 {
+                    // send the unsuback if the qos is set to 1
                     logger.debug(format!(
                         "Sending unsuback packet to client qos set to: {}",
                         qos
                     ));
-                    let packet = Packet::<VariableHeader, Payload>::new();
-                    let packet = packet.unsuback(packet_identifier);
-                    if let Err(e) = stream.write_all(&packet.value()) {
+                    Some(packet.unsuback(packet_identifier).value())
+                } else {
+                    None
+                };
+                if let Some(packet) = packet {
+                    if let Err(e) = stream.write_all(&packet) {
                         logger.debug("Client disconnect".to_string());
                         return Err(Error::new(
                             ErrorKind::Other,
@@ -623,6 +1757,20 @@ impl Server {
                 }
             }
 
+            control_type::PUBACK => {
+                // [ 0x40, remaining length, packet identifier msb, lsb, ... ]
+                let packet_identifier = (buff[2] as u16) << 8 | buff[3] as u16;
+                logger.debug(format!(
+                    "Puback packet received from client id: {} for packet identifier: {}",
+                    client_id, packet_identifier
+                ));
+                client_connections
+                    .inflight
+                    .lock()
+                    .unwrap()
+                    .remove(&packet_identifier);
+            }
+
             control_type::PINGREQ => {
                 logger.info("PingReq packet received".to_string());
                 logger.debug(format!(
@@ -640,22 +1788,328 @@ impl Server {
                     ));
                 }
             }
-            _ => {
-                logger.debug(format!("control type number: {:?}", control_type::PINGREQ));
-                logger.debug("Id not match with any control packet".to_string());
-                return Err(Error::new(
-                    ErrorKind::Other,
-                    "Error: cannot match with any control type".to_string(),
+            control_type::AUTH if is_v5 => {
+                logger.debug(format!(
+                    "Auth packet received from client id: {}",
+                    client_id
+                ));
+                let packet = Packet::<VariableHeader, Payload>::new();
+                let packet = packet.disconnect_v5(
+                    reason_codes::PROTOCOL_ERROR,
+                    mqtt5::reason_string("Enhanced authentication is not supported"),
+                );
+                let _ = stream.write_all(&packet.value());
+                return Err(Error::new(
+                    ErrorKind::PermissionDenied,
+                    "Error: enhanced authentication is not supported".to_string(),
+                ));
+            }
+
+            _ => {
+                logger.debug(format!("control type number: {:?}", control_type::PINGREQ));
+                logger.debug("Id not match with any control packet".to_string());
+                return Err(Error::new(
+                    ErrorKind::Other,
+                    "Error: cannot match with any control type".to_string(),
+                ));
+            }
+        }
+
+        Ok(client_id.to_string())
+    }
+
+    /// Logs a limit broken by a client, MQTT 5 clients are told why they are disconnected
+    fn limit_violation(
+        stream: &mut dyn Transport,
+        logger: &Logger,
+        is_v5: bool,
+        client_id: &str,
+        violation: Violation,
+    ) -> Error {
+        Server::invalid_packet(
+            stream,
+            logger,
+            is_v5,
+            client_id,
+            violation.reason_code(),
+            Error::new(ErrorKind::PermissionDenied, violation.description()),
+        )
+    }
+
+    /// Logs the error of a packet that closes the connection, MQTT 5 clients get
+    /// a disconnect with reason_code before. Returns error.
+    fn invalid_packet(
+        stream: &mut dyn Transport,
+        logger: &Logger,
+        is_v5: bool,
+        client_id: &str,
+        reason_code: u8,
+        error: Error,
+    ) -> Error {
+        let peer = stream
+            .peer_addr()
+            .map(|peer| peer.to_string())
+            .unwrap_or_default();
+        logger.info(format!(
+            "Closing connection of client id {} ({}): {}",
+            client_id, peer, error
+        ));
+        if is_v5 {
+            let packet = Packet::<VariableHeader, Payload>::new()
+                .disconnect_v5(reason_code, mqtt5::reason_string(&error.to_string()));
+            let _ = stream.write_all(&packet.value());
+        }
+        error
+    }
+
+    /// Tells a MQTT 5 client why its connection is refused, 3.1.1 clients are just disconnected
+    fn refuse_connection(
+        stream: &mut dyn Transport,
+        stats: &BrokerStats,
+        level: u8,
+        reason_code: u8,
+        reason: &str,
+    ) {
+        stats.auth_failure();
+        if level == protocol_level::MQTT_5 {
+            let packet = Packet::<VariableHeader, Payload>::new();
+            let packet = packet.connack_v5(0, reason_code, mqtt5::reason_string(reason));
+            let _ = stream.write_all(&packet.value());
+        }
+    }
+
+    fn is_mqtt5_client(
+        hash_server_connections: &Arc<Mutex<HashServerConnections>>,
+        client_id: &str,
+    ) -> bool {
+        hash_server_connections
+            .lock()
+            .unwrap()
+            .get(client_id)
+            .map(|e| *e.0.protocol_level.lock().unwrap() == protocol_level::MQTT_5)
+            .unwrap_or(false)
+    }
+
+    /// Counts a message sent to the channel of client_id until it is written,
+    /// QoS 1 messages stay inflight until the client acknowledges packet_identifier
+    fn message_queued(
+        hash_server_connections: &Arc<Mutex<HashServerConnections>>,
+        client_id: &str,
+        packet_identifier: Option<u16>,
+    ) {
+        if let Some(e) = hash_server_connections.lock().unwrap().get(client_id) {
+            *e.0.queued.lock().unwrap() += 1;
+            if let Some(packet_identifier) = packet_identifier {
+                e.0.inflight.lock().unwrap().insert(packet_identifier);
+            }
+        }
+    }
+
+    /// Queues a message sent with qos for client_id and counts it, returns true
+    /// when the queue of the client has one more message
+    fn enqueue(
+        tx: &OutboundSender,
+        client_id: &str,
+        packet: Vec<u8>,
+        qos: u8,
+        expires: Option<Instant>,
+        stats: &BrokerStats,
+        logger: &Logger,
+    ) -> bool {
+        Server::count_queued(tx.send(packet, qos, expires), client_id, stats, logger)
+    }
+
+    /// Counts the result of queueing a message for client_id, returns true when the
+    /// queue of the client has one more message
+    fn count_queued(
+        result: std::result::Result<Queued, QueueError>,
+        client_id: &str,
+        stats: &BrokerStats,
+        logger: &Logger,
+    ) -> bool {
+        match result {
+            Ok(Queued::Added) => {
+                stats.message_sent();
+                true
+            }
+            Ok(Queued::ReplacedOldest) => {
+                logger.debug(format!(
+                    "Queue of client id {} is full, oldest QoS 0 message dropped",
+                    client_id
+                ));
+                stats.message_sent();
+                stats.message_dropped(true);
+                false
+            }
+            Err(e) => {
+                logger.debug(format!(
+                    "Cannot send message to client id {}: {}",
+                    client_id, e
+                ));
+                if e != QueueError::Closed {
+                    stats.message_dropped(false);
+                }
+                false
+            }
+        }
+    }
+
+    /// Topic, qos and payload size of the publish packets queued for connection
+    fn queued_messages(connection: &HandleClientConnections) -> Vec<QueuedMessage> {
+        let is_v5 = *connection.protocol_level.lock().unwrap() == protocol_level::MQTT_5;
+        let queued = connection.tx.lock().unwrap().queued();
+        queued
+            .into_iter()
+            .filter(|(packet, _)| {
+                packet.first().map(|byte| byte & 0xF0) == Some(control_type::PUBLISH)
+            })
+            .map(|(packet, qos)| {
+                let publish = if is_v5 {
+                    Packet::<VariableHeaderPublish, PublishPayload>::unvalue_v5(packet)
+                } else {
+                    Packet::<VariableHeaderPublish, PublishPayload>::unvalue(packet)
+                };
+                QueuedMessage {
+                    topic: String::from_utf8_lossy(&publish.variable_header.topic_name).to_string(),
+                    qos,
+                    payload_size: publish.payload.message.len(),
+                }
+            })
+            .collect()
+    }
+
+    /// Drops the messages queued for connection that expired before being written,
+    /// they are no longer queued nor inflight. Returns the number of messages dropped.
+    fn drop_expired(connection: &HandleClientConnections, stats: &BrokerStats) -> usize {
+        let expired = connection.tx.lock().unwrap().purge_expired();
+        if expired.is_empty() {
+            return 0;
+        }
+        let mut inflight = connection.inflight.lock().unwrap();
+        for (packet, qos) in &expired {
+            if let Some(packet_identifier) = publish_packet_identifier(packet).filter(|_| *qos > 0)
+            {
+                inflight.remove(&packet_identifier);
+            }
+        }
+        let mut queued = connection.queued.lock().unwrap();
+        *queued = queued.saturating_sub(expired.len());
+        stats.messages_expired(expired.len(), true);
+        expired.len()
+    }
+
+    /// Writes a publish to the queue of every subscriber, publish builds the packet
+    /// once for the MQTT 5 (true) and once for the 3.1.1 (false) subscribers
+    #[allow(clippy::too_many_arguments)]
+    fn send_to_subscribers<F>(
+        hash_server_connections: &Arc<Mutex<HashServerConnections>>,
+        subscribers: &[(String, OutboundSender)],
+        stats: &BrokerStats,
+        publish: F,
+        qos: u8,
+        packet_identifier: Option<u16>,
+        expires: Option<Instant>,
+        logger: &Logger,
+    ) where
+        F: Fn(bool) -> Vec<u8>,
+    {
+        let mut packets: [Option<Vec<u8>>; 2] = [None, None];
+        // the connections are not locked while queueing, a QoS 1 send can wait for room
+        let connections: Vec<Option<HandleClientConnections>> = {
+            let connections = hash_server_connections.lock().unwrap();
+            subscribers
+                .iter()
+                .map(|(client_id, _)| connections.get(client_id).map(|e| e.0.clone()))
+                .collect()
+        };
+        // the subscribers with a full queue go last so they do not delay the others
+        let (ready, full): (Vec<_>, Vec<_>) = subscribers
+            .iter()
+            .zip(connections)
+            .partition(|((_, tx), _)| !tx.is_full());
+        for ((client_id, tx), connection) in ready.into_iter().chain(full) {
+            let is_v5 = connection.as_ref().is_some_and(|connection| {
+                *connection.protocol_level.lock().unwrap() == protocol_level::MQTT_5
+            });
+            let packet = packets[is_v5 as usize].get_or_insert_with(|| publish(is_v5));
+            if !Server::enqueue(tx, client_id, packet.clone(), qos, expires, stats, logger) {
+                continue;
+            }
+            if let Some(connection) = connection {
+                *connection.queued.lock().unwrap() += 1;
+                if let Some(packet_identifier) = packet_identifier {
+                    connection
+                        .inflight
+                        .lock()
+                        .unwrap()
+                        .insert(packet_identifier);
+                }
+            }
+        }
+    }
+
+    /// Delivers a publish to one member of each shared group subscribed to topic,
+    /// publish builds the packet for a MQTT 5 (true) or 3.1.1 (false) member
+    #[allow(clippy::too_many_arguments)]
+    fn send_to_shared_groups<F>(
+        hash_shared_subscriptions: &Arc<Mutex<HashSharedSubscriptions>>,
+        hash_server_connections: &Arc<Mutex<HashServerConnections>>,
+        topic: &str,
+        stats: &BrokerStats,
+        publish: F,
+        qos: u8,
+        packet_identifier: Option<u16>,
+        expires: Option<Instant>,
+        logger: &Logger,
+    ) where
+        F: Fn(bool) -> Vec<u8>,
+    {
+        // the shared subscriptions and the connections are never locked together:
+        // the members are read, their state is taken and then one is picked per group
+        let member_ids = shared_subscription::matching_members(
+            &hash_shared_subscriptions.lock().unwrap(),
+            topic,
+        );
+        if member_ids.is_empty() {
+            return;
+        }
+        let members: HashMap<String, (bool, bool)> = {
+            let connections = hash_server_connections.lock().unwrap();
+            member_ids
+                .into_iter()
+                .filter_map(|client_id| {
+                    let connection = &connections.get(&client_id)?.0;
+                    let connected = *connection.connected.lock().unwrap();
+                    let is_v5 =
+                        *connection.protocol_level.lock().unwrap() == protocol_level::MQTT_5;
+                    Some((client_id, (connected, is_v5)))
+                })
+                .collect()
+        };
+        let picked = shared_subscription::next_members(
+            &mut hash_shared_subscriptions.lock().unwrap(),
+            topic,
+            |client_id| {
+                members
+                    .get(client_id)
+                    .is_some_and(|(connected, _)| *connected)
+            },
+        );
+        for (group, client_id, tx) in picked {
+            let is_v5 = members.get(&client_id).is_some_and(|(_, is_v5)| *is_v5);
+            if Server::enqueue(&tx, &client_id, publish(is_v5), qos, expires, stats, logger) {
+                Server::message_queued(hash_server_connections, &client_id, packet_identifier);
+                logger.debug(format!(
+                    "Shared message for topic: {} sent to client id {} of group {}",
+                    topic, client_id, group
                 ));
             }
         }
-
-        Ok(client_id.to_string())
     }
 
     fn send_last_will(
         hash_server_connections: Arc<Mutex<HashServerConnections>>,
-        tx_server: Sender<Vec<String>>,
+        tx_server: DispatchSender,
         client_id: String,
         logger: &Logger,
     ) {
@@ -686,7 +2140,7 @@ impl Server {
 
     fn act_on_last_will(
         hash_server_connections: Arc<Mutex<HashServerConnections>>,
-        tx_server: Sender<Vec<String>>,
+        tx_server: DispatchSender,
         client_id: String,
         logger: &Logger,
     ) {
@@ -711,6 +2165,10 @@ impl Server {
                         0.to_string(),
                         will_tuple.0,
                         will_tuple.1,
+                        String::new(),
+                        String::new(),
+                        String::new(),
+                        client_id.to_string(),
                     ];
                     match tx_server.send(msg_server) {
                         Ok(_) => {
@@ -742,220 +2200,931 @@ impl Server {
         Ok(hash.contains_key(&client_id))
     }
 
-    fn _get_hash_topics(topic: &str, hash_topics: Arc<Mutex<HashTopics>>) -> Result<bool> {
-        let hash = hash_topics.lock().unwrap();
-        Ok(hash.contains_key(topic))
+    /// Subscribes client_id to topic and queues the last retained message of each matching
+    /// topic. The clients subscribe from their own connection, which cannot wait for room
+    /// in its queue, so the retained messages that do not fit are dropped.
+    #[allow(clippy::too_many_arguments)]
+    fn subscribe(
+        client_id: &str,
+        topic: &str,
+        hash_topics: &TopicTable,
+        hash_shared_subscriptions: &Arc<Mutex<HashSharedSubscriptions>>,
+        hash_server_connections: &Arc<Mutex<HashServerConnections>>,
+        cluster: Option<&Cluster>,
+        stats: &BrokerStats,
+        logger: &Logger,
+    ) {
+        let is_v5 = Server::is_mqtt5_client(hash_server_connections, client_id);
+        let value = hash_server_connections
+            .lock()
+            .unwrap()
+            .get(client_id)
+            .map(|e| e.0.tx.clone());
+        let value = match value {
+            Some(value) if !client_id.is_empty() => value,
+            _ => {
+                logger.debug("Cannot find client Identified".to_string());
+                return;
+            }
+        };
+        // the sender is cloned so its lock is not held while queueing
+        let tx = &value.lock().unwrap().clone();
+        // shared subscriptions do not get the retained message
+        if let Ok(Some((group, filter))) = parse_shared_topic(topic) {
+            shared_subscription::subscribe(
+                &mut hash_shared_subscriptions.lock().unwrap(),
+                &group,
+                &filter,
+                client_id,
+                tx.to_owned(),
+            );
+            logger.debug(format!(
+                "Client id {} joined shared group {} for topic: {}",
+                client_id, group, filter
+            ));
+            if let Some(cluster) = cluster {
+                cluster.subscribed(&filter);
+            }
+            return;
+        }
+        if let Some(cluster) = cluster {
+            cluster.subscribed(topic);
+        }
+        // send to this client the last retained message of each matching topic
+        for (topic, retained) in hash_topics.subscribe(topic, client_id, tx.to_owned()) {
+            logger.debug(format!(
+                "Sending retain message for topic: {} message: {}",
+                topic, retained
+            ));
+            let packet = Packet::<VariableHeader, Payload>::new();
+            let packet = packet.publish(0, 1, 1, 0, topic, retained);
+            let packet = if is_v5 {
+                packet.with_properties(Properties::new())
+            } else {
+                packet
+            };
+            let result = tx.try_send(packet.value(), 1, None);
+            if Server::count_queued(result, client_id, stats, logger) {
+                Server::message_queued(hash_server_connections, client_id, Some(0));
+            }
+        }
+    }
+
+    /// Removes the subscription of client_id to topic
+    fn unsubscribe(
+        client_id: &str,
+        topic: &str,
+        hash_topics: &TopicTable,
+        hash_shared_subscriptions: &Arc<Mutex<HashSharedSubscriptions>>,
+        cluster: Option<&Cluster>,
+        logger: &Logger,
+    ) {
+        let filter = if let Ok(Some((group, filter))) = parse_shared_topic(topic) {
+            shared_subscription::unsubscribe(
+                &mut hash_shared_subscriptions.lock().unwrap(),
+                &group,
+                &filter,
+                client_id,
+            );
+            filter
+        } else {
+            if !client_id.is_empty() {
+                hash_topics.unsubscribe(topic, client_id);
+            }
+            topic.to_string()
+        };
+        if let Some(cluster) = cluster {
+            Server::interest_lost(cluster, &[filter], hash_topics, hash_shared_subscriptions);
+        }
+        logger.debug(format!(
+            "Unsubscribed topic_name: {} for client id: {}",
+            topic, client_id
+        ));
+    }
+
+    /// Removes every subscription of client_id
+    fn clean_session(
+        client_id: &str,
+        hash_topics: &TopicTable,
+        hash_shared_subscriptions: &Arc<Mutex<HashSharedSubscriptions>>,
+        cluster: Option<&Cluster>,
+        logger: &Logger,
+    ) {
+        if client_id.is_empty() {
+            return;
+        }
+        let mut emptied = hash_topics.unsubscribe_all(client_id);
+        emptied.extend(shared_subscription::unsubscribe_all(
+            &mut hash_shared_subscriptions.lock().unwrap(),
+            client_id,
+        ));
+        if let Some(cluster) = cluster {
+            Server::interest_lost(cluster, &emptied, hash_topics, hash_shared_subscriptions);
+        }
+        logger.debug(format!(
+            "Request Clean session for client id: {} success",
+            client_id
+        ));
+    }
+
+    /// Tells the cluster about the topics that have no subscribers left on this node
+    fn interest_lost(
+        cluster: &Cluster,
+        topics: &[String],
+        hash_topics: &TopicTable,
+        hash_shared_subscriptions: &Arc<Mutex<HashSharedSubscriptions>>,
+    ) {
+        for topic in topics {
+            if !hash_topics.has_subscribers(topic)
+                && !hash_shared_subscriptions
+                    .lock()
+                    .unwrap()
+                    .contains_key(topic)
+            {
+                cluster.unsubscribed(topic);
+            }
+        }
     }
 
+    /// Starts a dispatcher thread for each receiver of rx_server, the receivers are
+    /// taken so the dispatchers are only started once
+    #[allow(clippy::too_many_arguments)]
     fn message_handler(
-        _tx_server: Arc<Mutex<Sender<Vec<String>>>>,
-        rx_server: Arc<Mutex<Receiver<Vec<String>>>>,
-        hash_topics: Arc<Mutex<HashTopics>>,
+        rx_server: Arc<Mutex<Vec<Receiver<Command>>>>,
+        hash_topics: Arc<TopicTable>,
+        hash_shared_subscriptions: Arc<Mutex<HashSharedSubscriptions>>,
         hash_server_connections: Arc<Mutex<HashServerConnections>>,
+        bridges: Vec<Arc<Bridge>>,
+        cluster: Option<Arc<Cluster>>,
+        recorder: Option<Arc<Recorder>>,
+        expiry: Arc<ExpiryRules>,
+        stats: Arc<BrokerStats>,
         logger: Arc<Logger>,
-    ) {
-        let _handle = thread::Builder::new()
-            .name("Thread: Message handler".to_string())
-            .spawn(move || loop {
-                let rx_server_guard = rx_server.lock().unwrap();
-                match rx_server_guard.recv() {
-                    Ok(msg) => {
-                        logger.debug(format!("Thread message handler received topic: {:?}", msg));
-                        let packet_type = msg[0].as_str();
-                        match packet_type {
-                            // si es publish debe tomar el array de hash topic, iterarlo y cada tx de ese array debe ejercutar send con el packet valuede un publish packet
-                            "publish" => {
-                                // message = [ packet_type, dup, qos, retain, topic, message ]
-                                let dup = msg[1].parse::<u8>().unwrap();
-                                let qos = msg[2].parse::<u8>().unwrap();
-                                let retain = msg[3].parse::<u8>().unwrap();
-                                let topic = &msg[4];
-                                let message = &msg[5];
-                                // create new packet identifier
-                                let mut rng = rand::thread_rng();
-                                let packet_identifier: u16 = rng.gen();
-                                if !topic.is_empty() {
-                                    hash_topics
-                                        .lock()
-                                        .unwrap()
-                                        .entry(topic.to_string())
-                                        .and_modify(|vector| {
-                                            logger.debug(format!(
-                                                "Found {} subscriptors for topic: {}",
-                                                vector.0.len(),
-                                                topic
-                                            ));
-                                            if retain == 1 {
-                                                logger.debug(format!(
-                                                    "Saving Retain message for topic: {}",
-                                                    topic
-                                                ));
-                                                vector.1 = message.to_string();
-                                            }
-                                            for val in &vector.0 {
-                                                let tx = val.1.clone();
-                                                let packet =
-                                                    Packet::<VariableHeader, Payload>::new();
-                                                let packet = packet.publish(
-                                                    dup,
-                                                    qos,
-                                                    retain,
-                                                    packet_identifier,
-                                                    topic.to_string(),
-                                                    message.to_string(),
-                                                );
-                                                tx.send(packet.value()).unwrap_or_else(|_| {
-                                                    panic!(
-                                                        "Cannot proccess publish message {:?}",
-                                                        msg
-                                                    )
-                                                });
-                                            }
-                                        })
-                                        .or_insert_with(|| {
-                                            logger.debug(format!(
-                                                "No subscriptors for topic: {}",
-                                                topic
-                                            ));
-                                            if retain == 1 {
-                                                logger.debug(format!(
-                                                    "Saving Retain message for topic: {}",
-                                                    topic
-                                                ));
-                                                return (vec![], message.to_string())
-                                            }
-                                            (vec![], "".to_string())
-                                        });
-                                } else {
-                                    logger.debug(
-                                        "Publish on server received a Topic that is is empty"
-                                            .to_string(),
-                                    );
-                                }
-                            }
-
-                            // si es subscribe debe guardar el topic name en el hash de topic y asignar el tx obtenido mediante el peer addr sumistrado en msg con el hash de connection
-                            // si ya se encuentra el topic name debe hacer push del tx
-                            "subscribe" => {
-                                // message = [ packet_type, client_id, packet_id, topic_name ]
-                                let client_id = msg[1].as_str();
-                                let _packet_id = (msg[2].as_bytes()[0] as u16) << 8
-                                    | msg[2].as_bytes()[1] as u16;
-                                let topic = &msg[3];
-
-                                if !client_id.is_empty() {
-                                    let value = hash_server_connections
-                                        .lock()
-                                        .unwrap()
-                                        .get(client_id)
-                                        .unwrap()
-                                        .0
-                                        .tx
-                                        .clone();
-                                    let tx = &*value.lock().unwrap();
-                                    hash_topics
-                                        .lock()
-                                        .unwrap()
-                                        .entry(topic.to_string())
-                                        .and_modify(|vector| {
-                                            vector.0.push((client_id.to_string(), tx.to_owned()));
-
-                                            if !vector.1.is_empty() {
-                                                logger.debug(format!(
-                                                    "Sending retain message for topic: {} message: {}",
-                                                    topic,
-                                                    vector.1.clone()
-                                                ));
-                                                // send to this client the last retained message
-                                                let packet =
-                                                    Packet::<VariableHeader, Payload>::new();
-                                                let packet = packet.publish(
-                                                    0,
-                                                    1,
-                                                    1,
-                                                    0,
-                                                    topic.to_string(),
-                                                    vector.1.to_string(),
-                                                );
-                                                tx.send(packet.value()).unwrap_or_else(|_| {
-                                                    panic!(
-                                                        "Cannot proccess subscribe message {:?}",
-                                                        msg
-                                                    )
-                                                });
-                                            }
-
-                                        })
-                                        .or_insert_with(|| {
-                                            (vec![(client_id.to_string(), tx.to_owned())], "".to_string())
-                                        });
-                                } else {
-                                    logger.debug("Cannot find client Identified".to_string());
-                                }
-                            }
+    ) -> Result<()> {
+        for (index, rx) in rx_server.lock().unwrap().drain(..).enumerate() {
+            let hash_topics = hash_topics.clone();
+            let hash_shared_subscriptions = hash_shared_subscriptions.clone();
+            let hash_server_connections = hash_server_connections.clone();
+            let bridges = bridges.clone();
+            let cluster = cluster.clone();
+            let recorder = recorder.clone();
+            let expiry = expiry.clone();
+            let stats = stats.clone();
+            let logger = logger.clone();
+            thread::Builder::new()
+                .name(format!("Thread: Message handler {}", index))
+                .spawn(move || {
+                    for msg in rx {
+                        Server::dispatch(
+                            msg,
+                            &hash_topics,
+                            &hash_shared_subscriptions,
+                            &hash_server_connections,
+                            &bridges,
+                            cluster.as_deref(),
+                            recorder.as_deref(),
+                            &expiry,
+                            &stats,
+                            &logger,
+                        );
+                    }
+                    logger.debug(format!("Thread message handler {} stopped", index));
+                })?;
+        }
+        Ok(())
+    }
 
-                            "unsubscribe" => {
-                                // unsuscribe client_id packet_identifier topic
-                                let client_id = msg[1].as_str();
-                                let _packet_id = (msg[2].as_bytes()[0] as u16) << 8
-                                    | msg[2].as_bytes()[1] as u16;
-                                let topic = &msg[3];
-
-                                if !client_id.is_empty() {
-                                    hash_topics
-                                        .lock()
-                                        .unwrap()
-                                        .entry(topic.to_string())
-                                        .and_modify(|vector| {
-                                            vector.0.retain(|entry| entry.0 != client_id);
-                                        });
-                                }
-                                logger.debug(format!(
-                                    "Unsubscribed topic_name: {} for client id: {}",
-                                    topic, client_id
-                                ));
-                            }
-                            "request_clean_session" => {
-                                // request_clean_session client_id 
-                                let client_id = msg[1].as_str();
-
-                                // unsubscribe client_id from all topics
-                                if !client_id.is_empty() {
-                                    hash_topics
-                                        .lock()
-                                        .unwrap()
-                                        .retain(|_, vector| {
-                                            vector.0.retain(|entry| entry.0 != client_id);
-                                            !vector.0.is_empty()
-                                        });
-                                    logger.debug(format!(
-                                        "Request Clean session for client id: {} success",
-                                        client_id
-                                    ));
-                                }
-                            }
-                            _ => {
+    #[allow(clippy::too_many_arguments)]
+    fn dispatch(
+        msg: Command,
+        hash_topics: &TopicTable,
+        hash_shared_subscriptions: &Arc<Mutex<HashSharedSubscriptions>>,
+        hash_server_connections: &Arc<Mutex<HashServerConnections>>,
+        bridges: &[Arc<Bridge>],
+        cluster: Option<&Cluster>,
+        recorder: Option<&Recorder>,
+        expiry: &ExpiryRules,
+        stats: &BrokerStats,
+        logger: &Logger,
+    ) {
+        logger.debug(format!("Thread message handler received topic: {:?}", msg));
+        let packet_type = msg[0].as_str();
+        match packet_type {
+            // si es publish debe tomar los suscriptores del topic y ejecutar send en cada tx con el packet value de un publish packet
+            "publish" => {
+                // message = [ packet_type, dup, qos, retain, topic, message, properties, received, origin, client_id ]
+                let properties =
+                    mqtt5::decode_properties(msg.get(6).map(|p| p.as_str()).unwrap_or(""));
+                let dup = msg[1].parse::<u8>().unwrap();
+                let qos = msg[2].parse::<u8>().unwrap();
+                let retain = msg[3].parse::<u8>().unwrap();
+                let topic = &msg[4];
+                let message = &msg[5];
+                // create new packet identifier
+                let mut rng = rand::thread_rng();
+                let packet_identifier: u16 = rng.gen();
+                if !topic.is_empty() {
+                    if retain == 1 {
+                        logger.debug(format!("Saving Retain message for topic: {}", topic));
+                    }
+                    let expires = expiry.expires(topic, &properties);
+                    let subscribers = hash_topics.publish(
+                        topic,
+                        Some(message.as_str()).filter(|_| retain == 1),
+                        expires,
+                    );
+                    logger.debug(format!(
+                        "Found {} subscriptors for topic: {}",
+                        subscribers.len(),
+                        topic
+                    ));
+                    let publish = |is_v5: bool| {
+                        let packet = Packet::<VariableHeader, Payload>::new().publish(
+                            dup,
+                            qos,
+                            retain,
+                            packet_identifier,
+                            topic.to_string(),
+                            message.to_string(),
+                        );
+                        if is_v5 {
+                            packet.with_properties(properties.clone()).value()
+                        } else {
+                            packet.value()
+                        }
+                    };
+                    let packet_identifier = Some(packet_identifier).filter(|_| qos > 0);
+                    Server::send_to_subscribers(
+                        hash_server_connections,
+                        &subscribers,
+                        stats,
+                        publish,
+                        qos,
+                        packet_identifier,
+                        expires,
+                        logger,
+                    );
+                    Server::send_to_shared_groups(
+                        hash_shared_subscriptions,
+                        hash_server_connections,
+                        topic,
+                        stats,
+                        publish,
+                        qos,
+                        packet_identifier,
+                        expires,
+                        logger,
+                    );
+                    if let Some(recorder) = recorder {
+                        if let Err(e) = recorder.record(topic, message, qos, retain == 1) {
+                            logger
+                                .error(format!("Cannot record message of topic {}: {}", topic, e));
+                        }
+                    }
+                    // the publishes received from a bridge or another node carry its name,
+                    // the ones of other nodes were already sent to the cluster and bridges there
+                    let origin = msg.get(8).map(|o| o.as_str()).filter(|o| !o.is_empty());
+                    if origin != Some(CLUSTER_ORIGIN) {
+                        if let Some(cluster) = cluster {
+                            cluster.publish(topic, message, qos, retain == 1);
+                        }
+                        for bridge in bridges {
+                            if !bridge.forward(topic, message, retain == 1, origin) {
                                 logger.debug(format!(
-                                    "Not received any known packet type, received: {}",
-                                    packet_type
+                                    "Queue of bridge {} is full, message for topic {} dropped",
+                                    bridge.name(),
+                                    topic
                                 ));
+                                stats.message_dropped(false);
                             }
-                        };
-                        // logger.debug("Thread message handler update topic hash".to_string());
-                        // for (key, value) in hash_topics.lock().unwrap().iter() {
-                        //     logger.debug(format!("{:?} - {:#?}", key, value));
-                        //     logger.debug(format!("{:?}", value));
-                        //     logger.debug("printing following topics".to_string());
-                        // }
-                        // println!("ending printing topics");
-                    }
-                    Err(e) => {
-                        logger.debug(format!("Thread message handler got an error: {:?}", e));
-                        break;
+                        }
                     }
-                };
-                drop(rx_server_guard);
-                thread::sleep(Duration::from_millis(10));
-            });
+                } else {
+                    logger.debug("Publish on server received a Topic that is is empty".to_string());
+                }
+                // only client publishes carry the time they were received
+                if let Some(received) = msg.get(7).and_then(|r| r.parse().ok()) {
+                    stats.publish_delivered(received);
+                }
+            }
+
+            _ => {
+                logger.debug(format!(
+                    "Not received any known packet type, received: {}",
+                    packet_type
+                ));
+            }
+        };
+    }
+}
+
+impl AdminBackend for Server {
+    fn clients(&self) -> Vec<ClientInfo> {
+        // the topics are read before locking hash_server_connections, as the dispatchers do
+        let mut subscriptions: HashMap<String, Vec<String>> = HashMap::new();
+        self.hash_topics.for_each(|topic, subscribers, _| {
+            for (client_id, _) in subscribers {
+                subscriptions
+                    .entry(client_id.to_string())
+                    .or_default()
+                    .push(topic.to_string());
+            }
+        });
+        for (filter, groups) in self.hash_shared_subscriptions.lock().unwrap().iter() {
+            for group in groups {
+                for client_id in group.members() {
+                    subscriptions
+                        .entry(client_id.to_string())
+                        .or_default()
+                        .push(format!("{}{}/{}", SHARED_PREFIX, group.name, filter));
+                }
+            }
+        }
+        let mut clients: Vec<ClientInfo> = self
+            .hash_server_connections
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(client_id, (connection, _))| {
+                let mut client_subscriptions = subscriptions.remove(client_id).unwrap_or_default();
+                client_subscriptions.sort();
+                ClientInfo {
+                    client_id: client_id.to_string(),
+                    peer: connection.peer.lock().unwrap().clone(),
+                    user_name: connection.user_name.lock().unwrap().clone(),
+                    protocol_level: *connection.protocol_level.lock().unwrap(),
+                    connected: *connection.connected.lock().unwrap(),
+                    subscriptions: client_subscriptions,
+                    inflight: connection
+                        .inflight
+                        .lock()
+                        .unwrap()
+                        .iter()
+                        .copied()
+                        .collect(),
+                    queued: Server::queued_messages(connection),
+                }
+            })
+            .collect();
+        clients.sort_by(|a, b| a.client_id.cmp(&b.client_id));
+        clients
+    }
+
+    fn topics(&self) -> Vec<TopicInfo> {
+        let mut topics: Vec<TopicInfo> = Vec::new();
+        self.hash_topics.for_each(|topic, subscribers, retained| {
+            topics.push(TopicInfo {
+                topic: topic.to_string(),
+                subscribers: subscribers.iter().map(|s| s.0.to_string()).collect(),
+                retained: Some(retained.to_string()).filter(|r| !r.is_empty()),
+            })
+        });
+        for (filter, groups) in self.hash_shared_subscriptions.lock().unwrap().iter() {
+            for group in groups {
+                topics.push(TopicInfo {
+                    topic: format!("{}{}/{}", SHARED_PREFIX, group.name, filter),
+                    subscribers: group.members().iter().map(|m| m.to_string()).collect(),
+                    retained: None,
+                });
+            }
+        }
+        topics.sort_by(|a, b| a.topic.cmp(&b.topic));
+        topics
+    }
+
+    fn kick(&self, client_id: &str) -> bool {
+        match self.hash_server_connections.lock().unwrap().get(client_id) {
+            Some((connection, _)) if *connection.connected.lock().unwrap() => {
+                *connection.kicked.lock().unwrap() = Some(reason_codes::ADMINISTRATIVE_ACTION);
+                connection.tx.lock().unwrap().wake();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn delete_retained(&self, topic: &str) -> bool {
+        if let Some(cluster) = &self.cluster {
+            cluster.delete_retained(topic);
+        }
+        self.hash_topics.delete_retained(topic)
+    }
+
+    fn traced(&self) -> Vec<String> {
+        self.tracer.targets()
+    }
+
+    fn trace(&self, target: &str, traced: bool) -> bool {
+        self.tracer.set_traced(target, traced)
+    }
+
+    fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    fn publish(&self, topic: &str, message: &str, qos: u8, retain: bool) -> Result<()> {
+        // message = [ packet_type, dup, qos, retain, topic, message, properties ]
+        let msg_server = vec![
+            "publish".to_string(),
+            0.to_string(),
+            qos.to_string(),
+            (retain as u8).to_string(),
+            topic.to_string(),
+            message.to_string(),
+            String::new(),
+        ];
+        self.tx_server
+            .lock()
+            .unwrap()
+            .send(msg_server)
+            .map_err(|e| Error::new(ErrorKind::BrokenPipe, e.to_string()))
+    }
+}
+
+impl ClusterBackend for Server {
+    fn interest(&self) -> Vec<String> {
+        let mut topics = Vec::new();
+        self.hash_topics.for_each(|topic, subscribers, _| {
+            if !subscribers.is_empty() {
+                topics.push(topic.to_string());
+            }
+        });
+        topics.extend(
+            self.hash_shared_subscriptions
+                .lock()
+                .unwrap()
+                .keys()
+                .cloned(),
+        );
+        topics
+    }
+
+    fn retained(&self) -> Vec<(String, String)> {
+        let mut retained = Vec::new();
+        self.hash_topics.for_each(|topic, _, message| {
+            if !message.is_empty() {
+                retained.push((topic.to_string(), message.to_string()));
+            }
+        });
+        retained
+    }
+
+    fn publish(&self, topic: &str, message: &str, qos: u8, retain: bool) {
+        let received = self.stats.message_received();
+        // message = [ packet_type, dup, qos, retain, topic, message, properties, received, origin ]
+        let msg_server = vec![
+            "publish".to_string(),
+            0.to_string(),
+            qos.to_string(),
+            (retain as u8).to_string(),
+            topic.to_string(),
+            message.to_string(),
+            String::new(),
+            received.to_string(),
+            CLUSTER_ORIGIN.to_string(),
+        ];
+        if let Err(e) = self.tx_server.lock().unwrap().send(msg_server) {
+            self.logger
+                .error(format!("Cannot dispatch publish of the cluster: {}", e));
+        }
+    }
+
+    fn store_retained(&self, topic: &str, message: &str) {
+        if message.is_empty() {
+            self.hash_topics.delete_retained(topic);
+        } else {
+            self.hash_topics.publish(topic, Some(message), None);
+        }
+    }
+
+    fn take_over(&self, client_id: &str) -> Option<Vec<String>> {
+        // removed without its last will, the client is still online on the other node
+        let (connection, _) = self
+            .hash_server_connections
+            .lock()
+            .unwrap()
+            .remove(client_id)?;
+        *connection.kicked.lock().unwrap() = Some(reason_codes::SESSION_TAKEN_OVER);
+        connection.tx.lock().unwrap().wake();
+
+        let mut topics = Vec::new();
+        self.hash_topics.for_each(|topic, subscribers, _| {
+            if subscribers.iter().any(|(id, _)| id == client_id) {
+                topics.push(topic.to_string());
+            }
+        });
+        for (filter, groups) in self.hash_shared_subscriptions.lock().unwrap().iter() {
+            for group in groups {
+                if group.members().contains(&client_id) {
+                    topics.push(format!("{}{}/{}", SHARED_PREFIX, group.name, filter));
+                }
+            }
+        }
+        Server::clean_session(
+            client_id,
+            &self.hash_topics,
+            &self.hash_shared_subscriptions,
+            self.cluster.as_deref(),
+            &self.logger,
+        );
+        self.logger.info(format!(
+            "Session of client id {} taken over by another node",
+            client_id
+        ));
+        Some(topics)
+    }
+
+    fn resume(&self, client_id: &str, topics: &[String]) {
+        let connected = self
+            .hash_server_connections
+            .lock()
+            .unwrap()
+            .get(client_id)
+            .is_some_and(|(connection, _)| *connection.connected.lock().unwrap());
+        if !connected {
+            return;
+        }
+        for topic in topics {
+            Server::subscribe(
+                client_id,
+                topic,
+                &self.hash_topics,
+                &self.hash_shared_subscriptions,
+                &self.hash_server_connections,
+                self.cluster.as_deref(),
+                &self.stats,
+                &self.logger,
+            );
+        }
+    }
+}
+
+/// Unique client identifier for a client that connects with an empty one
+fn assign_client_identifier(connections: &HashServerConnections) -> String {
+    let mut rng = rand::thread_rng();
+    loop {
+        // 20 letters and digits, like the identifiers every 3.1.1 server accepts
+        let client_id = format!("auto{:016x}", rng.gen::<u64>());
+        if !connections.contains_key(&client_id) {
+            return client_id;
+        }
+    }
+}
+
+/// Packet identifier of an encoded publish packet, None when it has no identifier
+fn publish_packet_identifier(packet: &[u8]) -> Option<u16> {
+    if packet.first()? & 0xF0 != control_type::PUBLISH || packet[0] & 0x06 == 0 {
+        return None;
+    }
+    // the remaining length takes up to 4 bytes, the last one without the 0x80 bit
+    let length_bytes = packet[1..]
+        .iter()
+        .take(4)
+        .position(|byte| byte & 0x80 == 0)?
+        + 1;
+    let topic_start = 1 + length_bytes;
+    let topic_length =
+        u16::from_be_bytes([*packet.get(topic_start)?, *packet.get(topic_start + 1)?]);
+    let identifier = topic_start + 2 + topic_length as usize;
+    Some(u16::from_be_bytes([
+        *packet.get(identifier)?,
+        *packet.get(identifier + 1)?,
+    ]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpStream;
+
+    fn free_address() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_assign_client_identifier() {
+        let connections = HashServerConnections::new();
+        let first = assign_client_identifier(&connections);
+        assert_eq!(first.len(), 20);
+        assert!(first.chars().all(|c| c.is_ascii_alphanumeric()));
+        assert_ne!(first, assign_client_identifier(&connections));
+    }
+
+    fn connection(tx: OutboundSender, rx: OutboundReceiver) -> HandleClientConnections {
+        HandleClientConnections {
+            tx: Arc::new(Mutex::new(tx)),
+            rx: Arc::new(Mutex::new(rx)),
+            peer: Arc::new(Mutex::new(String::new())),
+            user_name: Arc::new(Mutex::new(String::new())),
+            protocol_level: Arc::new(Mutex::new(protocol_level::MQTT_3_1_1)),
+            topic_aliases: Arc::new(Mutex::new(HashMap::new())),
+            session_expiry_interval: Arc::new(Mutex::new(0)),
+            clean_session: Arc::new(Mutex::new(true)),
+            connected: Arc::new(Mutex::new(true)),
+            disconnected_at: Arc::new(Mutex::new(None)),
+            queued: Arc::new(Mutex::new(0)),
+            inflight: Arc::new(Mutex::new(BTreeSet::new())),
+            kicked: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    #[test]
+    fn test_stalled_subscriber_does_not_delay_the_others() {
+        let settings = QueueSettings {
+            max_queued_messages: 1,
+            slow_consumer_timeout: Duration::from_secs(2),
+            ..QueueSettings::default()
+        };
+        let (stalled_tx, stalled_rx) = outbound::channel(settings);
+        let (tx, rx) = outbound::channel(settings);
+        // a connected client that does not read its full queue
+        let waker = Arc::new(Waker::new(|| {}));
+        stalled_rx.set_waker(&waker);
+        stalled_tx.send(vec![0], 1, None).unwrap();
+        let subscribers = vec![
+            ("stalled".to_string(), stalled_tx.clone()),
+            ("reader".to_string(), tx.clone()),
+        ];
+        let connections: Arc<Mutex<HashServerConnections>> = Arc::new(Mutex::new(
+            vec![
+                ("stalled", connection(stalled_tx, stalled_rx)),
+                ("reader", connection(tx, rx)),
+            ]
+            .into_iter()
+            .map(|(id, c)| (id.to_string(), (c, (String::new(), String::new()))))
+            .collect(),
+        ));
+        let log_file = std::env::temp_dir().join("mqtt-server-fanout-test.log");
+        let logger = Logger::new(log_file.to_str().unwrap(), false);
+
+        let start = Instant::now();
+        let fanout = {
+            let connections = connections.clone();
+            thread::spawn(move || {
+                Server::send_to_subscribers(
+                    &connections,
+                    &subscribers,
+                    &BrokerStats::new(),
+                    |_| vec![1],
+                    1,
+                    Some(1),
+                    None,
+                    &logger,
+                );
+            })
+        };
+        let reader_rx = connections.lock().unwrap()["reader"].0.rx.clone();
+        let received = loop {
+            if let Some(packet) = reader_rx.lock().unwrap().try_recv() {
+                break packet;
+            }
+            assert!(
+                start.elapsed() < Duration::from_secs(1),
+                "reader was delayed"
+            );
+            thread::sleep(Duration::from_millis(5));
+        };
+        assert_eq!(received, vec![1]);
+        // the connections stay free while the stalled subscriber is waited for
+        assert!(connections.lock().unwrap().contains_key("stalled"));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        fanout.join().unwrap();
+        drop(waker);
+    }
+
+    #[test]
+    fn test_publish_packet_identifier() {
+        let publish = |qos: u8, topic: &str| {
+            Packet::<VariableHeader, Payload>::new().publish(
+                0,
+                qos,
+                0,
+                0x1234,
+                topic.to_string(),
+                "21.5".to_string(),
+            )
+        };
+        assert_eq!(
+            publish_packet_identifier(&publish(1, "sensors/temperature").value()),
+            Some(0x1234)
+        );
+        let v5 = publish(1, &"a".repeat(200)).with_properties(Properties::new());
+        assert_eq!(publish_packet_identifier(&v5.value()), Some(0x1234));
+        assert_eq!(publish_packet_identifier(&publish(0, "a").value()), None);
+        assert_eq!(publish_packet_identifier(&[0xF0]), None);
+    }
+
+    #[test]
+    fn test_queued_messages() {
+        let (tx, rx) = outbound::channel(QueueSettings::default());
+        let packet = Packet::<VariableHeader, Payload>::new().publish(
+            0,
+            1,
+            0,
+            7,
+            "sensors/temperature".to_string(),
+            "21.5".to_string(),
+        );
+        tx.send(packet.value(), 1, None).unwrap();
+        tx.send(vec![0xF0], 0, None).unwrap();
+        let connection = connection(tx, rx);
+        assert_eq!(
+            Server::queued_messages(&connection),
+            vec![QueuedMessage {
+                topic: "sensors/temperature".to_string(),
+                qos: 1,
+                payload_size: 4,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_publish_after_suback_is_delivered() {
+        let log_file = std::env::temp_dir().join("mqtt-server-suback-test.log");
+        let mut server = Server::new(
+            "127.0.0.1".to_string(),
+            "0".to_string(),
+            log_file.to_str().unwrap(),
+            HashMap::new(),
+        );
+        server.set_dispatchers(8);
+        let address = free_address();
+        server
+            .add_listener(ListenerSettings::new(
+                "test",
+                address.clone(),
+                Protocol::Tcp,
+            ))
+            .unwrap();
+        let listening = {
+            let server = server.clone();
+            thread::spawn(move || server.listening())
+        };
+
+        let mut client = loop {
+            if let Ok(client) = TcpStream::connect(&address) {
+                break client;
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+        client
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        let connect = Packet::<VariableHeader, Payload>::new().connect(
+            "suback-test".to_string(),
+            true,
+            String::new(),
+            String::new(),
+        );
+        client.write_all(&connect.value()).unwrap();
+        let mut connack = [0_u8; 4];
+        client.read_exact(&mut connack).unwrap();
+        assert_eq!(connack[0], control_type::CONNACK);
+
+        // the publishes of the client and the subscriptions of each topic would go to
+        // different dispatchers, the publish must still find the subscription
+        for index in 0..16_u8 {
+            let topic = format!("sensors/{}", index);
+            let mut subscribe = vec![0x82, 5 + topic.len() as u8, 0, index + 1, 0];
+            subscribe.push(topic.len() as u8);
+            subscribe.extend(topic.as_bytes());
+            subscribe.push(0);
+            client.write_all(&subscribe).unwrap();
+            let mut suback = [0_u8; 5];
+            client.read_exact(&mut suback).unwrap();
+            assert_eq!(suback[0], control_type::SUBACK);
+
+            let publish = Packet::<VariableHeader, Payload>::new().publish(
+                0,
+                0,
+                0,
+                0,
+                topic.clone(),
+                "21.5".to_string(),
+            );
+            client.write_all(&publish.value()).unwrap();
+            let mut byte = [0_u8; 1];
+            // the server keepalive checks are a single byte
+            while byte[0] == 0 || byte[0] == 0xF0 {
+                client.read_exact(&mut byte).unwrap();
+            }
+            assert_eq!(byte[0] & 0xF0, control_type::PUBLISH);
+            // remaining length, topic, packet identifier, message length and message
+            let mut rest = vec![0_u8; 1 + 2 + topic.len() + 2 + 2 + 4];
+            client.read_exact(&mut rest).unwrap();
+            assert_eq!(&rest[3..3 + topic.len()], topic.as_bytes());
+            assert!(rest.ends_with(b"21.5"));
+        }
+
+        server.shutdown();
+        assert!(listening.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_pending_handshake_does_not_take_a_worker() {
+        let log_file = std::env::temp_dir().join("mqtt-server-handshake-test.log");
+        let mut server = Server::new(
+            "127.0.0.1".to_string(),
+            "0".to_string(),
+            log_file.to_str().unwrap(),
+            HashMap::new(),
+        );
+        server.set_client_workers(1);
+        let (address, ws_address) = (free_address(), free_address());
+        server
+            .add_listener(ListenerSettings::new(
+                "test",
+                address.clone(),
+                Protocol::Tcp,
+            ))
+            .unwrap();
+        server
+            .add_listener(ListenerSettings::new(
+                "ws",
+                ws_address.clone(),
+                Protocol::WebSocket,
+            ))
+            .unwrap();
+        let listening = {
+            let server = server.clone();
+            thread::spawn(move || server.listening())
+        };
+        let connect = |address: &str| loop {
+            if let Ok(client) = TcpStream::connect(address) {
+                break client;
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+
+        // a websocket client that never sends its handshake
+        let _stalled = connect(&ws_address);
+        thread::sleep(Duration::from_millis(200));
+        let mut client = connect(&address);
+        client
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        let packet = Packet::<VariableHeader, Payload>::new().connect(
+            "handshake-test".to_string(),
+            true,
+            String::new(),
+            String::new(),
+        );
+        client.write_all(&packet.value()).unwrap();
+        let mut connack = [0_u8; 4];
+        client.read_exact(&mut connack).unwrap();
+        assert_eq!(connack[0], control_type::CONNACK);
+
+        server.shutdown();
+        assert!(listening.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_shutdown_closes_clients() {
+        let log_file = std::env::temp_dir().join("mqtt-server-shutdown-test.log");
+        let mut server = Server::new(
+            "127.0.0.1".to_string(),
+            "0".to_string(),
+            log_file.to_str().unwrap(),
+            HashMap::new(),
+        );
+        let address = free_address();
+        server
+            .add_listener(ListenerSettings::new(
+                "test",
+                address.clone(),
+                Protocol::Tcp,
+            ))
+            .unwrap();
+        let listening = {
+            let server = server.clone();
+            thread::spawn(move || server.listening())
+        };
+
+        let mut client = loop {
+            if let Ok(client) = TcpStream::connect(&address) {
+                break client;
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+        let connect = Packet::<VariableHeader, Payload>::new().connect(
+            "shutdown-test".to_string(),
+            true,
+            String::new(),
+            String::new(),
+        );
+        client.write_all(&connect.value()).unwrap();
+        let mut connack = [0_u8; 4];
+        client.read_exact(&mut connack).unwrap();
+        assert_eq!(connack[0], control_type::CONNACK);
+
+        server.shutdown();
+        assert!(listening.join().unwrap().is_ok());
+        let mut rest = Vec::new();
+        client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        assert_eq!(client.read_to_end(&mut rest).unwrap(), 0);
+        assert!(TcpStream::connect(&address).is_err());
     }
 }
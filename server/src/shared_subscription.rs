@@ -0,0 +1,280 @@
+use crate::outbound::OutboundSender;
+use crate::topics::topic_matches;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+
+/// Prefix of the topic filters of shared subscriptions: $share/<group>/<filter>
+pub const SHARED_PREFIX: &str = "$share/";
+
+/// Shared subscriptions by topic filter, each filter can have several groups
+pub type HashSharedSubscriptions = HashMap<String, Vec<SharedGroup>>;
+
+/// Splits a $share/<group>/<filter> subscription into (group, filter).
+/// Ok(None) is returned for a normal topic filter.
+pub fn parse_shared_topic(topic: &str) -> Result<Option<(String, String)>> {
+    let rest = match topic.strip_prefix(SHARED_PREFIX) {
+        Some(rest) => rest,
+        None => return Ok(None),
+    };
+    match rest.split_once('/') {
+        Some((group, filter))
+            if !group.is_empty() && !filter.is_empty() && !group.contains(['+', '#']) =>
+        {
+            Ok(Some((group.to_string(), filter.to_string())))
+        }
+        _ => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Invalid shared subscription: {}", topic),
+        )),
+    }
+}
+
+/// Clients subscribed to a filter under the same share name, each message goes to one of them
+#[derive(Debug)]
+pub struct SharedGroup {
+    pub name: String,
+    members: Vec<(String, OutboundSender)>, // (client_id, tx sender)
+    next: usize,                            // member that gets the next message
+}
+
+impl SharedGroup {
+    pub fn new(name: &str) -> SharedGroup {
+        SharedGroup {
+            name: name.to_string(),
+            members: Vec::new(),
+            next: 0,
+        }
+    }
+
+    /// Adds a member, a client that subscribes again only gets its sender updated
+    pub fn subscribe(&mut self, client_id: &str, tx: OutboundSender) {
+        match self.members.iter_mut().find(|member| member.0 == client_id) {
+            Some(member) => member.1 = tx,
+            None => self.members.push((client_id.to_string(), tx)),
+        }
+    }
+
+    pub fn unsubscribe(&mut self, client_id: &str) {
+        self.members.retain(|member| member.0 != client_id);
+        if self.next >= self.members.len() {
+            self.next = 0;
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    /// Client ids of the members in round-robin order
+    pub fn members(&self) -> Vec<&str> {
+        self.members
+            .iter()
+            .map(|member| member.0.as_str())
+            .collect()
+    }
+
+    /// Picks the member for the next message in round-robin order skipping the
+    /// disconnected ones. When no member is connected the message is queued for
+    /// the next one in order, it gets it if it resumes its session.
+    pub fn next_member<F>(&mut self, is_connected: F) -> Option<&(String, OutboundSender)>
+    where
+        F: Fn(&str) -> bool,
+    {
+        let len = self.members.len();
+        if len == 0 {
+            return None;
+        }
+        let start = self.next % len;
+        let index = (0..len)
+            .map(|offset| (start + offset) % len)
+            .find(|&index| is_connected(&self.members[index].0))
+            .unwrap_or(start);
+        self.next = (index + 1) % len;
+        self.members.get(index)
+    }
+}
+
+/// Client ids of the members of the groups whose filter matches topic
+pub fn matching_members(shared: &HashSharedSubscriptions, topic: &str) -> Vec<String> {
+    shared
+        .iter()
+        .filter(|(filter, _)| topic_matches(filter, topic))
+        .flat_map(|(_, groups)| groups.iter())
+        .flat_map(|group| group.members())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Picks the member that gets a message of topic in every group whose filter
+/// matches it, returns (group, client_id, tx sender) of each one
+pub fn next_members<F>(
+    shared: &mut HashSharedSubscriptions,
+    topic: &str,
+    is_connected: F,
+) -> Vec<(String, String, OutboundSender)>
+where
+    F: Fn(&str) -> bool,
+{
+    shared
+        .iter_mut()
+        .filter(|(filter, _)| topic_matches(filter, topic))
+        .flat_map(|(_, groups)| groups.iter_mut())
+        .filter_map(|group| {
+            let (client_id, tx) = group.next_member(&is_connected)?.clone();
+            Some((group.name.clone(), client_id, tx))
+        })
+        .collect()
+}
+
+/// Adds client_id to the group of a filter, creating the group if needed
+pub fn subscribe(
+    shared: &mut HashSharedSubscriptions,
+    group: &str,
+    filter: &str,
+    client_id: &str,
+    tx: OutboundSender,
+) {
+    let groups = shared.entry(filter.to_string()).or_default();
+    match groups.iter_mut().find(|g| g.name == group) {
+        Some(shared_group) => shared_group.subscribe(client_id, tx),
+        None => {
+            let mut shared_group = SharedGroup::new(group);
+            shared_group.subscribe(client_id, tx);
+            groups.push(shared_group);
+        }
+    }
+}
+
+/// Removes client_id from the group of a filter, empty groups are dropped
+pub fn unsubscribe(
+    shared: &mut HashSharedSubscriptions,
+    group: &str,
+    filter: &str,
+    client_id: &str,
+) {
+    if let Some(groups) = shared.get_mut(filter) {
+        groups
+            .iter_mut()
+            .filter(|g| g.name == group)
+            .for_each(|g| g.unsubscribe(client_id));
+        groups.retain(|g| !g.is_empty());
+        if groups.is_empty() {
+            shared.remove(filter);
+        }
+    }
+}
+
+/// Removes client_id from every group, used on clean session. Returns the filters
+/// left without groups.
+pub fn unsubscribe_all(shared: &mut HashSharedSubscriptions, client_id: &str) -> Vec<String> {
+    let mut emptied = Vec::new();
+    shared.retain(|filter, groups| {
+        groups.iter_mut().for_each(|g| g.unsubscribe(client_id));
+        groups.retain(|g| !g.is_empty());
+        if groups.is_empty() {
+            emptied.push(filter.clone());
+        }
+        !groups.is_empty()
+    });
+    emptied
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::outbound::{channel, OutboundReceiver, QueueSettings};
+
+    fn member(group: &mut SharedGroup, client_id: &str) -> OutboundReceiver {
+        let (tx, rx) = channel(QueueSettings::default());
+        group.subscribe(client_id, tx);
+        rx
+    }
+
+    #[test]
+    fn test_parse_shared_topic() {
+        assert_eq!(parse_shared_topic("jobs").unwrap(), None);
+        assert_eq!(
+            parse_shared_topic("$share/workers/jobs/new").unwrap(),
+            Some(("workers".to_string(), "jobs/new".to_string()))
+        );
+        assert!(parse_shared_topic("$share/workers").is_err());
+        assert!(parse_shared_topic("$share//jobs").is_err());
+        assert!(parse_shared_topic("$share/work+/jobs").is_err());
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let mut group = SharedGroup::new("workers");
+        let _a = member(&mut group, "a");
+        let _b = member(&mut group, "b");
+        let _c = member(&mut group, "c");
+        let picked: Vec<String> = (0..4)
+            .map(|_| group.next_member(|_| true).unwrap().0.clone())
+            .collect();
+        assert_eq!(picked, vec!["a", "b", "c", "a"]);
+    }
+
+    #[test]
+    fn test_disconnected_members_are_skipped() {
+        let mut group = SharedGroup::new("workers");
+        let _a = member(&mut group, "a");
+        let _b = member(&mut group, "b");
+        let _c = member(&mut group, "c");
+        let picked: Vec<String> = (0..3)
+            .map(|_| group.next_member(|id| id != "b").unwrap().0.clone())
+            .collect();
+        assert_eq!(picked, vec!["a", "c", "a"]);
+        // with nobody connected the messages are still queued in order
+        assert_eq!(group.next_member(|_| false).unwrap().0, "b");
+    }
+
+    #[test]
+    fn test_subscribe_and_unsubscribe() {
+        let mut shared = HashSharedSubscriptions::new();
+        let (tx, _rx) = channel(QueueSettings::default());
+        subscribe(&mut shared, "workers", "jobs", "a", tx.clone());
+        subscribe(&mut shared, "workers", "jobs", "b", tx.clone());
+        subscribe(&mut shared, "audit", "jobs", "a", tx);
+        assert_eq!(shared["jobs"].len(), 2);
+
+        unsubscribe(&mut shared, "audit", "jobs", "a");
+        assert_eq!(shared["jobs"].len(), 1);
+        assert!(unsubscribe_all(&mut shared, "a").is_empty());
+        assert_eq!(unsubscribe_all(&mut shared, "b"), vec!["jobs"]);
+        assert!(shared.is_empty());
+    }
+
+    #[test]
+    fn test_wildcard_filters() {
+        let mut shared = HashSharedSubscriptions::new();
+        let (tx, _rx) = channel(QueueSettings::default());
+        subscribe(&mut shared, "workers", "jobs/#", "a", tx.clone());
+        subscribe(&mut shared, "workers", "jobs/#", "b", tx.clone());
+        subscribe(&mut shared, "audit", "jobs/+/done", "c", tx);
+
+        assert_eq!(matching_members(&shared, "jobs/1"), vec!["a", "b"]);
+        let picked: Vec<String> = (0..2)
+            .flat_map(|_| next_members(&mut shared, "jobs/1", |_| true))
+            .map(|(_, client_id, _)| client_id)
+            .collect();
+        assert_eq!(picked, vec!["a", "b"]);
+
+        let mut picked: Vec<(String, String)> = next_members(&mut shared, "jobs/1/done", |_| true)
+            .into_iter()
+            .map(|(group, client_id, _)| (group, client_id))
+            .collect();
+        picked.sort();
+        assert_eq!(
+            picked,
+            vec![
+                ("audit".to_string(), "c".to_string()),
+                ("workers".to_string(), "a".to_string())
+            ]
+        );
+        assert!(next_members(&mut shared, "reports/1", |_| true).is_empty());
+    }
+}
@@ -0,0 +1,514 @@
+use crate::transport::Transport;
+use std::io::{Read, Result, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Prefix of the topics with the broker statistics, clients cannot publish on them
+pub const SYS_PREFIX: &str = "$SYS/";
+/// Seconds between two publications of the $SYS topics when not set in config
+pub const DEFAULT_SYS_INTERVAL_SECS: u64 = 10;
+/// Upper bounds in seconds of the publish latency histogram buckets
+pub const LATENCY_BUCKETS: [f64; 9] = [0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+/// Names of the mqtt control packets indexed by control type
+pub const PACKET_TYPES: [&str; 16] = [
+    "reserved",
+    "connect",
+    "connack",
+    "publish",
+    "puback",
+    "pubrec",
+    "pubrel",
+    "pubcomp",
+    "subscribe",
+    "suback",
+    "unsubscribe",
+    "unsuback",
+    "pingreq",
+    "pingresp",
+    "disconnect",
+    "auth",
+];
+
+/// Counters of the broker, updated by the client connections and the message handler
+#[derive(Debug)]
+pub struct BrokerStats {
+    started: Instant,
+    clients_connected: AtomicU64,
+    connections_total: AtomicU64,
+    connections_peak: AtomicU64,
+    messages_received: AtomicU64,
+    messages_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    packets_received: [AtomicU64; 16], // by control type
+    auth_failures: AtomicU64,
+    handler_queue: AtomicU64, // client publishes waiting for the message handler
+    outbound_queue: AtomicU64, // messages waiting in the channels of the clients
+    messages_dropped: AtomicU64, // messages that did not fit in the queue of a client
+    messages_expired: AtomicU64, // retained and queued messages dropped when their ttl passed
+    sessions_expired: AtomicU64, // sessions of disconnected clients deleted by the sweeper
+    slow_consumers: AtomicU64, // clients disconnected because their queue stayed full
+    latency_buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    latency_count: AtomicU64,
+    latency_sum_micros: AtomicU64,
+}
+
+impl Default for BrokerStats {
+    fn default() -> Self {
+        BrokerStats::new()
+    }
+}
+
+impl BrokerStats {
+    pub fn new() -> BrokerStats {
+        BrokerStats {
+            started: Instant::now(),
+            clients_connected: AtomicU64::new(0),
+            connections_total: AtomicU64::new(0),
+            connections_peak: AtomicU64::new(0),
+            messages_received: AtomicU64::new(0),
+            messages_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            packets_received: Default::default(),
+            auth_failures: AtomicU64::new(0),
+            handler_queue: AtomicU64::new(0),
+            outbound_queue: AtomicU64::new(0),
+            messages_dropped: AtomicU64::new(0),
+            messages_expired: AtomicU64::new(0),
+            sessions_expired: AtomicU64::new(0),
+            slow_consumers: AtomicU64::new(0),
+            latency_buckets: Default::default(),
+            latency_count: AtomicU64::new(0),
+            latency_sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn client_connected(&self) {
+        let connected = self.clients_connected.fetch_add(1, Ordering::SeqCst) + 1;
+        self.connections_total.fetch_add(1, Ordering::SeqCst);
+        self.connections_peak.fetch_max(connected, Ordering::SeqCst);
+    }
+
+    pub fn client_disconnected(&self) {
+        let _ = self
+            .clients_connected
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1));
+    }
+
+    pub fn packet_received(&self, control_type: u8) {
+        self.packets_received[(control_type >> 4) as usize].fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn auth_failure(&self) {
+        self.auth_failures.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// A client publish sent to the message handler, returns the receive time
+    /// that goes along with the message to measure the publish latency
+    pub fn message_received(&self) -> u64 {
+        self.messages_received.fetch_add(1, Ordering::SeqCst);
+        self.handler_queue.fetch_add(1, Ordering::SeqCst);
+        self.uptime().as_micros() as u64
+    }
+
+    /// The message handler delivered a client publish received at received_micros
+    pub fn publish_delivered(&self, received_micros: u64) {
+        let _ = self
+            .handler_queue
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1));
+        let latency_micros = (self.uptime().as_micros() as u64).saturating_sub(received_micros);
+        let latency = latency_micros as f64 / 1_000_000.0;
+        for (bucket, bound) in self.latency_buckets.iter().zip(LATENCY_BUCKETS.iter()) {
+            if latency <= *bound {
+                bucket.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+        self.latency_count.fetch_add(1, Ordering::SeqCst);
+        self.latency_sum_micros
+            .fetch_add(latency_micros, Ordering::SeqCst);
+    }
+
+    /// A message queued in the channel of a client
+    pub fn message_sent(&self) {
+        self.messages_sent.fetch_add(1, Ordering::SeqCst);
+        self.outbound_queue.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// A queued message written by the client thread
+    pub fn message_written(&self) {
+        let _ = self
+            .outbound_queue
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1));
+    }
+
+    /// A message for a client that did not fit in its queue, queued tells that it
+    /// was already queued and it was dropped to make room for a newer one
+    pub fn message_dropped(&self, queued: bool) {
+        self.messages_dropped.fetch_add(1, Ordering::SeqCst);
+        if queued {
+            self.message_written();
+        }
+    }
+
+    /// Messages dropped when their ttl passed, queued tells that they were waiting
+    /// in the queue of a client and not retained messages
+    pub fn messages_expired(&self, count: usize, queued: bool) {
+        self.messages_expired
+            .fetch_add(count as u64, Ordering::SeqCst);
+        if queued {
+            let _ = self
+                .outbound_queue
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                    Some(n.saturating_sub(count as u64))
+                });
+        }
+    }
+
+    /// A session was deleted with the messages still in its queue
+    pub fn session_expired(&self, queued: usize) {
+        self.sessions_expired.fetch_add(1, Ordering::SeqCst);
+        let _ = self
+            .outbound_queue
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                Some(n.saturating_sub(queued as u64))
+            });
+    }
+
+    pub fn slow_consumer_disconnected(&self) {
+        self.slow_consumers.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn add_bytes_received(&self, bytes: usize) {
+        self.bytes_received
+            .fetch_add(bytes as u64, Ordering::SeqCst);
+    }
+
+    pub fn add_bytes_sent(&self, bytes: usize) {
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::SeqCst);
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Topics and values published on $SYS, subscriptions and retained messages
+    /// are counted by the caller from the topics table.
+    pub fn sys_messages(&self, subscriptions: usize, retained: usize) -> Vec<(String, String)> {
+        let load = |counter: &AtomicU64| counter.load(Ordering::SeqCst).to_string();
+        vec![
+            ("version", env!("CARGO_PKG_VERSION").to_string()),
+            ("uptime", format!("{} seconds", self.uptime().as_secs())),
+            ("clients/connected", load(&self.clients_connected)),
+            ("clients/total", load(&self.connections_total)),
+            ("clients/maximum", load(&self.connections_peak)),
+            ("clients/expired", load(&self.sessions_expired)),
+            ("messages/received", load(&self.messages_received)),
+            ("messages/sent", load(&self.messages_sent)),
+            ("messages/dropped", load(&self.messages_dropped)),
+            ("messages/expired", load(&self.messages_expired)),
+            ("bytes/received", load(&self.bytes_received)),
+            ("bytes/sent", load(&self.bytes_sent)),
+            ("subscriptions/count", subscriptions.to_string()),
+            ("retained messages/count", retained.to_string()),
+        ]
+        .into_iter()
+        .map(|(topic, value)| (format!("{}broker/{}", SYS_PREFIX, topic), value))
+        .collect()
+    }
+
+    /// Counters and gauges in the Prometheus text exposition format
+    pub fn prometheus(&self, subscriptions: usize, retained: usize) -> String {
+        let load = |counter: &AtomicU64| counter.load(Ordering::SeqCst);
+        let mut text = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: Vec<(String, String)>| {
+            text.push_str(&format!(
+                "# HELP {} {}\n# TYPE {} {}\n",
+                name, help, name, kind
+            ));
+            for (labels, value) in samples {
+                text.push_str(&format!("{}{} {}\n", name, labels, value));
+            }
+        };
+        let single = |value: u64| vec![(String::new(), value.to_string())];
+
+        metric(
+            "mqtt_uptime_seconds",
+            "gauge",
+            "Seconds since the broker started.",
+            single(self.uptime().as_secs()),
+        );
+        metric(
+            "mqtt_clients_connected",
+            "gauge",
+            "Clients connected.",
+            single(load(&self.clients_connected)),
+        );
+        metric(
+            "mqtt_clients_maximum",
+            "gauge",
+            "Maximum of clients connected at the same time.",
+            single(load(&self.connections_peak)),
+        );
+        metric(
+            "mqtt_connections_total",
+            "counter",
+            "Accepted mqtt connections.",
+            single(load(&self.connections_total)),
+        );
+        metric(
+            "mqtt_auth_failures_total",
+            "counter",
+            "Connections refused by authentication.",
+            single(load(&self.auth_failures)),
+        );
+        metric(
+            "mqtt_packets_received_total",
+            "counter",
+            "Control packets received by type.",
+            PACKET_TYPES
+                .iter()
+                .zip(self.packets_received.iter())
+                .skip(1)
+                .map(|(name, counter)| {
+                    (format!("{{type=\"{}\"}}", name), load(counter).to_string())
+                })
+                .collect(),
+        );
+        metric(
+            "mqtt_messages_received_total",
+            "counter",
+            "Publish messages received from clients.",
+            single(load(&self.messages_received)),
+        );
+        metric(
+            "mqtt_messages_sent_total",
+            "counter",
+            "Publish messages sent to subscribers.",
+            single(load(&self.messages_sent)),
+        );
+        metric(
+            "mqtt_bytes_received_total",
+            "counter",
+            "Bytes read from clients.",
+            single(load(&self.bytes_received)),
+        );
+        metric(
+            "mqtt_bytes_sent_total",
+            "counter",
+            "Bytes written to clients.",
+            single(load(&self.bytes_sent)),
+        );
+        metric(
+            "mqtt_subscriptions",
+            "gauge",
+            "Subscriptions, including the members of shared groups.",
+            single(subscriptions as u64),
+        );
+        metric(
+            "mqtt_retained_messages",
+            "gauge",
+            "Topics with a retained message.",
+            single(retained as u64),
+        );
+        metric(
+            "mqtt_handler_queue_depth",
+            "gauge",
+            "Client publishes waiting for the message handler.",
+            single(load(&self.handler_queue)),
+        );
+        metric(
+            "mqtt_outbound_queue_depth",
+            "gauge",
+            "Messages waiting to be written to the clients.",
+            single(load(&self.outbound_queue)),
+        );
+        metric(
+            "mqtt_messages_dropped_total",
+            "counter",
+            "Messages dropped because the queue of a client was full.",
+            single(load(&self.messages_dropped)),
+        );
+        metric(
+            "mqtt_messages_expired_total",
+            "counter",
+            "Retained and queued messages dropped because their ttl passed.",
+            single(load(&self.messages_expired)),
+        );
+        metric(
+            "mqtt_sessions_expired_total",
+            "counter",
+            "Sessions of disconnected clients deleted after their expiry.",
+            single(load(&self.sessions_expired)),
+        );
+        metric(
+            "mqtt_slow_consumers_disconnected_total",
+            "counter",
+            "Clients disconnected because their queue stayed full.",
+            single(load(&self.slow_consumers)),
+        );
+
+        let count = load(&self.latency_count);
+        let mut samples: Vec<(String, String)> = LATENCY_BUCKETS
+            .iter()
+            .zip(self.latency_buckets.iter())
+            .map(|(bound, bucket)| {
+                (
+                    format!("_bucket{{le=\"{}\"}}", bound),
+                    load(bucket).to_string(),
+                )
+            })
+            .collect();
+        samples.push(("_bucket{le=\"+Inf\"}".to_string(), count.to_string()));
+        samples.push((
+            "_sum".to_string(),
+            (load(&self.latency_sum_micros) as f64 / 1_000_000.0).to_string(),
+        ));
+        samples.push(("_count".to_string(), count.to_string()));
+        metric(
+            "mqtt_publish_latency_seconds",
+            "histogram",
+            "Time from a publish received to its delivery to the subscribers queues.",
+            samples,
+        );
+        text
+    }
+}
+
+/// Client stream that adds the bytes read and written to the broker counters
+pub struct CountingStream {
+    inner: Box<dyn Transport>,
+    stats: Arc<BrokerStats>,
+}
+
+impl CountingStream {
+    pub fn new(inner: Box<dyn Transport>, stats: Arc<BrokerStats>) -> CountingStream {
+        CountingStream { inner, stats }
+    }
+}
+
+impl Read for CountingStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let size = self.inner.read(buf)?;
+        self.stats.add_bytes_received(size);
+        Ok(size)
+    }
+}
+
+impl Write for CountingStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let size = self.inner.write(buf)?;
+        self.stats.add_bytes_sent(size);
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Transport for CountingStream {
+    fn peer_addr(&self) -> Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+
+    fn shutdown(&mut self, how: Shutdown) -> Result<()> {
+        self.inner.shutdown(how)
+    }
+
+    fn try_clone_socket(&self) -> Result<TcpStream> {
+        self.inner.try_clone_socket()
+    }
+
+    fn handshake(&mut self) -> Result<()> {
+        self.inner.handshake()
+    }
+
+    fn peer_identity(&self) -> Option<String> {
+        self.inner.peer_identity()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(messages: &[(String, String)], topic: &str) -> String {
+        messages
+            .iter()
+            .find(|(t, _)| t == topic)
+            .map(|(_, v)| v.clone())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_connection_counters() {
+        let stats = BrokerStats::new();
+        stats.client_connected();
+        stats.client_connected();
+        stats.client_disconnected();
+        stats.client_connected();
+        stats.client_disconnected();
+        stats.client_disconnected();
+        // a disconnection without connection does not go below zero
+        stats.client_disconnected();
+        stats.session_expired(0);
+        let messages = stats.sys_messages(0, 0);
+        assert_eq!(value(&messages, "$SYS/broker/clients/connected"), "0");
+        assert_eq!(value(&messages, "$SYS/broker/clients/total"), "3");
+        assert_eq!(value(&messages, "$SYS/broker/clients/maximum"), "2");
+        assert_eq!(value(&messages, "$SYS/broker/clients/expired"), "1");
+    }
+
+    #[test]
+    fn test_sys_messages() {
+        let stats = BrokerStats::new();
+        stats.message_received();
+        stats.message_sent();
+        stats.message_sent();
+        stats.add_bytes_received(10);
+        stats.add_bytes_sent(4);
+        let messages = stats.sys_messages(3, 1);
+        assert_eq!(value(&messages, "$SYS/broker/messages/received"), "1");
+        assert_eq!(value(&messages, "$SYS/broker/messages/sent"), "2");
+        assert_eq!(value(&messages, "$SYS/broker/bytes/received"), "10");
+        assert_eq!(value(&messages, "$SYS/broker/bytes/sent"), "4");
+        assert_eq!(value(&messages, "$SYS/broker/subscriptions/count"), "3");
+        assert_eq!(value(&messages, "$SYS/broker/retained messages/count"), "1");
+        assert_eq!(
+            value(&messages, "$SYS/broker/version"),
+            env!("CARGO_PKG_VERSION")
+        );
+    }
+
+    #[test]
+    fn test_prometheus() {
+        let stats = BrokerStats::new();
+        stats.packet_received(0x30);
+        stats.packet_received(0x32);
+        stats.auth_failure();
+        let received = stats.message_received();
+        stats.message_sent();
+        stats.message_sent();
+        stats.message_dropped(true);
+        stats.message_dropped(false);
+        stats.publish_delivered(received);
+        let text = stats.prometheus(2, 1);
+        assert!(text.contains("# TYPE mqtt_packets_received_total counter\n"));
+        assert!(text.contains("mqtt_packets_received_total{type=\"publish\"} 2\n"));
+        assert!(text.contains("mqtt_packets_received_total{type=\"connect\"} 0\n"));
+        assert!(text.contains("mqtt_auth_failures_total 1\n"));
+        assert!(text.contains("mqtt_subscriptions 2\n"));
+        assert!(text.contains("mqtt_handler_queue_depth 0\n"));
+        assert!(text.contains("mqtt_outbound_queue_depth 1\n"));
+        assert!(text.contains("mqtt_messages_dropped_total 2\n"));
+        assert!(text.contains("mqtt_publish_latency_seconds_bucket{le=\"+Inf\"} 1\n"));
+        assert!(text.contains("mqtt_publish_latency_seconds_count 1\n"));
+    }
+}
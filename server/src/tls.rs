@@ -0,0 +1,277 @@
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig};
+use serde::Deserialize;
+use std::fs::File;
+use std::io::{BufReader, Error, ErrorKind, Result};
+use std::sync::Arc;
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+/// How the client certificate is used to authenticate the CONNECT packet
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CertAuth {
+    #[default]
+    #[serde(rename = "off", alias = "false")]
+    Disabled, // only user and password from the credentials file
+    Allow,   // certificate identity if present, otherwise user and password
+    Require, // clients without a certificate identity are refused
+}
+
+/// TLS listener settings read from the server config file
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TlsSettings {
+    pub cert_file: String,
+    pub key_file: String,
+    #[serde(default)]
+    pub ca_file: String, // CA used to verify client certificates, empty if not used
+    #[serde(default)]
+    pub require_client_cert: bool,
+    #[serde(default)]
+    pub cert_auth: CertAuth,
+}
+
+pub fn load_certs(file_name: &str) -> Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(File::open(file_name)?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<Result<Vec<_>>>()?;
+    if certs.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("No certificates found in {}", file_name),
+        ));
+    }
+    Ok(certs)
+}
+
+pub fn load_private_key(file_name: &str) -> Result<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(File::open(file_name)?);
+    match rustls_pemfile::private_key(&mut reader)? {
+        Some(key) => Ok(key),
+        None => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("No private key found in {}", file_name),
+        )),
+    }
+}
+
+/// Builds the rustls configuration used by the TLS listener
+pub fn server_config(settings: &TlsSettings) -> Result<Arc<ServerConfig>> {
+    let certs = load_certs(&settings.cert_file)?;
+    let key = load_private_key(&settings.key_file)?;
+
+    let require_client_cert =
+        settings.require_client_cert || settings.cert_auth == CertAuth::Require;
+    let builder = if settings.ca_file.is_empty() {
+        if require_client_cert || settings.cert_auth != CertAuth::Disabled {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "A CA file is needed to verify client certificates",
+            ));
+        }
+        ServerConfig::builder().with_no_client_auth()
+    } else {
+        let mut roots = RootCertStore::empty();
+        for cert in load_certs(&settings.ca_file)? {
+            roots
+                .add(cert)
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        }
+        let verifier = WebPkiClientVerifier::builder(Arc::new(roots));
+        let verifier = if require_client_cert {
+            verifier.build()
+        } else {
+            verifier.allow_unauthenticated().build()
+        }
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+        ServerConfig::builder().with_client_cert_verifier(verifier)
+    };
+
+    let config = builder
+        .with_single_cert(certs, key)
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+    Ok(Arc::new(config))
+}
+
+/// Identity of a client certificate, the subject CN or the first DNS, email or URI SAN
+pub fn certificate_identity(cert: &CertificateDer) -> Option<String> {
+    let (_, cert) = X509Certificate::from_der(cert.as_ref()).ok()?;
+    if let Some(common_name) = cert
+        .subject()
+        .iter_common_name()
+        .next()
+        .and_then(|cn| cn.as_str().ok())
+    {
+        return Some(common_name.to_string());
+    }
+    let alternative_names = cert.subject_alternative_name().ok()??;
+    alternative_names
+        .value
+        .general_names
+        .iter()
+        .find_map(|name| match name {
+            GeneralName::DNSName(value)
+            | GeneralName::RFC822Name(value)
+            | GeneralName::URI(value) => Some(value.to_string()),
+            _ => None,
+        })
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::transport::{TlsStream, Transport};
+    use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, KeyPair};
+    use rustls::pki_types::ServerName;
+    use rustls::{ClientConfig, ClientConnection, ServerConnection, StreamOwned};
+    use serde::de::value::StrDeserializer;
+    use serde::de::IntoDeserializer;
+    use std::convert::TryFrom;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::path::{Path, PathBuf};
+    use std::thread;
+
+    /// Writes a self signed CA, a server certificate for localhost and a client
+    /// certificate with the given common name into a temporary directory.
+    pub fn write_test_certs(name: &str, client_cn: &str) -> (PathBuf, TlsSettings) {
+        let dir = std::env::temp_dir().join(format!("mqtt_tls_{}_{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        ca_params
+            .distinguished_name
+            .push(DnType::CommonName, "mqtt test ca");
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+
+        let server_key = KeyPair::generate().unwrap();
+        let server_params = CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+        let server_cert = server_params.signed_by(&server_key, &ca, &ca_key).unwrap();
+
+        let client_key = KeyPair::generate().unwrap();
+        let mut client_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        client_params
+            .distinguished_name
+            .push(DnType::CommonName, client_cn);
+        let client_cert = client_params.signed_by(&client_key, &ca, &ca_key).unwrap();
+
+        let write = |file: &str, contents: String| {
+            let path = dir.join(file);
+            std::fs::write(&path, contents).unwrap();
+            path.to_string_lossy().to_string()
+        };
+        let settings = TlsSettings {
+            cert_file: write("server.pem", server_cert.pem()),
+            key_file: write("server.key", server_key.serialize_pem()),
+            ca_file: write("ca.pem", ca.pem()),
+            require_client_cert: false,
+            cert_auth: CertAuth::Disabled,
+        };
+        write("client.pem", client_cert.pem());
+        write("client.key", client_key.serialize_pem());
+        (dir, settings)
+    }
+
+    pub fn client_config(dir: &Path, with_cert: bool) -> Arc<ClientConfig> {
+        let mut roots = RootCertStore::empty();
+        for cert in load_certs(dir.join("ca.pem").to_str().unwrap()).unwrap() {
+            roots.add(cert).unwrap();
+        }
+        let builder = ClientConfig::builder().with_root_certificates(roots);
+        let config = if with_cert {
+            builder
+                .with_client_auth_cert(
+                    load_certs(dir.join("client.pem").to_str().unwrap()).unwrap(),
+                    load_private_key(dir.join("client.key").to_str().unwrap()).unwrap(),
+                )
+                .unwrap()
+        } else {
+            builder.with_no_client_auth()
+        };
+        Arc::new(config)
+    }
+
+    fn tls_echo(settings: &TlsSettings, config: Arc<ClientConfig>) -> Result<Vec<u8>> {
+        let server_config = server_config(settings)?;
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let address = listener.local_addr()?;
+        let handle = thread::spawn(move || -> Result<()> {
+            let (socket, _) = listener.accept()?;
+            let conn = ServerConnection::new(server_config).unwrap();
+            let mut stream: TlsStream = StreamOwned::new(conn, socket);
+            stream.handshake()?;
+            let mut buff = [0_u8; 4];
+            stream.read_exact(&mut buff)?;
+            stream.write_all(&buff)?;
+            stream.flush()
+        });
+
+        let conn =
+            ClientConnection::new(config, ServerName::try_from("localhost").unwrap()).unwrap();
+        let mut stream = StreamOwned::new(conn, TcpStream::connect(address)?);
+        stream.write_all(&[0xC0, 0x00, 0xC0, 0x00])?;
+        let mut buff = vec![0_u8; 4];
+        let read = stream.read_exact(&mut buff);
+        let server = handle.join().unwrap();
+        read?;
+        server?;
+        Ok(buff)
+    }
+
+    #[test]
+    fn test_tls_listener_round_trip() {
+        let (dir, settings) = write_test_certs("round_trip", "device");
+        let echoed = tls_echo(&settings, client_config(&dir, false)).unwrap();
+        assert_eq!(echoed, vec![0xC0, 0x00, 0xC0, 0x00]);
+    }
+
+    #[test]
+    fn test_tls_require_client_cert() {
+        let (dir, mut settings) = write_test_certs("require_cert", "device");
+        settings.require_client_cert = true;
+        assert!(tls_echo(&settings, client_config(&dir, false)).is_err());
+        assert!(tls_echo(&settings, client_config(&dir, true)).is_ok());
+    }
+
+    #[test]
+    fn test_certificate_identity() {
+        let (dir, _settings) = write_test_certs("identity", "sensor-01");
+        let certs = load_certs(dir.join("client.pem").to_str().unwrap()).unwrap();
+        assert_eq!(
+            certificate_identity(&certs[0]),
+            Some("sensor-01".to_string())
+        );
+
+        // without CN the SAN is used
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec!["sensor-02.local".to_string()]).unwrap();
+        params.distinguished_name = rcgen::DistinguishedName::new();
+        let cert = params.self_signed(&key).unwrap();
+        assert_eq!(
+            certificate_identity(cert.der()),
+            Some("sensor-02.local".to_string())
+        );
+    }
+
+    #[test]
+    fn test_cert_auth_names() {
+        let parse = |value: &str| {
+            let value: StrDeserializer<serde::de::value::Error> = value.into_deserializer();
+            CertAuth::deserialize(value)
+        };
+        assert_eq!(parse("off").unwrap(), CertAuth::Disabled);
+        assert_eq!(parse("allow").unwrap(), CertAuth::Allow);
+        assert_eq!(parse("require").unwrap(), CertAuth::Require);
+        assert!(parse("maybe").is_err());
+    }
+
+    #[test]
+    fn test_require_client_cert_without_ca() {
+        let (_dir, mut settings) = write_test_certs("no_ca", "device");
+        settings.ca_file = String::new();
+        settings.require_client_cert = true;
+        assert!(server_config(&settings).is_err());
+    }
+}
@@ -0,0 +1,418 @@
+use crate::outbound::OutboundSender;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
+
+/// Shards of the topic table, topics of different shards are locked independently
+pub const TOPIC_SHARDS: usize = 16;
+
+/// (client_id, tx sender) of the subscribers of a topic
+pub type Subscribers = Arc<Vec<(String, OutboundSender)>>;
+
+#[derive(Debug, Default)]
+struct TopicEntry {
+    subscribers: Subscribers, // replaced on subscribe and unsubscribe, publishes keep a copy
+    retained: String,         // empty when the topic has no retained message
+    retained_expires: Option<Instant>, // the retained message is dropped after it
+}
+
+impl TopicEntry {
+    /// The retained message, empty when there is none or it expired
+    fn retained(&self, now: Instant) -> &str {
+        match self.retained_expires {
+            Some(expires) if expires <= now => "",
+            _ => &self.retained,
+        }
+    }
+}
+
+/// Index of the shard of topic among shards
+pub fn shard_index(topic: &str, shards: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    topic.hash(&mut hasher);
+    (hasher.finish() % shards as u64) as usize
+}
+
+/// True if filter has a + or # wildcard
+pub fn is_wildcard(filter: &str) -> bool {
+    filter.contains(['+', '#'])
+}
+
+/// Subscribers and retained message of each topic. Publishes take a read lock of
+/// one shard to copy its subscribers and send the messages without any lock.
+/// The filters with wildcards are kept in the shards as the topics, wildcards lists
+/// them so a publish also copies the subscribers of the ones matching its topic.
+#[derive(Debug)]
+pub struct TopicTable {
+    shards: Vec<RwLock<HashMap<String, TopicEntry>>>,
+    wildcards: RwLock<HashSet<String>>,
+}
+
+impl Default for TopicTable {
+    fn default() -> TopicTable {
+        TopicTable::new()
+    }
+}
+
+impl TopicTable {
+    pub fn new() -> TopicTable {
+        TopicTable {
+            shards: (0..TOPIC_SHARDS).map(|_| RwLock::default()).collect(),
+            wildcards: RwLock::default(),
+        }
+    }
+
+    fn shard(&self, topic: &str) -> &RwLock<HashMap<String, TopicEntry>> {
+        &self.shards[shard_index(topic, self.shards.len())]
+    }
+
+    /// Subscribers of topic and of the wildcard filters matching it, each client once,
+    /// retained replaces its retained message until expires
+    pub fn publish(
+        &self,
+        topic: &str,
+        retained: Option<&str>,
+        expires: Option<Instant>,
+    ) -> Subscribers {
+        let subscribers = match retained {
+            Some(retained) => {
+                let mut shard = self.shard(topic).write().unwrap();
+                let entry = shard.entry(topic.to_string()).or_default();
+                entry.retained = retained.to_string();
+                entry.retained_expires = expires;
+                entry.subscribers.clone()
+            }
+            None => self.subscribers(topic),
+        };
+        let filters: Vec<String> = self
+            .wildcards
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|filter| topic_matches(filter, topic))
+            .cloned()
+            .collect();
+        if filters.is_empty() {
+            return subscribers;
+        }
+        let mut merged = subscribers.to_vec();
+        for filter in filters {
+            for (client_id, tx) in self.subscribers(&filter).iter() {
+                if !merged.iter().any(|(id, _)| id == client_id) {
+                    merged.push((client_id.clone(), tx.clone()));
+                }
+            }
+        }
+        Arc::new(merged)
+    }
+
+    fn subscribers(&self, topic: &str) -> Subscribers {
+        self.shard(topic)
+            .read()
+            .unwrap()
+            .get(topic)
+            .map(|entry| entry.subscribers.clone())
+            .unwrap_or_default()
+    }
+
+    /// Adds client_id to the subscribers of filter, returns the (topic, message) of
+    /// the retained messages of the topics matching filter
+    pub fn subscribe(
+        &self,
+        filter: &str,
+        client_id: &str,
+        tx: OutboundSender,
+    ) -> Vec<(String, String)> {
+        // the wildcards are changed with the lock of the shard of the filter
+        let mut shard = self.shard(filter).write().unwrap();
+        if is_wildcard(filter) {
+            self.wildcards.write().unwrap().insert(filter.to_string());
+        }
+        let entry = shard.entry(filter.to_string()).or_default();
+        Arc::make_mut(&mut entry.subscribers).push((client_id.to_string(), tx));
+        if !is_wildcard(filter) {
+            let retained = entry.retained(Instant::now());
+            if retained.is_empty() {
+                return Vec::new();
+            }
+            return vec![(filter.to_string(), retained.to_string())];
+        }
+        drop(shard);
+        let mut retained = Vec::new();
+        self.for_each(|topic, _, message| {
+            if !message.is_empty() && topic_matches(filter, topic) {
+                retained.push((topic.to_string(), message.to_string()));
+            }
+        });
+        retained
+    }
+
+    pub fn unsubscribe(&self, filter: &str, client_id: &str) {
+        let mut shard = self.shard(filter).write().unwrap();
+        if let Some(entry) = shard.get_mut(filter) {
+            Arc::make_mut(&mut entry.subscribers).retain(|(id, _)| id != client_id);
+            if is_wildcard(filter) && entry.subscribers.is_empty() {
+                shard.remove(filter);
+                self.wildcards.write().unwrap().remove(filter);
+            }
+        }
+    }
+
+    /// Removes client_id from every topic, the topics left without subscribers nor
+    /// retained message are removed. Returns the topics client_id was the last subscriber of.
+    pub fn unsubscribe_all(&self, client_id: &str) -> Vec<String> {
+        let mut emptied = Vec::new();
+        let now = Instant::now();
+        for shard in &self.shards {
+            let mut shard = shard.write().unwrap();
+            let from = emptied.len();
+            shard.retain(|topic, entry| {
+                if entry.subscribers.iter().any(|(id, _)| id == client_id) {
+                    Arc::make_mut(&mut entry.subscribers).retain(|(id, _)| id != client_id);
+                    if entry.subscribers.is_empty() {
+                        emptied.push(topic.clone());
+                    }
+                }
+                !entry.subscribers.is_empty() || !entry.retained(now).is_empty()
+            });
+            let mut wildcards = self.wildcards.write().unwrap();
+            for filter in emptied[from..].iter().filter(|filter| is_wildcard(filter)) {
+                wildcards.remove(filter);
+            }
+        }
+        emptied
+    }
+
+    /// Drops the retained messages that expired and the topics left without
+    /// subscribers, returns the number of retained messages dropped
+    pub fn purge_expired(&self) -> usize {
+        let now = Instant::now();
+        let mut expired = 0;
+        for shard in &self.shards {
+            shard.write().unwrap().retain(|_, entry| {
+                if !entry.retained.is_empty() && entry.retained(now).is_empty() {
+                    entry.retained.clear();
+                    entry.retained_expires = None;
+                    expired += 1;
+                }
+                !entry.subscribers.is_empty() || !entry.retained.is_empty()
+            });
+        }
+        expired
+    }
+
+    pub fn has_subscribers(&self, topic: &str) -> bool {
+        self.shard(topic)
+            .read()
+            .unwrap()
+            .get(topic)
+            .is_some_and(|entry| !entry.subscribers.is_empty())
+    }
+
+    /// Removes the retained message of topic, false when it has none
+    pub fn delete_retained(&self, topic: &str) -> bool {
+        match self.shard(topic).write().unwrap().get_mut(topic) {
+            Some(entry) if !entry.retained(Instant::now()).is_empty() => {
+                entry.retained.clear();
+                entry.retained_expires = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Calls f with (topic, subscribers, retained message) of every topic, an expired
+    /// retained message is given as empty
+    pub fn for_each<F>(&self, mut f: F)
+    where
+        F: FnMut(&str, &[(String, OutboundSender)], &str),
+    {
+        let now = Instant::now();
+        for shard in &self.shards {
+            for (topic, entry) in shard.read().unwrap().iter() {
+                f(topic, &entry.subscribers, entry.retained(now));
+            }
+        }
+    }
+}
+
+/// True if topic matches filter, the + and # wildcards do not match the topics
+/// starting with $ at the first level
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    if topic.starts_with('$') && !filter.starts_with('$') {
+        return false;
+    }
+    let mut levels = topic.split('/');
+    for level_filter in filter.split('/') {
+        match (level_filter, levels.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => {}
+            (level_filter, Some(level)) if level_filter == level => {}
+            _ => return false,
+        }
+    }
+    levels.next().is_none()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::outbound::{self, QueueSettings};
+
+    fn client_ids(subscribers: &Subscribers) -> Vec<&str> {
+        subscribers.iter().map(|(id, _)| id.as_str()).collect()
+    }
+
+    #[test]
+    fn test_topic_matches() {
+        assert!(topic_matches(
+            "sensors/+/temperature",
+            "sensors/kitchen/temperature"
+        ));
+        assert!(!topic_matches(
+            "sensors/+/temperature",
+            "sensors/kitchen/humidity"
+        ));
+        assert!(!topic_matches("sensors/+", "sensors/kitchen/humidity"));
+        assert!(topic_matches("sensors/#", "sensors"));
+        assert!(topic_matches("sensors/#", "sensors/kitchen/humidity"));
+        assert!(topic_matches("#", "sensors"));
+        assert!(!topic_matches("#", "$SYS/broker/uptime"));
+        assert!(topic_matches("$SYS/#", "$SYS/broker/uptime"));
+        assert!(!topic_matches("sensors", "sensors/kitchen"));
+    }
+
+    #[test]
+    fn test_subscribe_and_publish() {
+        let table = TopicTable::new();
+        let (tx, _rx) = outbound::channel(QueueSettings::default());
+        assert!(table.subscribe("temperature", "a", tx.clone()).is_empty());
+        table.subscribe("temperature", "b", tx.clone());
+        let subscribers = table.publish("temperature", None, None);
+        assert_eq!(client_ids(&subscribers), vec!["a", "b"]);
+
+        // the copy taken by a publish does not change with later subscriptions
+        table.unsubscribe("temperature", "a");
+        assert_eq!(client_ids(&subscribers), vec!["a", "b"]);
+        assert_eq!(
+            client_ids(&table.publish("temperature", None, None)),
+            vec!["b"]
+        );
+        assert!(table.publish("humidity", None, None).is_empty());
+    }
+
+    #[test]
+    fn test_retained_messages() {
+        let table = TopicTable::new();
+        let (tx, _rx) = outbound::channel(QueueSettings::default());
+        table.publish("temperature", Some("21"), None);
+        assert_eq!(
+            table.subscribe("temperature", "a", tx),
+            vec![("temperature".to_string(), "21".to_string())]
+        );
+        assert!(table.delete_retained("temperature"));
+        assert!(!table.delete_retained("temperature"));
+        assert!(!table.delete_retained("humidity"));
+    }
+
+    #[test]
+    fn test_expired_retained_messages() {
+        let table = TopicTable::new();
+        let (tx, _rx) = outbound::channel(QueueSettings::default());
+        let past = Instant::now() - std::time::Duration::from_millis(1);
+        table.publish("temperature", Some("21"), Some(past));
+        table.publish(
+            "humidity",
+            Some("60"),
+            Some(Instant::now() + std::time::Duration::from_secs(60)),
+        );
+        assert!(table.subscribe("temperature", "a", tx.clone()).is_empty());
+        assert!(!table.delete_retained("temperature"));
+
+        table.publish("pressure", Some("1013"), Some(past));
+        assert_eq!(table.purge_expired(), 2);
+        let mut topics = Vec::new();
+        table.for_each(|topic, _, retained| topics.push((topic.to_string(), retained.to_string())));
+        topics.sort();
+        // the expired topic without subscribers is removed
+        assert_eq!(
+            topics,
+            vec![
+                ("humidity".to_string(), "60".to_string()),
+                ("temperature".to_string(), String::new())
+            ]
+        );
+        assert_eq!(
+            table.subscribe("humidity", "a", tx),
+            vec![("humidity".to_string(), "60".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_unsubscribe_all() {
+        let table = TopicTable::new();
+        let (tx, _rx) = outbound::channel(QueueSettings::default());
+        for topic in ["a", "b", "c"] {
+            table.subscribe(topic, "sensor", tx.clone());
+        }
+        table.subscribe("c", "other", tx);
+        table.publish("b", Some("21"), None);
+        let mut emptied = table.unsubscribe_all("sensor");
+        emptied.sort();
+        assert_eq!(emptied, vec!["a", "b"]);
+        assert!(table.has_subscribers("c"));
+        assert!(!table.has_subscribers("b"));
+        let mut topics = Vec::new();
+        table.for_each(|topic, subscribers, retained| {
+            topics.push((topic.to_string(), subscribers.len(), retained.to_string()));
+        });
+        topics.sort();
+        // the retained message of b stays
+        assert_eq!(
+            topics,
+            vec![
+                ("b".to_string(), 0, "21".to_string()),
+                ("c".to_string(), 1, String::new())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_wildcard_subscriptions() {
+        let table = TopicTable::new();
+        let (tx, _rx) = outbound::channel(QueueSettings::default());
+        table.publish("sensors/1/temperature", Some("21"), None);
+        table.publish("sensors/1/humidity", Some("60"), None);
+        table.publish("$SYS/broker/uptime", Some("10"), None);
+        assert_eq!(
+            table.subscribe("sensors/+/temperature", "a", tx.clone()),
+            vec![("sensors/1/temperature".to_string(), "21".to_string())]
+        );
+        let mut retained = table.subscribe("#", "b", tx.clone());
+        retained.sort();
+        assert_eq!(retained.len(), 2);
+        table.subscribe("sensors/1/temperature", "b", tx.clone());
+        table.subscribe("sensors/1/temperature", "c", tx);
+
+        // b matches twice and gets the message once
+        let subscribers = table.publish("sensors/1/temperature", None, None);
+        let mut ids = client_ids(&subscribers);
+        ids.sort();
+        assert_eq!(ids, vec!["a", "b", "c"]);
+        assert_eq!(
+            client_ids(&table.publish("sensors/2/humidity", None, None)),
+            vec!["b"]
+        );
+        assert!(table.publish("$SYS/broker/uptime", None, None).is_empty());
+
+        table.unsubscribe("sensors/+/temperature", "a");
+        assert_eq!(table.unsubscribe_all("b"), vec!["#".to_string()]);
+        assert_eq!(
+            client_ids(&table.publish("sensors/1/temperature", None, None)),
+            vec!["c"]
+        );
+        assert!(table.wildcards.read().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,521 @@
+use crate::broker_config::BrokerConfig;
+use crate::transport::Transport;
+use mqtt_packet::mqtt_packet_service::header_packet::control_type;
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Read, Result, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Trace file used when the config does not set trace_file
+pub const DEFAULT_TRACE_FILE: &str = "trace.log";
+/// Bytes of a packet written in hex, the rest is left out
+const MAX_HEX_BYTES: usize = 512;
+/// Bytes of a payload shown as text
+const PAYLOAD_PREVIEW: usize = 64;
+
+const PACKET_NAMES: [&str; 16] = [
+    "RESERVED",
+    "CONNECT",
+    "CONNACK",
+    "PUBLISH",
+    "PUBACK",
+    "PUBREC",
+    "PUBREL",
+    "PUBCOMP",
+    "SUBSCRIBE",
+    "SUBACK",
+    "UNSUBSCRIBE",
+    "UNSUBACK",
+    "PINGREQ",
+    "PINGRESP",
+    "DISCONNECT",
+    "AUTH",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    In,  // read from the client
+    Out, // written to the client
+}
+
+/// Writes every packet of the traced clients, by client id or ip, to a trace file
+pub struct Tracer {
+    file_source: String,
+    targets: RwLock<HashSet<String>>,
+    active: AtomicBool, // false when there are no targets, the packets are not decoded
+    file: Mutex<Option<BufWriter<File>>>, // opened with the first traced packet
+}
+
+impl Default for Tracer {
+    fn default() -> Tracer {
+        Tracer::new(DEFAULT_TRACE_FILE)
+    }
+}
+
+impl Tracer {
+    pub fn new(file_source: &str) -> Tracer {
+        Tracer {
+            file_source: file_source.to_owned(),
+            targets: RwLock::new(HashSet::new()),
+            active: AtomicBool::new(false),
+            file: Mutex::new(None),
+        }
+    }
+
+    /// Takes trace_file and trace_targets, a list of client ids and ips, from the server config
+    pub fn from_config(config: &BrokerConfig) -> Tracer {
+        let tracer = Tracer::new(match config.trace_file.as_str() {
+            "" => DEFAULT_TRACE_FILE,
+            file_source => file_source,
+        });
+        for target in &config.trace_targets {
+            tracer.set_traced(target, true);
+        }
+        tracer
+    }
+
+    /// Starts or stops tracing target, a client id or an ip, false when nothing changed
+    pub fn set_traced(&self, target: &str, traced: bool) -> bool {
+        let mut targets = self.targets.write().unwrap();
+        let changed = if traced {
+            targets.insert(target.to_string())
+        } else {
+            targets.remove(target)
+        };
+        self.active.store(!targets.is_empty(), Ordering::SeqCst);
+        changed
+    }
+
+    pub fn targets(&self) -> Vec<String> {
+        let mut targets: Vec<String> = self.targets.read().unwrap().iter().cloned().collect();
+        targets.sort();
+        targets
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    pub fn is_traced(&self, client_id: &str, ip: &str) -> bool {
+        let targets = self.targets.read().unwrap();
+        targets.contains(ip) || (!client_id.is_empty() && targets.contains(client_id))
+    }
+
+    /// Writes a line with the decoded fields and the hex bytes of packet
+    pub fn packet(
+        &self,
+        direction: Direction,
+        client_id: &str,
+        peer: &str,
+        packet: &[u8],
+        v5: bool,
+    ) {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis())
+            .unwrap_or_default();
+        let line = format!(
+            "{}.{:03} {} client_id={} peer={} {} | {}\n",
+            millis / 1000,
+            millis % 1000,
+            match direction {
+                Direction::In => "<-",
+                Direction::Out => "->",
+            },
+            client_id,
+            peer,
+            describe(packet, v5),
+            hex(packet)
+        );
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        if file.is_none() {
+            match OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(&self.file_source)
+            {
+                Ok(opened) => *file = Some(BufWriter::new(opened)),
+                Err(_) => return,
+            }
+        }
+        if let Some(writer) = file.as_mut() {
+            let _ = writer.write_all(line.as_bytes());
+            let _ = writer.flush();
+        }
+    }
+}
+
+fn hex(packet: &[u8]) -> String {
+    let mut hex: Vec<String> = packet
+        .iter()
+        .take(MAX_HEX_BYTES)
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    if packet.len() > MAX_HEX_BYTES {
+        hex.push(format!("... ({} bytes)", packet.len()));
+    }
+    hex.join(" ")
+}
+
+/// (remaining length, bytes of the fixed header) of the packet that starts at bytes
+fn fixed_header(bytes: &[u8]) -> Option<(usize, usize)> {
+    let mut length = 0;
+    for (index, byte) in bytes.iter().skip(1).take(4).enumerate() {
+        length |= ((byte & 0x7F) as usize) << (7 * index);
+        if byte & 0x80 == 0 {
+            return Some((length, index + 2));
+        }
+    }
+    None
+}
+
+/// Size of a publish packet of this broker. Its message comes after two bytes with its
+/// length that are not counted in the remaining length; the publishes written by the
+/// broker count the message while the ones of its client do not.
+fn publish_size(bytes: &[u8], length: usize, header: usize, v5: bool) -> usize {
+    let mut fields = Fields {
+        bytes: &bytes[header..],
+    };
+    let message_length = fields.string().and_then(|_| {
+        if (bytes[0] >> 1) & 0x03 > 0 {
+            fields.take(2)?;
+        }
+        if v5 {
+            fields.properties()?;
+        }
+        let variable_header = bytes.len() - header - fields.bytes.len();
+        let message_length = fields.u16()? as usize;
+        Some(if length == variable_header {
+            message_length
+        } else {
+            0
+        })
+    });
+    header + length + 2 + message_length.unwrap_or_default()
+}
+
+/// Splits bytes into packets, the server keepalive check of MQTT 3.1.1 clients is a
+/// single 0xF0 byte
+pub fn split_packets(mut bytes: &[u8], v5: bool) -> Vec<&[u8]> {
+    let mut packets = Vec::new();
+    while !bytes.is_empty() {
+        let size = if bytes[0] == control_type::RESERVED && !v5 {
+            1
+        } else {
+            match fixed_header(bytes) {
+                Some((length, header)) if bytes[0] & 0xF0 == control_type::PUBLISH => {
+                    publish_size(bytes, length, header, v5)
+                }
+                Some((length, header)) => header + length,
+                None => bytes.len(),
+            }
+        };
+        let (packet, rest) = bytes.split_at(size.min(bytes.len()));
+        packets.push(packet);
+        bytes = rest;
+    }
+    packets
+}
+
+/// Reads the fields of a packet, every read is checked against its end
+struct Fields<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Fields<'a> {
+    fn take(&mut self, size: usize) -> Option<&'a [u8]> {
+        if self.bytes.len() < size {
+            return None;
+        }
+        let (taken, rest) = self.bytes.split_at(size);
+        self.bytes = rest;
+        Some(taken)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let size = self.u16()? as usize;
+        self.take(size)
+            .map(|bytes| String::from_utf8_lossy(bytes).to_string())
+    }
+
+    /// Skips the properties of a MQTT 5 packet
+    fn properties(&mut self) -> Option<()> {
+        // the length is read as the remaining length of a fixed header with no type
+        let length_bytes = &self.bytes[..self.bytes.len().min(4)];
+        let (length, header) = fixed_header(&[&[0], length_bytes].concat())?;
+        self.take(header - 1 + length).map(|_| ())
+    }
+}
+
+fn preview(payload: &[u8]) -> String {
+    let text: String = payload
+        .iter()
+        .take(PAYLOAD_PREVIEW)
+        .map(|&byte| {
+            if byte.is_ascii_graphic() || byte == b' ' {
+                byte as char
+            } else {
+                '.'
+            }
+        })
+        .collect();
+    format!("payload=\"{}\" ({} bytes)", text, payload.len())
+}
+
+/// Name, flags, remaining length and, by packet type, the packet identifier, topics,
+/// client id or payload of a packet
+pub fn describe(packet: &[u8], v5: bool) -> String {
+    if packet == [control_type::RESERVED] && !v5 {
+        return "KEEPALIVE".to_string();
+    }
+    let (length, header) = match fixed_header(packet) {
+        Some(fixed_header) => fixed_header,
+        None => return "INCOMPLETE".to_string(),
+    };
+    let packet_type = packet[0] & 0xF0;
+    let mut description = format!(
+        "{} flags=0x{:x} length={}",
+        PACKET_NAMES[(packet_type >> 4) as usize],
+        packet[0] & 0x0F,
+        length
+    );
+    let mut fields = Fields {
+        bytes: &packet[header..],
+    };
+    let packet_id = |fields: &mut Fields| {
+        fields
+            .u16()
+            .map_or(String::new(), |id| format!(" packet_id={}", id))
+    };
+    match packet_type {
+        control_type::CONNECT => {
+            let client_id = connect_client_id(packet).unwrap_or_default();
+            description.push_str(&format!(" client_id={}", client_id));
+        }
+        control_type::PUBLISH => {
+            if let Some(topic) = fields.string() {
+                description.push_str(&format!(" topic={}", topic));
+            }
+            if (packet[0] >> 1) & 0x03 > 0 {
+                description.push_str(&packet_id(&mut fields));
+            }
+            if v5 {
+                fields.properties();
+            }
+            let payload = fields
+                .u16()
+                .and_then(|size| fields.take((size as usize).min(fields.bytes.len())))
+                .unwrap_or_default();
+            description.push(' ');
+            description.push_str(&preview(payload));
+        }
+        control_type::PUBACK
+        | control_type::PUBREC
+        | control_type::PUBREL
+        | control_type::PUBCOMP
+        | control_type::SUBACK
+        | control_type::UNSUBACK => description.push_str(&packet_id(&mut fields)),
+        control_type::SUBSCRIBE | control_type::UNSUBSCRIBE => {
+            description.push_str(&packet_id(&mut fields));
+            if v5 {
+                fields.properties();
+            }
+            let mut topics = Vec::new();
+            while let Some(topic) = fields.string() {
+                topics.push(topic);
+                if packet_type == control_type::SUBSCRIBE {
+                    fields.take(1);
+                }
+            }
+            description.push_str(&format!(" topics={}", topics.join(",")));
+        }
+        _ => {}
+    }
+    description
+}
+
+/// (client id, protocol level) of a CONNECT packet
+fn connect_fields(packet: &[u8]) -> Option<(String, u8)> {
+    let (_, header) = fixed_header(packet)?;
+    let mut fields = Fields {
+        bytes: &packet[header..],
+    };
+    fields.string()?; // protocol name
+    let level = fields.take(1)?[0];
+    fields.take(3)?; // flags and keepalive
+    if level == 5 {
+        fields.properties()?;
+    }
+    Some((fields.string()?, level))
+}
+
+fn connect_client_id(packet: &[u8]) -> Option<String> {
+    connect_fields(packet).map(|(client_id, _)| client_id)
+}
+
+/// Stream of a client connection that writes its packets to the tracer while the
+/// client id or the ip of the client are traced
+pub struct TracingStream {
+    inner: Box<dyn Transport>,
+    tracer: Arc<Tracer>,
+    peer: String,
+    ip: String,
+    client_id: String, // taken from the CONNECT packet
+    v5: bool,
+}
+
+impl TracingStream {
+    pub fn new(inner: Box<dyn Transport>, tracer: Arc<Tracer>) -> Result<TracingStream> {
+        let peer = inner.peer_addr()?;
+        Ok(TracingStream {
+            inner,
+            tracer,
+            peer: peer.to_string(),
+            ip: peer.ip().to_string(),
+            client_id: String::new(),
+            v5: false,
+        })
+    }
+
+    fn trace(&mut self, direction: Direction, bytes: &[u8]) {
+        if !self.tracer.is_active() {
+            return;
+        }
+        for packet in split_packets(bytes, self.v5) {
+            if direction == Direction::In && packet[0] & 0xF0 == control_type::CONNECT {
+                if let Some((client_id, level)) = connect_fields(packet) {
+                    self.client_id = client_id;
+                    self.v5 = level == 5;
+                }
+            }
+            if self.tracer.is_traced(&self.client_id, &self.ip) {
+                self.tracer
+                    .packet(direction, &self.client_id, &self.peer, packet, self.v5);
+            }
+        }
+    }
+}
+
+impl Read for TracingStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let size = self.inner.read(buf)?;
+        self.trace(Direction::In, &buf[..size]);
+        Ok(size)
+    }
+}
+
+impl Write for TracingStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let size = self.inner.write(buf)?;
+        self.trace(Direction::Out, &buf[..size]);
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Transport for TracingStream {
+    fn peer_addr(&self) -> Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+
+    fn shutdown(&mut self, how: Shutdown) -> Result<()> {
+        self.inner.shutdown(how)
+    }
+
+    fn try_clone_socket(&self) -> Result<TcpStream> {
+        self.inner.try_clone_socket()
+    }
+
+    fn handshake(&mut self) -> Result<()> {
+        self.inner.handshake()
+    }
+
+    fn peer_identity(&self) -> Option<String> {
+        self.inner.peer_identity()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::config_file::ConfigFile;
+
+    fn connect(client_id: &str) -> Vec<u8> {
+        let mut packet = vec![0x10, 16 + client_id.len() as u8, 0, 4];
+        packet.extend(b"MQTT");
+        packet.extend([4, 0x02, 0, 60, 0, client_id.len() as u8]);
+        packet.extend(client_id.as_bytes());
+        packet.extend([0, 0, 0, 0]);
+        packet
+    }
+
+    #[test]
+    fn test_split_and_describe() {
+        // publish QoS 1 on a/b with packet id 7 and message 21.5, then a puback
+        let publish = [
+            0x32, 0x07, 0x00, 0x03, b'a', b'/', b'b', 0x00, 0x07, 0x00, 0x04, b'2', b'1', b'.',
+            b'5',
+        ];
+        let mut bytes = publish.to_vec();
+        bytes.extend([0x40, 0x02, 0x00, 0x07, 0xF0]);
+        // the same publish written by the broker counts the message in its remaining length
+        bytes.extend(publish);
+        let last = bytes.len() - publish.len();
+        bytes[last + 1] = 0x0b;
+        let packets = split_packets(&bytes, false);
+        assert_eq!(packets.len(), 4);
+        assert_eq!(packets[3].len(), publish.len());
+        assert_eq!(
+            describe(packets[0], false),
+            "PUBLISH flags=0x2 length=7 topic=a/b packet_id=7 payload=\"21.5\" (4 bytes)"
+        );
+        assert_eq!(
+            describe(packets[1], false),
+            "PUBACK flags=0x0 length=2 packet_id=7"
+        );
+        assert_eq!(describe(packets[2], false), "KEEPALIVE");
+
+        let subscribe = [
+            0x82, 0x0A, 0x12, 0x34, 0x00, 0x01, b'a', 0x01, 0x00, 0x01, b'b', 0x00,
+        ];
+        assert_eq!(
+            describe(&subscribe, false),
+            "SUBSCRIBE flags=0x2 length=10 packet_id=4660 topics=a,b"
+        );
+        assert_eq!(
+            describe(&connect("sensor"), false),
+            "CONNECT flags=0x0 length=22 client_id=sensor"
+        );
+    }
+
+    #[test]
+    fn test_targets() {
+        let config =
+            ConfigFile::parse("config.yaml", "trace_targets: [sensor, 10.0.0.5]\n").unwrap();
+        let tracer = Tracer::from_config(&BrokerConfig::from_config(&config).unwrap());
+        assert!(tracer.is_active());
+        assert!(tracer.is_traced("sensor", "127.0.0.1"));
+        assert!(tracer.is_traced("", "10.0.0.5"));
+        assert!(!tracer.is_traced("", "127.0.0.1"));
+        assert!(tracer.set_traced("sensor", false));
+        assert!(!tracer.set_traced("sensor", false));
+        assert_eq!(tracer.targets(), vec!["10.0.0.5"]);
+        tracer.set_traced("10.0.0.5", false);
+        assert!(!tracer.is_active());
+    }
+}
@@ -0,0 +1,81 @@
+use crate::tls::certificate_identity;
+use rustls::{ServerConnection, StreamOwned};
+use std::io::{Read, Result, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::time::Duration;
+
+/// Time allowed to a client to finish the TLS handshake
+const HANDSHAKE_TIMEOUT_SECS: u64 = 10;
+
+/// Stream used by the client connections of the server, it can be a plain tcp socket
+/// or an encrypted one, the packets handling does not need to know which one it is.
+pub trait Transport: Read + Write + Send {
+    fn peer_addr(&self) -> Result<SocketAddr>;
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()>;
+    fn shutdown(&mut self, how: Shutdown) -> Result<()>;
+    /// Clone of the tcp socket under the stream, used to wait until it can be read
+    fn try_clone_socket(&self) -> Result<TcpStream>;
+    /// Completes any negotiation needed before exchanging mqtt packets
+    fn handshake(&mut self) -> Result<()> {
+        Ok(())
+    }
+    /// Identity given by the client certificate, None if the client did not send one
+    fn peer_identity(&self) -> Option<String> {
+        None
+    }
+}
+
+pub type TlsStream = StreamOwned<ServerConnection, TcpStream>;
+
+impl Transport for TcpStream {
+    fn peer_addr(&self) -> Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn shutdown(&mut self, how: Shutdown) -> Result<()> {
+        TcpStream::shutdown(self, how)
+    }
+
+    fn try_clone_socket(&self) -> Result<TcpStream> {
+        self.try_clone()
+    }
+}
+
+impl Transport for TlsStream {
+    fn peer_addr(&self) -> Result<SocketAddr> {
+        self.sock.peer_addr()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        self.sock.set_read_timeout(timeout)
+    }
+
+    fn shutdown(&mut self, how: Shutdown) -> Result<()> {
+        // let the client know that the session is over before closing the socket
+        self.conn.send_close_notify();
+        let _ = self.conn.complete_io(&mut self.sock);
+        self.sock.shutdown(how)
+    }
+
+    fn try_clone_socket(&self) -> Result<TcpStream> {
+        self.sock.try_clone()
+    }
+
+    fn handshake(&mut self) -> Result<()> {
+        self.sock
+            .set_read_timeout(Some(Duration::from_secs(HANDSHAKE_TIMEOUT_SECS)))?;
+        while self.conn.is_handshaking() {
+            self.conn.complete_io(&mut self.sock)?;
+        }
+        Ok(())
+    }
+
+    fn peer_identity(&self) -> Option<String> {
+        let certs = self.conn.peer_certificates()?;
+        certificate_identity(certs.first()?)
+    }
+}
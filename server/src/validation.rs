@@ -0,0 +1,162 @@
+use crate::limits::Limits;
+use crate::shared_subscription::parse_shared_topic;
+use std::io::{Error, ErrorKind, Result};
+
+/// Longest string of a packet, its length is encoded in two bytes
+const MAX_STRING_LENGTH: usize = 65535;
+
+fn invalid(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+/// Longest value allowed by a limit of the config, 0 leaves the one of the protocol
+fn max_length(limit: usize) -> usize {
+    match limit {
+        0 => MAX_STRING_LENGTH,
+        limit => limit.min(MAX_STRING_LENGTH),
+    }
+}
+
+/// Checks what topic names and topic filters share: they are not empty,
+/// have no U+0000 and fit in max_topic_length and max_topic_levels
+fn topic(kind: &str, topic: &str, limits: &Limits) -> Result<()> {
+    if topic.is_empty() {
+        return Err(invalid(format!("Empty {}", kind)));
+    }
+    if topic.contains('\0') {
+        return Err(invalid(format!("U+0000 in {}: {:?}", kind, topic)));
+    }
+    let max_length = max_length(limits.max_topic_length);
+    if topic.len() > max_length {
+        return Err(invalid(format!(
+            "Over {} bytes in {}: {}",
+            max_length, kind, topic
+        )));
+    }
+    let max_levels = limits.max_topic_levels;
+    if max_levels > 0 && topic.split('/').count() > max_levels {
+        return Err(invalid(format!(
+            "Over {} levels in {}: {}",
+            max_levels, kind, topic
+        )));
+    }
+    Ok(())
+}
+
+/// Topic name of a publish or of a last will, it cannot have wildcards
+pub fn topic_name(name: &str, limits: &Limits) -> Result<()> {
+    topic("topic name", name, limits)?;
+    if name.contains(['+', '#']) {
+        return Err(invalid(format!("Topic name with wildcards: {}", name)));
+    }
+    Ok(())
+}
+
+/// Topic filter of a subscribe or unsubscribe, `+` takes a whole level
+/// and `#` takes the last one
+pub fn topic_filter(filter: &str, limits: &Limits) -> Result<()> {
+    topic("topic filter", filter, limits)?;
+    let levels: Vec<&str> = filter.split('/').collect();
+    for (index, level) in levels.iter().enumerate() {
+        let valid = match *level {
+            "+" => true,
+            "#" => index == levels.len() - 1,
+            level => !level.contains(['+', '#']),
+        };
+        if !valid {
+            return Err(invalid(format!("Invalid topic filter: {}", filter)));
+        }
+    }
+    Ok(())
+}
+
+/// Topic filter of a subscription, shared ones are checked without their `$share/<group>/`
+pub fn subscription_filter(filter: &str, limits: &Limits) -> Result<()> {
+    match parse_shared_topic(filter)? {
+        Some((_, filter)) => topic_filter(&filter, limits),
+        None => topic_filter(filter, limits),
+    }
+}
+
+/// Client identifier of a connect, the caller decides what to do with an empty one
+pub fn client_identifier(client_id: &str, limits: &Limits) -> Result<()> {
+    if client_id.contains('\0') {
+        return Err(invalid(format!(
+            "Client identifier with U+0000: {:?}",
+            client_id
+        )));
+    }
+    let max_length = max_length(limits.max_client_id_length);
+    if client_id.len() > max_length {
+        return Err(invalid(format!(
+            "Client identifier longer than {} bytes: {}",
+            max_length, client_id
+        )));
+    }
+    Ok(())
+}
+
+/// Message of a publish, it has to fit in max_payload_size
+pub fn payload(message: &str, limits: &Limits) -> Result<()> {
+    let max_size = limits.max_payload_size;
+    if max_size > 0 && message.len() > max_size {
+        return Err(invalid(format!(
+            "Payload of {} bytes, larger than {} bytes",
+            message.len(),
+            max_size
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_topics() {
+        let none = Limits::default();
+        assert!(topic_name("sensors/1/temperature", &none).is_ok());
+        assert!(topic_name("/", &none).is_ok());
+        assert!(topic_name("", &none).is_err());
+        assert!(topic_name("sensors/+", &none).is_err());
+        assert!(topic_name("sensors/#", &none).is_err());
+        assert!(topic_name("sensors\0", &none).is_err());
+        assert!(topic_name(&"a".repeat(65536), &none).is_err());
+
+        for filter in ["#", "+", "sensors/+/temperature", "sensors/#", "+/+/#", "/"] {
+            assert!(topic_filter(filter, &none).is_ok(), "{}", filter);
+        }
+        for filter in ["", "sensors/#/temperature", "sensors#", "sensors/+1", "##"] {
+            assert!(topic_filter(filter, &none).is_err(), "{}", filter);
+        }
+        assert!(subscription_filter("$share/group/sensors/+", &none).is_ok());
+        assert!(subscription_filter("$share/group/sensors/#/x", &none).is_err());
+        assert!(subscription_filter("$share/group", &none).is_err());
+
+        let limits = Limits {
+            max_topic_length: 10,
+            max_topic_levels: 2,
+            ..Limits::default()
+        };
+        assert!(topic_name("a/b", &limits).is_ok());
+        assert!(topic_name("a/b/c", &limits).is_err());
+        assert!(topic_filter("sensors/temp", &limits).is_err());
+    }
+
+    #[test]
+    fn test_client_identifier_and_payload() {
+        let limits = Limits {
+            max_client_id_length: 8,
+            max_payload_size: 4,
+            ..Limits::default()
+        };
+        assert!(client_identifier("sensor-1", &limits).is_ok());
+        assert!(client_identifier("", &limits).is_ok());
+        assert!(client_identifier("sensor-10", &limits).is_err());
+        assert!(client_identifier("a\0b", &limits).is_err());
+        assert!(payload("21.5", &limits).is_ok());
+        assert!(payload("21.55", &limits).is_err());
+        assert!(payload(&"x".repeat(100_000), &Limits::default()).is_ok());
+    }
+}
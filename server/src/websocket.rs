@@ -0,0 +1,212 @@
+use crate::transport::Transport;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::time::Duration;
+use tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tungstenite::http::{HeaderValue, StatusCode};
+use tungstenite::{Message, WebSocket};
+
+/// Subprotocol that websocket clients must ask for (MQTT 3.1.1 section 6)
+const MQTT_SUBPROTOCOL: &str = "mqtt";
+/// Time allowed to a client to send the HTTP upgrade request
+const HANDSHAKE_TIMEOUT_SECS: u64 = 10;
+
+/// MQTT over websocket, every mqtt packet written goes in a binary frame and the
+/// binary frames received are read as a stream of bytes.
+pub struct WebSocketStream {
+    socket: Option<TcpStream>, // tcp socket waiting for the upgrade handshake
+    websocket: Option<WebSocket<TcpStream>>,
+    read_buffer: Vec<u8>,
+}
+
+impl WebSocketStream {
+    pub fn new(socket: TcpStream) -> WebSocketStream {
+        WebSocketStream {
+            socket: Some(socket),
+            websocket: None,
+            read_buffer: Vec::new(),
+        }
+    }
+
+    fn tcp_stream(&self) -> Result<&TcpStream> {
+        match (&self.socket, &self.websocket) {
+            (Some(socket), _) => Ok(socket),
+            (None, Some(websocket)) => Ok(websocket.get_ref()),
+            (None, None) => Err(Error::new(
+                ErrorKind::NotConnected,
+                "Websocket handshake failed",
+            )),
+        }
+    }
+
+    fn websocket(&mut self) -> Result<&mut WebSocket<TcpStream>> {
+        self.websocket
+            .as_mut()
+            .ok_or_else(|| Error::new(ErrorKind::NotConnected, "Websocket handshake not completed"))
+    }
+}
+
+/// Accepts the upgrade request only if the client asked for the mqtt subprotocol
+#[allow(clippy::result_large_err)] // signature required by tungstenite::accept_hdr
+fn check_subprotocol(
+    request: &Request,
+    mut response: Response,
+) -> std::result::Result<Response, ErrorResponse> {
+    let asks_for_mqtt = request
+        .headers()
+        .get_all("Sec-WebSocket-Protocol")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|protocol| protocol.trim() == MQTT_SUBPROTOCOL);
+    if !asks_for_mqtt {
+        let mut error = ErrorResponse::new(Some("mqtt subprotocol required".to_string()));
+        *error.status_mut() = StatusCode::BAD_REQUEST;
+        return Err(error);
+    }
+    response.headers_mut().insert(
+        "Sec-WebSocket-Protocol",
+        HeaderValue::from_static(MQTT_SUBPROTOCOL),
+    );
+    Ok(response)
+}
+
+fn to_io_error(error: tungstenite::Error) -> Error {
+    match error {
+        tungstenite::Error::Io(e) => e,
+        tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed => {
+            Error::new(ErrorKind::ConnectionAborted, "Websocket closed")
+        }
+        e => Error::new(ErrorKind::InvalidData, e.to_string()),
+    }
+}
+
+impl Read for WebSocketStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        while self.read_buffer.is_empty() {
+            match self.websocket()?.read() {
+                Ok(Message::Binary(data)) => self.read_buffer = data,
+                Ok(Message::Close(_)) => return Ok(0),
+                Ok(Message::Text(_)) => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "MQTT packets must be sent in binary frames",
+                    ))
+                }
+                // ping and pong are answered by tungstenite
+                Ok(_) => {}
+                Err(tungstenite::Error::ConnectionClosed) => return Ok(0),
+                Err(e) => return Err(to_io_error(e)),
+            }
+        }
+        let size = buf.len().min(self.read_buffer.len());
+        buf[..size].copy_from_slice(&self.read_buffer[..size]);
+        self.read_buffer.drain(..size);
+        Ok(size)
+    }
+}
+
+impl Write for WebSocketStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.websocket()?
+            .send(Message::Binary(buf.to_vec()))
+            .map_err(to_io_error)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.websocket()?.flush().map_err(to_io_error)
+    }
+}
+
+impl Transport for WebSocketStream {
+    fn peer_addr(&self) -> Result<SocketAddr> {
+        self.tcp_stream()?.peer_addr()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        self.tcp_stream()?.set_read_timeout(timeout)
+    }
+
+    fn shutdown(&mut self, how: Shutdown) -> Result<()> {
+        let websocket = self.websocket()?;
+        let _ = websocket.close(None);
+        let _ = websocket.flush();
+        websocket.get_ref().shutdown(how)
+    }
+
+    fn try_clone_socket(&self) -> Result<TcpStream> {
+        self.tcp_stream()?.try_clone()
+    }
+
+    fn handshake(&mut self) -> Result<()> {
+        if let Some(socket) = self.socket.take() {
+            socket.set_read_timeout(Some(Duration::from_secs(HANDSHAKE_TIMEOUT_SECS)))?;
+            let websocket = tungstenite::accept_hdr(socket, check_subprotocol)
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+            self.websocket = Some(websocket);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+    use tungstenite::client::IntoClientRequest;
+
+    fn websocket_client(address: SocketAddr, subprotocol: &str) -> Result<WebSocket<TcpStream>> {
+        let mut request = format!("ws://{}/mqtt", address)
+            .into_client_request()
+            .unwrap();
+        if !subprotocol.is_empty() {
+            request.headers_mut().insert(
+                "Sec-WebSocket-Protocol",
+                HeaderValue::from_str(subprotocol).unwrap(),
+            );
+        }
+        let socket = TcpStream::connect(address).unwrap();
+        tungstenite::client(request, socket)
+            .map(|(websocket, _)| websocket)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))
+    }
+
+    #[test]
+    fn test_websocket_binary_frames() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let (socket, _) = listener.accept().unwrap();
+            let mut stream = WebSocketStream::new(socket);
+            stream.handshake().unwrap();
+            // a packet split in two frames is read as one stream
+            let mut buff = [0_u8; 4];
+            stream.read_exact(&mut buff).unwrap();
+            stream.write_all(&[0xD0, 0x00]).unwrap();
+            buff
+        });
+
+        let mut client = websocket_client(address, "mqtt").unwrap();
+        client
+            .send(Message::Binary(vec![0xC0, 0x00, 0xC0]))
+            .unwrap();
+        client.send(Message::Binary(vec![0x00])).unwrap();
+        assert_eq!(client.read().unwrap(), Message::Binary(vec![0xD0, 0x00]));
+        assert_eq!(handle.join().unwrap(), [0xC0, 0x00, 0xC0, 0x00]);
+    }
+
+    #[test]
+    fn test_websocket_requires_mqtt_subprotocol() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let (socket, _) = listener.accept().unwrap();
+            let mut stream = WebSocketStream::new(socket);
+            stream.handshake().is_err()
+        });
+        assert!(websocket_client(address, "chat").is_err());
+        assert!(handle.join().unwrap());
+    }
+}
@@ -2,48 +2,41 @@ use std::io::prelude::*;
 use std::net::TcpListener;
 use std::net::TcpStream;
 use std::fs;
-use web_client::{ ThreadPool, file_loader::load_contents, http::parse_request_line, broker_client::BrokerClient };
+use web_client::{ ThreadPool, web_config::WebConfig, http::parse_request_line, broker_client::BrokerClient };
 use std::sync::{ Mutex, Arc };
+use std::env;
+use std::process;
+use config::config_file::{ ConfigError, ConfigFile, DEFAULT_CONFIG_PATH };
 
 
+/// Path given with --config, the default config otherwise
+fn config_path(args: &[String]) -> Result<String, String> {
+    match args {
+        [] => Ok(DEFAULT_CONFIG_PATH.to_string()),
+        [flag, path] if flag == "--config" => Ok(path.clone()),
+        _ => Err("usage: main [--config PATH]".to_string()),
+    }
+}
+
+fn load_config(path: &str) -> Result<WebConfig, ConfigError> {
+    WebConfig::from_config(&ConfigFile::load(path)?)
+}
+
 fn main() {
-    let config = load_contents("src/config.yaml");
-    let host = config
-        .get("host")
-        .unwrap_or_else(|| panic!("Cannot found host in config"));
-    let port = config
-        .get("port")
-        .unwrap_or_else(|| panic!("Cannot found port in config"));
-    let broker_host = config
-        .get("broker_host")
-        .unwrap_or_else(|| panic!("Cannot found broker_host in config"));
-    let broker_port = config
-        .get("broker_port")
-        .unwrap_or_else(|| panic!("Cannot found broker_port in config"));
-    let topic = config
-        .get("topic")
-        .unwrap_or_else(|| panic!("Cannot found topic in config"));
-    let default_broker_conn_retries = String::from("10");
-    let broker_conn_retries: usize = config
-        .get("broker_conn_retries")
-        .unwrap_or_else(|| &default_broker_conn_retries).parse().unwrap();
-    let default_show_last_x_messages = String::from("10");
-    let show_last_x_messages: usize = config
-        .get("show_last_x_messages")
-        .unwrap_or_else(|| &default_show_last_x_messages).parse().unwrap();
-    let default_user = String::from("");
-    let user: String = config
-        .get("user")
-        .unwrap_or_else(|| &default_user)
-        .parse()
-        .unwrap();
-    let default_password = String::from("");
-    let password: String = config
-        .get("password")
-        .unwrap_or_else(|| &default_password)
-        .parse()
-        .unwrap();
-    
+    let args: Vec<String> = env::args().skip(1).collect();
+    let config = match config_path(&args) {
+        Ok(path) => load_config(&path).unwrap_or_else(|e| {
+            eprintln!("Cannot load config: {}", e);
+            process::exit(1);
+        }),
+        Err(usage) => {
+            eprintln!("{}", usage);
+            process::exit(2);
+        }
+    };
+    let WebConfig { host, port, topic, broker_host, broker_port, broker_conn_retries, show_last_x_messages, user, password } = config;
+    let port = port.to_string();
+    let broker_port = broker_port.to_string();
 
     // connects to broker and creates a thread to handle IO messages
     let broker_client = BrokerClient::new(broker_host.clone(), broker_port.clone(), topic.clone(), broker_conn_retries, user, password).unwrap_or_else(|e| panic!("Connection to broker failed: {}", e));
@@ -2,7 +2,7 @@ use std::thread;
 use std::sync::mpsc;
 use std::sync::Arc;
 use std::sync::Mutex;
-pub mod file_loader;
+pub mod web_config;
 pub mod http;
 pub mod broker_client;
 
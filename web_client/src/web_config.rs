@@ -0,0 +1,66 @@
+use config::config_file::{ConfigError, ConfigFile};
+use serde::Deserialize;
+
+/// Settings of the web client, read from its config file
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebConfig {
+    pub host: String,
+    pub port: u16,
+    pub topic: String, // required
+    pub broker_host: String,
+    pub broker_port: u16,
+    pub broker_conn_retries: usize,
+    pub show_last_x_messages: usize,
+    pub user: String,
+    pub password: String,
+}
+
+impl Default for WebConfig {
+    fn default() -> WebConfig {
+        WebConfig {
+            host: "127.0.0.1".to_string(),
+            port: 8888,
+            topic: String::new(),
+            broker_host: "127.0.0.1".to_string(),
+            broker_port: 1883,
+            broker_conn_retries: 10,
+            show_last_x_messages: 10,
+            user: String::new(),
+            password: String::new(),
+        }
+    }
+}
+
+impl WebConfig {
+    pub fn from_config(config: &ConfigFile) -> Result<WebConfig, ConfigError> {
+        let settings: WebConfig = config.deserialize()?;
+        if settings.topic.is_empty() {
+            return Err(config.error("topic", "Missing required key"));
+        }
+        Ok(settings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_config() {
+        let config = ConfigFile::load("src/config.yaml").unwrap();
+        let settings = WebConfig::from_config(&config).unwrap();
+        assert_eq!(settings.host, "localhost");
+        assert_eq!(settings.port, 8888);
+        assert_eq!(settings.broker_port, 3333);
+        assert_eq!(settings.show_last_x_messages, 15);
+
+        let config = ConfigFile::parse("config.yaml", "topic: test\nbroker_conn_retries: -1\n").unwrap();
+        let error = WebConfig::from_config(&config).unwrap_err();
+        assert_eq!(error.line, Some(2));
+        assert_eq!(error.key.as_deref(), Some("broker_conn_retries"));
+        let config = ConfigFile::parse("config.yaml", "topic: test\nbroker: localhost\n").unwrap();
+        let error = WebConfig::from_config(&config).unwrap_err();
+        assert_eq!(error.to_string(), "config.yaml:2: broker: Unknown key");
+    }
+}